@@ -0,0 +1,10 @@
+use maelstrom_node::Node;
+
+/// Challenge #3: Broadcast.
+fn main() {
+    maelstrom_node::cli::main(
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        |config| Node::new(config),
+    );
+}
@@ -0,0 +1,11 @@
+use maelstrom_node::kv_counter::KvCounter;
+
+/// Challenge #4: Grow-Only Counter, kept in `seq-kv` (or `lin-kv`, with
+/// `--consistency linearizable`); negative deltas work too.
+fn main() {
+    maelstrom_node::cli::main(
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        |config| KvCounter::new(config),
+    );
+}
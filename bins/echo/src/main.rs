@@ -0,0 +1,9 @@
+use maelstrom_node::echo::Echo;
+
+/// Challenge #1: Echo. Every echo stands alone, so `--workers` may spread
+/// them over several threads.
+fn main() {
+    maelstrom_node::cli::main_pooled(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), |_| {
+        Echo::new()
+    });
+}
@@ -0,0 +1,58 @@
+//! Runs the built binary, since what `--banner` must not do is touch stdout.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+#[test]
+fn the_banner_goes_to_stderr_and_leaves_stdout_to_the_protocol() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_echo"))
+        .arg("--banner")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(
+            concat!(
+                r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+                "\n",
+                r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hi"}}"#,
+                "\n",
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let kinds: Vec<_> = stdout
+        .lines()
+        .map(|line| {
+            assert!(
+                line.starts_with(r#"{"src":"n1","#),
+                "not a message: {}",
+                line
+            );
+            line.split(r#""type":""#)
+                .nth(1)
+                .and_then(|rest| rest.split('"').next())
+        })
+        .collect();
+    assert_eq!(kinds, [Some("init_ok"), Some("echo_ok")]);
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with(concat!("echo ", env!("CARGO_PKG_VERSION"), " (")),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,10 @@
+use maelstrom_node::kafka::Kafka;
+
+/// Challenge #5: Kafka-Style Log.
+fn main() {
+    maelstrom_node::cli::main(
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        |config| Kafka::new(config),
+    );
+}
@@ -0,0 +1,10 @@
+use maelstrom_node::raft::Raft;
+
+/// Maelstrom's `lin-kv` workload, served with Raft.
+fn main() {
+    maelstrom_node::cli::main(
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        |config| Raft::new(config),
+    );
+}
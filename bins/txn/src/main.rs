@@ -0,0 +1,10 @@
+use maelstrom_node::txn::Txn;
+
+/// Challenge #6: Totally-Available Transactions.
+fn main() {
+    maelstrom_node::cli::main(
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        |config| Txn::new(config),
+    );
+}
@@ -0,0 +1,10 @@
+use maelstrom_node::unique_ids::UniqueIds;
+
+/// Challenge #2: Unique ID Generation.
+fn main() {
+    maelstrom_node::cli::main(
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        |config| UniqueIds::new(config),
+    );
+}
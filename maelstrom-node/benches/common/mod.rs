@@ -0,0 +1,29 @@
+//! Timing shared by the benches. criterion isn't available, so each bench is
+//! a plain `main` that runs its cases through [`measure`].
+
+use std::time::{Duration, Instant};
+
+/// How long each case runs for after warming up.
+const BUDGET: Duration = Duration::from_secs(2);
+
+/// Calls `step` once to warm up, then repeatedly until the budget runs out,
+/// and prints the rate. `step` returns how many operations it just did.
+pub fn measure(name: &str, unit: &str, mut step: impl FnMut() -> u64) {
+    step();
+
+    let mut done = 0u64;
+    let start = Instant::now();
+    while start.elapsed() < BUDGET {
+        done += step();
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:<20} {:>10.0} {}/s {:>8.2} µs/{}",
+        name,
+        done as f64 / elapsed.as_secs_f64(),
+        unit,
+        elapsed.as_secs_f64() * 1e6 / done as f64,
+        unit
+    );
+}
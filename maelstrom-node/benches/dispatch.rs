@@ -0,0 +1,144 @@
+//! Throughput of `Runtime::dispatch` per message type: parsing a line,
+//! handling it and encoding whatever goes out, as one step. Run with
+//! `cargo bench -p maelstrom-node --bench dispatch`.
+
+mod common;
+
+use maelstrom_node::{
+    counter::GossipCounter, echo::Echo, kafka::Kafka, unique_ids::UniqueIds, Config, Handler, Node,
+    Runtime,
+};
+use std::hint::black_box;
+
+const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3"]}}"#;
+
+/// Lines dispatched per step. Large enough that msg_ids are out of the
+/// dedup window by the time they come round again.
+const LINES: u64 = 10_000;
+
+fn main() {
+    let config = Config::default();
+
+    bench("echo", Echo::new(), &[], |n| {
+        format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":{},"echo":"Please echo {}"}}}}"#,
+            n, n
+        )
+    });
+    bench("generate", UniqueIds::new(&config), &[], |n| {
+        format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"generate","msg_id":{}}}}}"#,
+            n
+        )
+    });
+    bench("topology", Node::new(&config), &[], |n| {
+        format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"topology","msg_id":{},"topology":{{"n1":["n2","n3"],"n2":["n1"],"n3":["n1"]}}}}}}"#,
+            n
+        )
+    });
+    bench("broadcast", Node::new(&config), &[], |n| {
+        format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+            n, n
+        )
+    });
+    bench("gossip", Node::new(&config), &[], |n| {
+        format!(
+            r#"{{"src":"n2","dest":"n1","body":{{"type":"gossip","msg_id":{},"messages":[{},{}]}}}}"#,
+            n,
+            2 * n,
+            2 * n + 1
+        )
+    });
+
+    // A read's cost is mostly the size of the set it answers with.
+    for size in [0, 100, 1_000] {
+        let values: Vec<String> = (0..size)
+            .map(|n| {
+                format!(
+                    r#"{{"src":"c1","dest":"n1","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+                    n + 2,
+                    n
+                )
+            })
+            .collect();
+
+        bench(
+            &format!("read/{}", size),
+            Node::new(&config),
+            &values,
+            |n| {
+                format!(
+                    r#"{{"src":"c2","dest":"n1","body":{{"type":"read","msg_id":{}}}}}"#,
+                    n
+                )
+            },
+        );
+    }
+
+    bench("add", GossipCounter::new(&config), &[], |n| {
+        format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"add","msg_id":{},"delta":{}}}}}"#,
+            n,
+            n % 7
+        )
+    });
+    bench("kafka/replicate", Kafka::new(&config), &[], |n| {
+        format!(
+            r#"{{"src":"n2","dest":"n1","body":{{"type":"replicate","msg_id":{},"key":"k{}","offset":{},"msg":{}}}}}"#,
+            n,
+            n % 10,
+            n,
+            n
+        )
+    });
+    bench("kafka/poll", Kafka::new(&config), &replicated(1_000), |n| {
+        format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"poll","msg_id":{},"offsets":{{"k{}":{}}}}}}}"#,
+            n,
+            n % 10,
+            n % 100
+        )
+    });
+}
+
+/// `count` log entries spread over ten keys, as another node would send them.
+fn replicated(count: u64) -> Vec<String> {
+    (0..count)
+        .map(|n| {
+            format!(
+                r#"{{"src":"n2","dest":"n1","body":{{"type":"replicate","msg_id":{},"key":"k{}","offset":{},"msg":{}}}}}"#,
+                n + 2,
+                n % 10,
+                n / 10,
+                n
+            )
+        })
+        .collect()
+}
+
+/// Dispatches `setup` to `handler` after its init, then times lines made by
+/// `line`. Lines are built up front so only dispatch is timed.
+fn bench<H: Handler>(name: &str, mut handler: H, setup: &[String], line: impl Fn(u64) -> String) {
+    let config = Config::default();
+    let (mut runtime, output) = Runtime::<H::Body>::in_memory(&config);
+
+    runtime.dispatch(&mut handler, INIT);
+    for line in setup {
+        runtime.dispatch(&mut handler, line);
+    }
+    output.try_iter().for_each(drop);
+
+    let lines: Vec<String> = (LINES..2 * LINES).map(line).collect();
+
+    common::measure(name, "msg", || {
+        for line in &lines {
+            runtime.dispatch(&mut handler, line);
+        }
+        output
+            .try_iter()
+            .for_each(|message| drop(black_box(message)));
+        LINES
+    });
+}
@@ -0,0 +1,38 @@
+//! Cost of building a gossip round: picking peers, filtering out what each
+//! already has, and splitting the rest into batches. Run with
+//! `cargo bench -p maelstrom-node --bench gossip`.
+
+mod common;
+
+use maelstrom_node::gossip::GossipScheduler;
+use rand::{rngs::StdRng, SeedableRng};
+use serde_json::Value;
+use std::{hint::black_box, time::Duration};
+
+fn main() {
+    let neighbors: Vec<String> = (1..=24).map(|n| format!("n{}", n)).collect();
+
+    for (name, fanout, batch_size, pending) in [
+        ("all/100", None, None, 100),
+        ("all/1000", None, None, 1_000),
+        ("fanout-3/1000", Some(3), None, 1_000),
+        ("batch-50/1000", None, Some(50), 1_000),
+    ] {
+        let mut scheduler = GossipScheduler::new(Duration::ZERO, fanout, batch_size);
+        let mut rng = StdRng::seed_from_u64(0);
+        let values: Vec<Value> = (0..pending).map(Value::from).collect();
+
+        common::measure(name, "round", || {
+            for value in &values {
+                scheduler.push(value.clone());
+            }
+
+            // Every peer is taken to have the odd values already.
+            let batches = scheduler.round(&neighbors, &mut rng, |_, value| {
+                value.as_u64().is_some_and(|n| n % 2 == 1)
+            });
+            black_box(batches);
+            1
+        });
+    }
+}
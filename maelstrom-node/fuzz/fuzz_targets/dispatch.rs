@@ -0,0 +1,26 @@
+//! Arbitrary input lines through an initialized node, as if they had come in
+//! on stdin. Whatever arrives, the node has to keep going.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use maelstrom_node::{Config, Node, Runtime};
+
+const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3"]}}"#;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let config = Config::default();
+    let mut node = Node::new(&config);
+    let (mut runtime, output) = Runtime::in_memory(&config);
+
+    runtime.dispatch(&mut node, INIT);
+
+    for line in input.lines() {
+        runtime.dispatch(&mut node, line);
+        output.try_iter().for_each(drop);
+    }
+});
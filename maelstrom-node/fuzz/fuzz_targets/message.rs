@@ -0,0 +1,31 @@
+//! Arbitrary bytes through each workload's message decoder. Decoding may fail
+//! but must not panic, and whatever decodes must encode again.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use maelstrom_node::{
+    counter::CounterBody, echo::EchoBody, kafka::KafkaBody, message::Envelope, raft::RaftBody,
+    txn::TxnBody, unique_ids::UniqueIdsBody, Codec, JsonCodec, Message, MessageBody,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+fuzz_target!(|data: &[u8]| {
+    round_trip::<EchoBody>(data);
+    round_trip::<UniqueIdsBody>(data);
+    round_trip::<MessageBody>(data);
+    round_trip::<CounterBody>(data);
+    round_trip::<KafkaBody>(data);
+    round_trip::<TxnBody>(data);
+    round_trip::<RaftBody>(data);
+});
+
+fn round_trip<B: Serialize + DeserializeOwned>(data: &[u8]) {
+    let decoded: Result<Message<Envelope<B>>, _> = JsonCodec.decode(data);
+
+    if let Ok(message) = decoded {
+        JsonCodec
+            .encode(&message)
+            .expect("a decoded message encodes again");
+    }
+}
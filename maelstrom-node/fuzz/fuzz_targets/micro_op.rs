@@ -0,0 +1,18 @@
+//! Arbitrary bytes as a transaction's micro-ops. Anything that parses has to
+//! come back out exactly as it went in.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use maelstrom_node::txn::MicroOp;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(ops) = serde_json::from_slice::<Vec<MicroOp>>(data) else {
+        return;
+    };
+
+    let encoded = serde_json::to_vec(&ops).expect("micro-ops encode");
+    let decoded: Vec<MicroOp> = serde_json::from_slice(&encoded).expect("micro-ops decode again");
+
+    assert_eq!(ops, decoded);
+});
@@ -0,0 +1,85 @@
+//! Putting a node's settings together: a workload's preset first, then
+//! whatever the environment, the command line and the binary override.
+
+use crate::config::{Config, Workload};
+
+/// Builds a handler from a [`Config`] that starts out as a workload's
+/// preset rather than the bare defaults.
+#[derive(Debug, Clone, Default)]
+pub struct NodeBuilder {
+    config: Config,
+}
+
+impl NodeBuilder {
+    /// Starts from the defaults.
+    pub fn new() -> NodeBuilder {
+        NodeBuilder::default()
+    }
+
+    /// Starts from `workload`'s preset; see [`Config::for_workload`].
+    pub fn for_workload(workload: Workload) -> NodeBuilder {
+        NodeBuilder {
+            config: Config::for_workload(workload),
+        }
+    }
+
+    /// Applies the environment and then the command line, as
+    /// [`Config::from_args`] does to the defaults.
+    pub fn with_args(self) -> NodeBuilder {
+        NodeBuilder {
+            config: self.config.with_env().with_args(),
+        }
+    }
+
+    /// Changes settings directly.
+    pub fn configure(mut self, change: impl FnOnce(&mut Config)) -> NodeBuilder {
+        change(&mut self.config);
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Builds the handler with `make`, which may adjust the settings first,
+    /// and returns it with the settings it ended up with.
+    pub fn build<H>(self, make: impl FnOnce(&mut Config) -> H) -> (Config, H) {
+        let mut config = self.config;
+        let handler = make(&mut config);
+        (config, handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echo::Echo;
+    use std::time::Duration;
+
+    #[test]
+    fn broadcast_batches_and_retries_while_echo_flushes_every_reply() {
+        let broadcast = NodeBuilder::for_workload(Workload::Broadcast);
+        let broadcast = broadcast.config();
+        assert!(broadcast.gossip_batch.is_some());
+        assert!(broadcast.gossip_retry > Duration::ZERO);
+        assert!(broadcast.flush_every > 1);
+        assert!(broadcast.flush_delay > Duration::ZERO);
+
+        let echo = NodeBuilder::for_workload(Workload::Echo);
+        let echo = echo.config();
+        assert_eq!(echo.gossip_batch, None);
+        assert_eq!(echo.workers, 1);
+        assert_eq!(echo.flush_every, 1);
+        assert_eq!(echo.flush_delay, Duration::ZERO);
+    }
+
+    #[test]
+    fn a_preset_can_be_overridden() {
+        let (config, _echo) = NodeBuilder::for_workload(Workload::Echo)
+            .configure(|config| config.workers = 4)
+            .build(|_| Echo::new());
+
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.flush_every, 1);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::vector_clock::VectorClock;
+use serde_json::Value;
+
+/// A broadcast that arrived before everything it causally depends on.
+#[derive(Debug)]
+struct Held {
+    origin: String,
+    clock: VectorClock,
+    value: Value,
+}
+
+/// Causal delivery for broadcast values. Each value travels with the vector
+/// clock its origin stamped it with, and is only delivered once every value
+/// that origin had delivered before sending it has been delivered here too.
+#[derive(Debug, Default)]
+pub struct CausalBuffer {
+    /// How many broadcasts from each origin have been delivered.
+    delivered: VectorClock,
+    held: Vec<Held>,
+}
+
+impl CausalBuffer {
+    pub fn new() -> CausalBuffer {
+        CausalBuffer::default()
+    }
+
+    /// Stamps a broadcast originating at `node_id`. It is delivered locally
+    /// straight away, since it can only depend on what is already here.
+    pub fn stamp(&mut self, node_id: &str) -> VectorClock {
+        self.delivered.increment(node_id);
+        self.delivered.clone()
+    }
+
+    /// Whether the broadcast `origin` stamped with `clock` has been received
+    /// already, delivered or not.
+    pub fn has_seen(&self, origin: &str, clock: &VectorClock) -> bool {
+        let seq = clock.get(origin);
+
+        self.delivered.get(origin) >= seq
+            || self
+                .held
+                .iter()
+                .any(|held| held.origin == origin && held.clock.get(origin) == seq)
+    }
+
+    /// Takes a broadcast from another node and returns every value that can
+    /// now be delivered, in an order that respects causality. Duplicates
+    /// come back empty.
+    pub fn receive(&mut self, origin: &str, clock: VectorClock, value: Value) -> Vec<Value> {
+        if self.has_seen(origin, &clock) {
+            return Vec::new();
+        }
+
+        self.held.push(Held {
+            origin: origin.to_string(),
+            clock,
+            value,
+        });
+
+        let mut ready = Vec::new();
+
+        while let Some(index) = self.held.iter().position(|held| self.deliverable(held)) {
+            let held = self.held.swap_remove(index);
+
+            self.delivered
+                .set(&held.origin, held.clock.get(&held.origin));
+            ready.push(held.value);
+        }
+
+        ready
+    }
+
+    /// The next broadcast from its origin, with everything it saw delivered.
+    fn deliverable(&self, held: &Held) -> bool {
+        held.clock.iter().all(|(node_id, value)| {
+            if node_id == held.origin {
+                value == self.delivered.get(node_id) + 1
+            } else {
+                value <= self.delivered.get(node_id)
+            }
+        })
+    }
+
+    /// Number of broadcasts waiting on their dependencies.
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn clock(&self) -> &VectorClock {
+        &self.delivered
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{builder::NodeBuilder, config::Config, pool, runtime::Handler, Error, Runtime};
+use std::io::{self, Write};
+
+/// Everything a challenge binary's `main` does: sets up stderr logging,
+/// parses the command line, builds the handler with `make` (which may adjust
+/// the config first), runs it until stdin closes and reports on the way out.
+/// Exits with status 1 if the run ended in an error.
+///
+/// `name` and `version` are for the `--banner` line; pass the binary's own
+/// `CARGO_PKG_NAME` and `CARGO_PKG_VERSION`. If `name` is a [`Workload`],
+/// the command line is applied over its preset instead of the defaults.
+///
+/// [`Workload`]: crate::config::Workload
+pub fn main<H: Handler>(name: &str, version: &str, make: impl FnOnce(&mut Config) -> H) {
+    let (config, mut handler) = start(name, version, make);
+
+    let result = run(&config, &mut handler);
+    finish(&config, &handler, result);
+}
+
+/// [`main`] for handlers whose requests are independent, which run on a
+/// [`pool`](crate::pool) of clones when `--workers` is over 1.
+pub fn main_pooled<H>(name: &str, version: &str, make: impl FnOnce(&mut Config) -> H)
+where
+    H: Handler + Clone + Send,
+{
+    let (config, mut handler) = start(name, version, make);
+
+    let result = match config.workers {
+        0 | 1 => run(&config, &mut handler),
+        _ => pool::run(&config, &handler),
+    };
+    finish(&config, &handler, result);
+}
+
+/// Sets up logging, parses the command line and builds the handler.
+fn start<H: Handler>(
+    name: &str,
+    version: &str,
+    make: impl FnOnce(&mut Config) -> H,
+) -> (Config, H) {
+    // Maelstrom keeps each node's stderr as its log; stdout is protocol only.
+    // Set RUST_LOG=debug for a line per message.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let builder = match name.parse() {
+        Ok(workload) => NodeBuilder::for_workload(workload),
+        Err(_) => NodeBuilder::new(),
+    }
+    .with_args();
+
+    if builder.config().banner {
+        // Best effort: a banner that can't be written isn't worth stopping for.
+        let _ = write_banner(&mut io::stderr().lock(), name, version);
+    }
+
+    builder.build(make)
+}
+
+/// Runs `handler` on the event loop, printing its traffic at the end.
+fn run<H: Handler>(config: &Config, handler: &mut H) -> Result<(), Error> {
+    let mut runtime = Runtime::new(config);
+
+    let result = runtime.run(handler);
+
+    eprintln!("metrics: {}", runtime.stats());
+
+    if config.summary {
+        runtime.print_summary();
+    }
+
+    result
+}
+
+/// Reports on the way out, exiting with status 1 if the run failed.
+fn finish<H: Handler>(config: &Config, handler: &H, result: Result<(), Error>) {
+    handler.report();
+
+    if config.summary {
+        handler.print_summary();
+    }
+
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+/// Writes a single line identifying this build to `out`, which is stderr
+/// outside tests. `GIT_HASH` is read at compile time, e.g.
+/// `GIT_HASH=$(git rev-parse --short HEAD) cargo build`.
+fn write_banner(out: &mut impl Write, name: &str, version: &str) -> io::Result<()> {
+    writeln!(
+        out,
+        "{} {} ({})",
+        name,
+        version,
+        option_env!("GIT_HASH").unwrap_or("unknown")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_banner_is_one_line_naming_the_build() {
+        let mut out = Vec::new();
+        write_banner(&mut out, "echo", "1.2.3").unwrap();
+
+        let banner = String::from_utf8(out).unwrap();
+        assert_eq!(
+            banner,
+            format!(
+                "echo 1.2.3 ({})\n",
+                option_env!("GIT_HASH").unwrap_or("unknown")
+            )
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{error::Error, message::Message};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Wire format for messages on stdin/stdout. Each encoded message is one line;
+/// `encode` must not emit the trailing newline.
+pub trait Codec<B> {
+    fn encode(&self, message: &Message<B>) -> Result<Vec<u8>, Error>;
+    fn decode(&self, line: &[u8]) -> Result<Message<B>, Error>;
+
+    /// Appends the encoded message to `buf`, so one buffer can be reused for
+    /// every message instead of allocating each time. On error, `buf` may
+    /// hold part of the message.
+    fn encode_into(&self, message: &Message<B>, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.extend_from_slice(&self.encode(message)?);
+        Ok(())
+    }
+}
+
+/// The default codec, plain `serde_json`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl<B: Serialize + DeserializeOwned> Codec<B> for JsonCodec {
+    fn encode(&self, message: &Message<B>) -> Result<Vec<u8>, Error> {
+        Ok(serde_json::to_vec(message)?)
+    }
+
+    fn decode(&self, line: &[u8]) -> Result<Message<B>, Error> {
+        Ok(serde_json::from_slice(line)?)
+    }
+
+    fn encode_into(&self, message: &Message<B>, buf: &mut Vec<u8>) -> Result<(), Error> {
+        Ok(serde_json::to_writer(buf, message)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        counter::CounterBody, echo::EchoBody, kafka::KafkaBody, message::Envelope, txn::TxnBody,
+        MessageBody,
+    };
+    use serde_json::Value;
+
+    /// A second codec to hold the default against: everything goes through
+    /// an intermediate `serde_json::Value`, which reorders object keys and
+    /// takes numbers apart and back together.
+    struct ValueCodec;
+
+    impl<B: Serialize + DeserializeOwned> Codec<B> for ValueCodec {
+        fn encode(&self, message: &Message<B>) -> Result<Vec<u8>, Error> {
+            Ok(serde_json::to_vec(&serde_json::to_value(message)?)?)
+        }
+
+        fn decode(&self, line: &[u8]) -> Result<Message<B>, Error> {
+            let value: Value = serde_json::from_slice(line)?;
+            Ok(serde_json::from_value(value)?)
+        }
+    }
+
+    const NODE: &[&str] = &[
+        r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#,
+        r#"{"src":"n1","dest":"c0","body":{"type":"init_ok","msg_id":1,"in_reply_to":1}}"#,
+        r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":3,"message":{"nested":[1,2.5,null]}}}"#,
+        r#"{"src":"n1","dest":"c1","body":{"type":"read_ok","msg_id":4,"in_reply_to":3,"messages":[1,18446744073709551615,"x"]}}"#,
+        r#"{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":5,"topology":{"n1":["n2"],"n2":["n1"]}}}"#,
+        r#"{"src":"n2","dest":"n1","body":{"type":"gossip","msg_id":6,"messages":[-1,0,1]}}"#,
+        r#"{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":7,"code":20,"text":"key does not exist"}}"#,
+    ];
+
+    const ECHO: &[&str] = &[
+        r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"héllo \"quoted\""}}"#,
+    ];
+
+    const COUNTER: &[&str] = &[
+        r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":8,"delta":-9223372036854775808}}"#,
+        r#"{"src":"n1","dest":"c1","body":{"type":"read_ok","msg_id":9,"in_reply_to":8,"value":-1}}"#,
+    ];
+
+    const KAFKA: &[&str] = &[
+        r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":1,"key":"k","msg":123}}"#,
+        r#"{"src":"c1","dest":"n1","body":{"type":"poll","msg_id":2,"offsets":{"k":0,"j":18446744073709551615}}}"#,
+        r#"{"src":"n0","dest":"n1","body":{"type":"heartbeat","msg_id":3,"term":2}}"#,
+    ];
+
+    const TXN: &[&str] = &[
+        r#"{"src":"c1","dest":"n1","body":{"type":"txn","msg_id":1,"txn":[["r",1,null],["w",1,6]]}}"#,
+    ];
+
+    /// Decodes `line` with `decoder` and normalises the result, so messages
+    /// can be compared without `PartialEq`.
+    fn decoded<B: Serialize + DeserializeOwned>(decoder: &dyn Codec<B>, line: &[u8]) -> Value {
+        let message = decoder.decode(line).expect("sample decodes");
+        serde_json::to_value(message).expect("sample re-encodes")
+    }
+
+    fn check<B: Serialize + DeserializeOwned>(lines: &[&str]) {
+        let codecs: [&dyn Codec<Envelope<B>>; 2] = [&JsonCodec, &ValueCodec];
+
+        for line in lines {
+            let expected = decoded(&JsonCodec as &dyn Codec<Envelope<B>>, line.as_bytes());
+
+            for encoder in codecs {
+                for decoder in codecs {
+                    let message = decoder.decode(line.as_bytes()).expect("sample decodes");
+                    let encoded = encoder.encode(&message).expect("sample encodes");
+
+                    assert_eq!(decoded(decoder, &encoded), expected, "{}", line);
+                    assert!(!encoded.contains(&b'\n'), "{}", line);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn codecs_round_trip_to_the_same_messages() {
+        check::<MessageBody>(NODE);
+        check::<EchoBody>(ECHO);
+        check::<CounterBody>(COUNTER);
+        check::<KafkaBody>(KAFKA);
+        check::<TxnBody>(TXN);
+    }
+
+    #[test]
+    fn encode_into_appends_what_encode_returns() {
+        let message: Message<Envelope<EchoBody>> = JsonCodec.decode(ECHO[0].as_bytes()).unwrap();
+        let mut buf = b"prefix".to_vec();
+
+        JsonCodec.encode_into(&message, &mut buf).unwrap();
+
+        assert_eq!(&buf[..6], b"prefix");
+        assert_eq!(buf[6..], JsonCodec.encode(&message).unwrap());
+    }
+
+    fn encoded<B: Serialize + DeserializeOwned>(body: B) -> String {
+        let message = Message {
+            src: "n1".to_string(),
+            dest: "c1".to_string(),
+            body: Envelope::Body(body),
+        };
+
+        String::from_utf8(JsonCodec.encode(&message).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn large_offsets_and_counts_encode_as_bare_integers() {
+        let send_ok = encoded(KafkaBody::SendOk {
+            msg_id: 1,
+            in_reply_to: 1,
+            offset: u64::MAX,
+        });
+        assert!(
+            send_ok.contains(r#""offset":18446744073709551615}"#),
+            "{}",
+            send_ok
+        );
+
+        let poll_ok = encoded(KafkaBody::PollOk {
+            msg_id: 1,
+            in_reply_to: 1,
+            msgs: [("k".to_string(), vec![(u64::MAX, Value::from(u64::MAX))])].into(),
+        });
+        assert!(
+            poll_ok.contains("[[18446744073709551615,18446744073709551615]]"),
+            "{}",
+            poll_ok
+        );
+
+        for value in [i64::MAX, i64::MIN] {
+            let read_ok = encoded(CounterBody::ReadOk {
+                msg_id: 1,
+                in_reply_to: 1,
+                value: value.into(),
+            });
+            assert!(
+                read_ok.contains(&format!(r#""value":{}}}"#, value)),
+                "{}",
+                read_ok
+            );
+        }
+    }
+}
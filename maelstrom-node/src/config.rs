@@ -0,0 +1,474 @@
+use crate::{
+    hash::fnv1a, ids::IdFormat, kv_counter::CounterConsistency, topology::TopologyStrategy,
+};
+use log::warn;
+use rand::{rngs::StdRng, SeedableRng};
+use std::{str::FromStr, time::Duration};
+
+/// Command-line switches for the node. Maelstrom passes none by default, so
+/// every option here is off (or at its default) unless given explicitly.
+///
+/// The gossip tuning knobs can also be set through environment variables
+/// (see [`Config::from_env`]), which is handier when Maelstrom launches the
+/// binary. A flag wins over its variable.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub banner: bool,
+    pub summary: bool,
+    pub set_soft_cap: Option<usize>,
+    pub debug_topology: bool,
+    pub max_value: Option<u64>,
+    pub trace_out: Option<String>,
+    /// Where to keep state snapshots; see [`crate::persist`].
+    pub snapshot_dir: Option<String>,
+    pub snapshot_interval: Duration,
+    pub parse_error_threshold: usize,
+    pub tick_interval: Duration,
+    pub gossip_interval: Duration,
+    pub gossip_fanout: Option<usize>,
+    pub gossip_batch: Option<usize>,
+    /// Most gossip messages a node sends per tick; over this, gossip to the
+    /// same peer is merged and the rest waits.
+    pub gossip_per_tick: Option<usize>,
+    /// Longest gossip, or a transaction's replicated writes, waits for an
+    /// ack before it is resent. Once a peer's round-trip time is known,
+    /// gossip to it is resent sooner; see [`crate::rtt`].
+    pub gossip_retry: Duration,
+    /// How many times in a row gossip to a neighbor may be resent, or the
+    /// neighbor probed, without an answer before what is waiting for it is
+    /// dead-lettered and no longer retried. Unlimited unless given.
+    pub gossip_max_retries: Option<u32>,
+    /// How long dead-lettered gossip waits before it is retried again, in
+    /// case the neighbor has come back; doubled each time it is given up on
+    /// again. Off unless given, so dead letters stay dead.
+    pub dead_letter_retry: Option<Duration>,
+    /// How many times a peer's estimated round-trip time one ack may count
+    /// for, so one very late ack doesn't inflate the estimate.
+    pub rtt_outlier_multiple: f64,
+    /// How often to compare broadcast-set digests with a random neighbor;
+    /// off unless given.
+    pub sync_interval: Option<Duration>,
+    /// Deliver broadcast values in causal order, flooding each one with its
+    /// origin's vector clock instead of gossiping batches.
+    pub causal_broadcast: bool,
+    /// How many times a causal broadcast may be passed on after leaving its
+    /// origin; unlimited unless given.
+    ///
+    /// A limit below the overlay's diameter less one would leave the
+    /// farthest nodes without the value, which gossip can't make up for in
+    /// causal mode. So each node raises it to that, with a warning, whenever
+    /// its overlay changes.
+    pub max_hops: Option<u32>,
+    pub topology: TopologyStrategy,
+    /// What `generate` answers with.
+    pub id_format: IdFormat,
+    /// How the KV-backed counter keeps its value.
+    pub counter_consistency: CounterConsistency,
+    /// Whether a client's `broadcast`, `add` or `send` is answered before or
+    /// after it is passed on.
+    pub ack: AckStrategy,
+    /// How long a request sent with `Context::rpc` waits for its reply.
+    pub rpc_timeout: Duration,
+    /// Most `Context::rpc` requests waiting on replies at once; past it, a
+    /// new one fails straight away instead of being sent. Unlimited unless
+    /// given.
+    pub max_pending_rpcs: Option<usize>,
+    /// How long a peer can stay silent before a Kafka or Raft node stops
+    /// waiting on it: to elect a new leader, or to skip a gap in a log.
+    pub peer_timeout: Duration,
+    /// After SIGTERM, how long to keep going while requests are still
+    /// waiting on replies before exiting anyway.
+    pub shutdown_grace: Duration,
+    /// Most input events, and separately most outgoing messages, queued at
+    /// once before stdin stops being read or sending waits on stdout.
+    pub queue_capacity: usize,
+    /// How many threads handle messages, for binaries that support it; see
+    /// [`crate::pool`]. At 1, the default, everything runs on the event loop.
+    pub workers: usize,
+    /// Most messages to peer nodes buffered before stdout is flushed.
+    /// Anything for a client or service is flushed straight away.
+    pub flush_every: usize,
+    /// Longest a message to a peer node is buffered before stdout is
+    /// flushed; zero flushes whenever nothing else is waiting to go out.
+    pub flush_delay: Duration,
+    /// Longest a message to a client or service is buffered so that others
+    /// sent soon after share its flush; zero, the default, flushes each one
+    /// as soon as it is written.
+    pub coalesce_window: Duration,
+    /// How many recent client requests are remembered, so a retransmitted
+    /// one is answered again rather than reprocessed; 0 turns this off. A
+    /// request still unanswered after `rpc_timeout` is processed afresh.
+    pub dedup_window: usize,
+    /// Seeds every random choice a node makes, so a run can be repeated
+    /// exactly; `None` seeds from the OS. See [`node_rng`].
+    pub seed: Option<u64>,
+    /// Answer message types the node doesn't know with `not-supported`
+    /// rather than only logging them.
+    pub reply_unsupported: bool,
+    /// Most entries a Kafka `poll_ok` returns for any one key.
+    pub poll_max_per_key: Option<usize>,
+    /// Most entries a Kafka `poll_ok` returns across all its keys.
+    pub poll_max: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            banner: false,
+            summary: false,
+            set_soft_cap: None,
+            debug_topology: false,
+            max_value: None,
+            trace_out: None,
+            snapshot_dir: None,
+            snapshot_interval: Duration::from_secs(1),
+            parse_error_threshold: 10,
+            tick_interval: Duration::from_millis(100),
+            gossip_interval: Duration::from_millis(100),
+            gossip_fanout: None,
+            gossip_batch: None,
+            gossip_per_tick: None,
+            gossip_retry: Duration::from_millis(500),
+            gossip_max_retries: None,
+            dead_letter_retry: None,
+            rtt_outlier_multiple: 3.0,
+            sync_interval: None,
+            causal_broadcast: false,
+            max_hops: None,
+            topology: TopologyStrategy::Given,
+            id_format: IdFormat::Ulid,
+            counter_consistency: CounterConsistency::Eventual,
+            ack: AckStrategy::Eager,
+            rpc_timeout: Duration::from_secs(1),
+            max_pending_rpcs: None,
+            peer_timeout: Duration::from_secs(2),
+            shutdown_grace: Duration::from_millis(500),
+            queue_capacity: 1024,
+            workers: 1,
+            flush_every: 64,
+            flush_delay: Duration::from_millis(5),
+            coalesce_window: Duration::ZERO,
+            dedup_window: 1024,
+            seed: None,
+            reply_unsupported: false,
+            poll_max_per_key: None,
+            poll_max: None,
+        }
+    }
+}
+
+impl Config {
+    /// The defaults, with the environment applied as [`Config::from_env`]
+    /// does and then the command line.
+    pub fn from_args() -> Config {
+        Config::default().with_env().with_args()
+    }
+
+    /// The settings `workload` runs best with; see [`Workload`].
+    pub fn for_workload(workload: Workload) -> Config {
+        let defaults = Config::default();
+
+        match workload {
+            // Many small messages between peers: batch values up and resend
+            // what isn't acked.
+            Workload::Broadcast => Config {
+                gossip_interval: Duration::from_millis(100),
+                gossip_batch: Some(256),
+                gossip_retry: Duration::from_millis(500),
+                flush_every: 64,
+                flush_delay: Duration::from_millis(5),
+                ..defaults
+            },
+            // Only client requests, each answered on its own.
+            Workload::Echo | Workload::UniqueIds => Config {
+                gossip_batch: None,
+                workers: 1,
+                flush_every: 1,
+                flush_delay: Duration::ZERO,
+                ..defaults
+            },
+            Workload::Counter | Workload::Kafka | Workload::Txn | Workload::LinKv => defaults,
+        }
+    }
+
+    /// These settings, with the command line applied over them.
+    pub fn with_args(self) -> Config {
+        let mut config = self;
+
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--banner" => config.banner = true,
+                "--summary" => config.summary = true,
+                "--debug-topology" => config.debug_topology = true,
+                "--reply-unsupported" => config.reply_unsupported = true,
+                "--causal" => config.causal_broadcast = true,
+                "--max-hops" => config.max_hops = args.next().and_then(|value| value.parse().ok()),
+                "--max-value" => {
+                    config.max_value = args.next().and_then(|value| value.parse().ok());
+                }
+                "--trace-out" => config.trace_out = args.next(),
+                "--snapshot-dir" => config.snapshot_dir = args.next(),
+                "--snapshot-ms" => {
+                    if let Some(ms) = args.next().and_then(|value| value.parse().ok()) {
+                        config.snapshot_interval = Duration::from_millis(ms);
+                    }
+                }
+                "--parse-error-threshold" => {
+                    if let Some(threshold) = args.next().and_then(|value| value.parse().ok()) {
+                        config.parse_error_threshold = threshold;
+                    }
+                }
+                "--tick-ms" => {
+                    if let Some(ms) = args.next().and_then(|value| value.parse().ok()) {
+                        config.tick_interval = Duration::from_millis(ms);
+                    }
+                }
+                "--gossip-ms" => {
+                    if let Some(ms) = args.next().and_then(|value| value.parse().ok()) {
+                        config.gossip_interval = Duration::from_millis(ms);
+                    }
+                }
+                "--retry-ms" => {
+                    if let Some(ms) = args.next().and_then(|value| value.parse().ok()) {
+                        config.gossip_retry = Duration::from_millis(ms);
+                    }
+                }
+                "--max-retries" => {
+                    config.gossip_max_retries = args.next().and_then(|value| value.parse().ok());
+                }
+                "--dead-letter-retry-ms" => {
+                    config.dead_letter_retry = args
+                        .next()
+                        .and_then(|value| value.parse().ok())
+                        .map(Duration::from_millis);
+                }
+                "--rtt-outlier-multiple" => {
+                    if let Some(multiple) = args.next().and_then(|value| value.parse().ok()) {
+                        config.rtt_outlier_multiple = multiple;
+                    }
+                }
+                "--sync-ms" => {
+                    config.sync_interval = args
+                        .next()
+                        .and_then(|value| value.parse().ok())
+                        .map(Duration::from_millis);
+                }
+                "--rpc-timeout-ms" => {
+                    if let Some(ms) = args.next().and_then(|value| value.parse().ok()) {
+                        config.rpc_timeout = Duration::from_millis(ms);
+                    }
+                }
+                "--max-pending-rpcs" => {
+                    config.max_pending_rpcs = args.next().and_then(|value| value.parse().ok());
+                }
+                "--peer-timeout-ms" => {
+                    if let Some(ms) = args.next().and_then(|value| value.parse().ok()) {
+                        config.peer_timeout = Duration::from_millis(ms);
+                    }
+                }
+                "--shutdown-grace-ms" => {
+                    if let Some(ms) = args.next().and_then(|value| value.parse().ok()) {
+                        config.shutdown_grace = Duration::from_millis(ms);
+                    }
+                }
+                "--queue-capacity" => {
+                    if let Some(capacity) = args.next().and_then(|value| value.parse().ok()) {
+                        config.queue_capacity = capacity;
+                    }
+                }
+                "--workers" => {
+                    if let Some(workers) = args.next().and_then(|value| value.parse().ok()) {
+                        config.workers = workers;
+                    }
+                }
+                "--flush-every" => {
+                    if let Some(count) = args.next().and_then(|value| value.parse().ok()) {
+                        config.flush_every = count;
+                    }
+                }
+                "--flush-ms" => {
+                    if let Some(ms) = args.next().and_then(|value| value.parse().ok()) {
+                        config.flush_delay = Duration::from_millis(ms);
+                    }
+                }
+                "--coalesce-ms" => {
+                    if let Some(ms) = args.next().and_then(|value| value.parse().ok()) {
+                        config.coalesce_window = Duration::from_millis(ms);
+                    }
+                }
+                "--dedup-window" => {
+                    if let Some(window) = args.next().and_then(|value| value.parse().ok()) {
+                        config.dedup_window = window;
+                    }
+                }
+                "--seed" => config.seed = args.next().and_then(|value| value.parse().ok()),
+                "--gossip-fanout" => {
+                    config.gossip_fanout = args.next().and_then(|value| value.parse().ok());
+                }
+                "--gossip-batch" => {
+                    config.gossip_batch = args.next().and_then(|value| value.parse().ok());
+                }
+                "--gossip-max-per-tick" => {
+                    config.gossip_per_tick = args.next().and_then(|value| value.parse().ok());
+                }
+                "--poll-max-per-key" => {
+                    config.poll_max_per_key = args.next().and_then(|value| value.parse().ok());
+                }
+                "--poll-max" => {
+                    config.poll_max = args.next().and_then(|value| value.parse().ok());
+                }
+                "--topology" => match args.next().map(|value| value.parse()) {
+                    Some(Ok(strategy)) => config.topology = strategy,
+                    Some(Err(error)) => warn!("{}", error),
+                    None => {}
+                },
+                "--ids" => match args.next().map(|value| value.parse()) {
+                    Some(Ok(format)) => config.id_format = format,
+                    Some(Err(error)) => warn!("{}", error),
+                    None => {}
+                },
+                "--consistency" => match args.next().map(|value| value.parse()) {
+                    Some(Ok(consistency)) => config.counter_consistency = consistency,
+                    Some(Err(error)) => warn!("{}", error),
+                    None => {}
+                },
+                "--ack" => match args.next().map(|value| value.parse()) {
+                    Some(Ok(ack)) => config.ack = ack,
+                    Some(Err(error)) => warn!("{}", error),
+                    None => {}
+                },
+                "--set-soft-cap" => {
+                    config.set_soft_cap = args.next().and_then(|value| value.parse().ok());
+                }
+                other => warn!("ignoring unknown argument {}", other),
+            }
+        }
+
+        config
+    }
+
+    /// The defaults, with the environment applied; see [`Config::with_env`].
+    pub fn from_env() -> Config {
+        Config::default().with_env()
+    }
+
+    /// These settings, with any of these environment variables applied:
+    ///
+    /// - `GOSSIP_INTERVAL_MS`, as `--gossip-ms`
+    /// - `GOSSIP_RETRY_MS`, as `--retry-ms`
+    /// - `GOSSIP_BATCH`, as `--gossip-batch`
+    /// - `GOSSIP_FANOUT`, as `--gossip-fanout`
+    /// - `GOSSIP_MAX_PER_TICK`, as `--gossip-max-per-tick`
+    /// - `TOPOLOGY`, as `--topology`
+    /// - `SEED`, as `--seed`
+    pub fn with_env(self) -> Config {
+        let mut config = self;
+
+        if let Some(ms) = env_var("GOSSIP_INTERVAL_MS") {
+            config.gossip_interval = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_var("GOSSIP_RETRY_MS") {
+            config.gossip_retry = Duration::from_millis(ms);
+        }
+        if let Some(batch) = env_var("GOSSIP_BATCH") {
+            config.gossip_batch = Some(batch);
+        }
+        if let Some(fanout) = env_var("GOSSIP_FANOUT") {
+            config.gossip_fanout = Some(fanout);
+        }
+        if let Some(cap) = env_var("GOSSIP_MAX_PER_TICK") {
+            config.gossip_per_tick = Some(cap);
+        }
+        if let Some(strategy) = env_var("TOPOLOGY") {
+            config.topology = strategy;
+        }
+        if let Some(seed) = env_var("SEED") {
+            config.seed = Some(seed);
+        }
+
+        config
+    }
+}
+
+/// One of the challenges, each with its own [`Config::for_workload`] preset.
+/// Parsed from the binary's name, such as `unique-ids`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    Echo,
+    UniqueIds,
+    Broadcast,
+    Counter,
+    Kafka,
+    Txn,
+    LinKv,
+}
+
+impl FromStr for Workload {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "echo" => Ok(Workload::Echo),
+            "unique-ids" => Ok(Workload::UniqueIds),
+            "broadcast" => Ok(Workload::Broadcast),
+            "counter" => Ok(Workload::Counter),
+            "kafka" => Ok(Workload::Kafka),
+            "txn" => Ok(Workload::Txn),
+            "lin-kv" => Ok(Workload::LinKv),
+            _ => Err(format!("unknown workload {}", value)),
+        }
+    }
+}
+
+/// When a request that changes state is answered, relative to the messages
+/// it causes to other nodes or services.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AckStrategy {
+    /// Answer first, then pass the change on, for the lowest client latency.
+    #[default]
+    Eager,
+    /// Apply the change and send it on first, so the client only hears back
+    /// once it is on its way to the rest of the cluster. Gossip that is
+    /// normally batched goes out straight away instead.
+    Lazy,
+}
+
+impl FromStr for AckStrategy {
+    type Err = String;
+
+    /// Parses `eager` or `lazy`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "eager" => Ok(AckStrategy::Eager),
+            "lazy" => Ok(AckStrategy::Lazy),
+            _ => Err(format!("unknown ack strategy {}", value)),
+        }
+    }
+}
+
+/// The random number generator for `node_id`. With a seed, each node's
+/// sequence is fixed but differs from every other node's, since the node id is
+/// mixed in. That only varies seeds: IDs themselves are kept apart by node
+/// index, see [`crate::ids`].
+pub fn node_rng(seed: Option<u64>, node_id: &str) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed ^ fnv1a(node_id.as_bytes())),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// Parses environment variable `name`, warning about and ignoring a value
+/// that doesn't parse.
+fn env_var<T: FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            warn!("ignoring invalid {}={}", name, value);
+            None
+        }
+    }
+}
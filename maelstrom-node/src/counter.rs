@@ -0,0 +1,315 @@
+use crate::{
+    config::{AckStrategy, Config},
+    crdt::{Merge, PnCounter},
+    kv::{KvBody, KvOk},
+    message::{Body, Message},
+    runtime::{Context, DebugState, Handler},
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+/// Message types of the counter workload (challenge #4), plus the KV service
+/// requests [`KvCounter`](crate::kv_counter::KvCounter) makes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CounterBody {
+    /// `delta` may be negative.
+    Add {
+        msg_id: u32,
+        delta: i64,
+    },
+    AddOk {
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    /// Without a key, a client asking for the counter's value; with one, a
+    /// request to a KV service.
+    Read {
+        msg_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    ReadOk {
+        #[serde(default)]
+        msg_id: u32,
+        in_reply_to: u32,
+        value: Value,
+    },
+    /// Node-to-node: the sender's whole counter state, to be merged.
+    CounterSync {
+        msg_id: u32,
+        #[serde(flatten)]
+        counter: PnCounter,
+    },
+    Write {
+        msg_id: u32,
+        key: String,
+        value: Value,
+    },
+    WriteOk {
+        #[serde(default)]
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    Cas {
+        msg_id: u32,
+        key: String,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+    CasOk {
+        #[serde(default)]
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+impl Body for CounterBody {
+    fn kind(&self) -> &'static str {
+        match self {
+            CounterBody::Add { .. } => "add",
+            CounterBody::AddOk { .. } => "add_ok",
+            CounterBody::Read { .. } => "read",
+            CounterBody::ReadOk { .. } => "read_ok",
+            CounterBody::CounterSync { .. } => "counter_sync",
+            CounterBody::Write { .. } => "write",
+            CounterBody::WriteOk { .. } => "write_ok",
+            CounterBody::Cas { .. } => "cas",
+            CounterBody::CasOk { .. } => "cas_ok",
+            CounterBody::Unsupported => "unsupported",
+        }
+    }
+
+    fn msg_id(&self) -> Option<u32> {
+        match self {
+            CounterBody::Add { msg_id, .. }
+            | CounterBody::AddOk { msg_id, .. }
+            | CounterBody::Read { msg_id, .. }
+            | CounterBody::ReadOk { msg_id, .. }
+            | CounterBody::CounterSync { msg_id, .. }
+            | CounterBody::Write { msg_id, .. }
+            | CounterBody::WriteOk { msg_id, .. }
+            | CounterBody::Cas { msg_id, .. }
+            | CounterBody::CasOk { msg_id, .. } => Some(*msg_id),
+            CounterBody::Unsupported => None,
+        }
+    }
+
+    fn in_reply_to(&self) -> Option<u32> {
+        match self {
+            CounterBody::AddOk { in_reply_to, .. }
+            | CounterBody::ReadOk { in_reply_to, .. }
+            | CounterBody::WriteOk { in_reply_to, .. }
+            | CounterBody::CasOk { in_reply_to, .. } => Some(*in_reply_to),
+            CounterBody::Add { .. }
+            | CounterBody::Read { .. }
+            | CounterBody::CounterSync { .. }
+            | CounterBody::Write { .. }
+            | CounterBody::Cas { .. }
+            | CounterBody::Unsupported => None,
+        }
+    }
+
+    fn set_msg_id(&mut self, id: u32) {
+        match self {
+            CounterBody::Add { msg_id, .. }
+            | CounterBody::AddOk { msg_id, .. }
+            | CounterBody::Read { msg_id, .. }
+            | CounterBody::ReadOk { msg_id, .. }
+            | CounterBody::CounterSync { msg_id, .. }
+            | CounterBody::Write { msg_id, .. }
+            | CounterBody::WriteOk { msg_id, .. }
+            | CounterBody::Cas { msg_id, .. }
+            | CounterBody::CasOk { msg_id, .. } => *msg_id = id,
+            CounterBody::Unsupported => {}
+        }
+    }
+
+    fn set_in_reply_to(&mut self, id: u32) {
+        match self {
+            CounterBody::AddOk { in_reply_to, .. }
+            | CounterBody::ReadOk { in_reply_to, .. }
+            | CounterBody::WriteOk { in_reply_to, .. }
+            | CounterBody::CasOk { in_reply_to, .. } => *in_reply_to = id,
+            CounterBody::Add { .. }
+            | CounterBody::Read { .. }
+            | CounterBody::CounterSync { .. }
+            | CounterBody::Write { .. }
+            | CounterBody::Cas { .. }
+            | CounterBody::Unsupported => {}
+        }
+    }
+
+    fn is_unsupported(&self) -> bool {
+        matches!(self, CounterBody::Unsupported)
+    }
+}
+
+impl KvBody for CounterBody {
+    fn kv_read(key: String) -> Self {
+        CounterBody::Read {
+            msg_id: 0,
+            key: Some(key),
+        }
+    }
+
+    fn kv_write(key: String, value: Value) -> Self {
+        CounterBody::Write {
+            msg_id: 0,
+            key,
+            value,
+        }
+    }
+
+    fn kv_cas(key: String, from: Value, to: Value, create_if_not_exists: bool) -> Self {
+        CounterBody::Cas {
+            msg_id: 0,
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        }
+    }
+
+    fn kv_reply(&self) -> Option<KvOk> {
+        match self {
+            CounterBody::ReadOk { value, .. } => Some(KvOk::Read(value.clone())),
+            CounterBody::WriteOk { .. } => Some(KvOk::Write),
+            CounterBody::CasOk { .. } => Some(KvOk::Cas),
+            _ => None,
+        }
+    }
+}
+
+/// Counter gossiped between nodes as a [`PnCounter`], with no KV service
+/// involved. Every node adds to its own slot, so merges never conflict, and a
+/// `read` answers straight away with whatever this node has heard of so far.
+pub struct GossipCounter {
+    id: String,
+    peers: Vec<String>,
+    counter: PnCounter,
+    sync_interval: Duration,
+    last_sync: Instant,
+    ack: AckStrategy,
+}
+
+impl GossipCounter {
+    pub fn new(config: &Config) -> GossipCounter {
+        GossipCounter {
+            id: String::new(),
+            peers: Vec::new(),
+            counter: PnCounter::default(),
+            sync_interval: config.gossip_interval,
+            last_sync: Instant::now(),
+            ack: config.ack,
+        }
+    }
+
+    /// The value as this node currently sees it.
+    pub fn value(&self) -> i64 {
+        self.counter.value()
+    }
+
+    /// Sends our counter totals to every other node.
+    fn sync(&mut self, ctx: &mut Context<CounterBody>) {
+        self.last_sync = Instant::now();
+
+        for peer in &self.peers {
+            ctx.send(
+                peer,
+                CounterBody::CounterSync {
+                    msg_id: 0,
+                    counter: self.counter.clone(),
+                },
+            );
+        }
+    }
+}
+
+impl Handler for GossipCounter {
+    type Body = CounterBody;
+
+    fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        self.id = node_id.to_string();
+        self.peers = node_ids
+            .iter()
+            .filter(|node| *node != node_id)
+            .cloned()
+            .collect();
+    }
+
+    fn handle(&mut self, message: Message<CounterBody>, ctx: &mut Context<CounterBody>) {
+        match &message.body {
+            CounterBody::Add { delta, .. } => {
+                let ok = CounterBody::AddOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                };
+
+                if self.ack == AckStrategy::Eager {
+                    ctx.reply(&message, ok.clone());
+                }
+
+                self.counter.add(&self.id, *delta);
+
+                if self.ack == AckStrategy::Lazy {
+                    self.sync(ctx);
+                    ctx.reply(&message, ok);
+                }
+            }
+
+            CounterBody::Read { .. } => {
+                ctx.reply(
+                    &message,
+                    CounterBody::ReadOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        value: self.counter.value().into(),
+                    },
+                );
+            }
+
+            CounterBody::CounterSync { counter, .. } => {
+                self.counter.merge(counter);
+            }
+
+            _ => {}
+        }
+    }
+
+    /// Sends our counter totals to every other node once per sync interval.
+    /// The whole state goes out each time, so a lost sync is simply repaired
+    /// by the next one.
+    fn tick(&mut self, ctx: &mut Context<CounterBody>) {
+        if self.counter.is_empty() || self.last_sync.elapsed() < self.sync_interval {
+            return;
+        }
+
+        self.sync(ctx);
+    }
+
+    fn snapshot(&self) -> Option<Value> {
+        serde_json::to_value(&self.counter).ok()
+    }
+
+    fn restore(&mut self, state: Value) {
+        match serde_json::from_value::<PnCounter>(state) {
+            Ok(counter) => self.counter.merge(&counter),
+            Err(error) => warn!("ignoring malformed snapshot: {}", error),
+        }
+    }
+
+    fn print_summary(&self) {
+        eprintln!("summary: counter value {}", self.counter.value());
+    }
+
+    fn debug_state(&self, state: &mut DebugState) {
+        state.counter = Some(self.counter.value());
+    }
+}
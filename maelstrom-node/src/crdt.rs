@@ -0,0 +1,415 @@
+//! State-based CRDTs: values that nodes can update independently and then
+//! reconcile by [merging](Merge) whole copies, in any order and any number of
+//! times, always ending up in the same state. Each type serializes so it can
+//! be shipped to peers as is.
+
+use crate::mvcc::Version;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+/// Folding another replica's state into this one. Implementations are
+/// commutative, associative and idempotent, so anti-entropy can resend and
+/// reorder state freely.
+pub trait Merge {
+    fn merge(&mut self, other: &Self);
+}
+
+/// A grow-only set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GSet<T: Eq + Hash> {
+    items: HashSet<T>,
+}
+
+impl<T: Eq + Hash> Default for GSet<T> {
+    fn default() -> GSet<T> {
+        GSet {
+            items: HashSet::new(),
+        }
+    }
+}
+
+impl<T: Eq + Hash> GSet<T> {
+    /// Adds `item`, returning whether it is new.
+    pub fn insert(&mut self, item: T) -> bool {
+        self.items.insert(item)
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.items.contains(item)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+impl<T: Eq + Hash> FromIterator<T> for GSet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> GSet<T> {
+        GSet {
+            items: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> Merge for GSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.items.extend(other.items.iter().cloned());
+    }
+}
+
+/// Identifies one `add` to an [`OrSet`]: the node that made it and a
+/// sequence number of that node's.
+pub type Tag = (String, u64);
+
+/// An observed-remove set. Every add is tagged, and a remove only cancels
+/// the adds it has seen, so an add that is concurrent with a remove wins.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "OrSetState<T>")]
+pub struct OrSet<T: Eq + Hash> {
+    adds: HashSet<(T, Tag)>,
+    removed: HashSet<Tag>,
+    /// The next sequence number for this replica's own tags: above every
+    /// tag it has seen, so a replica restored from a copy of its own old
+    /// state, which doesn't carry this, never reuses one.
+    #[serde(skip_serializing)]
+    next_seq: u64,
+}
+
+/// What an [`OrSet`] is shipped as.
+#[derive(Deserialize)]
+struct OrSetState<T: Eq + Hash> {
+    adds: HashSet<(T, Tag)>,
+    removed: HashSet<Tag>,
+}
+
+impl<T: Eq + Hash> From<OrSetState<T>> for OrSet<T> {
+    fn from(OrSetState { adds, removed }: OrSetState<T>) -> OrSet<T> {
+        let next_seq = OrSet::seq_after(&adds);
+        OrSet {
+            adds,
+            removed,
+            next_seq,
+        }
+    }
+}
+
+impl<T: Eq + Hash> Default for OrSet<T> {
+    fn default() -> OrSet<T> {
+        OrSet {
+            adds: HashSet::new(),
+            removed: HashSet::new(),
+            next_seq: 0,
+        }
+    }
+}
+
+impl<T: Eq + Hash> OrSet<T> {
+    /// One more than the highest sequence number among `adds`. Removed tags
+    /// all come from adds, so they needn't be looked at.
+    fn seq_after(adds: &HashSet<(T, Tag)>) -> u64 {
+        adds.iter()
+            .map(|(_, (_, seq))| seq + 1)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+impl<T: Eq + Hash + Clone> OrSet<T> {
+    pub fn add(&mut self, node_id: &str, item: T) {
+        let tag = (node_id.to_string(), self.next_seq);
+        self.next_seq += 1;
+
+        self.adds.insert((item, tag));
+    }
+
+    /// Removes every add of `item` this replica has seen.
+    pub fn remove(&mut self, item: &T) {
+        let tags: Vec<Tag> = self
+            .adds
+            .iter()
+            .filter(|(added, _)| added == item)
+            .map(|(_, tag)| tag.clone())
+            .collect();
+
+        self.removed.extend(tags);
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.adds
+            .iter()
+            .any(|(added, tag)| added == item && !self.removed.contains(tag))
+    }
+
+    /// The items currently in the set.
+    pub fn items(&self) -> HashSet<&T> {
+        self.adds
+            .iter()
+            .filter(|(_, tag)| !self.removed.contains(tag))
+            .map(|(item, _)| item)
+            .collect()
+    }
+}
+
+impl<T: Eq + Hash + Clone> Merge for OrSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.adds.extend(other.adds.iter().cloned());
+        self.removed.extend(other.removed.iter().cloned());
+        self.next_seq = self.next_seq.max(other.next_seq);
+    }
+}
+
+/// A single value where the write with the later [`Version`] wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwRegister<T> {
+    value: Option<T>,
+    version: Option<Version>,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> LwwRegister<T> {
+        LwwRegister {
+            value: None,
+            version: None,
+        }
+    }
+}
+
+impl<T: Clone> LwwRegister<T> {
+    /// Stores `value` unless something newer than `version` is already held.
+    /// Returns whether it was stored.
+    pub fn set(&mut self, value: T, version: Version) -> bool {
+        if self
+            .version
+            .as_ref()
+            .is_some_and(|current| *current >= version)
+        {
+            return false;
+        }
+
+        self.value = Some(value);
+        self.version = Some(version);
+        true
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+}
+
+impl<T: Clone> Merge for LwwRegister<T> {
+    fn merge(&mut self, other: &Self) {
+        if let (Some(value), Some(version)) = (&other.value, &other.version) {
+            self.set(value.clone(), version.clone());
+        }
+    }
+}
+
+/// A grow-only counter: one running total per node, merged by taking the
+/// larger total for each node. Each node only ever increments its own entry,
+/// so merging is safe to repeat and to apply in any order.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GCounter {
+    totals: HashMap<String, u64>,
+}
+
+impl GCounter {
+    pub fn add(&mut self, node_id: &str, delta: u64) {
+        *self.totals.entry(node_id.to_string()).or_default() += delta;
+    }
+
+    pub fn value(&self) -> u64 {
+        self.totals.values().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.totals.is_empty()
+    }
+}
+
+impl Merge for GCounter {
+    fn merge(&mut self, other: &Self) {
+        for (node_id, total) in &other.totals {
+            let entry = self.totals.entry(node_id.clone()).or_default();
+            *entry = (*entry).max(*total);
+        }
+    }
+}
+
+/// A counter that can also go down: one [`GCounter`] for increments and one
+/// for decrements, its value being the difference.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PnCounter {
+    increments: GCounter,
+    #[serde(default, skip_serializing_if = "GCounter::is_empty")]
+    decrements: GCounter,
+}
+
+impl PnCounter {
+    pub fn add(&mut self, node_id: &str, delta: i64) {
+        if delta >= 0 {
+            self.increments.add(node_id, delta.unsigned_abs());
+        } else {
+            self.decrements.add(node_id, delta.unsigned_abs());
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.increments.is_empty() && self.decrements.is_empty()
+    }
+}
+
+impl Merge for PnCounter {
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that merging `a`, `b` and `c` is commutative, associative and
+    /// idempotent, comparing results with `same`.
+    fn check_laws<T: Merge + Clone + std::fmt::Debug>(
+        a: &T,
+        b: &T,
+        c: &T,
+        same: impl Fn(&T, &T) -> bool,
+    ) {
+        let merged = |x: &T, y: &T| {
+            let mut x = x.clone();
+            x.merge(y);
+            x
+        };
+
+        let (ab, ba) = (merged(a, b), merged(b, a));
+        assert!(same(&ab, &ba), "not commutative: {:?} vs {:?}", ab, ba);
+
+        let (ab_c, a_bc) = (merged(&ab, c), merged(a, &merged(b, c)));
+        assert!(
+            same(&ab_c, &a_bc),
+            "not associative: {:?} vs {:?}",
+            ab_c,
+            a_bc
+        );
+
+        let (aa, ab_b) = (merged(a, a), merged(&ab, b));
+        assert!(same(&aa, a), "not idempotent: {:?} vs {:?}", aa, a);
+        assert!(same(&ab_b, &ab), "not idempotent: {:?} vs {:?}", ab_b, ab);
+    }
+
+    #[test]
+    fn g_set_merges_obey_the_laws() {
+        let a: GSet<u64> = [1, 2].into_iter().collect();
+        let b: GSet<u64> = [2, 3].into_iter().collect();
+        let c: GSet<u64> = [4].into_iter().collect();
+
+        check_laws(&a, &b, &c, |x, y| x == y);
+    }
+
+    #[test]
+    fn or_set_merges_obey_the_laws() {
+        let mut a = OrSet::default();
+        a.add("n1", 1);
+        a.add("n1", 2);
+
+        let mut b = a.clone();
+        b.remove(&1);
+        b.add("n2", 3);
+
+        // Concurrent with b's remove, so this add of 1 survives it.
+        let mut c = OrSet::default();
+        c.add("n3", 1);
+
+        check_laws(&a, &b, &c, |x, y| {
+            x.adds == y.adds && x.removed == y.removed
+        });
+
+        let mut all = a.clone();
+        all.merge(&b);
+        all.merge(&c);
+        assert_eq!(all.items(), HashSet::from([&1, &2, &3]));
+    }
+
+    #[test]
+    fn lww_register_merges_obey_the_laws() {
+        let mut a = LwwRegister::default();
+        a.set("a", (1, "n1".to_string()));
+        let mut b = LwwRegister::default();
+        b.set("b", (2, "n1".to_string()));
+        // The same clock as b, with the node breaking the tie.
+        let mut c = LwwRegister::default();
+        c.set("c", (2, "n2".to_string()));
+
+        check_laws(&a, &b, &c, |x, y| x == y);
+        check_laws(&a, &b, &LwwRegister::default(), |x, y| x == y);
+    }
+
+    #[test]
+    fn g_counter_merges_obey_the_laws() {
+        let mut a = GCounter::default();
+        a.add("n1", 3);
+        let mut b = a.clone();
+        b.add("n1", 2);
+        b.add("n2", 1);
+        let mut c = GCounter::default();
+        c.add("n3", 7);
+
+        check_laws(&a, &b, &c, |x, y| x == y);
+    }
+
+    #[test]
+    fn pn_counter_merges_obey_the_laws() {
+        let mut a = PnCounter::default();
+        a.add("n1", 5);
+        let mut b = a.clone();
+        b.add("n1", -2);
+        let mut c = PnCounter::default();
+        c.add("n2", -4);
+        c.add("n2", 1);
+
+        check_laws(&a, &b, &c, |x, y| x == y);
+
+        a.merge(&b);
+        a.merge(&c);
+        assert_eq!(a.value(), 0);
+    }
+
+    #[test]
+    fn a_restored_or_set_never_reuses_a_tag() {
+        let mut before = OrSet::default();
+        before.add("n1", 1);
+        before.add("n1", 2);
+
+        let json = serde_json::to_string(&before).unwrap();
+        let mut restored: OrSet<u64> = serde_json::from_str(&json).unwrap();
+
+        // Removing 1 before re-adding it elsewhere must not cancel the new add.
+        restored.remove(&1);
+        restored.add("n1", 1);
+        assert!(restored.contains(&1));
+
+        let mut fresh = OrSet::default();
+        fresh.merge(&restored);
+        fresh.add("n1", 1);
+        assert_eq!(fresh.adds.len(), 4);
+    }
+}
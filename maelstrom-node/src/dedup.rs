@@ -0,0 +1,130 @@
+use crate::message::{Body, Envelope, Message};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// What [`Dedup::check`] found out about a request.
+#[derive(Debug)]
+pub enum Seen<B> {
+    /// First time, or the first attempt was given up on: let the handler
+    /// process it.
+    New,
+    /// Already being processed; its reply hasn't gone out yet.
+    InProgress,
+    /// Already answered with this. It still carries its original msg_id.
+    Replied(Message<Envelope<B>>),
+}
+
+/// Where a tracked request has got to.
+#[derive(Debug)]
+enum Entry<B> {
+    InProgress { since: Instant },
+    Replied(Message<Envelope<B>>),
+}
+
+/// The most recent client requests and their replies, so a retransmitted
+/// request gets the original reply again instead of being applied twice.
+/// Every reply the runtime sends is recorded here, so an `add`, `send` or
+/// `txn` takes effect once however often the client retries it, without
+/// the handler keeping track itself.
+///
+/// Requests are identified by `(src, msg_id)` and only the `capacity` most
+/// recently used are remembered: a retransmit counts as a use, so a request
+/// that keeps being retried outlives quieter ones. Traffic from other nodes
+/// is left alone: their retries are node-to-node protocol, which is already
+/// idempotent.
+///
+/// A request still unanswered after `timeout` is forgotten, so a handler
+/// that never replies doesn't swallow every retransmit of it for good.
+#[derive(Debug)]
+pub struct Dedup<B> {
+    capacity: usize,
+    timeout: Duration,
+    peers: HashSet<String>,
+    order: VecDeque<(String, u32)>,
+    replies: HashMap<(String, u32), Entry<B>>,
+}
+
+impl<B: Body> Dedup<B> {
+    /// A `capacity` of zero turns deduplication off.
+    pub fn new(capacity: usize, timeout: Duration) -> Dedup<B> {
+        Dedup {
+            capacity,
+            timeout,
+            peers: HashSet::new(),
+            order: VecDeque::new(),
+            replies: HashMap::new(),
+        }
+    }
+
+    pub fn init(&mut self, node_ids: &[String]) {
+        self.peers = node_ids.iter().cloned().collect();
+    }
+
+    /// Looks up request `msg_id` from `src`, starting to track it if new.
+    pub fn check(&mut self, src: &str, msg_id: u32) -> Seen<B> {
+        if self.capacity == 0 || self.peers.contains(src) {
+            return Seen::New;
+        }
+
+        let key = (src.to_string(), msg_id);
+
+        let seen = match self.replies.get_mut(&key) {
+            Some(Entry::Replied(reply)) => Some(Seen::Replied(reply.clone())),
+            Some(Entry::InProgress { since }) if since.elapsed() < self.timeout => {
+                Some(Seen::InProgress)
+            }
+            Some(Entry::InProgress { since }) => {
+                *since = Instant::now();
+                Some(Seen::New)
+            }
+            None => None,
+        };
+
+        if let Some(seen) = seen {
+            self.touch(key);
+            return seen;
+        }
+
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+
+        self.order.push_back(key.clone());
+        self.replies.insert(
+            key,
+            Entry::InProgress {
+                since: Instant::now(),
+            },
+        );
+
+        Seen::New
+    }
+
+    /// Moves `key` to the back of the eviction order. Only retransmits get
+    /// here, so the scan is rare.
+    fn touch(&mut self, key: (String, u32)) {
+        if let Some(position) = self.order.iter().position(|used| *used == key) {
+            self.order.remove(position);
+        }
+
+        self.order.push_back(key);
+    }
+
+    /// Stores `message` if it answers a tracked request. Only the first
+    /// reply to a request is kept.
+    pub fn record(&mut self, message: &Message<Envelope<B>>) {
+        let Some(in_reply_to) = message.body.in_reply_to() else {
+            return;
+        };
+
+        let key = (message.dest.clone(), in_reply_to);
+
+        if let Some(entry @ Entry::InProgress { .. }) = self.replies.get_mut(&key) {
+            *entry = Entry::Replied(message.clone());
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{
+    message::{Body, Message},
+    runtime::{Context, Handler},
+};
+use serde::{Deserialize, Serialize};
+
+/// Message types of the echo workload (challenge #1).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EchoBody {
+    Echo {
+        msg_id: u32,
+        echo: String,
+    },
+    EchoOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        echo: String,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+impl Body for EchoBody {
+    fn kind(&self) -> &'static str {
+        match self {
+            EchoBody::Echo { .. } => "echo",
+            EchoBody::EchoOk { .. } => "echo_ok",
+            EchoBody::Unsupported => "unsupported",
+        }
+    }
+
+    fn msg_id(&self) -> Option<u32> {
+        match self {
+            EchoBody::Echo { msg_id, .. } | EchoBody::EchoOk { msg_id, .. } => Some(*msg_id),
+            EchoBody::Unsupported => None,
+        }
+    }
+
+    fn in_reply_to(&self) -> Option<u32> {
+        match self {
+            EchoBody::EchoOk { in_reply_to, .. } => Some(*in_reply_to),
+            EchoBody::Echo { .. } | EchoBody::Unsupported => None,
+        }
+    }
+
+    fn set_msg_id(&mut self, id: u32) {
+        match self {
+            EchoBody::Echo { msg_id, .. } | EchoBody::EchoOk { msg_id, .. } => *msg_id = id,
+            EchoBody::Unsupported => {}
+        }
+    }
+
+    fn set_in_reply_to(&mut self, id: u32) {
+        match self {
+            EchoBody::EchoOk { in_reply_to, .. } => *in_reply_to = id,
+            EchoBody::Echo { .. } | EchoBody::Unsupported => {}
+        }
+    }
+
+    fn is_unsupported(&self) -> bool {
+        matches!(self, EchoBody::Unsupported)
+    }
+}
+
+/// Handler for the echo workload: sends every `echo` straight back.
+#[derive(Debug, Default, Clone)]
+pub struct Echo;
+
+impl Echo {
+    pub fn new() -> Echo {
+        Echo
+    }
+}
+
+impl Handler for Echo {
+    type Body = EchoBody;
+
+    fn init(&mut self, _node_id: &str, _node_ids: &[String]) {}
+
+    fn handle(&mut self, message: Message<EchoBody>, ctx: &mut Context<EchoBody>) {
+        if let EchoBody::Echo { echo, .. } = &message.body {
+            let echo = echo.clone();
+
+            ctx.reply(
+                &message,
+                EchoBody::EchoOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                    echo,
+                },
+            );
+        }
+    }
+}
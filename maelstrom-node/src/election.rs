@@ -0,0 +1,155 @@
+//! Leader election for handlers that want one node to coordinate, such as
+//! routing every write through a single node.
+//!
+//! The leader sends a heartbeat to every peer each tick. A node that hears
+//! none for a while assumes the leader is gone and claims the next term
+//! itself. Claims are staggered by node ID, lowest first, so usually the
+//! lowest-numbered live node takes over and the others hear its heartbeat
+//! before their own timeout. If two claims do race, the higher term wins, and
+//! within a term the lower node ID.
+//!
+//! Nothing is voted on, so this is failure detection rather than consensus:
+//! during a partition each side elects its own leader until it heals.
+
+use crate::{
+    message::{Body, Message},
+    runtime::Context,
+};
+use log::info;
+use std::time::{Duration, Instant};
+
+/// How long a node waits, on top of its stagger, before deciding the
+/// leader has failed.
+pub const DEFAULT_LEADER_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How much later each successive node ID claims leadership.
+const CLAIM_STAGGER: Duration = Duration::from_millis(100);
+
+/// The heartbeat message, for a workload's body type to carry.
+pub trait ElectionBody: Body {
+    fn heartbeat(term: u64) -> Self;
+
+    /// The term of a heartbeat, or `None` if this isn't one.
+    fn as_heartbeat(&self) -> Option<u64>;
+}
+
+/// One node's view of who leads. Call [`Election::init`] from the handler's
+/// `init`, pass incoming messages through [`Election::on_message`], and call
+/// [`Election::tick`] every tick.
+#[derive(Debug)]
+pub struct Election {
+    node_id: String,
+    peers: Vec<String>,
+    term: u64,
+    leader: Option<String>,
+    /// When the leader was last heard from, or when we last claimed.
+    last_heard: Instant,
+    /// How long we wait for the leader before claiming: the timeout plus
+    /// our stagger.
+    patience: Duration,
+    timeout: Duration,
+}
+
+impl Default for Election {
+    fn default() -> Election {
+        Election::new(DEFAULT_LEADER_TIMEOUT)
+    }
+}
+
+impl Election {
+    pub fn new(timeout: Duration) -> Election {
+        Election {
+            node_id: String::new(),
+            peers: Vec::new(),
+            term: 0,
+            leader: None,
+            last_heard: Instant::now(),
+            patience: timeout,
+            timeout,
+        }
+    }
+
+    pub fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        let mut sorted = node_ids.to_vec();
+        sorted.sort();
+        let rank = sorted
+            .iter()
+            .position(|node| node == node_id)
+            .unwrap_or_default();
+
+        self.node_id = node_id.to_string();
+        self.peers = node_ids
+            .iter()
+            .filter(|node| *node != node_id)
+            .cloned()
+            .collect();
+        self.patience = self.timeout + CLAIM_STAGGER * rank as u32;
+        self.last_heard = Instant::now();
+    }
+
+    /// The current leader, if one has been heard from or elected.
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader.as_deref() == Some(self.node_id.as_str())
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Handles a heartbeat, returning whether `message` was one; anything
+    /// else should go on to the handler.
+    pub fn on_message<B: ElectionBody>(&mut self, message: &Message<B>) -> bool {
+        let Some(term) = message.body.as_heartbeat() else {
+            return false;
+        };
+
+        let current = (self.term, self.leader.as_deref());
+        let newer = match current {
+            (current_term, _) if term != current_term => term > current_term,
+            (_, None) => true,
+            // Two leaders in one term: the lower ID keeps it.
+            (_, Some(leader)) => message.src.as_str() <= leader,
+        };
+
+        if newer {
+            self.follow(term, &message.src);
+        }
+
+        if self.leader.as_deref() == Some(message.src.as_str()) {
+            self.last_heard = Instant::now();
+        }
+
+        true
+    }
+
+    /// Sends heartbeats as leader; otherwise claims leadership once the
+    /// leader has been silent too long.
+    pub fn tick<B: ElectionBody>(&mut self, ctx: &mut Context<B>) {
+        if !self.is_leader() && self.last_heard.elapsed() >= self.patience {
+            self.term += 1;
+            self.leader = Some(self.node_id.clone());
+            self.last_heard = Instant::now();
+
+            info!("claiming leadership for term {}", self.term);
+        }
+
+        if self.is_leader() {
+            for peer in &self.peers {
+                ctx.send(peer, B::heartbeat(self.term));
+            }
+        }
+    }
+
+    fn follow(&mut self, term: u64, leader: &str) {
+        if self.leader.as_deref() != Some(leader) {
+            info!("following {} in term {}", leader, term);
+        }
+
+        self.term = term;
+        self.leader = Some(leader.to_string());
+    }
+}
@@ -0,0 +1,46 @@
+use std::{fmt, io};
+
+/// Everything that can go wrong talking to Maelstrom.
+///
+/// Not every error is fatal: the runtime logs and skips malformed input, and
+/// gives up on the trace file if it can't be written. Losing stdin or stdout
+/// ends the run, and [`Runtime::run`](crate::Runtime::run) returns the error.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// The stdout writer thread stopped, so nothing more can be sent.
+    WriterClosed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "I/O error: {}", error),
+            Error::Json(error) => write!(f, "JSON error: {}", error),
+            Error::WriterClosed => write!(f, "stdout writer has stopped"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Json(error) => Some(error),
+            Error::WriterClosed => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Error {
+        Error::Json(error)
+    }
+}
@@ -0,0 +1,139 @@
+use rand::{seq::SliceRandom, Rng};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+/// Batches newly seen broadcast values and hands them out in periodic gossip
+/// rounds instead of forwarding each one as soon as it arrives.
+///
+/// Every `interval`, the values collected since the last round are sent to
+/// `fanout` randomly chosen neighbors (all of them if unset), split into
+/// messages of at most `batch_size` values (unbounded if unset). Values a
+/// neighbor is already known to have are left out of its messages.
+///
+/// Without a fanout every neighbor gets each value once and the queue is
+/// emptied. With one, a value stays queued until every neighbor is known to
+/// have it, and each round samples only among the neighbors still missing
+/// something, so no value dies out with the few peers that happened to be
+/// picked first.
+pub struct GossipScheduler {
+    interval: Duration,
+    fanout: Option<usize>,
+    batch_size: Option<usize>,
+    last_round: Instant,
+    pending: Vec<Value>,
+}
+
+impl GossipScheduler {
+    pub fn new(interval: Duration, fanout: Option<usize>, batch_size: Option<usize>) -> Self {
+        GossipScheduler {
+            interval,
+            fanout,
+            batch_size,
+            last_round: Instant::now(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Whether nothing is waiting for the next round.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queues a value for the next round.
+    pub fn push(&mut self, value: Value) {
+        self.pending.push(value);
+    }
+
+    /// If a round is due, returns the `(dest, values)` messages to send,
+    /// skipping values for which `known(dest, value)` holds, and drops from
+    /// the queue whatever is done with. Neighbors are sampled with `rng`.
+    /// Returns nothing between rounds or when there is nothing new.
+    pub fn round(
+        &mut self,
+        neighbors: &[String],
+        rng: &mut impl Rng,
+        known: impl Fn(&str, &Value) -> bool,
+    ) -> Vec<(String, Vec<Value>)> {
+        if self.last_round.elapsed() < self.interval {
+            return Vec::new();
+        }
+
+        self.last_round = Instant::now();
+
+        if self.pending.is_empty() {
+            return Vec::new();
+        }
+
+        let peers: Vec<&String> = match self.fanout {
+            Some(fanout) => {
+                let missing: Vec<&String> = neighbors
+                    .iter()
+                    .filter(|peer| self.pending.iter().any(|value| !known(peer, value)))
+                    .collect();
+
+                missing.choose_multiple(rng, fanout).copied().collect()
+            }
+            None => neighbors.iter().collect(),
+        };
+
+        let mut batches = Vec::new();
+
+        for peer in peers {
+            let values: Vec<Value> = self
+                .pending
+                .iter()
+                .filter(|value| !known(peer, value))
+                .cloned()
+                .collect();
+
+            let batch_size = self.batch_size.unwrap_or(values.len()).max(1);
+
+            for chunk in values.chunks(batch_size) {
+                batches.push((peer.clone(), chunk.to_vec()));
+            }
+        }
+
+        match self.fanout {
+            Some(_) => self
+                .pending
+                .retain(|value| neighbors.iter().any(|peer| !known(peer, value))),
+            None => self.pending.clear(),
+        }
+
+        batches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    #[test]
+    fn fanout_keeps_a_value_until_every_neighbor_has_it() {
+        let neighbors: Vec<String> = ["n1", "n2", "n3", "n4"].map(String::from).to_vec();
+        let mut gossip = GossipScheduler::new(Duration::ZERO, Some(1), None);
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut have: HashSet<String> = HashSet::new();
+
+        gossip.push(json!(1));
+
+        // Each round reaches one peer that still lacks the value, which then
+        // acks it.
+        for round in 1..=neighbors.len() {
+            let batches = gossip.round(&neighbors, &mut rng, |peer, _| have.contains(peer));
+
+            assert_eq!(batches.len(), 1, "round {}", round);
+            assert!(have.insert(batches[0].0.clone()), "round {}", round);
+        }
+
+        // The next round finds nobody missing it and lets it go.
+        assert!(!gossip.is_empty());
+        assert!(gossip
+            .round(&neighbors, &mut rng, |peer, _| have.contains(peer))
+            .is_empty());
+        assert!(gossip.is_empty());
+    }
+}
@@ -0,0 +1,81 @@
+//! Hashing whose results are compared between nodes, such as broadcast-set
+//! checksums and which node owns a key. Every node has to use the same
+//! [`ClusterHasher`] for those to agree, so it is fixed here rather than
+//! left to `DefaultHasher`, which may change between builds and toolchains.
+
+use serde_json::Value;
+
+/// A hash every node computes the same way.
+pub trait ClusterHasher {
+    fn hash_bytes(&self, bytes: &[u8]) -> u64;
+
+    fn hash_u64(&self, value: u64) -> u64 {
+        self.hash_bytes(&value.to_le_bytes())
+    }
+
+    /// Integers are hashed as numbers, anything else by its JSON text.
+    fn hash_value(&self, value: &Value) -> u64 {
+        match value.as_u64() {
+            Some(value) => self.hash_u64(value),
+            None => self.hash_bytes(value.to_string().as_bytes()),
+        }
+    }
+
+    /// Which of `nodes` owns `key`: the one whose hash combined with the
+    /// key's is highest. That depends only on who is in `nodes`, not their
+    /// order, and a node leaving only moves the keys it owned.
+    fn owner<'a>(&self, key: &str, nodes: &'a [String]) -> Option<&'a str> {
+        let key = self.hash_bytes(key.as_bytes());
+
+        nodes
+            .iter()
+            .max_by_key(|node| (self.hash_u64(self.hash_bytes(node.as_bytes()) ^ key), *node))
+            .map(String::as_str)
+    }
+}
+
+/// The default: 64-bit FNV-1a, finished with the splitmix64 mixer so that
+/// nearby inputs spread over the whole range.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Fnv;
+
+impl ClusterHasher for Fnv {
+    fn hash_bytes(&self, bytes: &[u8]) -> u64 {
+        mix(fnv1a(bytes))
+    }
+
+    fn hash_u64(&self, value: u64) -> u64 {
+        mix(value)
+    }
+}
+
+/// Hashes that can be worked out by hand, for tests: integers are their
+/// own hash and bytes are summed. Nothing is spread out, so it is no use
+/// for anything else.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Plain;
+
+impl ClusterHasher for Plain {
+    fn hash_bytes(&self, bytes: &[u8]) -> u64 {
+        bytes.iter().map(|&byte| u64::from(byte)).sum()
+    }
+
+    fn hash_u64(&self, value: u64) -> u64 {
+        value
+    }
+}
+
+/// 64-bit FNV-1a of `bytes`.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// splitmix64 finalizer.
+pub fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,106 @@
+use log::info;
+use std::collections::BTreeMap;
+
+/// Which other nodes are likely cut off by a partition.
+///
+/// A quiet peer may just have nothing to say, so silence alone proves
+/// nothing. A peer is only reported down once something sent to it has gone
+/// unanswered: a [`Context::rpc`](crate::runtime::Context::rpc) that timed
+/// out, or anything else the handler reports through
+/// [`Context::timed_out`](crate::runtime::Context::timed_out). It is back up
+/// as soon as it sends us anything, replies and acks included.
+#[derive(Debug, Default)]
+pub struct PeerHealth {
+    /// Every peer, with whether it is down.
+    down: BTreeMap<String, bool>,
+}
+
+impl PeerHealth {
+    pub fn new() -> PeerHealth {
+        PeerHealth::default()
+    }
+
+    pub fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        self.down = node_ids
+            .iter()
+            .filter(|node| *node != node_id)
+            .map(|node| (node.clone(), false))
+            .collect();
+    }
+
+    /// Records a message from `src`. Clients and services aren't tracked.
+    pub fn heard_from(&mut self, src: &str) {
+        if let Some(down) = self.down.get_mut(src) {
+            if *down {
+                info!("{} is reachable again", src);
+            }
+
+            *down = false;
+        }
+    }
+
+    /// Records that something sent to `dest` went unanswered.
+    pub fn timed_out(&mut self, dest: &str) {
+        if let Some(down) = self.down.get_mut(dest) {
+            if !*down {
+                info!("{} looks unreachable", dest);
+            }
+
+            *down = true;
+        }
+    }
+
+    /// Whether `node` has answered everything that timed out. Anything that
+    /// isn't a peer, such as a client, is assumed reachable.
+    pub fn is_alive(&self, node: &str) -> bool {
+        self.down.get(node).is_none_or(|down| !down)
+    }
+
+    /// The peers not known to be down, in ID order.
+    pub fn alive_peers(&self) -> Vec<&str> {
+        self.down
+            .iter()
+            .filter(|(_, down)| !**down)
+            .map(|(node, _)| node.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health() -> PeerHealth {
+        let mut health = PeerHealth::new();
+        health.init("n0", &["n0".into(), "n1".into(), "n2".into()]);
+        health
+    }
+
+    #[test]
+    fn a_silent_peer_stays_alive() {
+        let health = health();
+
+        assert!(health.is_alive("n1"));
+        assert_eq!(health.alive_peers(), ["n1", "n2"]);
+    }
+
+    #[test]
+    fn a_peer_is_down_from_a_timeout_until_it_is_heard_from() {
+        let mut health = health();
+
+        health.timed_out("n1");
+        assert!(!health.is_alive("n1"));
+        assert_eq!(health.alive_peers(), ["n2"]);
+
+        health.heard_from("n1");
+        assert!(health.is_alive("n1"));
+    }
+
+    #[test]
+    fn clients_are_never_down() {
+        let mut health = health();
+
+        health.timed_out("c1");
+        assert!(health.is_alive("c1"));
+    }
+}
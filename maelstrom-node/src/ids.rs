@@ -0,0 +1,272 @@
+//! Globally unique IDs for the `generate` workload (challenge #2).
+//!
+//! Each node mints IDs on its own, without coordinating. The time-based
+//! formats put the node's index in the `init` node list into every ID, so
+//! two nodes never collide, whatever their clocks say; within a node
+//! a sequence number keeps IDs strictly increasing, even when many are made
+//! in the same millisecond or the clock steps backwards. What randomness
+//! the formats use comes from the RNG they are built with, so a seeded run
+//! mints the same IDs every time.
+
+use rand::{rngs::StdRng, Rng};
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use ulid::Ulid;
+
+/// A source of unique IDs for one node.
+pub trait IdGenerator {
+    fn next_id(&mut self) -> String;
+
+    /// `count` IDs at once, in increasing order. Implementations reserve
+    /// the whole block in one step rather than generating them one by one.
+    fn next_ids(&mut self, count: usize) -> Vec<String> {
+        (0..count).map(|_| self.next_id()).collect()
+    }
+}
+
+/// Which [`IdGenerator`] a node uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdFormat {
+    Ulid,
+    UuidV7,
+    /// `<node>-<n>`, e.g. `n1-42`.
+    Counter,
+}
+
+impl FromStr for IdFormat {
+    type Err = String;
+
+    /// Parses `ulid`, `uuidv7` or `counter`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "ulid" => Ok(IdFormat::Ulid),
+            "uuidv7" => Ok(IdFormat::UuidV7),
+            "counter" => Ok(IdFormat::Counter),
+            _ => Err(format!("unknown id format {}", value)),
+        }
+    }
+}
+
+impl IdFormat {
+    /// A generator for `node_id`, which is `node_index` in the cluster's
+    /// node list.
+    pub fn generator(self, node_id: &str, node_index: u32, rng: StdRng) -> Box<dyn IdGenerator> {
+        match self {
+            IdFormat::Ulid => Box::new(UlidGenerator::new(node_index, rng)),
+            IdFormat::UuidV7 => Box::new(UuidV7Generator::new(node_index, rng)),
+            IdFormat::Counter => Box::new(CounterGenerator::new(node_id)),
+        }
+    }
+}
+
+/// Milliseconds since the epoch plus a per-millisecond sequence number,
+/// never going backwards.
+#[derive(Debug)]
+struct MonotonicClock {
+    last_ms: u64,
+    seq: u64,
+    /// Largest sequence number before the clock is pushed into the next
+    /// millisecond.
+    max_seq: u64,
+}
+
+impl MonotonicClock {
+    fn new(seq_bits: u32) -> MonotonicClock {
+        MonotonicClock {
+            last_ms: 0,
+            seq: 0,
+            max_seq: (1 << seq_bits) - 1,
+        }
+    }
+
+    /// The next `(ms, seq)`. A new millisecond starts its sequence at a
+    /// random point in the lower half of the range, so IDs don't give away
+    /// how many came before while leaving plenty of room to count up.
+    fn tick(&mut self, rng: &mut StdRng) -> (u64, u64) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+
+        if now > self.last_ms {
+            self.last_ms = now;
+            self.seq = rng.gen_range(0..=self.max_seq / 2);
+        } else {
+            self.advance();
+        }
+
+        (self.last_ms, self.seq)
+    }
+
+    /// `count` consecutive stamps for a single read of the clock, spilling
+    /// into the following milliseconds if the sequence runs out.
+    fn reserve(&mut self, count: usize, rng: &mut StdRng) -> Vec<(u64, u64)> {
+        let mut stamps = Vec::with_capacity(count);
+
+        if count > 0 {
+            stamps.push(self.tick(rng));
+        }
+
+        for _ in 1..count {
+            self.advance();
+            stamps.push((self.last_ms, self.seq));
+        }
+
+        stamps
+    }
+
+    fn advance(&mut self) {
+        if self.seq < self.max_seq {
+            self.seq += 1;
+        } else {
+            self.last_ms += 1;
+            self.seq = 0;
+        }
+    }
+}
+
+/// ULIDs whose 80 random bits are a 32-bit node index followed by a 48-bit
+/// sequence number.
+#[derive(Debug)]
+pub struct UlidGenerator {
+    node_index: u32,
+    clock: MonotonicClock,
+    rng: StdRng,
+}
+
+impl UlidGenerator {
+    pub fn new(node_index: u32, rng: StdRng) -> UlidGenerator {
+        UlidGenerator {
+            node_index,
+            clock: MonotonicClock::new(48),
+            rng,
+        }
+    }
+
+    fn format(&self, (ms, seq): (u64, u64)) -> String {
+        let random = (u128::from(self.node_index) << 48) | u128::from(seq);
+
+        Ulid::from_parts(ms, random).to_string()
+    }
+}
+
+impl IdGenerator for UlidGenerator {
+    fn next_id(&mut self) -> String {
+        let stamp = self.clock.tick(&mut self.rng);
+        self.format(stamp)
+    }
+
+    fn next_ids(&mut self, count: usize) -> Vec<String> {
+        let stamps = self.clock.reserve(count, &mut self.rng);
+        stamps.into_iter().map(|stamp| self.format(stamp)).collect()
+    }
+}
+
+/// RFC 9562 version 7 UUIDs: the 12 `rand_a` bits are the sequence number
+/// and `rand_b` starts with the 32-bit node index.
+#[derive(Debug)]
+pub struct UuidV7Generator {
+    node_index: u32,
+    clock: MonotonicClock,
+    rng: StdRng,
+}
+
+impl UuidV7Generator {
+    pub fn new(node_index: u32, rng: StdRng) -> UuidV7Generator {
+        UuidV7Generator {
+            node_index,
+            clock: MonotonicClock::new(12),
+            rng,
+        }
+    }
+
+    fn format(&mut self, (ms, seq): (u64, u64)) -> String {
+        let tail: u32 = self.rng.gen_range(0..1 << 30);
+
+        let high = ((ms & 0xffff_ffff_ffff) << 16) | (0x7 << 12) | seq;
+        let low = (0b10 << 62) | (u64::from(self.node_index) << 30) | u64::from(tail);
+        let uuid = (u128::from(high) << 64) | u128::from(low);
+
+        format!(
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            uuid >> 96,
+            (uuid >> 80) & 0xffff,
+            (uuid >> 64) & 0xffff,
+            (uuid >> 48) & 0xffff,
+            uuid & 0xffff_ffff_ffff
+        )
+    }
+}
+
+impl IdGenerator for UuidV7Generator {
+    fn next_id(&mut self) -> String {
+        let stamp = self.clock.tick(&mut self.rng);
+        self.format(stamp)
+    }
+
+    fn next_ids(&mut self, count: usize) -> Vec<String> {
+        let stamps = self.clock.reserve(count, &mut self.rng);
+        stamps.into_iter().map(|stamp| self.format(stamp)).collect()
+    }
+}
+
+/// `<node>-<n>` with `n` counting up from zero. Unique because node IDs
+/// are, but only within one run: a restarted node starts over.
+#[derive(Debug)]
+pub struct CounterGenerator {
+    node_id: String,
+    next: u64,
+}
+
+impl CounterGenerator {
+    pub fn new(node_id: &str) -> CounterGenerator {
+        CounterGenerator {
+            node_id: node_id.to_string(),
+            next: 0,
+        }
+    }
+}
+
+impl IdGenerator for CounterGenerator {
+    fn next_id(&mut self) -> String {
+        let id = format!("{}-{}", self.node_id, self.next);
+        self.next += 1;
+        id
+    }
+
+    fn next_ids(&mut self, count: usize) -> Vec<String> {
+        let start = self.next;
+        self.next += count as u64;
+
+        (start..self.next)
+            .map(|n| format!("{}-{}", self.node_id, n))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use std::collections::HashSet;
+
+    /// Two nodes minting in the same milliseconds from the same seed.
+    fn minted_by_two_nodes(format: IdFormat) -> usize {
+        let mut ids = HashSet::new();
+
+        for (node_id, node_index) in [("n0", 0), ("n1", 1)] {
+            let mut generator = format.generator(node_id, node_index, StdRng::seed_from_u64(7));
+            ids.extend(generator.next_ids(1000));
+        }
+
+        ids.len()
+    }
+
+    #[test]
+    fn nodes_never_mint_the_same_id() {
+        for format in [IdFormat::Ulid, IdFormat::UuidV7, IdFormat::Counter] {
+            assert_eq!(minted_by_two_nodes(format), 2000, "{:?}", format);
+        }
+    }
+}
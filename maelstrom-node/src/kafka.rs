@@ -0,0 +1,1346 @@
+use crate::{
+    config::{AckStrategy, Config},
+    election::{Election, ElectionBody},
+    kv::{self, Kv, KvBody, KvError, KvOk, KvReply},
+    message::{
+        Body, Message, CRASH, KEY_DOES_NOT_EXIST, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE,
+        TIMEOUT,
+    },
+    runtime::{Backoff, Context, Handler, RpcError},
+};
+use log::{debug, warn};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+/// How many times a client's `send` may be passed on before it is refused,
+/// so nodes that each think another leads can't bounce it forever.
+pub const MAX_SEND_HOPS: u32 = 3;
+
+/// Message types of the Kafka-style log workload (challenge #5).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KafkaBody {
+    /// Appends `msg` to the log under `key`; replied to with the offset it
+    /// was given. `hops` is how many more times a node may pass it on to the
+    /// leader: left out by clients, and counted down by each forward.
+    Send {
+        msg_id: u32,
+        key: String,
+        msg: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hops: Option<u32>,
+    },
+    SendOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        offset: u64,
+    },
+    /// Asks for each key's entries from the given offset on.
+    Poll {
+        msg_id: u32,
+        #[serde(deserialize_with = "poll_offsets")]
+        offsets: HashMap<String, u64>,
+    },
+    /// `[offset, msg]` pairs per key, in offset order.
+    PollOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        msgs: HashMap<String, Vec<(u64, Value)>>,
+    },
+    CommitOffsets {
+        msg_id: u32,
+        offsets: HashMap<String, u64>,
+    },
+    CommitOffsetsOk {
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    ListCommittedOffsets {
+        msg_id: u32,
+        keys: Vec<String>,
+    },
+    /// Keys with nothing committed yet are left out.
+    ListCommittedOffsetsOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        offsets: HashMap<String, u64>,
+    },
+    /// Node-to-node: an entry some other node appended, so every node can
+    /// answer polls for it.
+    Replicate {
+        msg_id: u32,
+        key: String,
+        offset: u64,
+        msg: Value,
+    },
+    ReplicateOk {
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    /// Node-to-node: the leader's [`Election`] heartbeat.
+    Heartbeat {
+        msg_id: u32,
+        term: u64,
+    },
+    /// `lin-kv` request, for offset allocation and committed offsets.
+    Read {
+        msg_id: u32,
+        key: String,
+    },
+    ReadOk {
+        #[serde(default)]
+        msg_id: u32,
+        in_reply_to: u32,
+        value: Value,
+    },
+    Write {
+        msg_id: u32,
+        key: String,
+        value: Value,
+    },
+    WriteOk {
+        #[serde(default)]
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    Cas {
+        msg_id: u32,
+        key: String,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+    CasOk {
+        #[serde(default)]
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+impl Body for KafkaBody {
+    fn kind(&self) -> &'static str {
+        match self {
+            KafkaBody::Send { .. } => "send",
+            KafkaBody::SendOk { .. } => "send_ok",
+            KafkaBody::Poll { .. } => "poll",
+            KafkaBody::PollOk { .. } => "poll_ok",
+            KafkaBody::CommitOffsets { .. } => "commit_offsets",
+            KafkaBody::CommitOffsetsOk { .. } => "commit_offsets_ok",
+            KafkaBody::ListCommittedOffsets { .. } => "list_committed_offsets",
+            KafkaBody::ListCommittedOffsetsOk { .. } => "list_committed_offsets_ok",
+            KafkaBody::Replicate { .. } => "replicate",
+            KafkaBody::ReplicateOk { .. } => "replicate_ok",
+            KafkaBody::Heartbeat { .. } => "heartbeat",
+            KafkaBody::Read { .. } => "read",
+            KafkaBody::ReadOk { .. } => "read_ok",
+            KafkaBody::Write { .. } => "write",
+            KafkaBody::WriteOk { .. } => "write_ok",
+            KafkaBody::Cas { .. } => "cas",
+            KafkaBody::CasOk { .. } => "cas_ok",
+            KafkaBody::Unsupported => "unsupported",
+        }
+    }
+
+    fn msg_id(&self) -> Option<u32> {
+        match self {
+            KafkaBody::Send { msg_id, .. }
+            | KafkaBody::SendOk { msg_id, .. }
+            | KafkaBody::Poll { msg_id, .. }
+            | KafkaBody::PollOk { msg_id, .. }
+            | KafkaBody::CommitOffsets { msg_id, .. }
+            | KafkaBody::CommitOffsetsOk { msg_id, .. }
+            | KafkaBody::ListCommittedOffsets { msg_id, .. }
+            | KafkaBody::ListCommittedOffsetsOk { msg_id, .. }
+            | KafkaBody::Replicate { msg_id, .. }
+            | KafkaBody::ReplicateOk { msg_id, .. }
+            | KafkaBody::Heartbeat { msg_id, .. }
+            | KafkaBody::Read { msg_id, .. }
+            | KafkaBody::ReadOk { msg_id, .. }
+            | KafkaBody::Write { msg_id, .. }
+            | KafkaBody::WriteOk { msg_id, .. }
+            | KafkaBody::Cas { msg_id, .. }
+            | KafkaBody::CasOk { msg_id, .. } => Some(*msg_id),
+            KafkaBody::Unsupported => None,
+        }
+    }
+
+    fn in_reply_to(&self) -> Option<u32> {
+        match self {
+            KafkaBody::SendOk { in_reply_to, .. }
+            | KafkaBody::PollOk { in_reply_to, .. }
+            | KafkaBody::CommitOffsetsOk { in_reply_to, .. }
+            | KafkaBody::ListCommittedOffsetsOk { in_reply_to, .. }
+            | KafkaBody::ReplicateOk { in_reply_to, .. }
+            | KafkaBody::ReadOk { in_reply_to, .. }
+            | KafkaBody::WriteOk { in_reply_to, .. }
+            | KafkaBody::CasOk { in_reply_to, .. } => Some(*in_reply_to),
+            KafkaBody::Send { .. }
+            | KafkaBody::Poll { .. }
+            | KafkaBody::CommitOffsets { .. }
+            | KafkaBody::ListCommittedOffsets { .. }
+            | KafkaBody::Replicate { .. }
+            | KafkaBody::Heartbeat { .. }
+            | KafkaBody::Read { .. }
+            | KafkaBody::Write { .. }
+            | KafkaBody::Cas { .. }
+            | KafkaBody::Unsupported => None,
+        }
+    }
+
+    fn set_msg_id(&mut self, id: u32) {
+        match self {
+            KafkaBody::Send { msg_id, .. }
+            | KafkaBody::SendOk { msg_id, .. }
+            | KafkaBody::Poll { msg_id, .. }
+            | KafkaBody::PollOk { msg_id, .. }
+            | KafkaBody::CommitOffsets { msg_id, .. }
+            | KafkaBody::CommitOffsetsOk { msg_id, .. }
+            | KafkaBody::ListCommittedOffsets { msg_id, .. }
+            | KafkaBody::ListCommittedOffsetsOk { msg_id, .. }
+            | KafkaBody::Replicate { msg_id, .. }
+            | KafkaBody::ReplicateOk { msg_id, .. }
+            | KafkaBody::Heartbeat { msg_id, .. }
+            | KafkaBody::Read { msg_id, .. }
+            | KafkaBody::ReadOk { msg_id, .. }
+            | KafkaBody::Write { msg_id, .. }
+            | KafkaBody::WriteOk { msg_id, .. }
+            | KafkaBody::Cas { msg_id, .. }
+            | KafkaBody::CasOk { msg_id, .. } => *msg_id = id,
+            KafkaBody::Unsupported => {}
+        }
+    }
+
+    fn set_in_reply_to(&mut self, id: u32) {
+        match self {
+            KafkaBody::SendOk { in_reply_to, .. }
+            | KafkaBody::PollOk { in_reply_to, .. }
+            | KafkaBody::CommitOffsetsOk { in_reply_to, .. }
+            | KafkaBody::ListCommittedOffsetsOk { in_reply_to, .. }
+            | KafkaBody::ReplicateOk { in_reply_to, .. }
+            | KafkaBody::ReadOk { in_reply_to, .. }
+            | KafkaBody::WriteOk { in_reply_to, .. }
+            | KafkaBody::CasOk { in_reply_to, .. } => *in_reply_to = id,
+            KafkaBody::Send { .. }
+            | KafkaBody::Poll { .. }
+            | KafkaBody::CommitOffsets { .. }
+            | KafkaBody::ListCommittedOffsets { .. }
+            | KafkaBody::Replicate { .. }
+            | KafkaBody::Heartbeat { .. }
+            | KafkaBody::Read { .. }
+            | KafkaBody::Write { .. }
+            | KafkaBody::Cas { .. }
+            | KafkaBody::Unsupported => {}
+        }
+    }
+
+    fn is_unsupported(&self) -> bool {
+        matches!(self, KafkaBody::Unsupported)
+    }
+}
+
+impl ElectionBody for KafkaBody {
+    fn heartbeat(term: u64) -> Self {
+        KafkaBody::Heartbeat { msg_id: 0, term }
+    }
+
+    fn as_heartbeat(&self) -> Option<u64> {
+        match self {
+            KafkaBody::Heartbeat { term, .. } => Some(*term),
+            _ => None,
+        }
+    }
+}
+
+impl KvBody for KafkaBody {
+    fn kv_read(key: String) -> Self {
+        KafkaBody::Read { msg_id: 0, key }
+    }
+
+    fn kv_write(key: String, value: Value) -> Self {
+        KafkaBody::Write {
+            msg_id: 0,
+            key,
+            value,
+        }
+    }
+
+    fn kv_cas(key: String, from: Value, to: Value, create_if_not_exists: bool) -> Self {
+        KafkaBody::Cas {
+            msg_id: 0,
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        }
+    }
+
+    fn kv_reply(&self) -> Option<KvOk> {
+        match self {
+            KafkaBody::ReadOk { value, .. } => Some(KvOk::Read(value.clone())),
+            KafkaBody::WriteOk { .. } => Some(KvOk::Write),
+            KafkaBody::CasOk { .. } => Some(KvOk::Cas),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the offsets of a `poll`. A negative offset asks for everything
+/// there is, so it is clamped to 0 rather than failing the whole request;
+/// one past the end of any log, however large, simply finds nothing.
+fn poll_offsets<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, u64>, D::Error> {
+    let offsets = HashMap::<String, Value>::deserialize(deserializer)?;
+
+    offsets
+        .into_iter()
+        .map(|(key, offset)| {
+            let clamped = kv::as_u64(&offset)
+                .or_else(|| offset.as_f64().filter(|offset| *offset < 0.0).map(|_| 0));
+
+            match clamped {
+                Some(offset) => Ok((key, offset)),
+                None => Err(D::Error::custom(format!(
+                    "offset {} for key {} is not a whole number",
+                    offset, key
+                ))),
+            }
+        })
+        .collect()
+}
+
+/// One key's append-only log, as far as this node has heard of it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+struct Log {
+    entries: BTreeMap<u64, Value>,
+    /// Runs of offsets that were missing when a later entry arrived, as
+    /// `start => (end, noticed)` with `end` exclusive. Dropped once filled.
+    #[serde(skip)]
+    gaps: BTreeMap<u64, (u64, Instant)>,
+}
+
+impl Log {
+    fn insert(&mut self, offset: u64, msg: Value) {
+        let end = self
+            .entries
+            .last_key_value()
+            .map_or(0, |(last, _)| last.saturating_add(1));
+
+        if offset > end {
+            self.gaps.insert(end, (offset, Instant::now()));
+        }
+
+        self.entries.insert(offset, msg);
+
+        if let Some((&start, &(end, _))) = self.gaps.range(..=offset).next_back() {
+            if offset < end && self.entries.range(start..end).count() as u64 == end - start {
+                self.gaps.remove(&start);
+            }
+        }
+    }
+
+    /// Whether `offset` is missing and has been for at least `grace`.
+    fn abandoned(&self, offset: u64, grace: Duration) -> bool {
+        self.gaps
+            .range(..=offset)
+            .next_back()
+            .is_some_and(|(_, (end, noticed))| offset < *end && noticed.elapsed() >= grace)
+    }
+
+    /// At most `limit` entries from `offset` on. Offsets are handed out
+    /// without gaps, so a missing one is usually still being replicated, and
+    /// answering past it would make the poll skip a message; the reply stops
+    /// there. A gap still open after `grace` is taken to be an offset whose
+    /// `send` never completed, e.g. because the node allocating it crashed,
+    /// and is skipped so it doesn't hold the log up forever.
+    fn read_from(&self, offset: u64, limit: usize, grace: Duration) -> Vec<(u64, Value)> {
+        let mut entries = Vec::new();
+        let mut next = offset;
+
+        for (&at, msg) in self.entries.range(offset..).take(limit) {
+            // The offsets skipped since the last entry were all missing when
+            // `at` arrived, so they are one gap, noticed at one time.
+            if at > next && !self.abandoned(next, grace) {
+                break;
+            }
+
+            entries.push((at, msg.clone()));
+            next = at + 1;
+        }
+
+        entries
+    }
+}
+
+/// A client request waiting on one or more `lin-kv` requests.
+#[derive(Debug)]
+enum Waiting {
+    /// A `send` whose offset is being allocated.
+    Send {
+        client: String,
+        msg_id: u32,
+        key: String,
+        msg: Value,
+    },
+    /// One key of the [`Batch`] `batch`.
+    Key { batch: u32, key: String },
+}
+
+/// A `commit_offsets` or `list_committed_offsets`, answered once every key
+/// has been dealt with.
+#[derive(Debug)]
+struct Batch {
+    client: String,
+    msg_id: u32,
+    list: bool,
+    remaining: usize,
+    offsets: HashMap<String, u64>,
+}
+
+/// Handler for the Kafka-style log workload, single- or multi-node.
+///
+/// Offsets are allocated by incrementing a per-key counter in `lin-kv`, so
+/// every node hands out distinct, gapless offsets. A `send` from a client is
+/// passed on to the [`Election`] leader, if there is one, so that one node
+/// does the allocating and its `cas`es don't race the others'; the node that
+/// allocated stores the entry and replicates it to every other node.
+/// Committed offsets live in `lin-kv` too, so all nodes agree on them.
+pub struct Kafka {
+    poll_max_per_key: Option<usize>,
+    poll_max: Option<usize>,
+    /// Whether a `send` is answered before or after it is replicated; the
+    /// offset has to be allocated first either way.
+    ack: AckStrategy,
+    peers: Vec<String>,
+    election: Election,
+    /// How long a gap in a log may stay open before polls skip it; see
+    /// [`Log::read_from`].
+    gap_grace: Duration,
+    /// Client sends passed on to the leader, by the msg_id they went out
+    /// with, as the client and msg_id to answer.
+    forwarded: HashMap<u32, (String, u32)>,
+    logs: HashMap<String, Log>,
+    kv: Kv,
+    waiting: HashMap<u32, Waiting>,
+    batches: HashMap<u32, Batch>,
+    next_batch: u32,
+}
+
+impl Kafka {
+    pub fn new(config: &Config) -> Kafka {
+        Kafka {
+            poll_max_per_key: config.poll_max_per_key,
+            poll_max: config.poll_max,
+            ack: config.ack,
+            peers: Vec::new(),
+            election: Election::new(config.peer_timeout),
+            // A gap open for longer than a peer may be silent means the
+            // node that allocated it is gone.
+            gap_grace: config.peer_timeout,
+            forwarded: HashMap::new(),
+            logs: HashMap::new(),
+            kv: Kv::lin_kv(),
+            waiting: HashMap::new(),
+            batches: HashMap::new(),
+            next_batch: 0,
+        }
+    }
+
+    /// Allocates the next offset of `key` for a `send` of `msg`, to be
+    /// answered once `lin-kv` has it. A counter holding anything but an
+    /// offset is written back as it was and the send fails, rather than
+    /// starting again from 0 and handing out offsets twice.
+    fn allocate(
+        &mut self,
+        ctx: &mut Context<KafkaBody>,
+        client: String,
+        msg_id: u32,
+        key: &str,
+        msg: &Value,
+    ) {
+        let request = self
+            .kv
+            .read_modify_write(ctx, &Kafka::offset_key(key), |last| match last {
+                None => 0.into(),
+                Some(last) => {
+                    kv::as_u64(last).map_or_else(|| last.clone(), |last| (last + 1).into())
+                }
+            });
+
+        self.waiting.insert(
+            request,
+            Waiting::Send {
+                client,
+                msg_id,
+                key: key.to_string(),
+                msg: msg.clone(),
+            },
+        );
+    }
+
+    /// The node `send`s are being passed on to, if any.
+    pub fn leader(&self) -> Option<&str> {
+        self.election.leader()
+    }
+
+    /// Answers a forwarded send from whatever the leader said.
+    fn on_forwarded(
+        &mut self,
+        ctx: &mut Context<KafkaBody>,
+        (client, msg_id): (String, u32),
+        reply: Result<Message<KafkaBody>, RpcError>,
+    ) {
+        match reply {
+            Ok(Message {
+                body: KafkaBody::SendOk { offset, .. },
+                ..
+            }) => ctx.reply_to(
+                &client,
+                msg_id,
+                KafkaBody::SendOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                    offset,
+                },
+            ),
+            Ok(message) => ctx.reply_error(
+                &client,
+                msg_id,
+                CRASH,
+                format!("leader answered send with {}", message.body.kind()),
+            ),
+            Err(RpcError::Rejected { code, text }) => ctx.reply_error(&client, msg_id, code, text),
+            Err(RpcError::Timeout) => {
+                ctx.reply_error(&client, msg_id, TIMEOUT, "leader did not answer")
+            }
+            Err(error) => {
+                ctx.reply_error(&client, msg_id, TEMPORARILY_UNAVAILABLE, error.to_string())
+            }
+        }
+    }
+
+    /// Reads `offsets` for a poll. The per-request cap is shared out among
+    /// the keys in key order, each getting an even share of what the keys
+    /// before it left, so no key is starved and the reply never goes over
+    /// the cap. Clients poll again from where the reply left off to get the
+    /// rest.
+    fn poll(&self, offsets: &HashMap<String, u64>) -> HashMap<String, Vec<(u64, Value)>> {
+        // Keys we've never seen are left out rather than sent empty.
+        let mut keys: Vec<(&String, u64)> = offsets
+            .iter()
+            .filter(|(key, _)| self.logs.contains_key(*key))
+            .map(|(key, &offset)| (key, offset))
+            .collect();
+        keys.sort();
+
+        let mut budget = self.poll_max.unwrap_or(usize::MAX);
+        let mut msgs = HashMap::new();
+
+        for (left, (key, offset)) in (1..=keys.len()).rev().zip(keys) {
+            let limit = self
+                .poll_max_per_key
+                .unwrap_or(usize::MAX)
+                .min(budget.div_ceil(left));
+
+            let entries = self.logs[key].read_from(offset, limit, self.gap_grace);
+            budget -= entries.len();
+            msgs.insert(key.clone(), entries);
+        }
+
+        msgs
+    }
+
+    fn offset_key(key: &str) -> String {
+        format!("offset/{}", key)
+    }
+
+    fn committed_key(key: &str) -> String {
+        format!("committed/{}", key)
+    }
+
+    /// Starts a commit or list over `keys`, or answers it straight away if
+    /// there are none.
+    fn start_batch(
+        &mut self,
+        ctx: &mut Context<KafkaBody>,
+        client: String,
+        msg_id: u32,
+        list: bool,
+        offsets: HashMap<String, u64>,
+    ) {
+        let batch = self.next_batch;
+        self.next_batch += 1;
+
+        let remaining = offsets.len();
+
+        for (key, offset) in &offsets {
+            let committed_key = Kafka::committed_key(key);
+            let request = if list {
+                self.kv.read(ctx, &committed_key)
+            } else {
+                let offset = *offset;
+                // Never move a committed offset backwards.
+                self.kv
+                    .read_modify_write(ctx, &committed_key, move |current| {
+                        current
+                            .and_then(kv::as_u64)
+                            .map_or(offset, |current| current.max(offset))
+                            .into()
+                    })
+            };
+
+            self.waiting.insert(
+                request,
+                Waiting::Key {
+                    batch,
+                    key: key.clone(),
+                },
+            );
+        }
+
+        self.batches.insert(
+            batch,
+            Batch {
+                client,
+                msg_id,
+                list,
+                remaining,
+                // A list fills these in from lin-kv as the reads come back.
+                offsets: if list { HashMap::new() } else { offsets },
+            },
+        );
+
+        self.finish_batch(ctx, batch);
+    }
+
+    /// Replies to `batch` if it has no keys left to wait for.
+    fn finish_batch(&mut self, ctx: &mut Context<KafkaBody>, batch: u32) {
+        if self
+            .batches
+            .get(&batch)
+            .is_none_or(|batch| batch.remaining > 0)
+        {
+            return;
+        }
+
+        let Some(batch) = self.batches.remove(&batch) else {
+            return;
+        };
+
+        let body = if batch.list {
+            KafkaBody::ListCommittedOffsetsOk {
+                msg_id: 0,
+                in_reply_to: 0,
+                offsets: batch.offsets,
+            }
+        } else {
+            KafkaBody::CommitOffsetsOk {
+                msg_id: 0,
+                in_reply_to: 0,
+            }
+        };
+
+        ctx.reply_to(&batch.client, batch.msg_id, body);
+    }
+
+    fn on_kv_reply(&mut self, ctx: &mut Context<KafkaBody>, reply: KvReply) {
+        let Some(waiting) = self.waiting.remove(&reply.msg_id) else {
+            return;
+        };
+
+        match waiting {
+            Waiting::Send {
+                client,
+                msg_id,
+                key,
+                msg,
+            } => {
+                let offset = match reply.result {
+                    Ok(KvOk::Updated(offset)) => match kv::as_u64(&offset) {
+                        Some(offset) => offset,
+                        None => {
+                            ctx.reply_error(
+                                &client,
+                                msg_id,
+                                CRASH,
+                                format!(
+                                    "offset counter for {} holds {}, not an offset",
+                                    key, offset
+                                ),
+                            );
+                            return;
+                        }
+                    },
+                    Ok(_) => {
+                        let error = KvError::UnexpectedReply;
+                        ctx.reply_error(&client, msg_id, error_code(&error), error.to_string());
+                        return;
+                    }
+                    Err(error) => {
+                        ctx.reply_error(&client, msg_id, error_code(&error), error.to_string());
+                        return;
+                    }
+                };
+
+                let ok = KafkaBody::SendOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                    offset,
+                };
+
+                if self.ack == AckStrategy::Eager {
+                    ctx.reply_to(&client, msg_id, ok.clone());
+                }
+
+                self.logs
+                    .entry(key.clone())
+                    .or_default()
+                    .insert(offset, msg.clone());
+
+                for peer in &self.peers {
+                    ctx.rpc_with(
+                        peer,
+                        KafkaBody::Replicate {
+                            msg_id: 0,
+                            key: key.clone(),
+                            offset,
+                            msg: msg.clone(),
+                        },
+                        Backoff::REPLICATION,
+                    );
+                }
+
+                if self.ack == AckStrategy::Lazy {
+                    ctx.reply_to(&client, msg_id, ok);
+                }
+            }
+
+            Waiting::Key { batch, key } => {
+                let Some(pending) = self.batches.get_mut(&batch) else {
+                    return;
+                };
+
+                match reply.result {
+                    Ok(KvOk::Read(offset)) => {
+                        if let Some(offset) = kv::as_u64(&offset) {
+                            pending.offsets.insert(key, offset);
+                        }
+                    }
+                    // Nothing committed for this key yet.
+                    Err(KvError::KeyDoesNotExist) if pending.list => {}
+                    Ok(_) => {}
+                    Err(error) => {
+                        // The batch fails as a whole; replies for its other
+                        // keys find it gone and are dropped.
+                        let pending = self.batches.remove(&batch).expect("batch exists");
+                        ctx.reply_error(
+                            &pending.client,
+                            pending.msg_id,
+                            error_code(&error),
+                            error.to_string(),
+                        );
+                        return;
+                    }
+                }
+
+                pending.remaining -= 1;
+                self.finish_batch(ctx, batch);
+            }
+        }
+    }
+}
+
+/// The Maelstrom error code a client gets when `lin-kv` let us down. A
+/// definite error keeps its own code, so the client knows nothing happened;
+/// only a reply we couldn't make sense of leaves it indefinite.
+fn error_code(error: &KvError) -> u32 {
+    match error {
+        KvError::KeyDoesNotExist => KEY_DOES_NOT_EXIST,
+        KvError::PreconditionFailed => PRECONDITION_FAILED,
+        KvError::Other { code, .. } => *code,
+        KvError::Timeout => TIMEOUT,
+        KvError::UnexpectedReply => CRASH,
+    }
+}
+
+impl Handler for Kafka {
+    type Body = KafkaBody;
+
+    fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        self.election.init(node_id, node_ids);
+        self.peers = node_ids
+            .iter()
+            .filter(|node| *node != node_id)
+            .cloned()
+            .collect();
+    }
+
+    fn handle(&mut self, message: Message<KafkaBody>, ctx: &mut Context<KafkaBody>) {
+        if self.election.on_message(&message) {
+            return;
+        }
+
+        let body = match &message.body {
+            KafkaBody::Send {
+                msg_id,
+                key,
+                msg,
+                hops,
+            } if !self.election.is_leader() => {
+                let Some(leader) = self.election.leader().map(str::to_string) else {
+                    // Nobody leads yet; allocate here, as lin-kv keeps it safe.
+                    self.allocate(ctx, message.src.clone(), *msg_id, key, msg);
+                    return;
+                };
+
+                let hops = hops.unwrap_or(MAX_SEND_HOPS);
+                if hops == 0 {
+                    ctx.reply_error(
+                        &message.src,
+                        *msg_id,
+                        TEMPORARILY_UNAVAILABLE,
+                        "send passed on too many times; the nodes disagree on who leads",
+                    );
+                    return;
+                }
+
+                let request = ctx.rpc(
+                    &leader,
+                    KafkaBody::Send {
+                        msg_id: 0,
+                        key: key.clone(),
+                        msg: msg.clone(),
+                        hops: Some(hops - 1),
+                    },
+                );
+                self.forwarded
+                    .insert(request, (message.src.clone(), *msg_id));
+                return;
+            }
+
+            KafkaBody::Send {
+                msg_id, key, msg, ..
+            } => {
+                self.allocate(ctx, message.src.clone(), *msg_id, key, msg);
+                return;
+            }
+
+            KafkaBody::Poll { offsets, .. } => KafkaBody::PollOk {
+                msg_id: 0,
+                in_reply_to: 0,
+                msgs: self.poll(offsets),
+            },
+
+            KafkaBody::CommitOffsets { msg_id, offsets } => {
+                self.start_batch(ctx, message.src.clone(), *msg_id, false, offsets.clone());
+                return;
+            }
+
+            KafkaBody::ListCommittedOffsets { msg_id, keys } => {
+                let keys = keys.iter().map(|key| (key.clone(), 0)).collect();
+                self.start_batch(ctx, message.src.clone(), *msg_id, true, keys);
+                return;
+            }
+
+            KafkaBody::Replicate {
+                key, offset, msg, ..
+            } => {
+                self.logs
+                    .entry(key.clone())
+                    .or_default()
+                    .insert(*offset, msg.clone());
+
+                KafkaBody::ReplicateOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                }
+            }
+
+            KafkaBody::SendOk { .. }
+            | KafkaBody::PollOk { .. }
+            | KafkaBody::CommitOffsetsOk { .. }
+            | KafkaBody::ListCommittedOffsetsOk { .. }
+            | KafkaBody::ReplicateOk { .. }
+            | KafkaBody::Heartbeat { .. }
+            | KafkaBody::Read { .. }
+            | KafkaBody::ReadOk { .. }
+            | KafkaBody::Write { .. }
+            | KafkaBody::WriteOk { .. }
+            | KafkaBody::Cas { .. }
+            | KafkaBody::CasOk { .. }
+            | KafkaBody::Unsupported => return,
+        };
+
+        ctx.reply(&message, body);
+    }
+
+    fn reply(
+        &mut self,
+        request: u32,
+        reply: Result<Message<KafkaBody>, RpcError>,
+        ctx: &mut Context<KafkaBody>,
+    ) {
+        if let Some(forwarded) = self.forwarded.remove(&request) {
+            self.on_forwarded(ctx, forwarded, reply);
+            return;
+        }
+
+        if !self.kv.owns(request) {
+            match reply {
+                Ok(message) => self.handle(message, ctx),
+                Err(error) => debug!("request {} failed: {}", request, error),
+            }
+            return;
+        }
+
+        if let Some(reply) = self.kv.on_reply(ctx, request, reply) {
+            self.on_kv_reply(ctx, reply);
+        }
+    }
+
+    fn tick(&mut self, ctx: &mut Context<KafkaBody>) {
+        self.election.tick(ctx);
+        self.kv.tick(ctx);
+    }
+
+    /// The logs; offsets and commits are in `lin-kv` already.
+    fn snapshot(&self) -> Option<Value> {
+        serde_json::to_value(&self.logs).ok()
+    }
+
+    fn restore(&mut self, state: Value) {
+        match serde_json::from_value::<HashMap<String, Log>>(state) {
+            Ok(logs) => {
+                for (key, restored) in logs {
+                    let log = self.logs.entry(key).or_default();
+
+                    for (offset, msg) in restored.entries {
+                        log.insert(offset, msg);
+                    }
+                }
+            }
+            Err(error) => warn!("ignoring malformed snapshot: {}", error),
+        }
+    }
+
+    fn print_summary(&self) {
+        eprintln!(
+            "summary: {} logs holding {} entries",
+            self.logs.len(),
+            self.logs
+                .values()
+                .map(|log| log.entries.len())
+                .sum::<usize>()
+        );
+        eprintln!("summary: {} requests waiting on lin-kv", self.waiting.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::{Codec, JsonCodec},
+        message::Envelope,
+        mock_kv::MockKv,
+        runtime::Runtime,
+        sim::Simulator,
+    };
+    use serde_json::json;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn node() -> (
+        Runtime<KafkaBody>,
+        std::sync::mpsc::Receiver<Message<Envelope<KafkaBody>>>,
+        Kafka,
+    ) {
+        node_with(Config::default())
+    }
+
+    fn node_with(
+        config: Config,
+    ) -> (
+        Runtime<KafkaBody>,
+        std::sync::mpsc::Receiver<Message<Envelope<KafkaBody>>>,
+        Kafka,
+    ) {
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut kafka = Kafka::new(&config);
+
+        runtime.dispatch(
+            &mut kafka,
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#,
+        );
+
+        (runtime, output, kafka)
+    }
+
+    /// Has `n2` replicate `msg` at `offset` of `key` to us.
+    fn replicate(runtime: &mut Runtime<KafkaBody>, kafka: &mut Kafka, key: &str, offset: u64) {
+        let line = json!({
+            "src": "n2",
+            "dest": "n1",
+            "body": {"type": "replicate", "msg_id": offset + 100, "key": key, "offset": offset, "msg": offset * 10},
+        });
+        runtime.dispatch(kafka, &line.to_string());
+    }
+
+    /// Polls as `c1`, with a fresh msg_id each time so it isn't answered as
+    /// a duplicate.
+    fn poll(runtime: &mut Runtime<KafkaBody>, kafka: &mut Kafka, offsets: Value) {
+        static NEXT_MSG_ID: AtomicU32 = AtomicU32::new(1000);
+
+        let line = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": {"type": "poll", "msg_id": NEXT_MSG_ID.fetch_add(1, Ordering::Relaxed), "offsets": offsets},
+        });
+        runtime.dispatch(kafka, &line.to_string());
+    }
+
+    fn polled(
+        output: &std::sync::mpsc::Receiver<Message<Envelope<KafkaBody>>>,
+    ) -> HashMap<String, Vec<(u64, Value)>> {
+        output
+            .try_iter()
+            .find_map(|message| match message.body {
+                Envelope::Body(KafkaBody::PollOk { msgs, .. }) => Some(msgs),
+                _ => None,
+            })
+            .expect("poll_ok")
+    }
+
+    #[test]
+    fn polls_past_the_end_of_a_log_find_nothing() {
+        let (mut runtime, output, mut kafka) = node();
+
+        for offset in 0..3 {
+            replicate(&mut runtime, &mut kafka, "a", offset);
+            replicate(&mut runtime, &mut kafka, "b", offset);
+        }
+
+        poll(&mut runtime, &mut kafka, json!({"a": 1, "b": u64::MAX}));
+
+        let msgs = polled(&output);
+        assert_eq!(msgs["a"], [(1, json!(10)), (2, json!(20))]);
+        assert_eq!(msgs["b"], []);
+    }
+
+    #[test]
+    fn polls_wait_at_a_gap_then_skip_it_once_it_is_stale() {
+        let (mut runtime, output, mut kafka) = node_with(Config {
+            peer_timeout: Duration::from_millis(50),
+            ..Config::default()
+        });
+
+        for offset in [0, 2, 3] {
+            replicate(&mut runtime, &mut kafka, "a", offset);
+        }
+
+        poll(&mut runtime, &mut kafka, json!({"a": 0}));
+        assert_eq!(polled(&output)["a"], [(0, json!(0))]);
+
+        std::thread::sleep(Duration::from_millis(60));
+
+        poll(&mut runtime, &mut kafka, json!({"a": 0}));
+        assert_eq!(
+            polled(&output)["a"],
+            [(0, json!(0)), (2, json!(20)), (3, json!(30))]
+        );
+
+        // Filled late after all: it is served like any other entry.
+        replicate(&mut runtime, &mut kafka, "a", 1);
+        poll(&mut runtime, &mut kafka, json!({"a": 1}));
+        assert_eq!(
+            polled(&output)["a"],
+            [(1, json!(10)), (2, json!(20)), (3, json!(30))]
+        );
+        assert!(kafka.logs["a"].gaps.is_empty());
+    }
+
+    #[test]
+    fn a_long_log_is_polled_a_window_at_a_time() {
+        let (mut runtime, output, mut kafka) = node_with(Config {
+            poll_max_per_key: Some(1_000),
+            ..Config::default()
+        });
+
+        for offset in 0..10_000 {
+            replicate(&mut runtime, &mut kafka, "a", offset);
+        }
+        output.try_iter().for_each(drop);
+
+        let high_water = *kafka.logs["a"].entries.keys().next_back().unwrap();
+
+        for start in [0, 1_000, 9_000] {
+            poll(&mut runtime, &mut kafka, json!({ "a": start }));
+            let entries = &polled(&output)["a"];
+
+            let offsets: Vec<u64> = entries.iter().map(|(offset, _)| *offset).collect();
+            assert_eq!(offsets, (start..start + 1_000).collect::<Vec<_>>());
+
+            // A last offset short of the high-water mark means there's more.
+            let last = *offsets.last().unwrap();
+            assert_eq!(last < high_water, start < 9_000);
+        }
+    }
+
+    #[test]
+    fn a_capped_poll_never_returns_more_than_the_cap() {
+        let (mut runtime, output, mut kafka) = node_with(Config {
+            poll_max: Some(10),
+            ..Config::default()
+        });
+
+        replicate(&mut runtime, &mut kafka, "a", 0);
+        for offset in 0..10 {
+            for key in ["b", "c", "d"] {
+                replicate(&mut runtime, &mut kafka, key, offset);
+            }
+        }
+        output.try_iter().for_each(drop);
+
+        poll(&mut runtime, &mut kafka, json!({"b": 0, "c": 0, "d": 0}));
+        let msgs = polled(&output);
+        assert_eq!(msgs.values().map(Vec::len).sum::<usize>(), 10);
+        assert!(
+            msgs.values().all(|entries| entries.len() >= 3),
+            "{:?}",
+            msgs
+        );
+
+        // What a short key doesn't use goes to the keys after it.
+        poll(&mut runtime, &mut kafka, json!({"a": 0, "b": 0, "c": 0}));
+        let msgs = polled(&output);
+        assert_eq!(msgs["a"].len(), 1);
+        assert_eq!(msgs.values().map(Vec::len).sum::<usize>(), 10);
+    }
+
+    #[test]
+    fn negative_poll_offsets_read_from_the_start() {
+        let (mut runtime, output, mut kafka) = node();
+
+        replicate(&mut runtime, &mut kafka, "a", 0);
+        poll(&mut runtime, &mut kafka, json!({"a": -5}));
+
+        assert_eq!(polled(&output)["a"], [(0, json!(0))]);
+    }
+
+    #[test]
+    fn definite_lin_kv_errors_reach_the_client_with_their_code() {
+        let (mut runtime, output, mut kafka) = node();
+
+        let send = json!({"src": "c1", "dest": "n1", "body": {"type": "send", "msg_id": 5, "key": "k", "msg": 1}});
+        runtime.dispatch(&mut kafka, &send.to_string());
+
+        let read = output
+            .try_iter()
+            .find(|message| message.dest == "lin-kv")
+            .and_then(|message| message.body.msg_id())
+            .expect("offset read");
+
+        let rejected = json!({
+            "src": "lin-kv",
+            "dest": "n1",
+            "body": {"type": "error", "in_reply_to": read, "code": TEMPORARILY_UNAVAILABLE, "text": "busy"},
+        });
+        runtime.dispatch(&mut kafka, &rejected.to_string());
+
+        let error = output
+            .try_iter()
+            .find(|message| message.dest == "c1")
+            .map(|message| serde_json::to_value(message.body).unwrap())
+            .expect("error for the client");
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["in_reply_to"], 5);
+        assert_eq!(error["code"], TEMPORARILY_UNAVAILABLE);
+    }
+
+    #[test]
+    fn offsets_go_out_as_bare_integers_however_the_counter_was_stored() {
+        // A float from lin-kv, and one past the last integer a double holds.
+        for (stored, next) in [
+            (json!(41.0), "42"),
+            (json!(9_007_199_254_740_993u64), "9007199254740994"),
+        ] {
+            let (mut runtime, output, mut kafka) = node();
+            let encode = |message: &Message<Envelope<KafkaBody>>| {
+                String::from_utf8(JsonCodec.encode(message).unwrap()).unwrap()
+            };
+
+            let send = json!({"src": "c1", "dest": "n1", "body": {"type": "send", "msg_id": 5, "key": "k", "msg": 1}});
+            runtime.dispatch(&mut kafka, &send.to_string());
+            let read = output
+                .try_iter()
+                .find(|message| message.dest == "lin-kv")
+                .unwrap();
+            let read_ok = json!({"src": "lin-kv", "dest": "n1", "body": {"type": "read_ok", "in_reply_to": read.body.msg_id(), "value": stored}});
+            runtime.dispatch(&mut kafka, &read_ok.to_string());
+
+            let cas = output
+                .try_iter()
+                .find(|message| message.dest == "lin-kv")
+                .unwrap();
+            assert!(
+                encode(&cas).contains(&format!(r#""to":{}"#, next)),
+                "{}",
+                encode(&cas)
+            );
+            let cas_ok = json!({"src": "lin-kv", "dest": "n1", "body": {"type": "cas_ok", "in_reply_to": cas.body.msg_id()}});
+            runtime.dispatch(&mut kafka, &cas_ok.to_string());
+
+            let send_ok = output
+                .try_iter()
+                .find(|message| message.dest == "c1")
+                .unwrap();
+            assert_eq!(
+                encode(&send_ok),
+                format!(
+                    r#"{{"src":"n1","dest":"c1","body":{{"type":"send_ok","msg_id":{},"in_reply_to":5,"offset":{}}}}}"#,
+                    send_ok.body.msg_id().unwrap(),
+                    next
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn a_counter_that_holds_no_offset_fails_the_send() {
+        let (mut runtime, output, mut kafka) = node();
+
+        let send = json!({"src": "c1", "dest": "n1", "body": {"type": "send", "msg_id": 5, "key": "k", "msg": 1}});
+        runtime.dispatch(&mut kafka, &send.to_string());
+
+        let to_kv = |output: &std::sync::mpsc::Receiver<Message<Envelope<KafkaBody>>>| {
+            output
+                .try_iter()
+                .find(|message| message.dest == "lin-kv")
+                .map(|message| serde_json::to_value(message.body).unwrap())
+                .expect("a lin-kv request")
+        };
+
+        let read = to_kv(&output);
+        let garbage = json!({"src": "lin-kv", "dest": "n1", "body": {"type": "read_ok", "in_reply_to": read["msg_id"], "value": "garbage"}});
+        runtime.dispatch(&mut kafka, &garbage.to_string());
+
+        // Left as it was rather than reset to 0.
+        let cas = to_kv(&output);
+        assert_eq!(cas["type"], "cas");
+        assert_eq!(cas["to"], "garbage");
+
+        let cas_ok = json!({"src": "lin-kv", "dest": "n1", "body": {"type": "cas_ok", "in_reply_to": cas["msg_id"]}});
+        runtime.dispatch(&mut kafka, &cas_ok.to_string());
+
+        let error = output
+            .try_iter()
+            .find(|message| message.dest == "c1")
+            .map(|message| serde_json::to_value(message.body).unwrap())
+            .expect("error for the client");
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["in_reply_to"], 5);
+        assert_eq!(error["code"], CRASH);
+        assert!(!kafka.logs.contains_key("k"));
+    }
+
+    #[test]
+    fn client_sends_are_allocated_by_the_leader() {
+        let config = Config {
+            tick_interval: Duration::from_millis(10),
+            peer_timeout: Duration::from_millis(50),
+            ..Config::default()
+        };
+        let mut simulator = Simulator::new(3, &config, Kafka::new);
+        simulator.add_service("lin-kv", MockKv::new());
+
+        let settled = simulator.run_until(Duration::from_secs(2), |simulator| {
+            simulator
+                .node_ids()
+                .iter()
+                .all(|node| simulator.handler(node).unwrap().leader() == Some("n0"))
+        });
+        assert!(settled);
+
+        for (n, node) in ["n1", "n2", "n1"].into_iter().enumerate() {
+            let reply = simulator.request(
+                node,
+                json!({"type": "send", "key": "k", "msg": n}),
+                Duration::from_secs(2),
+            );
+            assert_eq!(reply.unwrap()["offset"], n);
+        }
+
+        // Only the leader talked to lin-kv.
+        for node in ["n1", "n2"] {
+            let sent = &simulator.stats(node).unwrap()["sent"];
+            assert_eq!(sent["cas"], Value::Null, "{} sent {}", node, sent);
+            assert_eq!(sent["read"], Value::Null, "{} sent {}", node, sent);
+        }
+        assert_eq!(simulator.stats("n0").unwrap()["sent"]["cas"], 3);
+    }
+
+    #[test]
+    fn sends_bouncing_between_nodes_that_disagree_on_the_leader_are_refused() {
+        let config = Config::default();
+        let mut nodes: HashMap<&str, _> = HashMap::new();
+
+        // Each node has heard a heartbeat from the other, so each thinks
+        // the other leads.
+        for (node, other) in [("n1", "n2"), ("n2", "n1")] {
+            let (mut runtime, output) = Runtime::in_memory(&config);
+            let mut kafka = Kafka::new(&config);
+
+            let init = json!({"src": "c0", "dest": node, "body": {"type": "init", "msg_id": 1, "node_id": node, "node_ids": ["n1", "n2"]}});
+            runtime.dispatch(&mut kafka, &init.to_string());
+            let heartbeat = json!({"src": other, "dest": node, "body": {"type": "heartbeat", "msg_id": 2, "term": 1}});
+            runtime.dispatch(&mut kafka, &heartbeat.to_string());
+
+            assert_eq!(kafka.leader(), Some(other));
+            output.try_iter().for_each(drop);
+            nodes.insert(node, (runtime, output, kafka));
+        }
+
+        let send = json!({"src": "c1", "dest": "n1", "body": {"type": "send", "msg_id": 7, "key": "k", "msg": 1}});
+        let (runtime, _, kafka) = nodes.get_mut("n1").unwrap();
+        runtime.dispatch(kafka, &send.to_string());
+
+        // Carry messages between the two until the client hears back.
+        let mut forwards = 0;
+        let mut answers = Vec::new();
+
+        while answers.is_empty() && forwards <= MAX_SEND_HOPS {
+            let sent: Vec<Value> = nodes
+                .values()
+                .flat_map(|(_, output, _)| output.try_iter().collect::<Vec<_>>())
+                .map(|message| serde_json::to_value(message).unwrap())
+                .collect();
+
+            for message in sent {
+                let Some((runtime, _, kafka)) = nodes.get_mut(message["dest"].as_str().unwrap())
+                else {
+                    answers.push(message);
+                    continue;
+                };
+
+                if message["body"]["type"] == "send" {
+                    forwards += 1;
+                }
+                runtime.dispatch(kafka, &message.to_string());
+            }
+        }
+
+        assert_eq!(forwards, MAX_SEND_HOPS);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0]["dest"], "c1");
+        assert_eq!(answers[0]["body"]["type"], "error");
+        assert_eq!(answers[0]["body"]["in_reply_to"], 7);
+        assert_eq!(answers[0]["body"]["code"], TEMPORARILY_UNAVAILABLE);
+    }
+}
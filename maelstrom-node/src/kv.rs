@@ -0,0 +1,665 @@
+use crate::{
+    message::{Body, Message, KEY_DOES_NOT_EXIST, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE},
+    runtime::{Context, RpcError},
+};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// First retry delay after a `cas` conflict; doubles on each further conflict.
+const BACKOFF_BASE: Duration = Duration::from_millis(10);
+const BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Client for one of Maelstrom's built-in key/value services.
+///
+/// Each request method sends its message with [`Context::rpc`] and returns the
+/// msg_id it went out with. Outcomes delivered to
+/// [`Handler::reply`](crate::Handler::reply) for requests the client
+/// [owns](Kv::owns) have to be passed to [`Kv::on_reply`].
+pub struct Kv {
+    service: &'static str,
+    pending: HashMap<u32, Request>,
+    /// Requests sent on behalf of a read-modify-write, keyed by their msg_id
+    /// and pointing at the update they belong to.
+    steps: HashMap<u32, u32>,
+    updates: HashMap<u32, Update>,
+    /// Requests sent by a [`Kv::cas_many`], keyed by their msg_id and pointing
+    /// at the batch they belong to and their place in it.
+    batched: HashMap<u32, (u32, usize)>,
+    batches: HashMap<u32, Batch>,
+}
+
+type UpdateFn = Box<dyn FnMut(Option<&Value>) -> Value>;
+
+/// An in-flight [`Kv::read_modify_write`]. Cycles through read and cas until
+/// the cas goes through, sleeping between attempts when it loses a race.
+struct Update {
+    key: String,
+    update: UpdateFn,
+    /// What the outstanding cas is trying to store.
+    last_value: Value,
+    conflicts: u32,
+    retry_at: Option<Instant>,
+}
+
+/// An in-flight [`Kv::cas_many`]: each op's outcome so far, in the order
+/// the ops were given.
+struct Batch {
+    results: Vec<Option<Result<(), KvError>>>,
+    outstanding: usize,
+}
+
+/// Message bodies that can carry the KV service protocol. Workloads that
+/// use [`Kv`] implement it for their body type.
+pub trait KvBody: Body {
+    fn kv_read(key: String) -> Self;
+    fn kv_write(key: String, value: Value) -> Self;
+    fn kv_cas(key: String, from: Value, to: Value, create_if_not_exists: bool) -> Self;
+
+    /// What a successful service reply says, or `None` if this isn't one.
+    fn kv_reply(&self) -> Option<KvOk>;
+}
+
+/// One of the compare-and-sets sent together by [`Kv::cas_many`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CasOp {
+    pub key: String,
+    pub from: Value,
+    pub to: Value,
+    pub create_if_not_exists: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    Read,
+    Write,
+    Cas,
+    CasMany,
+    ReadModifyWrite,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvOk {
+    Read(Value),
+    Write,
+    Cas,
+    /// The value a read-modify-write ended up storing.
+    Updated(Value),
+    /// How each op of a [`Kv::cas_many`] went, in the order they were given.
+    Batch(Vec<Result<(), KvError>>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvError {
+    KeyDoesNotExist,
+    /// A `cas` whose `from` didn't match the stored value.
+    PreconditionFailed,
+    Other {
+        code: u32,
+        text: String,
+    },
+    /// No reply arrived in time; the request may or may not have been applied.
+    Timeout,
+    /// The service answered with something other than a KV reply.
+    UnexpectedReply,
+}
+
+impl From<RpcError> for KvError {
+    fn from(error: RpcError) -> KvError {
+        match error {
+            RpcError::Timeout => KvError::Timeout,
+            RpcError::Rejected { code, .. } if code == KEY_DOES_NOT_EXIST => {
+                KvError::KeyDoesNotExist
+            }
+            RpcError::Rejected { code, .. } if code == PRECONDITION_FAILED => {
+                KvError::PreconditionFailed
+            }
+            RpcError::Rejected { code, text } => KvError::Other { code, text },
+            RpcError::Overloaded => KvError::Other {
+                code: TEMPORARILY_UNAVAILABLE,
+                text: RpcError::Overloaded.to_string(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::KeyDoesNotExist => write!(f, "key does not exist"),
+            KvError::PreconditionFailed => write!(f, "precondition failed"),
+            KvError::Other { code, text } => write!(f, "kv error {}: {}", code, text),
+            KvError::Timeout => write!(f, "timed out"),
+            KvError::UnexpectedReply => write!(f, "unexpected reply"),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+/// Reads a stored count or offset. A service is free to hand a number back
+/// as a float (`3.0`); a whole one is taken at face value rather than read as
+/// missing, which would restart the count from zero.
+pub fn as_u64(value: &Value) -> Option<u64> {
+    value.as_u64().or_else(|| {
+        let float = value.as_f64()?;
+        (float.fract() == 0.0 && (0.0..=u64::MAX as f64).contains(&float)).then_some(float as u64)
+    })
+}
+
+/// [`as_u64`] for values that may be negative.
+pub fn as_i64(value: &Value) -> Option<i64> {
+    value.as_i64().or_else(|| {
+        let float = value.as_f64()?;
+        (float.fract() == 0.0 && (i64::MIN as f64..=i64::MAX as f64).contains(&float))
+            .then_some(float as i64)
+    })
+}
+
+/// A service reply matched back to the request that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KvReply {
+    /// The msg_id the request was sent with.
+    pub msg_id: u32,
+    pub request: Request,
+    pub result: Result<KvOk, KvError>,
+}
+
+impl Kv {
+    /// The sequentially consistent `seq-kv` service.
+    pub fn seq_kv() -> Kv {
+        Kv::new("seq-kv")
+    }
+
+    /// The linearizable `lin-kv` service.
+    pub fn lin_kv() -> Kv {
+        Kv::new("lin-kv")
+    }
+
+    fn new(service: &'static str) -> Kv {
+        Kv {
+            service,
+            pending: HashMap::new(),
+            steps: HashMap::new(),
+            updates: HashMap::new(),
+            batched: HashMap::new(),
+            batches: HashMap::new(),
+        }
+    }
+
+    pub fn read<B: KvBody>(&mut self, ctx: &mut Context<B>, key: &str) -> u32 {
+        self.send(ctx, Request::Read, B::kv_read(key.to_string()))
+    }
+
+    pub fn write<B: KvBody>(&mut self, ctx: &mut Context<B>, key: &str, value: Value) -> u32 {
+        self.send(ctx, Request::Write, B::kv_write(key.to_string(), value))
+    }
+
+    pub fn cas<B: KvBody>(
+        &mut self,
+        ctx: &mut Context<B>,
+        key: &str,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    ) -> u32 {
+        self.send(
+            ctx,
+            Request::Cas,
+            B::kv_cas(key.to_string(), from, to, create_if_not_exists),
+        )
+    }
+
+    /// Sends every op in `ops` at once rather than waiting for each reply in
+    /// turn, so the lot takes about one round trip. Nothing is reported until
+    /// every op has been answered; then a single `CasMany` [`KvReply`] carries
+    /// each op's outcome as a [`KvOk::Batch`], in the order of `ops`.
+    ///
+    /// Returns an id that the reply will carry as its `msg_id`, or `None` if
+    /// `ops` is empty, as there is nothing to wait for.
+    pub fn cas_many<B: KvBody>(
+        &mut self,
+        ctx: &mut Context<B>,
+        ops: impl IntoIterator<Item = CasOp>,
+    ) -> Option<u32> {
+        let msg_ids: Vec<u32> = ops
+            .into_iter()
+            .map(|op| self.cas(ctx, &op.key, op.from, op.to, op.create_if_not_exists))
+            .collect();
+
+        let id = *msg_ids.first()?;
+        for (index, &msg_id) in msg_ids.iter().enumerate() {
+            self.batched.insert(msg_id, (id, index));
+        }
+        self.batches.insert(
+            id,
+            Batch {
+                results: vec![None; msg_ids.len()],
+                outstanding: msg_ids.len(),
+            },
+        );
+
+        Some(id)
+    }
+
+    /// Replaces the value under `key` with `update(current)`, where `current`
+    /// is `None` if the key doesn't exist yet. The read and cas are repeated,
+    /// with exponential backoff, until the cas isn't beaten by another writer,
+    /// so `update` may run several times.
+    ///
+    /// Returns an id that the final [`KvReply`] will carry as its `msg_id`.
+    /// Retries are only sent from [`Kv::tick`].
+    pub fn read_modify_write<B: KvBody>(
+        &mut self,
+        ctx: &mut Context<B>,
+        key: &str,
+        update: impl FnMut(Option<&Value>) -> Value + 'static,
+    ) -> u32 {
+        let id = self.read(ctx, key);
+
+        self.steps.insert(id, id);
+        self.updates.insert(
+            id,
+            Update {
+                key: key.to_string(),
+                update: Box::new(update),
+                last_value: Value::Null,
+                conflicts: 0,
+                retry_at: None,
+            },
+        );
+
+        id
+    }
+
+    /// Re-reads for any read-modify-write whose backoff has run out.
+    pub fn tick<B: KvBody>(&mut self, ctx: &mut Context<B>) {
+        let now = Instant::now();
+        let due: Vec<(u32, String)> = self
+            .updates
+            .iter_mut()
+            .filter(|(_, update)| update.retry_at.is_some_and(|at| at <= now))
+            .map(|(&id, update)| {
+                update.retry_at = None;
+                (id, update.key.clone())
+            })
+            .collect();
+
+        for (id, key) in due {
+            let msg_id = self.read(ctx, &key);
+            self.steps.insert(msg_id, id);
+        }
+    }
+
+    /// Whether `request` is one of ours, i.e. its outcome belongs in
+    /// [`Kv::on_reply`].
+    pub fn owns(&self, request: u32) -> bool {
+        self.pending.contains_key(&request)
+    }
+
+    /// Takes the outcome of one of our requests. Returns the finished
+    /// [`KvReply`], or `None` while a read-modify-write or batch is still
+    /// going or if the request wasn't ours. A read-modify-write whose step times out fails
+    /// as a whole, since a lost `cas` reply leaves it unknown whether the
+    /// update landed.
+    pub fn on_reply<B: KvBody>(
+        &mut self,
+        ctx: &mut Context<B>,
+        request: u32,
+        reply: Result<Message<B>, RpcError>,
+    ) -> Option<KvReply> {
+        let kind = self.pending.remove(&request)?;
+
+        let result = match reply {
+            Ok(message) => message.body.kv_reply().ok_or(KvError::UnexpectedReply),
+            Err(error) => Err(error.into()),
+        };
+
+        if let Some((id, index)) = self.batched.remove(&request) {
+            return self.collect(id, index, result);
+        }
+
+        match self.steps.remove(&request) {
+            Some(id) => self.advance(ctx, id, result),
+            None => Some(KvReply {
+                msg_id: request,
+                request: kind,
+                result,
+            }),
+        }
+    }
+
+    /// Moves read-modify-write `id` on by one step given the `result` of its
+    /// last request. Returns a reply once it has finished either way.
+    fn advance<B: KvBody>(
+        &mut self,
+        ctx: &mut Context<B>,
+        id: u32,
+        result: Result<KvOk, KvError>,
+    ) -> Option<KvReply> {
+        let update = self.updates.get_mut(&id)?;
+
+        let (from, create) = match result {
+            Ok(KvOk::Read(current)) => (Some(current), false),
+            Err(KvError::KeyDoesNotExist) => (None, true),
+            Ok(KvOk::Cas) => {
+                let update = self.updates.remove(&id)?;
+                return Some(KvReply {
+                    msg_id: id,
+                    request: Request::ReadModifyWrite,
+                    result: Ok(KvOk::Updated(update.last_value)),
+                });
+            }
+            Err(KvError::PreconditionFailed) => {
+                let backoff = BACKOFF_BASE
+                    .saturating_mul(1 << update.conflicts.min(16))
+                    .min(BACKOFF_MAX);
+                update.conflicts += 1;
+                update.retry_at = Some(Instant::now() + backoff);
+                return None;
+            }
+            Err(err) => {
+                self.updates.remove(&id);
+                return Some(KvReply {
+                    msg_id: id,
+                    request: Request::ReadModifyWrite,
+                    result: Err(err),
+                });
+            }
+            Ok(_) => {
+                self.updates.remove(&id);
+                return Some(KvReply {
+                    msg_id: id,
+                    request: Request::ReadModifyWrite,
+                    result: Err(KvError::UnexpectedReply),
+                });
+            }
+        };
+
+        let to = (update.update)(from.as_ref());
+        let key = update.key.clone();
+        update.last_value = to.clone();
+
+        let msg_id = self.cas(ctx, &key, from.unwrap_or_default(), to, create);
+        self.steps.insert(msg_id, id);
+
+        None
+    }
+
+    /// Records the `result` of op `index` of batch `id`. Returns the batch's
+    /// reply once that was the last one outstanding.
+    fn collect(&mut self, id: u32, index: usize, result: Result<KvOk, KvError>) -> Option<KvReply> {
+        let batch = self.batches.get_mut(&id)?;
+
+        batch.results[index] = Some(match result {
+            Ok(KvOk::Cas) => Ok(()),
+            Ok(_) => Err(KvError::UnexpectedReply),
+            Err(err) => Err(err),
+        });
+        batch.outstanding -= 1;
+
+        if batch.outstanding > 0 {
+            return None;
+        }
+
+        let batch = self.batches.remove(&id)?;
+        Some(KvReply {
+            msg_id: id,
+            request: Request::CasMany,
+            result: Ok(KvOk::Batch(batch.results.into_iter().flatten().collect())),
+        })
+    }
+
+    /// Number of requests still waiting for a reply.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn send<B: KvBody>(&mut self, ctx: &mut Context<B>, request: Request, body: B) -> u32 {
+        let msg_id = ctx.rpc(self.service, body);
+
+        self.pending.insert(msg_id, request);
+
+        msg_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        counter::CounterBody,
+        message::Envelope,
+        mock_kv::MockKv,
+        runtime::{Handler, Runtime},
+        sim::Simulator,
+    };
+    use serde_json::json;
+
+    /// On `add`, sets `delta` keys to 1 in one [`Kv::cas_many`] and answers
+    /// once every cas is in.
+    struct Pipeliner {
+        kv: Kv,
+        waiting: HashMap<u32, (String, u32)>,
+    }
+
+    impl Handler for Pipeliner {
+        type Body = CounterBody;
+
+        fn init(&mut self, _node_id: &str, _node_ids: &[String]) {}
+
+        fn handle(&mut self, message: Message<CounterBody>, ctx: &mut Context<CounterBody>) {
+            let CounterBody::Add { msg_id, delta } = message.body else {
+                return;
+            };
+
+            let ops = (0..delta).map(|n| CasOp {
+                key: format!("k{}", n),
+                from: Value::Null,
+                to: 1.into(),
+                create_if_not_exists: true,
+            });
+
+            if let Some(batch) = self.kv.cas_many(ctx, ops) {
+                self.waiting.insert(batch, (message.src, msg_id));
+            }
+        }
+
+        fn reply(
+            &mut self,
+            request: u32,
+            reply: Result<Message<CounterBody>, RpcError>,
+            ctx: &mut Context<CounterBody>,
+        ) {
+            let Some(reply) = self.kv.on_reply(ctx, request, reply) else {
+                return;
+            };
+            assert_eq!(reply.request, Request::CasMany);
+            let Ok(KvOk::Batch(results)) = reply.result else {
+                panic!("not a batch: {:?}", reply.result);
+            };
+            assert!(results.iter().all(Result::is_ok), "{:?}", results);
+
+            let (client, msg_id) = self.waiting.remove(&reply.msg_id).unwrap();
+            ctx.reply_to(
+                &client,
+                msg_id,
+                CounterBody::AddOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                },
+            );
+        }
+    }
+
+    /// Sends `delta` cas requests in one batch on `add`, and a
+    /// read-modify-write of `x` on `read`, keeping every finished reply.
+    #[derive(Default)]
+    struct Recorder {
+        kv: Option<Kv>,
+        replies: Vec<KvReply>,
+    }
+
+    impl Handler for Recorder {
+        type Body = CounterBody;
+
+        fn init(&mut self, _node_id: &str, _node_ids: &[String]) {
+            self.kv = Some(Kv::seq_kv());
+        }
+
+        fn handle(&mut self, message: Message<CounterBody>, ctx: &mut Context<CounterBody>) {
+            let kv = self.kv.as_mut().unwrap();
+
+            match message.body {
+                CounterBody::Add { delta, .. } => {
+                    let ops = (0..delta).map(|n| CasOp {
+                        key: format!("k{}", n),
+                        from: 0.into(),
+                        to: 1.into(),
+                        create_if_not_exists: false,
+                    });
+                    kv.cas_many(ctx, ops);
+                }
+                CounterBody::Read { .. } => {
+                    kv.read_modify_write(ctx, "x", |_| 1.into());
+                }
+                _ => {}
+            }
+        }
+
+        fn reply(
+            &mut self,
+            request: u32,
+            reply: Result<Message<CounterBody>, RpcError>,
+            ctx: &mut Context<CounterBody>,
+        ) {
+            let kv = self.kv.as_mut().unwrap();
+            self.replies.extend(kv.on_reply(ctx, request, reply));
+        }
+    }
+
+    fn recorder() -> (
+        Runtime<CounterBody>,
+        std::sync::mpsc::Receiver<Message<Envelope<CounterBody>>>,
+        Recorder,
+    ) {
+        let (mut runtime, output) = Runtime::in_memory(&Config::default());
+        let mut recorder = Recorder::default();
+
+        runtime.dispatch(
+            &mut recorder,
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+        );
+        output.try_iter().for_each(drop);
+
+        (runtime, output, recorder)
+    }
+
+    /// The msg_ids of every request sent to seq-kv so far.
+    fn to_kv(output: &std::sync::mpsc::Receiver<Message<Envelope<CounterBody>>>) -> Vec<Value> {
+        output
+            .try_iter()
+            .filter(|message| message.dest == "seq-kv")
+            .map(|message| serde_json::to_value(message.body).unwrap()["msg_id"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn cas_many_takes_one_round_trip() {
+        const OPS: usize = 20;
+        let latency = Duration::from_millis(50);
+
+        let mut simulator = Simulator::new(1, &Config::default(), |_| Pipeliner {
+            kv: Kv::seq_kv(),
+            waiting: HashMap::new(),
+        });
+        simulator.add_service("seq-kv", MockKv::new().with_latency(latency));
+
+        let started = Instant::now();
+        let reply = simulator.request("n0", json!({"type": "add", "delta": OPS}), latency * 10);
+        let elapsed = started.elapsed();
+
+        assert_eq!(reply.unwrap()["type"], "add_ok");
+        // One at a time would take OPS round trips.
+        assert!(elapsed < latency * 3, "took {:?}", elapsed);
+
+        let kv = simulator.service("seq-kv").unwrap();
+        for n in 0..OPS {
+            assert_eq!(kv.get(&json!(format!("k{}", n))), Some(&json!(1)));
+        }
+    }
+
+    #[test]
+    fn a_batch_is_reported_once_every_op_is_in_and_in_order() {
+        let (mut runtime, output, mut recorder) = recorder();
+
+        let add =
+            json!({"src": "c1", "dest": "n1", "body": {"type": "add", "msg_id": 2, "delta": 3}});
+        runtime.dispatch(&mut recorder, &add.to_string());
+        let sent = to_kv(&output);
+        assert_eq!(sent.len(), 3);
+
+        // Answered last op first, with the middle one losing its race.
+        let cas_ok = |msg_id: &Value| json!({"src": "seq-kv", "dest": "n1", "body": {"type": "cas_ok", "in_reply_to": msg_id}});
+        runtime.dispatch(&mut recorder, &cas_ok(&sent[2]).to_string());
+        let conflict = json!({"src": "seq-kv", "dest": "n1", "body": {"type": "error", "in_reply_to": sent[1], "code": PRECONDITION_FAILED, "text": "no"}});
+        runtime.dispatch(&mut recorder, &conflict.to_string());
+        assert!(recorder.replies.is_empty());
+
+        runtime.dispatch(&mut recorder, &cas_ok(&sent[0]).to_string());
+
+        assert_eq!(
+            recorder.replies,
+            [KvReply {
+                msg_id: sent[0].as_u64().unwrap() as u32,
+                request: Request::CasMany,
+                result: Ok(KvOk::Batch(vec![
+                    Ok(()),
+                    Err(KvError::PreconditionFailed),
+                    Ok(())
+                ])),
+            }]
+        );
+        assert_eq!(recorder.kv.unwrap().pending(), 0);
+    }
+
+    #[test]
+    fn a_read_modify_write_given_the_wrong_reply_fails() {
+        let (mut runtime, output, mut recorder) = recorder();
+
+        let read = json!({"src": "c1", "dest": "n1", "body": {"type": "read", "msg_id": 2}});
+        runtime.dispatch(&mut recorder, &read.to_string());
+        let sent = to_kv(&output);
+
+        let write_ok = json!({"src": "seq-kv", "dest": "n1", "body": {"type": "write_ok", "in_reply_to": sent[0]}});
+        runtime.dispatch(&mut recorder, &write_ok.to_string());
+
+        assert_eq!(
+            recorder.replies,
+            [KvReply {
+                msg_id: sent[0].as_u64().unwrap() as u32,
+                request: Request::ReadModifyWrite,
+                result: Err(KvError::UnexpectedReply),
+            }]
+        );
+        assert!(recorder.kv.unwrap().updates.is_empty());
+    }
+
+    #[test]
+    fn whole_floats_read_as_integers() {
+        assert_eq!(as_u64(&json!(3)), Some(3));
+        assert_eq!(as_u64(&json!(3.0)), Some(3));
+        assert_eq!(as_u64(&json!(u64::MAX)), Some(u64::MAX));
+        assert_eq!(as_u64(&json!(3.5)), None);
+        assert_eq!(as_u64(&json!(-1.0)), None);
+        assert_eq!(as_u64(&json!("3")), None);
+
+        assert_eq!(as_i64(&json!(-7.0)), Some(-7));
+        assert_eq!(as_i64(&json!(i64::MIN)), Some(i64::MIN));
+        assert_eq!(as_i64(&json!(0.25)), None);
+    }
+}
@@ -0,0 +1,614 @@
+use crate::{
+    config::{AckStrategy, Config},
+    counter::CounterBody,
+    kv::{self, Kv, KvError, KvOk, KvReply},
+    message::{Message, TEMPORARILY_UNAVAILABLE, TIMEOUT},
+    runtime::{Context, DebugState, Handler, RpcError},
+};
+use log::debug;
+use std::{collections::HashMap, str::FromStr};
+
+/// The key every node updates when the counter is kept as a single value.
+const SHARED_KEY: &str = "counter";
+
+/// How strongly a [`KvCounter`] keeps its value, chosen at startup with
+/// `--consistency`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CounterConsistency {
+    /// A G-counter per node in `seq-kv`, summed on read. Each node writes
+    /// only its own key, so nothing ever contends.
+    #[default]
+    Eventual,
+    /// One shared value in `seq-kv`, updated by `cas`.
+    Sequential,
+    /// One shared value in `lin-kv`, updated by `cas`.
+    Linearizable,
+}
+
+impl FromStr for CounterConsistency {
+    type Err = String;
+
+    /// Parses `eventual`, `sequential` or `linearizable`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "eventual" => Ok(CounterConsistency::Eventual),
+            "sequential" => Ok(CounterConsistency::Sequential),
+            "linearizable" => Ok(CounterConsistency::Linearizable),
+            _ => Err(format!("unknown counter consistency {}", value)),
+        }
+    }
+}
+
+/// A client `read` being answered: the totals gathered so far and how many
+/// nodes are still to come.
+#[derive(Debug)]
+struct PendingRead {
+    client: String,
+    msg_id: u32,
+    remaining: usize,
+    value: i64,
+}
+
+/// What a `seq-kv` request was sent for.
+#[derive(Debug)]
+enum Waiting {
+    /// Our own total going out.
+    Flush { total: i64 },
+    /// Our own key being read back, to `cas` from. The first time, it also
+    /// picks up whatever an earlier run of this node had counted.
+    Load { first: bool },
+    /// The sentinel write that starts read `read`.
+    Sentinel { read: u32 },
+    /// One node's total for read `read`.
+    Total { read: u32 },
+    /// A client's `add` to the shared value.
+    Add { client: String, msg_id: u32 },
+    /// A client's `read` of the shared value.
+    Value { client: String, msg_id: u32 },
+}
+
+/// Counter kept in a KV service rather than gossiped between nodes.
+///
+/// With [`CounterConsistency::Eventual`], each node writes only its own
+/// running total, under `counter/<node>`, so writes never conflict. A `read`
+/// sums every node's key, but `seq-kv` is only sequentially consistent and may
+/// serve a stale total to a node that hasn't written lately. Writing a fresh
+/// sentinel value first moves this node's view of the store up to date, so the
+/// reads that follow see every total that was written before the `read`
+/// arrived.
+///
+/// Totals go out as a `cas` from the last value read back or written, never
+/// as a blind write, so a node restarted without its state adds to what it
+/// had counted before rather than resetting it.
+///
+/// The other levels keep one value under `counter` and apply each `add` to it
+/// with a read-modify-write, answering the client once the `cas` lands,
+/// whatever the [`AckStrategy`].
+pub struct KvCounter {
+    consistency: CounterConsistency,
+    ack: AckStrategy,
+    node_id: String,
+    node_ids: Vec<String>,
+    kv: Kv,
+    /// Net of every delta added here; may be negative.
+    total: i64,
+    /// The value last read back or written under our key, to `cas` from;
+    /// `None` until it has been read.
+    flushed: Option<i64>,
+    /// Whether a read or `cas` of our key is outstanding; only one is sent
+    /// at a time so they can't land out of order.
+    flushing: bool,
+    sentinel: u64,
+    waiting: HashMap<u32, Waiting>,
+    reads: HashMap<u32, PendingRead>,
+    next_read: u32,
+}
+
+impl KvCounter {
+    pub fn new(config: &Config) -> KvCounter {
+        let consistency = config.counter_consistency;
+
+        KvCounter {
+            consistency,
+            ack: config.ack,
+            node_id: String::new(),
+            node_ids: Vec::new(),
+            kv: match consistency {
+                CounterConsistency::Linearizable => Kv::lin_kv(),
+                CounterConsistency::Eventual | CounterConsistency::Sequential => Kv::seq_kv(),
+            },
+            total: 0,
+            flushed: None,
+            flushing: false,
+            sentinel: 0,
+            waiting: HashMap::new(),
+            reads: HashMap::new(),
+            next_read: 0,
+        }
+    }
+
+    fn total_key(node_id: &str) -> String {
+        format!("counter/{}", node_id)
+    }
+
+    /// Applies `delta` to the shared value, answering the client once it has.
+    fn add_shared(
+        &mut self,
+        ctx: &mut Context<CounterBody>,
+        client: String,
+        msg_id: u32,
+        delta: i64,
+    ) {
+        let request = self.kv.read_modify_write(ctx, SHARED_KEY, move |current| {
+            (current.and_then(kv::as_i64).unwrap_or_default() + delta).into()
+        });
+
+        self.waiting
+            .insert(request, Waiting::Add { client, msg_id });
+    }
+
+    /// Sends our total if `seq-kv` is behind and nothing is in flight,
+    /// reading our key first if we don't know what it holds.
+    fn flush(&mut self, ctx: &mut Context<CounterBody>) {
+        if self.consistency != CounterConsistency::Eventual || self.flushing {
+            return;
+        }
+
+        match self.flushed {
+            None => self.load(ctx, true),
+            Some(flushed) if flushed == self.total => {}
+            Some(flushed) => {
+                let key = KvCounter::total_key(&self.node_id);
+                let request = self
+                    .kv
+                    .cas(ctx, &key, flushed.into(), self.total.into(), true);
+
+                self.flushing = true;
+                self.waiting
+                    .insert(request, Waiting::Flush { total: self.total });
+            }
+        }
+    }
+
+    fn load(&mut self, ctx: &mut Context<CounterBody>, first: bool) {
+        let request = self.kv.read(ctx, &KvCounter::total_key(&self.node_id));
+
+        self.flushing = true;
+        self.waiting.insert(request, Waiting::Load { first });
+    }
+
+    fn start_read(&mut self, ctx: &mut Context<CounterBody>, client: String, msg_id: u32) {
+        let read = self.next_read;
+        self.next_read += 1;
+
+        self.sentinel += 1;
+
+        let key = format!("sentinel/{}", self.node_id);
+        let request = self.kv.write(ctx, &key, self.sentinel.into());
+
+        self.waiting.insert(request, Waiting::Sentinel { read });
+        self.reads.insert(
+            read,
+            PendingRead {
+                client,
+                msg_id,
+                // Our own total is known locally and never read back.
+                remaining: self.node_ids.len().saturating_sub(1),
+                value: self.total,
+            },
+        );
+    }
+
+    fn on_kv_reply(&mut self, ctx: &mut Context<CounterBody>, reply: KvReply) {
+        let Some(waiting) = self.waiting.remove(&reply.msg_id) else {
+            return;
+        };
+
+        match waiting {
+            Waiting::Flush { total } => {
+                self.flushing = false;
+
+                match reply.result {
+                    Ok(_) => {
+                        self.flushed = Some(total);
+                        self.flush(ctx);
+                    }
+                    // An earlier cas that timed out did land after all, or
+                    // our read was stale; find out what is there.
+                    Err(KvError::PreconditionFailed) => self.load(ctx, false),
+                    // Tried again on the next tick.
+                    Err(error) => debug!("writing counter total failed: {}", error),
+                }
+            }
+
+            Waiting::Load { first } => {
+                self.flushing = false;
+
+                let stored = match reply.result {
+                    Ok(KvOk::Read(value)) => kv::as_i64(&value).unwrap_or_default(),
+                    Err(KvError::KeyDoesNotExist) => 0,
+                    Ok(_) => {
+                        debug!("reading counter total failed: {}", KvError::UnexpectedReply);
+                        return;
+                    }
+                    // Tried again on the next tick.
+                    Err(error) => {
+                        debug!("reading counter total failed: {}", error);
+                        return;
+                    }
+                };
+
+                // Our total is only ever written from here, so after the
+                // first read what we hold locally is the truth.
+                if first {
+                    self.total += stored;
+                }
+
+                self.flushed = Some(stored);
+                self.flush(ctx);
+            }
+
+            Waiting::Sentinel { read } => {
+                if let Err(error) = reply.result {
+                    self.fail_read(ctx, read, &error);
+                    return;
+                }
+
+                let peers: Vec<String> = self
+                    .node_ids
+                    .iter()
+                    .filter(|node| **node != self.node_id)
+                    .cloned()
+                    .collect();
+
+                for node in peers {
+                    let request = self.kv.read(ctx, &KvCounter::total_key(&node));
+                    self.waiting.insert(request, Waiting::Total { read });
+                }
+
+                self.finish_read(ctx, read);
+            }
+
+            Waiting::Total { read } => {
+                let total = match reply.result {
+                    Ok(KvOk::Read(value)) => kv::as_i64(&value).unwrap_or_default(),
+                    // That node hasn't counted anything yet.
+                    Err(KvError::KeyDoesNotExist) => 0,
+                    Ok(_) => {
+                        self.fail_read(ctx, read, &KvError::UnexpectedReply);
+                        return;
+                    }
+                    Err(error) => {
+                        self.fail_read(ctx, read, &error);
+                        return;
+                    }
+                };
+
+                let Some(pending) = self.reads.get_mut(&read) else {
+                    return;
+                };
+
+                pending.value += total;
+                pending.remaining -= 1;
+
+                self.finish_read(ctx, read);
+            }
+
+            Waiting::Add { client, msg_id } => match reply.result {
+                Ok(_) => ctx.reply_to(
+                    &client,
+                    msg_id,
+                    CounterBody::AddOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    },
+                ),
+                // The cas may have landed; the client can't tell either.
+                Err(KvError::Timeout) => {
+                    ctx.reply_error(&client, msg_id, TIMEOUT, "counter update timed out")
+                }
+                Err(error) => ctx.reply_error(
+                    &client,
+                    msg_id,
+                    TEMPORARILY_UNAVAILABLE,
+                    format!("{}: {}", self.service(), error),
+                ),
+            },
+
+            Waiting::Value { client, msg_id } => {
+                let value = match reply.result {
+                    Ok(KvOk::Read(value)) => kv::as_i64(&value).unwrap_or_default(),
+                    // Nothing has been added yet.
+                    Err(KvError::KeyDoesNotExist) => 0,
+                    Ok(_) => {
+                        ctx.reply_error(
+                            &client,
+                            msg_id,
+                            TEMPORARILY_UNAVAILABLE,
+                            format!("{}: {}", self.service(), KvError::UnexpectedReply),
+                        );
+                        return;
+                    }
+                    Err(error) => {
+                        ctx.reply_error(
+                            &client,
+                            msg_id,
+                            TEMPORARILY_UNAVAILABLE,
+                            format!("{}: {}", self.service(), error),
+                        );
+                        return;
+                    }
+                };
+
+                ctx.reply_to(
+                    &client,
+                    msg_id,
+                    CounterBody::ReadOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        value: value.into(),
+                    },
+                );
+            }
+        }
+    }
+
+    fn service(&self) -> &'static str {
+        match self.consistency {
+            CounterConsistency::Linearizable => "lin-kv",
+            CounterConsistency::Eventual | CounterConsistency::Sequential => "seq-kv",
+        }
+    }
+
+    /// Replies to `read` once every node's total is in.
+    fn finish_read(&mut self, ctx: &mut Context<CounterBody>, read: u32) {
+        if self.reads.get(&read).is_none_or(|read| read.remaining > 0) {
+            return;
+        }
+
+        let Some(pending) = self.reads.remove(&read) else {
+            return;
+        };
+
+        ctx.reply_to(
+            &pending.client,
+            pending.msg_id,
+            CounterBody::ReadOk {
+                msg_id: 0,
+                in_reply_to: 0,
+                value: pending.value.into(),
+            },
+        );
+    }
+
+    /// Answers `read` with an error. A read changes nothing, so the client
+    /// can simply try again; replies for its other keys find it gone.
+    fn fail_read(&mut self, ctx: &mut Context<CounterBody>, read: u32, error: &KvError) {
+        if let Some(pending) = self.reads.remove(&read) {
+            ctx.reply_error(
+                &pending.client,
+                pending.msg_id,
+                TEMPORARILY_UNAVAILABLE,
+                format!("seq-kv: {}", error),
+            );
+        }
+    }
+}
+
+impl Handler for KvCounter {
+    type Body = CounterBody;
+
+    fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        self.node_id = node_id.to_string();
+        self.node_ids = node_ids.to_vec();
+    }
+
+    fn handle(&mut self, message: Message<CounterBody>, ctx: &mut Context<CounterBody>) {
+        let shared = self.consistency != CounterConsistency::Eventual;
+
+        match &message.body {
+            CounterBody::Add { msg_id, delta } if shared => {
+                self.add_shared(ctx, message.src.clone(), *msg_id, *delta)
+            }
+
+            CounterBody::Read { msg_id, .. } if shared => {
+                let request = self.kv.read(ctx, SHARED_KEY);
+                self.waiting.insert(
+                    request,
+                    Waiting::Value {
+                        client: message.src.clone(),
+                        msg_id: *msg_id,
+                    },
+                );
+            }
+
+            CounterBody::Add { delta, .. } => {
+                let ok = CounterBody::AddOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                };
+
+                if self.ack == AckStrategy::Eager {
+                    ctx.reply(&message, ok.clone());
+                }
+
+                self.total += delta;
+                self.flush(ctx);
+
+                if self.ack == AckStrategy::Lazy {
+                    ctx.reply(&message, ok);
+                }
+            }
+
+            CounterBody::Read { msg_id, .. } => self.start_read(ctx, message.src.clone(), *msg_id),
+
+            _ => {}
+        }
+    }
+
+    fn reply(
+        &mut self,
+        request: u32,
+        reply: Result<Message<CounterBody>, RpcError>,
+        ctx: &mut Context<CounterBody>,
+    ) {
+        if !self.kv.owns(request) {
+            match reply {
+                Ok(message) => self.handle(message, ctx),
+                Err(error) => debug!("request {} failed: {}", request, error),
+            }
+            return;
+        }
+
+        if let Some(reply) = self.kv.on_reply(ctx, request, reply) {
+            self.on_kv_reply(ctx, reply);
+        }
+    }
+
+    /// Retries a total that failed to go out, and any `cas` on the shared
+    /// value that lost a race.
+    fn tick(&mut self, ctx: &mut Context<CounterBody>) {
+        self.flush(ctx);
+        self.kv.tick(ctx);
+    }
+
+    fn print_summary(&self) {
+        eprintln!(
+            "summary: counter total {} ({} written to seq-kv)",
+            self.total,
+            self.flushed.unwrap_or_default()
+        );
+    }
+
+    fn debug_state(&self, state: &mut DebugState) {
+        state.counter = Some(self.total);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock_kv::MockKv, sim::Simulator};
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn cluster(consistency: CounterConsistency, service: &str, kv: MockKv) -> Simulator<KvCounter> {
+        let config = Config {
+            counter_consistency: consistency,
+            ..Config::default()
+        };
+
+        let mut simulator = Simulator::new(3, &config, KvCounter::new);
+        simulator.add_service(service, kv);
+        simulator
+    }
+
+    fn add_everywhere(simulator: &mut Simulator<KvCounter>) {
+        for (n, node) in simulator.node_ids().iter().enumerate() {
+            let reply = simulator.request(
+                node,
+                json!({"type": "add", "delta": n + 1}),
+                Duration::from_secs(2),
+            );
+
+            assert_eq!(reply.unwrap()["type"], "add_ok");
+        }
+    }
+
+    #[test]
+    fn eventual_nodes_write_only_their_own_key() {
+        let mut simulator = cluster(CounterConsistency::Eventual, "seq-kv", MockKv::new());
+
+        add_everywhere(&mut simulator);
+        simulator.run_for(Duration::from_millis(300));
+
+        // Each node's key holds its own adds and nothing else.
+        let kv = simulator.service("seq-kv").unwrap();
+        assert_eq!(kv.get(&json!("counter/n0")), Some(&json!(1)));
+        assert_eq!(kv.get(&json!("counter/n1")), Some(&json!(2)));
+        assert_eq!(kv.get(&json!("counter/n2")), Some(&json!(3)));
+        assert_eq!(kv.get(&json!(SHARED_KEY)), None);
+
+        let reply = simulator.request("n1", json!({"type": "read"}), Duration::from_secs(2));
+        assert_eq!(reply.unwrap()["value"], 6);
+    }
+
+    #[test]
+    fn linearizable_adds_cas_one_value_in_lin_kv() {
+        let mut simulator = cluster(
+            CounterConsistency::Linearizable,
+            "lin-kv",
+            MockKv::new().with_cas_conflicts(0.3),
+        );
+
+        add_everywhere(&mut simulator);
+
+        let kv = simulator.service("lin-kv").unwrap();
+        assert_eq!(kv.get(&json!(SHARED_KEY)), Some(&json!(6)));
+        assert_eq!(kv.get(&json!("counter/n0")), None);
+        assert!(simulator
+            .replies()
+            .iter()
+            .all(|message| message.dest != "seq-kv"));
+
+        let reply = simulator.request("n2", json!({"type": "read"}), Duration::from_secs(2));
+        assert_eq!(reply.unwrap()["value"], 6);
+    }
+
+    #[test]
+    fn a_restarted_node_adds_to_its_old_total() {
+        let mut seq_kv = MockKv::new();
+        let earlier_run = Message {
+            src: "n0".to_string(),
+            dest: "seq-kv".to_string(),
+            body: json!({"type": "write", "msg_id": 1, "key": "counter/n0", "value": 5}),
+        };
+        seq_kv.handle(&earlier_run);
+
+        let mut simulator = cluster(CounterConsistency::Eventual, "seq-kv", seq_kv);
+
+        add_everywhere(&mut simulator);
+        simulator.run_for(Duration::from_millis(300));
+
+        let kv = simulator.service("seq-kv").unwrap();
+        assert_eq!(kv.get(&json!("counter/n0")), Some(&json!(6)));
+
+        let reply = simulator.request("n2", json!({"type": "read"}), Duration::from_secs(2));
+        assert_eq!(reply.unwrap()["value"], 11);
+    }
+
+    #[test]
+    fn the_ack_strategy_orders_add_ok_around_the_kv_request() {
+        use crate::{message::Envelope, runtime::Runtime};
+
+        for (ack, expected) in [
+            (AckStrategy::Eager, ["c1", "seq-kv"]),
+            (AckStrategy::Lazy, ["seq-kv", "c1"]),
+        ] {
+            let config = Config {
+                ack,
+                ..Config::default()
+            };
+            let (mut runtime, output) = Runtime::in_memory(&config);
+            let mut counter = KvCounter::new(&config);
+
+            runtime.dispatch(
+                &mut counter,
+                r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+            );
+            output.try_iter().for_each(drop);
+
+            runtime.dispatch(
+                &mut counter,
+                r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":5}}"#,
+            );
+
+            let order: Vec<String> = output
+                .try_iter()
+                .map(|message: Message<Envelope<CounterBody>>| message.dest)
+                .collect();
+            assert_eq!(order, expected, "{:?}", ack);
+            assert_eq!(counter.total, 5);
+        }
+    }
+}
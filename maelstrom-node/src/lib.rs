@@ -0,0 +1,50 @@
+//! Shared plumbing for Gossip Glomers nodes: the Maelstrom message types, the
+//! stdin/stdout event loop, and the handlers that implement each workload.
+
+pub mod builder;
+pub mod causal;
+pub mod cli;
+pub mod codec;
+pub mod config;
+pub mod counter;
+pub mod crdt;
+pub mod dedup;
+pub mod echo;
+pub mod election;
+pub mod error;
+pub mod gossip;
+pub mod hash;
+pub mod health;
+pub mod ids;
+pub mod kafka;
+pub mod kv;
+pub mod kv_counter;
+pub mod message;
+pub mod metrics;
+pub mod mock_kv;
+pub mod mvcc;
+pub mod node;
+pub mod outbox;
+pub mod persist;
+pub mod pool;
+pub mod raft;
+pub mod ranges;
+pub mod replay;
+pub mod rtt;
+pub mod runtime;
+pub mod sim;
+pub mod topology;
+pub mod txn;
+pub mod unique_ids;
+pub mod vector_clock;
+
+#[cfg(test)]
+mod test_log;
+
+pub use builder::NodeBuilder;
+pub use codec::{Codec, JsonCodec};
+pub use config::{Config, Workload};
+pub use error::Error;
+pub use message::{Body, Message, MessageBody};
+pub use node::Node;
+pub use runtime::{Backoff, Context, DebugState, Handler, RpcError, Runtime, SpanId};
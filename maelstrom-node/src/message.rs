@@ -0,0 +1,494 @@
+use crate::vector_clock::VectorClock;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::Arc,
+};
+
+/// What the runtime needs to know about a workload's message bodies. Each
+/// workload defines an enum with just the message types it speaks and
+/// implements this for it; `init`, `init_ok`, `error` and `stats` are handled
+/// by the runtime and never reach it.
+pub trait Body: Serialize + DeserializeOwned + fmt::Debug + Clone + Send + 'static {
+    /// The value of the body's `type` tag.
+    fn kind(&self) -> &'static str;
+
+    fn msg_id(&self) -> Option<u32>;
+
+    /// The request this body answers, if it is a reply.
+    fn in_reply_to(&self) -> Option<u32>;
+
+    /// Overwrites the body's `msg_id`. Bodies without one are left alone.
+    fn set_msg_id(&mut self, msg_id: u32);
+
+    /// Overwrites the body's `in_reply_to`. Bodies that aren't replies are
+    /// left alone.
+    fn set_in_reply_to(&mut self, in_reply_to: u32);
+
+    /// Whether this is a `#[serde(other)]` catch-all for a type the workload
+    /// doesn't know. Without such a variant, unknown types are malformed.
+    fn is_unsupported(&self) -> bool {
+        false
+    }
+}
+
+/// The bodies every node handles the same way, whatever its workload.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Control {
+    Init {
+        msg_id: u32,
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk {
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    Error {
+        in_reply_to: u32,
+        code: u32,
+        text: String,
+    },
+    /// Not part of Maelstrom: asks for the node's traffic counters, e.g.
+    /// from a hand-fed session.
+    Stats {
+        msg_id: u32,
+    },
+    /// A [`Metrics`](crate::metrics::Metrics) snapshot.
+    StatsOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        stats: Value,
+    },
+}
+
+impl Control {
+    fn kind(&self) -> &'static str {
+        match self {
+            Control::Init { .. } => "init",
+            Control::InitOk { .. } => "init_ok",
+            Control::Error { .. } => "error",
+            Control::Stats { .. } => "stats",
+            Control::StatsOk { .. } => "stats_ok",
+        }
+    }
+}
+
+/// A body as it appears on the wire: either one the runtime handles itself or
+/// one for the workload.
+#[derive(Debug, Clone)]
+pub enum Envelope<B> {
+    Control(Control),
+    Body(B),
+}
+
+impl<B: Body> Envelope<B> {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Envelope::Control(control) => control.kind(),
+            Envelope::Body(body) => body.kind(),
+        }
+    }
+
+    pub fn msg_id(&self) -> Option<u32> {
+        match self {
+            Envelope::Control(
+                Control::Init { msg_id, .. }
+                | Control::InitOk { msg_id, .. }
+                | Control::Stats { msg_id }
+                | Control::StatsOk { msg_id, .. },
+            ) => Some(*msg_id),
+            Envelope::Control(Control::Error { .. }) => None,
+            Envelope::Body(body) => body.msg_id(),
+        }
+    }
+
+    pub fn in_reply_to(&self) -> Option<u32> {
+        match self {
+            Envelope::Control(Control::Init { .. } | Control::Stats { .. }) => None,
+            Envelope::Control(
+                Control::InitOk { in_reply_to, .. }
+                | Control::Error { in_reply_to, .. }
+                | Control::StatsOk { in_reply_to, .. },
+            ) => Some(*in_reply_to),
+            Envelope::Body(body) => body.in_reply_to(),
+        }
+    }
+
+    /// Restamps the body's msg_id, if it has one.
+    pub fn set_msg_id(&mut self, id: u32) {
+        match self {
+            Envelope::Control(
+                Control::Init { msg_id, .. }
+                | Control::InitOk { msg_id, .. }
+                | Control::Stats { msg_id }
+                | Control::StatsOk { msg_id, .. },
+            ) => *msg_id = id,
+            Envelope::Control(Control::Error { .. }) => {}
+            Envelope::Body(body) => body.set_msg_id(id),
+        }
+    }
+}
+
+impl<B: Serialize> Serialize for Envelope<B> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Envelope::Control(control) => control.serialize(serializer),
+            Envelope::Body(body) => body.serialize(serializer),
+        }
+    }
+}
+
+/// Picks the side by the `type` tag, so a malformed workload body is reported
+/// with the workload's own error rather than as "no variant matched".
+impl<'de, B: DeserializeOwned> Deserialize<'de> for Envelope<B> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let value = Value::deserialize(deserializer)?;
+
+        match value.get("type").and_then(Value::as_str) {
+            Some("init" | "init_ok" | "error" | "stats" | "stats_ok") => {
+                Control::deserialize(value)
+                    .map(Envelope::Control)
+                    .map_err(D::Error::custom)
+            }
+            _ => B::deserialize(value)
+                .map(Envelope::Body)
+                .map_err(D::Error::custom),
+        }
+    }
+}
+
+/// Message types of the broadcast workload (challenges #3a-e), served by
+/// [`Node`](crate::Node), plus the KV requests it answers from its own store.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageBody {
+    Broadcast {
+        msg_id: u32,
+        /// Any JSON value; the checker only cares that it comes back verbatim.
+        #[serde(rename = "message")]
+        msg: Value,
+    },
+    BroadcastOk {
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    /// Without a key, reads the broadcast set; with one, reads that key like
+    /// a KV service would.
+    Read {
+        msg_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    /// Carries `messages` for a read of the broadcast set and `value` for a
+    /// keyed read; whichever doesn't apply is left out.
+    ReadOk {
+        #[serde(default)]
+        msg_id: u32,
+        in_reply_to: u32,
+        /// Shared rather than owned, so a large set goes out without being
+        /// copied for every read.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        messages: Option<Arc<Vec<Value>>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<Value>,
+    },
+    /// Reads several keys of the same store as a keyed `Read` at once.
+    ReadMany {
+        msg_id: u32,
+        keys: Vec<String>,
+    },
+    /// The value of each requested key that exists; missing keys are left
+    /// out.
+    ReadManyOk {
+        #[serde(default)]
+        msg_id: u32,
+        in_reply_to: u32,
+        values: BTreeMap<String, Value>,
+    },
+    /// Node-to-node: a batch of broadcast values the sender wants to share.
+    Gossip {
+        msg_id: u32,
+        messages: Vec<Value>,
+    },
+    /// Acknowledges a `Gossip`, echoing the values that were received.
+    GossipOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        messages: Vec<Value>,
+    },
+    /// Node-to-node, in causal mode: one broadcast value, flooded with the
+    /// vector clock `origin` stamped it with.
+    CausalBroadcast {
+        msg_id: u32,
+        origin: String,
+        clock: VectorClock,
+        #[serde(rename = "message")]
+        msg: Value,
+        /// How many nodes it has passed through since leaving `origin`.
+        #[serde(default)]
+        hops: u32,
+    },
+    CausalBroadcastOk {
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    /// Node-to-node anti-entropy: a digest of the sender's broadcast set. A
+    /// receiver whose own digest differs answers with `SyncState`.
+    Sync {
+        msg_id: u32,
+        count: usize,
+        checksum: u64,
+    },
+    /// The whole broadcast set, sent when digests didn't match: integers as
+    /// inclusive `[start, end]` runs, anything else listed as is.
+    SyncState {
+        msg_id: u32,
+        ranges: Vec<(u64, u64)>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        others: Vec<Value>,
+    },
+    Topology {
+        msg_id: u32,
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        /// Debug-only echo of the neighbors this node adopted; only sent when
+        /// the node runs with `--debug-topology`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        adopted_neighbors: Option<Vec<String>>,
+    },
+    /// Admin request, not part of any workload: switch to another
+    /// [`TopologyStrategy`](crate::topology::TopologyStrategy) (e.g.
+    /// `"tree"` with `args` `[4]`) and rebuild the overlay on the spot. Sent
+    /// to one node, it is passed on to the rest of the cluster.
+    SetTopology {
+        msg_id: u32,
+        strategy: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        args: Vec<usize>,
+    },
+    /// Answers `SetTopology` with the neighbors the node now has.
+    SetTopologyOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        neighbors: Vec<String>,
+    },
+    /// Maelstrom KV service request, also served by a [`Node`](crate::Node)
+    /// from its own store; replied to with `WriteOk`.
+    Write {
+        msg_id: u32,
+        key: String,
+        value: Value,
+    },
+    WriteOk {
+        #[serde(default)]
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    /// Maelstrom KV service compare-and-swap; replied to with `CasOk`.
+    Cas {
+        msg_id: u32,
+        key: String,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+    CasOk {
+        #[serde(default)]
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    /// Any `type` not listed above. The runtime answers these itself, so
+    /// handlers never see them.
+    #[serde(other)]
+    Unsupported,
+}
+
+impl Body for MessageBody {
+    fn kind(&self) -> &'static str {
+        match self {
+            MessageBody::Broadcast { .. } => "broadcast",
+            MessageBody::BroadcastOk { .. } => "broadcast_ok",
+            MessageBody::Read { .. } => "read",
+            MessageBody::ReadOk { .. } => "read_ok",
+            MessageBody::ReadMany { .. } => "read_many",
+            MessageBody::ReadManyOk { .. } => "read_many_ok",
+            MessageBody::Gossip { .. } => "gossip",
+            MessageBody::GossipOk { .. } => "gossip_ok",
+            MessageBody::CausalBroadcast { .. } => "causal_broadcast",
+            MessageBody::CausalBroadcastOk { .. } => "causal_broadcast_ok",
+            MessageBody::Sync { .. } => "sync",
+            MessageBody::SyncState { .. } => "sync_state",
+            MessageBody::Topology { .. } => "topology",
+            MessageBody::TopologyOk { .. } => "topology_ok",
+            MessageBody::SetTopology { .. } => "set_topology",
+            MessageBody::SetTopologyOk { .. } => "set_topology_ok",
+            MessageBody::Write { .. } => "write",
+            MessageBody::WriteOk { .. } => "write_ok",
+            MessageBody::Cas { .. } => "cas",
+            MessageBody::CasOk { .. } => "cas_ok",
+            MessageBody::Unsupported => "unsupported",
+        }
+    }
+
+    fn msg_id(&self) -> Option<u32> {
+        match self {
+            MessageBody::Broadcast { msg_id, .. }
+            | MessageBody::BroadcastOk { msg_id, .. }
+            | MessageBody::Read { msg_id, .. }
+            | MessageBody::ReadOk { msg_id, .. }
+            | MessageBody::ReadMany { msg_id, .. }
+            | MessageBody::ReadManyOk { msg_id, .. }
+            | MessageBody::Gossip { msg_id, .. }
+            | MessageBody::GossipOk { msg_id, .. }
+            | MessageBody::CausalBroadcast { msg_id, .. }
+            | MessageBody::CausalBroadcastOk { msg_id, .. }
+            | MessageBody::Sync { msg_id, .. }
+            | MessageBody::SyncState { msg_id, .. }
+            | MessageBody::Topology { msg_id, .. }
+            | MessageBody::TopologyOk { msg_id, .. }
+            | MessageBody::SetTopology { msg_id, .. }
+            | MessageBody::SetTopologyOk { msg_id, .. }
+            | MessageBody::Write { msg_id, .. }
+            | MessageBody::WriteOk { msg_id, .. }
+            | MessageBody::Cas { msg_id, .. }
+            | MessageBody::CasOk { msg_id, .. } => Some(*msg_id),
+            MessageBody::Unsupported => None,
+        }
+    }
+
+    fn in_reply_to(&self) -> Option<u32> {
+        match self {
+            MessageBody::BroadcastOk { in_reply_to, .. }
+            | MessageBody::ReadOk { in_reply_to, .. }
+            | MessageBody::ReadManyOk { in_reply_to, .. }
+            | MessageBody::GossipOk { in_reply_to, .. }
+            | MessageBody::CausalBroadcastOk { in_reply_to, .. }
+            | MessageBody::TopologyOk { in_reply_to, .. }
+            | MessageBody::SetTopologyOk { in_reply_to, .. }
+            | MessageBody::WriteOk { in_reply_to, .. }
+            | MessageBody::CasOk { in_reply_to, .. } => Some(*in_reply_to),
+            MessageBody::Broadcast { .. }
+            | MessageBody::Read { .. }
+            | MessageBody::ReadMany { .. }
+            | MessageBody::Gossip { .. }
+            | MessageBody::CausalBroadcast { .. }
+            | MessageBody::Sync { .. }
+            | MessageBody::SyncState { .. }
+            | MessageBody::Topology { .. }
+            | MessageBody::SetTopology { .. }
+            | MessageBody::Write { .. }
+            | MessageBody::Cas { .. }
+            | MessageBody::Unsupported => None,
+        }
+    }
+
+    fn set_msg_id(&mut self, id: u32) {
+        match self {
+            MessageBody::Broadcast { msg_id, .. }
+            | MessageBody::BroadcastOk { msg_id, .. }
+            | MessageBody::Read { msg_id, .. }
+            | MessageBody::ReadOk { msg_id, .. }
+            | MessageBody::ReadMany { msg_id, .. }
+            | MessageBody::ReadManyOk { msg_id, .. }
+            | MessageBody::Gossip { msg_id, .. }
+            | MessageBody::GossipOk { msg_id, .. }
+            | MessageBody::CausalBroadcast { msg_id, .. }
+            | MessageBody::CausalBroadcastOk { msg_id, .. }
+            | MessageBody::Sync { msg_id, .. }
+            | MessageBody::SyncState { msg_id, .. }
+            | MessageBody::Topology { msg_id, .. }
+            | MessageBody::TopologyOk { msg_id, .. }
+            | MessageBody::SetTopology { msg_id, .. }
+            | MessageBody::SetTopologyOk { msg_id, .. }
+            | MessageBody::Write { msg_id, .. }
+            | MessageBody::WriteOk { msg_id, .. }
+            | MessageBody::Cas { msg_id, .. }
+            | MessageBody::CasOk { msg_id, .. } => *msg_id = id,
+            MessageBody::Unsupported => {}
+        }
+    }
+
+    fn set_in_reply_to(&mut self, id: u32) {
+        match self {
+            MessageBody::BroadcastOk { in_reply_to, .. }
+            | MessageBody::ReadOk { in_reply_to, .. }
+            | MessageBody::ReadManyOk { in_reply_to, .. }
+            | MessageBody::GossipOk { in_reply_to, .. }
+            | MessageBody::CausalBroadcastOk { in_reply_to, .. }
+            | MessageBody::TopologyOk { in_reply_to, .. }
+            | MessageBody::SetTopologyOk { in_reply_to, .. }
+            | MessageBody::WriteOk { in_reply_to, .. }
+            | MessageBody::CasOk { in_reply_to, .. } => *in_reply_to = id,
+            MessageBody::Broadcast { .. }
+            | MessageBody::Read { .. }
+            | MessageBody::ReadMany { .. }
+            | MessageBody::Gossip { .. }
+            | MessageBody::CausalBroadcast { .. }
+            | MessageBody::Sync { .. }
+            | MessageBody::SyncState { .. }
+            | MessageBody::Topology { .. }
+            | MessageBody::SetTopology { .. }
+            | MessageBody::Write { .. }
+            | MessageBody::Cas { .. }
+            | MessageBody::Unsupported => {}
+        }
+    }
+
+    fn is_unsupported(&self) -> bool {
+        matches!(self, MessageBody::Unsupported)
+    }
+}
+
+/// Maelstrom's `timeout` error code: the request may or may not have
+/// happened.
+pub const TIMEOUT: u32 = 0;
+
+/// Maelstrom's `node-not-found` error code.
+pub const NODE_NOT_FOUND: u32 = 1;
+
+/// Maelstrom's `not-supported` error code, for request types this node doesn't
+/// implement.
+pub const NOT_SUPPORTED: u32 = 10;
+
+/// Maelstrom's `temporarily-unavailable` error code.
+pub const TEMPORARILY_UNAVAILABLE: u32 = 11;
+
+/// Maelstrom's `malformed-request` error code.
+pub const MALFORMED_REQUEST: u32 = 12;
+
+/// Maelstrom's `crash` error code: something went wrong and the request may
+/// or may not have happened.
+pub const CRASH: u32 = 13;
+
+/// Maelstrom's `abort` error code: the request definitely didn't happen.
+pub const ABORT: u32 = 14;
+
+/// Maelstrom's `key-does-not-exist` error code.
+pub const KEY_DOES_NOT_EXIST: u32 = 20;
+
+/// Maelstrom's `key-already-exists` error code.
+pub const KEY_ALREADY_EXISTS: u32 = 21;
+
+/// Maelstrom's `precondition-failed` error code, e.g. a `cas` whose `from`
+/// didn't match.
+pub const PRECONDITION_FAILED: u32 = 22;
+
+/// Maelstrom's `txn-conflict` error code.
+pub const TXN_CONFLICT: u32 = 30;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message<B = MessageBody> {
+    pub src: String,
+    pub dest: String,
+    pub body: B,
+}
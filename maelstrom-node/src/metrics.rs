@@ -0,0 +1,65 @@
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Traffic counters for one node, for judging how many messages each
+/// operation costs. Reported by the `stats` RPC and on shutdown.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Messages by `type`.
+    pub received: BTreeMap<&'static str, u64>,
+    pub sent: BTreeMap<&'static str, u64>,
+    /// Input lines, newline included, whether or not they parsed.
+    pub bytes_in: u64,
+    /// Written by the stdout writer thread, which is the only thing that
+    /// knows how big each message came out.
+    pub bytes_out: Arc<AtomicU64>,
+    /// Requests resent by [`Context::rpc_with`](crate::Context::rpc_with)
+    /// after going unanswered.
+    pub retries: u64,
+    /// Most [`Context::rpc`](crate::Context::rpc) requests outstanding at
+    /// once.
+    pub peak_pending_rpcs: usize,
+    /// Requests failed without being sent because
+    /// [`Config::max_pending_rpcs`](crate::Config::max_pending_rpcs) were
+    /// already outstanding.
+    pub refused_rpcs: u64,
+    /// Events waiting for the dispatcher, as of the last one it took.
+    pub inbound_queue: usize,
+    pub peak_inbound_queue: usize,
+    /// Messages waiting for the stdout writer thread, which takes them off.
+    pub outbound_queue: Arc<AtomicUsize>,
+    pub peak_outbound_queue: usize,
+}
+
+impl Metrics {
+    /// Notes how many events were waiting for the dispatcher.
+    pub fn inbound(&mut self, depth: usize) {
+        self.inbound_queue = depth;
+        self.peak_inbound_queue = self.peak_inbound_queue.max(depth);
+    }
+
+    /// Everything as one JSON object, with the number of requests waiting on
+    /// a reply right now.
+    pub fn snapshot(&self, pending_rpcs: usize) -> Value {
+        json!({
+            "received": self.received,
+            "sent": self.sent,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out.load(Ordering::Relaxed),
+            "retries": self.retries,
+            "pending_rpcs": pending_rpcs,
+            "peak_pending_rpcs": self.peak_pending_rpcs,
+            "refused_rpcs": self.refused_rpcs,
+            "inbound_queue": self.inbound_queue,
+            "peak_inbound_queue": self.peak_inbound_queue,
+            "outbound_queue": self.outbound_queue.load(Ordering::Relaxed),
+            "peak_outbound_queue": self.peak_outbound_queue,
+        })
+    }
+}
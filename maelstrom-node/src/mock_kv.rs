@@ -0,0 +1,118 @@
+//! A stand-in for Maelstrom's key/value services (`seq-kv`, `lin-kv`), for
+//! registering with the [`Simulator`](crate::sim::Simulator).
+//!
+//! The store is a single map, so every read sees the latest write: that is
+//! linearizable, which is also a valid (if generous) `seq-kv`. What a real
+//! service does to a client on a bad day can be dialled in: replies can be
+//! delayed, and `cas` can be made to fail with `precondition-failed` even
+//! when it would have gone through, to exercise retry loops.
+
+use crate::message::{Message, KEY_DOES_NOT_EXIST, NOT_SUPPORTED, PRECONDITION_FAILED};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::{json, Value};
+use std::{collections::HashMap, time::Duration};
+
+#[derive(Debug)]
+pub struct MockKv {
+    /// Keys are stored by their JSON encoding, since Maelstrom allows any
+    /// JSON value as a key.
+    store: HashMap<String, Value>,
+    latency: Duration,
+    cas_conflicts: f64,
+    rng: StdRng,
+    next_msg_id: u32,
+}
+
+impl Default for MockKv {
+    fn default() -> MockKv {
+        MockKv {
+            store: HashMap::new(),
+            latency: Duration::ZERO,
+            cas_conflicts: 0.0,
+            rng: StdRng::from_entropy(),
+            next_msg_id: 1,
+        }
+    }
+}
+
+impl MockKv {
+    pub fn new() -> MockKv {
+        MockKv::default()
+    }
+
+    /// Delays every reply by `latency`, on top of the simulator's own.
+    pub fn with_latency(mut self, latency: Duration) -> MockKv {
+        self.latency = latency;
+        self
+    }
+
+    /// Fails a `cas` that would have succeeded with probability
+    /// `probability`, as if another client had got there first.
+    pub fn with_cas_conflicts(mut self, probability: f64) -> MockKv {
+        self.cas_conflicts = probability;
+        self
+    }
+
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// What is stored under `key`.
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.store.get(&key.to_string())
+    }
+
+    /// Replaces the RNG behind injected conflicts, so a seeded simulation
+    /// injects the same ones every run.
+    pub(crate) fn reseed(&mut self, rng: StdRng) {
+        self.rng = rng;
+    }
+
+    /// Applies `request` and returns the body to answer it with, or `None`
+    /// if it isn't a request.
+    pub fn handle(&mut self, request: &Message<Value>) -> Option<Value> {
+        let body = &request.body;
+        let in_reply_to = body["msg_id"].as_u64()?;
+        let key = body["key"].to_string();
+
+        let mut reply = match body["type"].as_str() {
+            Some("read") => match self.store.get(&key) {
+                Some(value) => json!({"type": "read_ok", "value": value}),
+                None => error(KEY_DOES_NOT_EXIST, "key does not exist"),
+            },
+            Some("write") => {
+                self.store.insert(key, body["value"].clone());
+                json!({"type": "write_ok"})
+            }
+            Some("cas") => {
+                let create = body["create_if_not_exists"].as_bool().unwrap_or(false);
+
+                match self.store.get(&key) {
+                    None if !create => error(KEY_DOES_NOT_EXIST, "key does not exist"),
+                    Some(current) if *current != body["from"] => error(
+                        PRECONDITION_FAILED,
+                        &format!("expected {}, but had {}", body["from"], current),
+                    ),
+                    _ if self.rng.gen_bool(self.cas_conflicts.clamp(0.0, 1.0)) => {
+                        error(PRECONDITION_FAILED, "injected conflict")
+                    }
+                    _ => {
+                        self.store.insert(key, body["to"].clone());
+                        json!({"type": "cas_ok"})
+                    }
+                }
+            }
+            _ => error(NOT_SUPPORTED, "not supported"),
+        };
+
+        reply["msg_id"] = self.next_msg_id.into();
+        reply["in_reply_to"] = in_reply_to.into();
+        self.next_msg_id += 1;
+
+        Some(reply)
+    }
+}
+
+fn error(code: u32, text: &str) -> Value {
+    json!({"type": "error", "code": code, "text": text})
+}
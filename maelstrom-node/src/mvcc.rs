@@ -0,0 +1,177 @@
+use std::{collections::HashMap, hash::Hash};
+
+/// When a value was written: a Lamport clock reading and the node that wrote
+/// it, which breaks ties. Versions order all writes the same way on every
+/// node, so nodes that have seen the same writes read the same values.
+pub type Version = (u64, String);
+
+/// A multi-version key/value store. Every committed write is kept alongside
+/// the version it was written at, so a [`Snapshot`] can read the store as it
+/// stood when the snapshot began, however the writes arrived.
+///
+/// A version that no snapshot can read any more is pruned when its key is
+/// next written, so histories stay short.
+#[derive(Debug)]
+pub struct Mvcc<K, V> {
+    /// Each key's versions, oldest first.
+    versions: HashMap<K, Vec<(Version, V)>>,
+    /// The newest version committed so far.
+    latest: Option<Version>,
+}
+
+impl<K, V> Default for Mvcc<K, V> {
+    fn default() -> Mvcc<K, V> {
+        Mvcc {
+            versions: HashMap::new(),
+            latest: None,
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Mvcc<K, V> {
+    pub fn new() -> Mvcc<K, V> {
+        Mvcc::default()
+    }
+
+    /// Starts a transaction that sees everything committed so far and none of
+    /// what is committed after.
+    pub fn begin_snapshot(&self) -> Snapshot<'_, K, V> {
+        Snapshot {
+            store: self,
+            at: self.latest.clone(),
+            writes: HashMap::new(),
+        }
+    }
+
+    /// Stores `writes` at `version`. Versions may arrive out of order, e.g.
+    /// when replicated from another node; each lands in its place in the
+    /// history, so it is only read if nothing newer was written to the key.
+    /// A write already stored at `version` is left as it is, so a
+    /// replicated commit can be applied any number of times.
+    pub fn commit(&mut self, version: Version, writes: impl IntoIterator<Item = (K, V)>) {
+        if self.latest.as_ref().is_none_or(|latest| *latest < version) {
+            self.latest = Some(version.clone());
+        }
+
+        for (key, value) in writes {
+            let history = self.versions.entry(key).or_default();
+            let at = history.partition_point(|(existing, _)| *existing < version);
+
+            if history
+                .get(at)
+                .is_none_or(|(existing, _)| *existing != version)
+            {
+                history.insert(at, (version.clone(), value));
+            }
+
+            Self::prune(history, self.latest.as_ref());
+        }
+    }
+
+    /// Drops the versions of one key that are older than what the oldest
+    /// snapshot still able to read reads. A [`Snapshot`] borrows the store,
+    /// so none is open while committing and the oldest one that can still
+    /// begin reads at `horizon`, the latest version.
+    fn prune(history: &mut Vec<(Version, V)>, horizon: Option<&Version>) {
+        let Some(horizon) = horizon else {
+            return;
+        };
+
+        let visible = history.partition_point(|(version, _)| version <= horizon);
+        history.drain(..visible.saturating_sub(1));
+    }
+
+    /// The newest value of `key` no later than `at`.
+    fn read_at(&self, key: &K, at: &Version) -> Option<&V> {
+        self.versions
+            .get(key)?
+            .iter()
+            .rev()
+            .find(|(version, _)| version <= at)
+            .map(|(_, value)| value)
+    }
+
+    /// Number of keys that have ever been written.
+    pub fn len(&self) -> usize {
+        self.versions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty()
+    }
+}
+
+/// A transaction's view of an [`Mvcc`] store: what was committed when it began,
+/// plus its own writes so far.
+pub struct Snapshot<'a, K, V> {
+    store: &'a Mvcc<K, V>,
+    /// `None` if the store was empty when the snapshot began.
+    at: Option<Version>,
+    writes: HashMap<K, V>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Snapshot<'_, K, V> {
+    pub fn read(&self, key: &K) -> Option<&V> {
+        if let Some(value) = self.writes.get(key) {
+            return Some(value);
+        }
+
+        self.store.read_at(key, self.at.as_ref()?)
+    }
+
+    /// Buffers a write; it only reaches the store through [`Mvcc::commit`].
+    pub fn write(&mut self, key: K, value: V) {
+        self.writes.insert(key, value);
+    }
+
+    /// The final value of every key this transaction wrote, for passing to
+    /// [`Mvcc::commit`].
+    pub fn into_writes(self) -> HashMap<K, V> {
+        self.writes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(clock: u64, node: &str) -> Version {
+        (clock, node.to_string())
+    }
+
+    #[test]
+    fn a_replayed_commit_is_stored_once() {
+        let mut store = Mvcc::new();
+
+        store.commit(version(1, "n0"), [(1, 10)]);
+        store.commit(version(2, "n1"), [(1, 20)]);
+        store.commit(version(1, "n0"), [(1, 10)]);
+
+        assert_eq!(store.begin_snapshot().read(&1), Some(&20));
+        assert_eq!(store.versions[&1], [(version(2, "n1"), 20)]);
+    }
+
+    #[test]
+    fn a_late_older_write_is_not_read() {
+        let mut store = Mvcc::new();
+
+        store.commit(version(3, "n1"), [(1, 30)]);
+        store.commit(version(2, "n0"), [(1, 20), (2, 20)]);
+
+        let snapshot = store.begin_snapshot();
+        assert_eq!(snapshot.read(&1), Some(&30));
+        assert_eq!(snapshot.read(&2), Some(&20));
+    }
+
+    #[test]
+    fn overwritten_versions_are_pruned() {
+        let mut store = Mvcc::new();
+
+        for clock in 1..=100 {
+            store.commit(version(clock, "n0"), [(1, clock)]);
+        }
+
+        assert_eq!(store.versions[&1].len(), 1);
+        assert_eq!(store.begin_snapshot().read(&1), Some(&100));
+    }
+}
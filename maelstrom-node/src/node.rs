@@ -0,0 +1,2095 @@
+use crate::{
+    causal::CausalBuffer,
+    config::{AckStrategy, Config},
+    crdt::GSet,
+    gossip::GossipScheduler,
+    hash::{ClusterHasher, Fnv},
+    message::{
+        Message, MessageBody, KEY_DOES_NOT_EXIST, MALFORMED_REQUEST, PRECONDITION_FAILED,
+        TEMPORARILY_UNAVAILABLE,
+    },
+    outbox::Outbox,
+    ranges::RangeSet,
+    runtime::{Backoff, Context, DebugState, Handler, RpcError, SpanId},
+    topology::{self, TopologyStrategy},
+    vector_clock::VectorClock,
+};
+use log::{debug, warn};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// What [`Node`] keeps across a restart with `--snapshot-dir`.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    messages: GSet<Value>,
+    #[serde(default)]
+    kv: HashMap<String, Value>,
+}
+
+/// Most integers one `sync_state` may expand to. A peer only sends runs of
+/// values it actually holds, so anything wider is bogus and would otherwise
+/// exhaust memory.
+const MAX_SYNC_VALUES: u64 = 1 << 20;
+
+/// Decides whether a broadcast value is accepted.
+type Validator = dyn Fn(&Value) -> bool;
+
+/// A client's `set_topology`, answered once every other node has switched.
+struct TopologyChange {
+    client: String,
+    msg_id: u32,
+    /// Nodes yet to answer.
+    remaining: usize,
+    /// Nodes that couldn't be switched.
+    failed: Vec<String>,
+}
+
+/// A causal broadcast to be flooded once our neighbors are known.
+struct HeldFlood {
+    /// The neighbor it came from, `None` if it started here.
+    from: Option<String>,
+    origin: String,
+    clock: VectorClock,
+    msg: Value,
+    hops: u32,
+}
+
+/// Gossip given up on for one neighbor; see [`Config::gossip_max_retries`].
+struct DeadLetters {
+    values: Vec<Value>,
+    /// When they go back to be retried, if ever.
+    retry_at: Option<Instant>,
+}
+
+/// Handler for the broadcast workload.
+pub struct Node {
+    id: String,
+    initialized: bool,
+    all_nodes: Vec<String>,
+    /// Where each of `all_nodes` is in it, rebuilt whenever it changes.
+    indices: HashMap<String, usize>,
+    neighbors: Vec<String>,
+    messages: GSet<Value>,
+    /// Running XOR of the hash of each of `messages`, kept up to date on
+    /// insert.
+    checksum: u64,
+    /// What `checksum` and [`Node::owner`] hash with; see [`crate::hash`].
+    hasher: Box<dyn ClusterHasher>,
+    /// `messages` in [`read_order`] as of the last `read`, shared with every
+    /// `read_ok` that goes out, so answering a read copies nothing. Brought
+    /// up to date only when read; see [`Node::sorted`].
+    sorted: Arc<Vec<Value>>,
+    /// Values stored since `sorted` was last brought up to date.
+    unsorted: Vec<Value>,
+    sync_interval: Option<Duration>,
+    last_sync: Instant,
+    /// Set in causal broadcast mode, which replaces batched gossip.
+    causal: Option<CausalBuffer>,
+    outbox: Outbox,
+    retry_timeout: Duration,
+    /// Gossip for peers that look partitioned away, sent in one go once they
+    /// are heard from again rather than retried at them every tick.
+    backlog: HashMap<String, Vec<Value>>,
+    /// Most gossip messages sent per tick; see [`Node::flush_gossip`].
+    gossip_per_tick: Option<usize>,
+    /// Gossip that didn't fit under the cap last tick.
+    deferred: Vec<(String, Vec<Value>)>,
+    /// When each backlogged peer was last sent an empty gossip, whose ack
+    /// tells us it is back.
+    last_probe: HashMap<String, Instant>,
+    /// See [`Config::gossip_max_retries`].
+    max_retries: Option<u32>,
+    /// Resends and probes to each neighbor since it last answered.
+    retries: HashMap<String, u32>,
+    dead_letters: HashMap<String, DeadLetters>,
+    /// How long each neighbor's dead letters wait next time they are given
+    /// up on, starting from [`Config::dead_letter_retry`]. Cleared once the
+    /// neighbor acks again.
+    dead_letter_waits: HashMap<String, Duration>,
+    dead_letter_retry: Option<Duration>,
+    gossip: GossipScheduler,
+    /// Spans that queued the values waiting for the next gossip round.
+    gossip_causes: BTreeSet<SpanId>,
+    topology: TopologyStrategy,
+    /// Our neighbors in Maelstrom's last `topology` message, kept whatever
+    /// the strategy so switching back to `Given` can use them.
+    given_neighbors: Option<Vec<String>>,
+    /// Causal broadcasts that arrived while we were still waiting for
+    /// Maelstrom's topology; see [`Node::awaiting_topology`].
+    held: Vec<HeldFlood>,
+    /// Client `set_topology` requests being passed round the cluster.
+    topology_changes: HashMap<u32, TopologyChange>,
+    /// The forwarded `set_topology` requests, each with the change it
+    /// belongs to and the node it went to.
+    topology_forwards: HashMap<u32, (u32, String)>,
+    next_change: u32,
+    /// What keyed `read`s, `write`s and `cas`es act on. Local to this node
+    /// and never replicated: it is there so one binary can take KV traffic
+    /// alongside broadcast while experimenting.
+    kv: HashMap<String, Value>,
+    /// Values each peer is known to already have, learnt from the values they
+    /// send us and the ones they acknowledge. Gossip to a peer skips these.
+    known: HashMap<String, HashSet<Value>>,
+    set_soft_cap: Option<usize>,
+    set_cap_warned: bool,
+    debug_topology: bool,
+    ack: AckStrategy,
+    /// See [`Config::max_hops`].
+    max_hops: Option<u32>,
+    /// `max_hops`, raised to what the current overlay needs.
+    hop_limit: Option<u32>,
+    /// Maelstrom's last `topology`, whole, for measuring the overlay.
+    given_overlay: Option<HashMap<String, Vec<String>>>,
+    value_validator: Option<Box<Validator>>,
+}
+
+impl Node {
+    pub fn new(config: &Config) -> Node {
+        Node {
+            id: String::new(),
+            initialized: false,
+            all_nodes: Vec::new(),
+            indices: HashMap::new(),
+            neighbors: Vec::new(),
+            messages: GSet::default(),
+            checksum: 0,
+            hasher: Box::new(Fnv),
+            sorted: Arc::new(Vec::new()),
+            unsorted: Vec::new(),
+            sync_interval: config.sync_interval,
+            last_sync: Instant::now(),
+            causal: config.causal_broadcast.then(CausalBuffer::new),
+            outbox: Outbox::new(config.gossip_retry, config.rtt_outlier_multiple),
+            retry_timeout: config.gossip_retry,
+            backlog: HashMap::new(),
+            last_probe: HashMap::new(),
+            max_retries: config.gossip_max_retries,
+            retries: HashMap::new(),
+            dead_letters: HashMap::new(),
+            dead_letter_waits: HashMap::new(),
+            dead_letter_retry: config.dead_letter_retry,
+            gossip_causes: BTreeSet::new(),
+            gossip_per_tick: config.gossip_per_tick,
+            deferred: Vec::new(),
+            gossip: GossipScheduler::new(
+                config.gossip_interval,
+                config.gossip_fanout,
+                config.gossip_batch,
+            ),
+            known: HashMap::new(),
+            topology: config.topology,
+            given_neighbors: None,
+            held: Vec::new(),
+            topology_changes: HashMap::new(),
+            topology_forwards: HashMap::new(),
+            next_change: 0,
+            kv: HashMap::new(),
+            set_soft_cap: config.set_soft_cap,
+            set_cap_warned: false,
+            debug_topology: config.debug_topology,
+            ack: config.ack,
+            max_hops: config.max_hops,
+            hop_limit: config.max_hops,
+            given_overlay: None,
+            // Only integers can be out of range; every other value passes.
+            value_validator: config.max_value.map(|max| {
+                Box::new(move |value: &Value| value.as_u64().is_none_or(|value| value <= max))
+                    as Box<Validator>
+            }),
+        }
+    }
+
+    /// Swaps the default [`Fnv`] hasher for `hasher`, which every node in
+    /// the cluster has to use too for checksums and owners to agree.
+    pub fn with_hasher(mut self, hasher: impl ClusterHasher + 'static) -> Node {
+        self.hasher = Box::new(hasher);
+        self.checksum = self.messages.iter().fold(0, |checksum, value| {
+            checksum ^ self.hasher.hash_value(value)
+        });
+        self
+    }
+
+    /// The node that owns `key`, for partitioning keys across the cluster.
+    /// Every node works out the same owner from the same membership.
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        self.hasher.owner(key, &self.all_nodes)
+    }
+
+    /// Where `node_id` is in the cluster's membership, if it is a member.
+    pub fn index_of(&self, node_id: &str) -> Option<usize> {
+        self.indices.get(node_id).copied()
+    }
+
+    /// Cleans up the membership handed to us in `Init`: duplicates are dropped
+    /// (keeping first occurrence order) and our own id is added if it was left
+    /// out. Anything unexpected is reported on stderr.
+    fn set_membership(&mut self, node_ids: &[String]) {
+        let mut all_nodes: Vec<String> = Vec::with_capacity(node_ids.len());
+
+        for node_id in node_ids {
+            if all_nodes.contains(node_id) {
+                warn!("duplicate node id {} in init", node_id);
+            } else {
+                all_nodes.push(node_id.clone());
+            }
+        }
+
+        if !all_nodes.contains(&self.id) {
+            warn!("init node_ids is missing own id {}", self.id);
+            all_nodes.push(self.id.clone());
+        }
+
+        self.indices = all_nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node_id)| (node_id.clone(), index))
+            .collect();
+        self.all_nodes = all_nodes;
+    }
+
+    /// Picks our neighbors under the current strategy. `Given` uses
+    /// Maelstrom's topology, or everyone else until one arrives.
+    fn rebuild_neighbors(&mut self) {
+        self.neighbors = self
+            .topology
+            .neighbors(&self.id, &self.all_nodes)
+            .or_else(|| self.given_neighbors.clone())
+            .unwrap_or_else(|| {
+                self.all_nodes
+                    .iter()
+                    .filter(|node| **node != self.id)
+                    .cloned()
+                    .collect()
+            });
+    }
+
+    /// Brings everything that depends on the overlay up to date after it
+    /// changed from one where our neighbors were `previous`. A computed
+    /// strategy keeps its own neighbors, whatever Maelstrom sent.
+    ///
+    /// A neighbor we didn't have before may be missing values the old
+    /// overlay carried past it, so it is sent whatever it isn't known to
+    /// have. Causal broadcast has no such catch-up: its values only travel
+    /// with their clocks.
+    fn on_topology_changed(&mut self, ctx: &mut Context, previous: &[String]) {
+        self.rebuild_neighbors();
+        self.update_hop_limit();
+        self.flush_held(ctx);
+
+        if self.causal.is_some() || self.awaiting_topology() {
+            return;
+        }
+
+        let added: Vec<String> = self
+            .neighbors
+            .iter()
+            .filter(|neighbor| !previous.contains(neighbor))
+            .cloned()
+            .collect();
+
+        for neighbor in added {
+            let missing: Vec<Value> = self
+                .messages
+                .iter()
+                .filter(|value| !self.is_known(&neighbor, value))
+                .cloned()
+                .collect();
+
+            if !missing.is_empty() {
+                self.send_gossip(ctx, &neighbor, missing);
+            }
+        }
+    }
+
+    /// Whether we are to use Maelstrom's topology but haven't been sent one
+    /// yet. Broadcasts are still stored and acked meanwhile, but nothing is
+    /// passed on: the stand-in neighbors would be everyone, and a value sent
+    /// to all of them is a value the real overlay never needed to carry. It
+    /// waits in the gossip queue, or in `held` for causal broadcast, and goes
+    /// out to the proper neighbors as soon as the topology arrives.
+    fn awaiting_topology(&self) -> bool {
+        self.topology == TopologyStrategy::Given && self.given_neighbors.is_none()
+    }
+
+    /// Floods everything held back by [`Node::awaiting_topology`], once it
+    /// no longer is.
+    fn flush_held(&mut self, ctx: &mut Context) {
+        if self.awaiting_topology() {
+            return;
+        }
+
+        for held in std::mem::take(&mut self.held) {
+            for neighbor in &self.neighbors {
+                if held.from.as_ref() != Some(neighbor) && *neighbor != held.origin {
+                    self.send_causal(
+                        ctx,
+                        neighbor,
+                        &held.origin,
+                        &held.clock,
+                        &held.msg,
+                        held.hops,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Counts one node's answer to a forwarded `set_topology`, and answers
+    /// the client once every node has.
+    fn topology_forwarded(&mut self, ctx: &mut Context, change: u32, node: String, ok: bool) {
+        let Some(pending) = self.topology_changes.get_mut(&change) else {
+            return;
+        };
+
+        pending.remaining -= 1;
+        if !ok {
+            pending.failed.push(node);
+        }
+
+        if pending.remaining > 0 {
+            return;
+        }
+
+        let Some(pending) = self.topology_changes.remove(&change) else {
+            return;
+        };
+
+        if pending.failed.is_empty() {
+            ctx.reply_to(
+                &pending.client,
+                pending.msg_id,
+                MessageBody::SetTopologyOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                    neighbors: self.neighbors.clone(),
+                },
+            );
+        } else {
+            ctx.reply_error(
+                &pending.client,
+                pending.msg_id,
+                TEMPORARILY_UNAVAILABLE,
+                format!("{} did not switch", pending.failed.join(", ")),
+            );
+        }
+    }
+
+    /// Every node's neighbors under the current strategy, as far as we know
+    /// them.
+    fn overlay(&self) -> HashMap<String, Vec<String>> {
+        if self.topology == TopologyStrategy::Given {
+            return self.given_overlay.clone().unwrap_or_default();
+        }
+
+        self.all_nodes
+            .iter()
+            .filter_map(|node| {
+                let neighbors = self.topology.neighbors(node, &self.all_nodes)?;
+                Some((node.clone(), neighbors))
+            })
+            .collect()
+    }
+
+    /// Raises the hop limit to what it takes to reach every node of the
+    /// current overlay; see [`Config::max_hops`].
+    fn update_hop_limit(&mut self) {
+        let Some(max) = self.max_hops else {
+            return;
+        };
+
+        let needed = topology::diameter(&self.overlay()).saturating_sub(1);
+
+        if max < needed {
+            warn!(
+                "max hops {} can't reach across the overlay, using {}",
+                max, needed
+            );
+        }
+
+        self.hop_limit = Some(max.max(needed));
+    }
+
+    /// Adds `value` to the broadcast set and queues it for gossip if it is
+    /// new. Returns whether it was.
+    fn add_value(&mut self, value: Value, cause: SpanId) -> bool {
+        if !self.store_value(value.clone()) {
+            return false;
+        }
+
+        self.gossip.push(value);
+        self.gossip_causes.insert(cause);
+        true
+    }
+
+    /// Adds `value` to the broadcast set and sends it to every neighbor
+    /// straight away rather than with the next gossip round, for
+    /// [`AckStrategy::Lazy`]. Returns whether it was new.
+    fn forward_now(&mut self, ctx: &mut Context, value: Value) -> bool {
+        if self.awaiting_topology() {
+            return self.add_value(value, ctx.span());
+        }
+
+        if !self.store_value(value.clone()) {
+            return false;
+        }
+
+        for neighbor in self.neighbors.clone() {
+            if let Some(dead) = self.dead_letters.get_mut(&neighbor) {
+                dead.values.push(value.clone());
+            } else if ctx.is_alive(&neighbor) {
+                self.send_gossip(ctx, &neighbor, vec![value.clone()]);
+            } else {
+                self.backlog
+                    .entry(neighbor)
+                    .or_default()
+                    .push(value.clone());
+            }
+        }
+
+        true
+    }
+
+    /// Adds `value` to the broadcast set without gossiping it.
+    fn store_value(&mut self, value: Value) -> bool {
+        if !self.messages.insert(value.clone()) {
+            return false;
+        }
+
+        self.checksum ^= self.hasher.hash_value(&value);
+        self.unsorted.push(value);
+
+        true
+    }
+
+    /// `messages` in [`read_order`]. Values stored since the last call are
+    /// sorted on their own and merged in, so a burst of inserts costs one
+    /// pass over the set at the next read rather than one each.
+    fn sorted(&mut self) -> Arc<Vec<Value>> {
+        if self.unsorted.is_empty() {
+            return self.sorted.clone();
+        }
+
+        let mut added = std::mem::take(&mut self.unsorted);
+        added.sort_by(read_order);
+
+        let mut merged = Vec::with_capacity(self.sorted.len() + added.len());
+        let mut old = self.sorted.iter().cloned().peekable();
+        let mut added = added.into_iter().peekable();
+
+        while let (Some(a), Some(b)) = (old.peek(), added.peek()) {
+            if read_order(a, b) == Ordering::Greater {
+                merged.extend(added.next());
+            } else {
+                merged.extend(old.next());
+            }
+        }
+
+        merged.extend(old);
+        merged.extend(added);
+
+        self.sorted = Arc::new(merged);
+        self.sorted.clone()
+    }
+
+    /// Sends a causal broadcast on to `dest`, resending until it is acked.
+    fn send_causal(
+        &self,
+        ctx: &mut Context,
+        dest: &str,
+        origin: &str,
+        clock: &VectorClock,
+        msg: &Value,
+        hops: u32,
+    ) {
+        ctx.rpc_with(
+            dest,
+            MessageBody::CausalBroadcast {
+                msg_id: 0,
+                origin: origin.to_string(),
+                clock: clock.clone(),
+                msg: msg.clone(),
+                hops,
+            },
+            Backoff::REPLICATION,
+        );
+    }
+
+    /// Sends a digest of the broadcast set to a random neighbor once per sync
+    /// interval. Only if the neighbor's digest differs does any state move,
+    /// so a converged cluster pays one small message per node per interval.
+    fn sync_digest(&mut self, ctx: &mut Context) {
+        let Some(interval) = self.sync_interval else {
+            return;
+        };
+
+        if self.last_sync.elapsed() < interval {
+            return;
+        }
+
+        self.last_sync = Instant::now();
+
+        let Some(peer) = self.neighbors.choose(ctx.rng()) else {
+            return;
+        };
+
+        ctx.send(
+            peer,
+            MessageBody::Sync {
+                msg_id: 0,
+                count: self.messages.len(),
+                checksum: self.checksum,
+            },
+        );
+    }
+
+    fn mark_known(&mut self, peer: &str, values: impl IntoIterator<Item = Value>) {
+        self.known
+            .entry(peer.to_string())
+            .or_default()
+            .extend(values);
+    }
+
+    fn is_known(&self, peer: &str, value: &Value) -> bool {
+        self.known
+            .get(peer)
+            .is_some_and(|values| values.contains(value))
+    }
+
+    /// Sends a batch of values to another node and keeps it in the outbox
+    /// until that node acks it.
+    fn send_gossip(&mut self, ctx: &mut Context, dest: &str, values: Vec<Value>) {
+        let msg_id = ctx.rpc(
+            dest,
+            MessageBody::Gossip {
+                msg_id: 0,
+                messages: values.clone(),
+            },
+        );
+
+        self.outbox.track(msg_id, dest, values);
+    }
+
+    /// Holds the gossip sent as `request` back for a neighbor that is still
+    /// starting up. It counts as down until it next answers anything, such
+    /// as [`Node::drain_backlog`]'s probe, and then gets the values again.
+    fn not_ready(&mut self, ctx: &mut Context, request: u32) {
+        let Some((dest, values)) = self.outbox.take(request) else {
+            return;
+        };
+
+        debug!("{} isn't ready for gossip yet", dest);
+        ctx.timed_out(&dest);
+        self.backlog.entry(dest).or_default().extend(values);
+    }
+
+    /// Counts one more resend or probe to `peer`, and returns whether that
+    /// is one more than it may have.
+    fn retry(&mut self, peer: &str) -> bool {
+        let retries = self.retries.entry(peer.to_string()).or_default();
+        *retries += 1;
+
+        self.max_retries.is_some_and(|max| *retries > max)
+    }
+
+    /// Gives up on `values`, and anything else held, for `peer`. They are
+    /// only sent again if [`Config::dead_letter_retry`] is set.
+    fn dead_letter(&mut self, peer: String, values: Vec<Value>) {
+        let held = self.backlog.remove(&peer).unwrap_or_default();
+        self.last_probe.remove(&peer);
+        self.retries.remove(&peer);
+
+        let wait = self
+            .dead_letter_waits
+            .get(&peer)
+            .copied()
+            .or(self.dead_letter_retry);
+
+        let dead = self
+            .dead_letters
+            .entry(peer.clone())
+            .or_insert_with(|| DeadLetters {
+                values: Vec::new(),
+                retry_at: wait.map(|wait| Instant::now() + wait),
+            });
+        dead.values.extend(values);
+        dead.values.extend(held);
+
+        warn!(
+            "giving up on {} values for {} after {} retries",
+            dead.values.len(),
+            peer,
+            self.max_retries.unwrap_or_default()
+        );
+    }
+
+    /// Puts the dead letters whose wait is over back in the backlog, to be
+    /// retried from scratch, and doubles the wait for next time.
+    fn reinject_dead_letters(&mut self) {
+        let now = Instant::now();
+
+        let due: Vec<String> = self
+            .dead_letters
+            .iter()
+            .filter(|(_, dead)| dead.retry_at.is_some_and(|at| at <= now))
+            .map(|(peer, _)| peer.clone())
+            .collect();
+
+        for peer in due {
+            let Some(dead) = self.dead_letters.remove(&peer) else {
+                continue;
+            };
+
+            if let Some(wait) = self
+                .dead_letter_waits
+                .get(&peer)
+                .copied()
+                .or(self.dead_letter_retry)
+            {
+                self.dead_letter_waits.insert(peer.clone(), wait * 2);
+            }
+
+            debug!(
+                "retrying {} dead-lettered values for {}",
+                dead.values.len(),
+                peer
+            );
+            self.backlog.entry(peer).or_default().extend(dead.values);
+        }
+    }
+
+    /// Queues everything held for peers that are reachable again, and probes
+    /// the rest with an empty gossip once per retry timeout.
+    fn drain_backlog(&mut self, ctx: &mut Context, outgoing: &mut Vec<(String, Vec<Value>)>) {
+        let peers: Vec<String> = self.backlog.keys().cloned().collect();
+
+        for peer in peers {
+            if ctx.is_alive(&peer) {
+                let mut seen = HashSet::new();
+                let mut values = self.backlog.remove(&peer).unwrap_or_default();
+                values.retain(|value| !self.is_known(&peer, value) && seen.insert(value.clone()));
+                self.last_probe.remove(&peer);
+
+                if !values.is_empty() {
+                    outgoing.push((peer, values));
+                }
+            } else if self
+                .last_probe
+                .get(&peer)
+                .is_none_or(|probed| probed.elapsed() >= self.retry_timeout)
+            {
+                if self.retry(&peer) {
+                    self.dead_letter(peer, Vec::new());
+                    continue;
+                }
+
+                self.last_probe.insert(peer.clone(), Instant::now());
+                ctx.rpc(
+                    &peer,
+                    MessageBody::Gossip {
+                        msg_id: 0,
+                        messages: Vec::new(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Sends this tick's gossip, after whatever was held over from the last
+    /// one. Within the per-tick cap it goes out as queued. Over it, all the
+    /// gossip for each peer is merged into one message, however large, and
+    /// any messages still over the cap wait for the next tick.
+    fn flush_gossip(&mut self, ctx: &mut Context, mut outgoing: Vec<(String, Vec<Value>)>) {
+        outgoing.splice(0..0, std::mem::take(&mut self.deferred));
+
+        if let Some(cap) = self.gossip_per_tick {
+            if outgoing.len() > cap {
+                outgoing = coalesce(outgoing);
+            }
+
+            if outgoing.len() > cap {
+                self.deferred = outgoing.split_off(cap);
+            }
+        }
+
+        for (dest, mut values) in outgoing {
+            values.retain(|value| !self.is_known(&dest, value));
+
+            if !values.is_empty() {
+                self.send_gossip(ctx, &dest, values);
+            }
+        }
+    }
+
+    /// Warns once when the broadcast set grows past the configured soft cap.
+    /// Values can't be dropped without failing the checker, so this is only a
+    /// heads-up about memory pressure.
+    fn check_set_size(&mut self) {
+        let Some(cap) = self.set_soft_cap else {
+            return;
+        };
+
+        if self.set_cap_warned || self.messages.len() <= cap {
+            return;
+        }
+
+        self.set_cap_warned = true;
+
+        warn!(
+            "broadcast set has {} values (soft cap {}), {} when stored as ranges",
+            self.messages.len(),
+            cap,
+            self.range_count()
+        );
+    }
+
+    /// What [`Handler::print_summary`] prints: the size of the broadcast
+    /// set and whatever gossip is still on its way.
+    fn summary(&self) -> String {
+        let mut summary = format!(
+            "summary: {} broadcast values in {} contiguous ranges\n",
+            self.messages.len(),
+            self.range_count()
+        );
+        summary += &format!("summary: {} gossips still unacked\n", self.outbox.len());
+        summary += &format!(
+            "summary: {} values held for unreachable peers\n",
+            self.backlog.values().map(Vec::len).sum::<usize>()
+        );
+        summary += &format!(
+            "summary: {} values dead-lettered\n",
+            self.dead_letters
+                .values()
+                .map(|dead| dead.values.len())
+                .sum::<usize>()
+        );
+        if let Some(causal) = &self.causal {
+            summary += &format!(
+                "summary: {} causal broadcasts held for dependencies\n",
+                causal.held()
+            );
+        }
+
+        summary
+    }
+
+    /// Number of contiguous `[start, end]` runs needed to hold the integer
+    /// values in the set, plus one per non-integer value, i.e. its size if
+    /// dense values were range-encoded.
+    fn range_count(&self) -> usize {
+        let (integers, others) = self.summarize();
+
+        integers.range_count() + others.len()
+    }
+
+    /// Splits the broadcast set into its integers, as runs, and everything
+    /// else.
+    fn summarize(&self) -> (RangeSet, Vec<Value>) {
+        let integers = self.messages.iter().filter_map(Value::as_u64).collect();
+        let others = self
+            .messages
+            .iter()
+            .filter(|value| value.as_u64().is_none())
+            .cloned()
+            .collect();
+
+        (integers, others)
+    }
+
+    /// Order-independent fingerprint of the broadcast set: the XOR of a mixed
+    /// hash of each value. Nodes holding the same values report the
+    /// same checksum no matter what order they saw them in.
+    fn set_checksum(&self) -> u64 {
+        self.checksum
+    }
+}
+
+/// The order `read_ok` lists the set in: integers ascending, then everything
+/// else by its JSON text.
+fn read_order(a: &Value, b: &Value) -> Ordering {
+    match (a.as_u64(), b.as_u64()) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+impl Handler for Node {
+    type Body = MessageBody;
+
+    fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        self.initialized = true;
+        self.id = node_id.to_string();
+        self.set_membership(node_ids);
+        self.rebuild_neighbors();
+        self.update_hop_limit();
+    }
+
+    fn handle(&mut self, message: Message, ctx: &mut Context) {
+        match &message.body {
+            MessageBody::Broadcast { msg, msg_id } => {
+                let valid = self
+                    .value_validator
+                    .as_ref()
+                    .is_none_or(|validator| validator(msg));
+
+                if !valid {
+                    ctx.reply_error(
+                        &message.src,
+                        *msg_id,
+                        MALFORMED_REQUEST,
+                        format!("broadcast value {} is out of range", msg),
+                    );
+                    return;
+                }
+
+                let ok = MessageBody::BroadcastOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                };
+
+                if self.ack == AckStrategy::Eager {
+                    ctx.reply(&message, ok.clone());
+                }
+
+                if let Some(causal) = &mut self.causal {
+                    let clock = causal.stamp(&self.id);
+
+                    if self.awaiting_topology() {
+                        self.held.push(HeldFlood {
+                            from: None,
+                            origin: self.id.clone(),
+                            clock,
+                            msg: msg.clone(),
+                            hops: 0,
+                        });
+                    } else {
+                        for neighbor in &self.neighbors {
+                            self.send_causal(ctx, neighbor, &self.id, &clock, msg, 0);
+                        }
+                    }
+
+                    self.store_value(msg.clone());
+                    self.check_set_size();
+                } else {
+                    let added = match self.ack {
+                        AckStrategy::Eager => self.add_value(msg.clone(), ctx.span()),
+                        AckStrategy::Lazy => self.forward_now(ctx, msg.clone()),
+                    };
+
+                    if added {
+                        self.check_set_size();
+                    }
+                }
+
+                if self.ack == AckStrategy::Lazy {
+                    ctx.reply(&message, ok);
+                }
+            }
+            MessageBody::BroadcastOk { .. } => {}
+
+            MessageBody::Gossip { messages, .. } => {
+                self.mark_known(&message.src, messages.iter().cloned());
+
+                for value in messages {
+                    self.add_value(value.clone(), ctx.span());
+                }
+
+                self.check_set_size();
+
+                ctx.reply(
+                    &message,
+                    MessageBody::GossipOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        messages: messages.clone(),
+                    },
+                );
+            }
+            MessageBody::GossipOk {
+                in_reply_to,
+                messages,
+                ..
+            } => {
+                self.outbox.ack(*in_reply_to);
+                self.retries.remove(&message.src);
+                self.dead_letter_waits.remove(&message.src);
+                self.mark_known(&message.src, messages.iter().cloned());
+            }
+
+            MessageBody::CausalBroadcast {
+                origin,
+                clock,
+                msg,
+                hops,
+                ..
+            } => {
+                if let Some(causal) = &mut self.causal {
+                    let first = !causal.has_seen(origin, clock);
+                    let ready = causal.receive(origin, clock.clone(), msg.clone());
+
+                    for value in ready {
+                        self.store_value(value);
+                    }
+
+                    self.check_set_size();
+
+                    // Flood it on the first time round, which is what stops it
+                    // going round a cycle forever; the origin and the node it
+                    // came from already have it. The hop limit caps how far
+                    // it spreads regardless.
+                    let within_reach = self.hop_limit.is_none_or(|max| *hops < max);
+
+                    if first && within_reach && self.awaiting_topology() {
+                        self.held.push(HeldFlood {
+                            from: Some(message.src.clone()),
+                            origin: origin.clone(),
+                            clock: clock.clone(),
+                            msg: msg.clone(),
+                            hops: hops + 1,
+                        });
+                    } else if first && within_reach {
+                        for neighbor in &self.neighbors {
+                            if *neighbor != message.src && neighbor != origin {
+                                self.send_causal(ctx, neighbor, origin, clock, msg, hops + 1);
+                            }
+                        }
+                    }
+                }
+
+                ctx.reply(
+                    &message,
+                    MessageBody::CausalBroadcastOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    },
+                );
+            }
+            MessageBody::CausalBroadcastOk { .. } => {}
+
+            MessageBody::Sync {
+                count, checksum, ..
+            } => {
+                if *count == self.messages.len() && *checksum == self.checksum {
+                    return;
+                }
+
+                let (integers, others) = self.summarize();
+
+                ctx.send(
+                    &message.src,
+                    MessageBody::SyncState {
+                        msg_id: 0,
+                        ranges: integers.ranges().collect(),
+                        others,
+                    },
+                );
+            }
+
+            MessageBody::SyncState { ranges, others, .. } => {
+                let expanded = ranges
+                    .iter()
+                    .filter(|(start, end)| start <= end)
+                    .fold(0u64, |total, (start, end)| {
+                        total.saturating_add((end - start).saturating_add(1))
+                    });
+
+                if expanded > MAX_SYNC_VALUES {
+                    warn!(
+                        "ignoring sync_state from {} covering {} values",
+                        message.src, expanded
+                    );
+                    return;
+                }
+
+                let messages: Vec<Value> = ranges
+                    .iter()
+                    .flat_map(|&(start, end)| start..=end)
+                    .map(Value::from)
+                    .chain(others.iter().cloned())
+                    .collect();
+
+                self.mark_known(&message.src, messages.iter().cloned());
+
+                for value in &messages {
+                    self.add_value(value.clone(), ctx.span());
+                }
+
+                self.check_set_size();
+
+                // Whatever the peer still lacks goes back as ordinary,
+                // acknowledged gossip.
+                let missing: Vec<Value> = self
+                    .messages
+                    .iter()
+                    .filter(|value| !self.is_known(&message.src, value))
+                    .cloned()
+                    .collect();
+
+                if !missing.is_empty() {
+                    self.send_gossip(ctx, &message.src, missing);
+                }
+            }
+
+            MessageBody::Read {
+                msg_id,
+                key: Some(key),
+            } => match self.kv.get(key) {
+                Some(value) => ctx.reply(
+                    &message,
+                    MessageBody::ReadOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        messages: None,
+                        value: Some(value.clone()),
+                    },
+                ),
+                None => ctx.reply_error(
+                    &message.src,
+                    *msg_id,
+                    KEY_DOES_NOT_EXIST,
+                    format!("key {} does not exist", key),
+                ),
+            },
+            MessageBody::Read { msg_id, key: None } => {
+                // An empty set is a valid answer once we're initialized, but
+                // before Init we can't tell "no values" from "not started".
+                if !self.initialized {
+                    ctx.reply_error(
+                        &message.src,
+                        *msg_id,
+                        TEMPORARILY_UNAVAILABLE,
+                        "node is not initialized",
+                    );
+                    return;
+                }
+
+                ctx.reply(
+                    &message,
+                    MessageBody::ReadOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        messages: Some(self.sorted()),
+                        value: None,
+                    },
+                );
+            }
+            MessageBody::ReadMany { keys, .. } => {
+                let values = keys
+                    .iter()
+                    .filter_map(|key| Some((key.clone(), self.kv.get(key)?.clone())))
+                    .collect();
+
+                ctx.reply(
+                    &message,
+                    MessageBody::ReadManyOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        values,
+                    },
+                );
+            }
+            MessageBody::ReadOk { .. } | MessageBody::ReadManyOk { .. } => {}
+
+            MessageBody::Topology { topology, .. } => {
+                // Maelstrom may send the same topology more than once; only
+                // a different one is worth reconciling.
+                if self.given_overlay.as_ref() != Some(topology) {
+                    let previous = self.neighbors.clone();
+
+                    self.given_neighbors =
+                        Some(topology.get(&self.id).cloned().unwrap_or_default());
+                    self.given_overlay = Some(topology.clone());
+
+                    self.on_topology_changed(ctx, &previous);
+                }
+
+                ctx.reply(
+                    &message,
+                    MessageBody::TopologyOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        adopted_neighbors: self.debug_topology.then(|| self.neighbors.clone()),
+                    },
+                );
+            }
+            MessageBody::TopologyOk { .. } => {}
+
+            MessageBody::SetTopology {
+                msg_id,
+                strategy,
+                args,
+            } => {
+                let parsed = match TopologyStrategy::with_args(strategy, args) {
+                    Ok(parsed) => parsed,
+                    Err(error) => {
+                        ctx.reply_error(&message.src, *msg_id, MALFORMED_REQUEST, error);
+                        return;
+                    }
+                };
+
+                let previous = self.neighbors.clone();
+                self.topology = parsed;
+                self.on_topology_changed(ctx, &previous);
+
+                // Every node has to switch for the overlay to stay
+                // consistent, so a request from outside the cluster is
+                // passed on to the rest of it, and only answered once they
+                // all have.
+                let others: Vec<String> = self
+                    .all_nodes
+                    .iter()
+                    .filter(|node| **node != self.id)
+                    .cloned()
+                    .collect();
+
+                if self.index_of(&message.src).is_none() && !others.is_empty() {
+                    let change = self.next_change;
+                    self.next_change += 1;
+
+                    for node in &others {
+                        let request = ctx.rpc_with(
+                            node,
+                            MessageBody::SetTopology {
+                                msg_id: 0,
+                                strategy: strategy.clone(),
+                                args: args.clone(),
+                            },
+                            Backoff::REPLICATION,
+                        );
+                        self.topology_forwards
+                            .insert(request, (change, node.clone()));
+                    }
+
+                    self.topology_changes.insert(
+                        change,
+                        TopologyChange {
+                            client: message.src.clone(),
+                            msg_id: *msg_id,
+                            remaining: others.len(),
+                            failed: Vec::new(),
+                        },
+                    );
+                    return;
+                }
+
+                ctx.reply(
+                    &message,
+                    MessageBody::SetTopologyOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        neighbors: self.neighbors.clone(),
+                    },
+                );
+            }
+            MessageBody::SetTopologyOk { .. } => {}
+
+            MessageBody::Write { key, value, .. } => {
+                self.kv.insert(key.clone(), value.clone());
+
+                ctx.reply(
+                    &message,
+                    MessageBody::WriteOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    },
+                );
+            }
+            MessageBody::Cas {
+                msg_id,
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.kv.get(key) {
+                None if !create_if_not_exists => ctx.reply_error(
+                    &message.src,
+                    *msg_id,
+                    KEY_DOES_NOT_EXIST,
+                    format!("key {} does not exist", key),
+                ),
+                Some(current) if current != from => ctx.reply_error(
+                    &message.src,
+                    *msg_id,
+                    PRECONDITION_FAILED,
+                    format!("expected {}, but had {}", from, current),
+                ),
+                _ => {
+                    self.kv.insert(key.clone(), to.clone());
+
+                    ctx.reply(
+                        &message,
+                        MessageBody::CasOk {
+                            msg_id: 0,
+                            in_reply_to: 0,
+                        },
+                    );
+                }
+            },
+
+            MessageBody::WriteOk { .. } | MessageBody::CasOk { .. } | MessageBody::Unsupported => {}
+        }
+    }
+
+    /// Replies to forwarded `set_topology` requests are counted towards the
+    /// client's change. Gossip turned away by a neighbor that isn't ready
+    /// yet is held until it answers again; anything else is handled as usual.
+    fn reply(&mut self, request: u32, reply: Result<Message, RpcError>, ctx: &mut Context) {
+        let Some((change, node)) = self.topology_forwards.remove(&request) else {
+            match reply {
+                Ok(message) => self.handle(message, ctx),
+                Err(RpcError::Rejected { code, .. }) if code == TEMPORARILY_UNAVAILABLE => {
+                    self.not_ready(ctx, request)
+                }
+                Err(error) => debug!("request {} failed: {}", request, error),
+            }
+            return;
+        };
+
+        let ok = match reply {
+            Ok(message) => matches!(message.body, MessageBody::SetTopologyOk { .. }),
+            Err(error) => {
+                warn!("forwarding set_topology to {} failed: {}", node, error);
+                false
+            }
+        };
+
+        self.topology_forwarded(ctx, change, node, ok);
+    }
+
+    /// Runs a gossip round when one is due, resends any gossip whose ack is
+    /// overdue and periodically shares set digests.
+    fn tick(&mut self, ctx: &mut Context) {
+        self.sync_digest(ctx);
+        self.reinject_dead_letters();
+
+        let known = &self.known;
+        let batches = if self.awaiting_topology() {
+            Vec::new()
+        } else {
+            self.gossip
+                .round(&self.neighbors, ctx.rng(), |peer, value| {
+                    known.get(peer).is_some_and(|values| values.contains(value))
+                })
+        };
+
+        // Once the queue has drained, this round carried everything those
+        // spans queued, whether or not any of it still had to go out.
+        if self.gossip.is_empty() {
+            let causes = std::mem::take(&mut self.gossip_causes);
+
+            if !batches.is_empty() {
+                for cause in causes {
+                    ctx.follows_from(cause);
+                }
+            }
+        }
+
+        let mut outgoing = Vec::new();
+
+        for (dest, values) in batches {
+            if let Some(dead) = self.dead_letters.get_mut(&dest) {
+                dead.values.extend(values);
+            } else if ctx.is_alive(&dest) {
+                outgoing.push((dest, values));
+            } else {
+                self.backlog.entry(dest).or_default().extend(values);
+            }
+        }
+
+        // A retry only needs whatever the peer still hasn't confirmed, which
+        // may be nothing if it gossiped those values to us in the meantime.
+        for (dest, values) in self.outbox.due() {
+            ctx.timed_out(&dest);
+
+            let values: Vec<Value> = values
+                .into_iter()
+                .filter(|value| !self.is_known(&dest, value))
+                .collect();
+
+            if values.is_empty() {
+                continue;
+            }
+
+            if self.retry(&dest) {
+                self.dead_letter(dest, values);
+                continue;
+            }
+
+            if ctx.is_alive(&dest) {
+                outgoing.push((dest, values));
+            } else {
+                self.backlog.entry(dest).or_default().extend(values);
+            }
+        }
+
+        self.drain_backlog(ctx, &mut outgoing);
+        self.flush_gossip(ctx, outgoing);
+    }
+
+    /// Logs the size and checksum of the broadcast set, for comparing nodes
+    /// at the end of a run.
+    fn report(&self) {
+        eprintln!(
+            "broadcast set: {} values, checksum {:016x}",
+            self.messages.len(),
+            self.set_checksum()
+        );
+    }
+
+    /// The broadcast set and local KV store, as JSON for
+    /// [`crate::persist`] to write out.
+    fn snapshot(&self) -> Option<Value> {
+        let snapshot = Snapshot {
+            messages: self.messages.clone(),
+            kv: self.kv.clone(),
+        };
+
+        serde_json::to_value(snapshot).ok()
+    }
+
+    /// Restored values aren't gossiped again: peers had the chance to hear
+    /// of them before we went down, and `--sync-ms` catches any they missed.
+    fn restore(&mut self, state: Value) {
+        match serde_json::from_value::<Snapshot>(state) {
+            Ok(snapshot) => {
+                for value in snapshot.messages.iter() {
+                    self.store_value(value.clone());
+                }
+                self.kv.extend(snapshot.kv);
+            }
+            Err(error) => warn!("ignoring malformed snapshot: {}", error),
+        }
+    }
+
+    fn debug_state(&self, state: &mut DebugState) {
+        state.set_size = Some(self.messages.len());
+        state.pending_gossip = Some(
+            self.outbox.len()
+                + self.backlog.values().map(Vec::len).sum::<usize>()
+                + self
+                    .dead_letters
+                    .values()
+                    .map(|dead| dead.values.len())
+                    .sum::<usize>()
+                + self
+                    .deferred
+                    .iter()
+                    .map(|(_, values)| values.len())
+                    .sum::<usize>(),
+        );
+    }
+
+    fn print_summary(&self) {
+        eprint!("{}", self.summary());
+    }
+}
+
+/// Merges gossip to the same peer into one message, keeping peers in the
+/// order they first appear and dropping repeated values.
+fn coalesce(outgoing: Vec<(String, Vec<Value>)>) -> Vec<(String, Vec<Value>)> {
+    let mut merged: Vec<(String, Vec<Value>)> = Vec::new();
+    let mut seen: HashMap<String, (usize, HashSet<Value>)> = HashMap::new();
+
+    for (dest, values) in outgoing {
+        let (index, dest_seen) = seen.entry(dest.clone()).or_insert_with(|| {
+            merged.push((dest, Vec::new()));
+            (merged.len() - 1, HashSet::new())
+        });
+
+        for value in values {
+            if dest_seen.insert(value.clone()) {
+                merged[*index].1.push(value);
+            }
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{message::Envelope, runtime::Runtime, sim::Simulator, test_log::warnings};
+    use serde_json::json;
+    use std::sync::mpsc::Receiver;
+
+    const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3"]}}"#;
+    const BROADCAST: &str =
+        r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":2,"message":7}}"#;
+    const TOPOLOGY: &str = r#"{"src":"c0","dest":"n1","body":{"type":"topology","msg_id":3,"topology":{"n1":["n2"],"n2":["n1","n3"],"n3":["n2"]}}}"#;
+
+    /// `(dest, type)` of everything sent since the last call.
+    fn sent(output: &Receiver<Message<Envelope<MessageBody>>>) -> Vec<(String, &'static str)> {
+        output
+            .try_iter()
+            .map(|message| (message.dest, message.body.kind()))
+            .collect()
+    }
+
+    fn early_broadcast_waits_for_topology(config: Config) {
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut node = Node::new(&config);
+
+        runtime.dispatch(&mut node, INIT);
+        runtime.dispatch(&mut node, BROADCAST);
+        runtime.tick(&mut node);
+
+        // The client is answered, but nothing goes to the other nodes.
+        let before: Vec<_> = sent(&output)
+            .into_iter()
+            .filter(|(_, kind)| *kind != "init_ok")
+            .collect();
+        assert_eq!(before, [("c1".to_string(), "broadcast_ok")]);
+
+        runtime.dispatch(&mut node, TOPOLOGY);
+        runtime.tick(&mut node);
+
+        let after: Vec<_> = sent(&output)
+            .into_iter()
+            .filter(|(_, kind)| *kind != "topology_ok")
+            .map(|(dest, _)| dest)
+            .collect();
+        assert_eq!(after, ["n2"]);
+    }
+
+    #[test]
+    fn gossip_before_topology_goes_to_the_given_neighbors() {
+        early_broadcast_waits_for_topology(Config {
+            gossip_interval: Duration::ZERO,
+            ..Config::default()
+        });
+    }
+
+    #[test]
+    fn causal_broadcast_before_topology_goes_to_the_given_neighbors() {
+        early_broadcast_waits_for_topology(Config {
+            causal_broadcast: true,
+            ..Config::default()
+        });
+    }
+
+    #[test]
+    fn set_topology_is_answered_once_every_node_has_switched() {
+        let mut simulator = Simulator::new(3, &Config::default(), Node::new);
+        simulator.partition(&["n0"], &["n2"]);
+
+        let msg_id = simulator.send("n0", json!({"type": "set_topology", "strategy": "ring"}));
+        simulator.run_for(Duration::from_millis(300));
+        assert!(simulator.reply_to(msg_id).is_none());
+
+        simulator.heal();
+        assert!(
+            simulator.run_until(Duration::from_secs(5), |simulator| simulator
+                .reply_to(msg_id)
+                .is_some())
+        );
+
+        let reply = &simulator.reply_to(msg_id).unwrap().body;
+        assert_eq!(reply["type"], "set_topology_ok");
+        for node in simulator.node_ids() {
+            assert_eq!(
+                simulator.handler(&node).unwrap().topology,
+                TopologyStrategy::Ring
+            );
+        }
+    }
+
+    #[test]
+    fn a_hop_limit_short_of_the_overlay_is_raised_to_reach_every_node() {
+        let config = Config {
+            causal_broadcast: true,
+            max_hops: Some(1),
+            topology: TopologyStrategy::Ring,
+            sync_interval: None,
+            ..Config::default()
+        };
+        let mut simulator = Simulator::new(8, &config, Node::new);
+
+        simulator.send("n0", json!({"type": "broadcast", "message": 7}));
+        simulator.run_for(Duration::from_millis(500));
+
+        // n4 is four links round the ring either way.
+        for node in simulator.node_ids() {
+            let handler = simulator.handler(&node).unwrap();
+            assert!(handler.messages.contains(&json!(7)), "{} missed it", node);
+            assert_eq!(handler.hop_limit, Some(3));
+        }
+    }
+
+    #[test]
+    fn reads_list_values_in_order_across_inserts() {
+        let config = Config::default();
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut node = Node::new(&config);
+        let mut msg_id = 10;
+
+        let mut read = |runtime: &mut Runtime<MessageBody>, node: &mut Node, values: &[Value]| {
+            for value in values {
+                msg_id += 1;
+                let body = json!({"type": "broadcast", "msg_id": msg_id, "message": value});
+                runtime.dispatch(
+                    node,
+                    &json!({"src": "c1", "dest": "n1", "body": body}).to_string(),
+                );
+            }
+
+            msg_id += 1;
+            let body = json!({"type": "read", "msg_id": msg_id});
+            runtime.dispatch(
+                node,
+                &json!({"src": "c1", "dest": "n1", "body": body}).to_string(),
+            );
+
+            output
+                .try_iter()
+                .find_map(|message| match message.body {
+                    Envelope::Body(MessageBody::ReadOk { messages, .. }) => messages,
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        runtime.dispatch(&mut node, INIT);
+        assert_eq!(
+            *read(&mut runtime, &mut node, &[json!(5), json!(1), json!("b")]),
+            [json!(1), json!(5), json!("b")]
+        );
+
+        let before = read(&mut runtime, &mut node, &[]);
+        let after = read(
+            &mut runtime,
+            &mut node,
+            &[json!(3), json!("a"), json!(9), json!(1)],
+        );
+        assert_eq!(*before, [json!(1), json!(5), json!("b")]);
+        assert_eq!(
+            *after,
+            [
+                json!(1),
+                json!(3),
+                json!(5),
+                json!(9),
+                json!("a"),
+                json!("b")
+            ]
+        );
+    }
+
+    #[test]
+    fn the_index_cache_matches_a_scan_of_the_membership() {
+        let mut node = Node::new(&Config::default());
+
+        for node_ids in [json!(["n1", "n2", "n1", "n3"]), json!(["n4", "n2", "n1"])] {
+            let node_ids: Vec<String> = serde_json::from_value(node_ids).unwrap();
+            node.init("n1", &node_ids);
+
+            for node_id in &node.all_nodes {
+                assert_eq!(
+                    node.index_of(node_id),
+                    node.all_nodes.iter().position(|node| node == node_id)
+                );
+            }
+            assert_eq!(node.indices.len(), node.all_nodes.len());
+        }
+
+        // A node dropped from the membership is forgotten.
+        assert_eq!(node.index_of("n3"), None);
+        assert_eq!(node.index_of("n1"), Some(2));
+    }
+
+    #[test]
+    fn duplicate_node_ids_in_init_are_dropped() {
+        let mut node = Node::new(&Config::default());
+        let node_ids: Vec<String> =
+            serde_json::from_value(json!(["n2", "n1", "n2", "n3", "n1"])).unwrap();
+
+        node.init("n1", &node_ids);
+
+        assert_eq!(node.all_nodes, ["n2", "n1", "n3"]);
+    }
+
+    #[test]
+    fn an_init_that_leaves_us_out_still_counts_us_in() {
+        let mut node = Node::new(&Config::default());
+        let node_ids: Vec<String> = serde_json::from_value(json!(["n2", "n3", "n3"])).unwrap();
+
+        node.init("n1", &node_ids);
+
+        assert_eq!(node.all_nodes, ["n2", "n3", "n1"]);
+        assert_eq!(node.index_of("n1"), Some(2));
+    }
+
+    #[test]
+    fn nodes_agree_on_key_owners_whatever_order_they_list_members_in() {
+        let members = json!(["n1", "n2", "n3", "n4", "n5"]);
+        let shuffled = json!(["n4", "n2", "n5", "n1", "n3"]);
+
+        let mut nodes = Vec::new();
+        for (node_id, node_ids) in [("n1", members), ("n4", shuffled)] {
+            let config = Config::default();
+            let (mut runtime, _output) = Runtime::in_memory(&config);
+            let mut node = Node::new(&config);
+
+            let init = json!({"src": "c0", "dest": node_id, "body": {"type": "init", "msg_id": 1, "node_id": node_id, "node_ids": node_ids}});
+            runtime.dispatch(&mut node, &init.to_string());
+            nodes.push(node);
+        }
+
+        let keys: Vec<String> = (0..100).map(|key| format!("key-{}", key)).collect();
+        let owners: Vec<_> = keys.iter().map(|key| nodes[0].owner(key)).collect();
+
+        assert_eq!(
+            owners,
+            keys.iter()
+                .map(|key| nodes[1].owner(key))
+                .collect::<Vec<_>>()
+        );
+
+        // Spread over everyone, not piled onto one node.
+        let owning: HashSet<_> = owners.into_iter().flatten().collect();
+        assert_eq!(owning.len(), 5);
+    }
+
+    #[test]
+    fn only_a_changed_topology_sends_catch_up_gossip() {
+        let config = Config::default();
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut node = Node::new(&config);
+
+        let topology = |msg_id: u32, neighbors: Value| {
+            json!({"src": "c0", "dest": "n1", "body": {"type": "topology", "msg_id": msg_id, "topology": {"n1": neighbors}}})
+                .to_string()
+        };
+
+        runtime.dispatch(&mut node, INIT);
+        runtime.dispatch(&mut node, &topology(3, json!(["n2"])));
+        runtime.dispatch(&mut node, BROADCAST);
+        runtime.tick(&mut node);
+        sent(&output);
+
+        runtime.dispatch(&mut node, &topology(4, json!(["n2"])));
+        assert_eq!(sent(&output), [("c0".to_string(), "topology_ok")]);
+
+        runtime.dispatch(&mut node, &topology(5, json!(["n2", "n3"])));
+        assert_eq!(
+            sent(&output),
+            [
+                ("n3".to_string(), "gossip"),
+                ("c0".to_string(), "topology_ok")
+            ]
+        );
+    }
+
+    #[test]
+    fn gossip_a_starting_neighbor_rejects_is_resent_once_it_is_ready() {
+        let config = Config {
+            gossip_interval: Duration::ZERO,
+            gossip_retry: Duration::ZERO,
+            ..Config::default()
+        };
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut node = Node::new(&config);
+
+        runtime.dispatch(&mut node, INIT);
+        runtime.dispatch(&mut node, TOPOLOGY);
+        runtime.dispatch(&mut node, BROADCAST);
+        output.try_iter().for_each(drop);
+
+        let mut delivered = false;
+
+        for round in 0..10 {
+            runtime.tick(&mut node);
+
+            let to_n2: Vec<_> = output.try_iter().filter(|m| m.dest == "n2").collect();
+
+            for message in to_n2 {
+                let Envelope::Body(MessageBody::Gossip { msg_id, messages }) = message.body else {
+                    continue;
+                };
+
+                // n2 turns everything away for its first few ticks.
+                let body = if round < 3 {
+                    json!({"type": "error", "in_reply_to": msg_id, "code": TEMPORARILY_UNAVAILABLE, "text": "not initialized"})
+                } else {
+                    delivered |= messages.contains(&json!(7));
+                    json!({"type": "gossip_ok", "msg_id": 100 + round, "in_reply_to": msg_id, "messages": messages})
+                };
+                let reply = json!({"src": "n2", "dest": "n1", "body": body});
+                runtime.dispatch(&mut node, &reply.to_string());
+            }
+
+            // Held back for n2 rather than waiting out a retry.
+            if round < 3 {
+                assert!(node.backlog["n2"].contains(&json!(7)));
+                assert!(node.outbox.is_empty());
+            }
+        }
+
+        assert!(delivered);
+        assert!(node.is_known("n2", &json!(7)));
+        assert!(node.outbox.is_empty());
+        assert!(node.backlog.is_empty());
+    }
+
+    #[test]
+    fn dead_letters_are_retried_once_their_wait_is_over() {
+        let config = Config {
+            gossip_interval: Duration::ZERO,
+            gossip_retry: Duration::ZERO,
+            gossip_max_retries: Some(2),
+            dead_letter_retry: Some(Duration::from_millis(30)),
+            ..Config::default()
+        };
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut node = Node::new(&config);
+
+        runtime.dispatch(&mut node, INIT);
+        runtime.dispatch(&mut node, TOPOLOGY);
+        runtime.dispatch(&mut node, BROADCAST);
+
+        // n2 is down: the gossip, its resend and a probe all go unanswered.
+        for _ in 0..4 {
+            runtime.tick(&mut node);
+        }
+        assert!(node.dead_letters["n2"].values.contains(&json!(7)));
+        assert!(node.backlog.is_empty());
+
+        output.try_iter().for_each(drop);
+        runtime.tick(&mut node);
+        assert_eq!(sent(&output), []);
+
+        // Back after the cap was used up; the dead letters go out again.
+        std::thread::sleep(Duration::from_millis(40));
+
+        let mut delivered = false;
+        for round in 0..5 {
+            runtime.tick(&mut node);
+
+            for message in output.try_iter().filter(|message| message.dest == "n2") {
+                let Envelope::Body(MessageBody::Gossip { msg_id, messages }) = message.body else {
+                    continue;
+                };
+
+                delivered |= messages.contains(&json!(7));
+                let ack = json!({"src": "n2", "dest": "n1", "body": {"type": "gossip_ok", "msg_id": 100 + round, "in_reply_to": msg_id, "messages": messages}});
+                runtime.dispatch(&mut node, &ack.to_string());
+            }
+        }
+
+        assert!(delivered);
+        assert!(node.is_known("n2", &json!(7)));
+        assert!(node.dead_letters.is_empty());
+        assert!(node.outbox.is_empty());
+    }
+
+    #[test]
+    fn read_many_returns_every_key_asked_for_that_exists() {
+        let config = Config::default();
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut node = Node::new(&config);
+
+        runtime.dispatch(&mut node, INIT);
+
+        for (msg_id, (key, value)) in [("a", 1), ("b", 2), ("c", 3)].into_iter().enumerate() {
+            let write = json!({"src": "c1", "dest": "n1", "body": {"type": "write", "msg_id": msg_id + 10, "key": key, "value": value}});
+            runtime.dispatch(&mut node, &write.to_string());
+        }
+        output.try_iter().for_each(drop);
+
+        let read = json!({"src": "c1", "dest": "n1", "body": {"type": "read_many", "msg_id": 20, "keys": ["a", "b", "c", "d"]}});
+        runtime.dispatch(&mut node, &read.to_string());
+
+        let reply = serde_json::to_value(output.try_recv().unwrap().body).unwrap();
+        assert_eq!(reply["type"], "read_many_ok");
+        assert_eq!(reply["in_reply_to"], 20);
+        assert_eq!(reply["values"], json!({"a": 1, "b": 2, "c": 3}));
+    }
+
+    #[test]
+    fn the_ack_strategy_orders_broadcast_ok_around_the_forward() {
+        for (ack, expected) in [
+            (AckStrategy::Eager, ["c1", "n2"]),
+            (AckStrategy::Lazy, ["n2", "c1"]),
+        ] {
+            let config = Config {
+                ack,
+                gossip_interval: Duration::ZERO,
+                ..Config::default()
+            };
+            let (mut runtime, output) = Runtime::in_memory(&config);
+            let mut node = Node::new(&config);
+
+            runtime.dispatch(&mut node, INIT);
+            runtime.dispatch(&mut node, TOPOLOGY);
+            sent(&output);
+
+            runtime.dispatch(&mut node, BROADCAST);
+            runtime.tick(&mut node);
+
+            let order: Vec<String> = sent(&output).into_iter().map(|(dest, _)| dest).collect();
+            assert_eq!(order, expected, "{:?}", ack);
+            assert!(node.messages.contains(&json!(7)));
+        }
+    }
+
+    #[test]
+    fn passing_the_soft_cap_warns_once() {
+        let config = Config {
+            set_soft_cap: Some(3),
+            ..Config::default()
+        };
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut node = Node::new(&config);
+        warnings("");
+
+        runtime.dispatch(&mut node, INIT);
+        for value in 0..10 {
+            let broadcast = json!({"src": "c1", "dest": "n1", "body": {"type": "broadcast", "msg_id": value + 10, "message": value}});
+            runtime.dispatch(&mut node, &broadcast.to_string());
+        }
+        output.try_iter().for_each(drop);
+
+        assert_eq!(node.messages.len(), 10);
+        // 0 to 9 are one run.
+        assert_eq!(
+            warnings("broadcast set has 4 values (soft cap 3), 1 when stored as ranges"),
+            1
+        );
+        assert_eq!(warnings("broadcast set has"), 1);
+    }
+
+    #[test]
+    fn debug_topology_ok_reports_the_neighbors_each_node_adopted() {
+        let config = Config {
+            debug_topology: true,
+            ..Config::default()
+        };
+        let mut simulator = Simulator::new(3, &config, Node::new);
+        let topology = json!({"n0": ["n1"], "n1": ["n0", "n2"], "n2": ["n1"]});
+
+        for node in simulator.node_ids() {
+            let reply = simulator
+                .request(
+                    &node,
+                    json!({"type": "topology", "topology": topology}),
+                    Duration::from_secs(1),
+                )
+                .unwrap();
+
+            assert_eq!(reply["type"], "topology_ok");
+            assert_eq!(reply["adopted_neighbors"], topology[&node], "{}", node);
+            assert_eq!(
+                json!(simulator.handler(&node).unwrap().neighbors),
+                topology[&node]
+            );
+        }
+    }
+
+    #[test]
+    fn the_set_checksum_ignores_insertion_order() {
+        let values = [json!(3), json!(17), json!("x"), json!(42), json!(17)];
+
+        let mut forwards = Node::new(&Config::default());
+        let mut backwards = Node::new(&Config::default());
+        for value in &values {
+            forwards.store_value(value.clone());
+        }
+        for value in values.iter().rev() {
+            backwards.store_value(value.clone());
+        }
+
+        assert_eq!(forwards.set_checksum(), backwards.set_checksum());
+
+        backwards.store_value(json!(5));
+        assert_ne!(forwards.set_checksum(), backwards.set_checksum());
+    }
+
+    #[test]
+    fn the_set_checksum_xors_each_value_hash() {
+        let mut node = Node::new(&Config::default()).with_hasher(crate::hash::Plain);
+
+        for value in [1, 2, 4, 2] {
+            node.store_value(json!(value));
+        }
+
+        // The repeated 2 is only counted once.
+        assert_eq!(node.set_checksum(), 1 ^ 2 ^ 4);
+    }
+
+    #[test]
+    fn broadcasts_above_the_max_value_are_rejected() {
+        let config = Config {
+            max_value: Some(100),
+            ..Config::default()
+        };
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut node = Node::new(&config);
+
+        runtime.dispatch(&mut node, INIT);
+        output.try_iter().for_each(drop);
+
+        for (msg_id, value) in [(10, json!(100)), (11, json!(101)), (12, json!("any"))] {
+            let broadcast = json!({"src": "c1", "dest": "n1", "body": {"type": "broadcast", "msg_id": msg_id, "message": value}});
+            runtime.dispatch(&mut node, &broadcast.to_string());
+        }
+
+        let replies: Vec<Value> = output
+            .try_iter()
+            .filter(|message| message.dest == "c1")
+            .map(|message| serde_json::to_value(message.body).unwrap())
+            .collect();
+
+        assert_eq!(replies[0]["type"], "broadcast_ok");
+        assert_eq!(replies[1]["type"], "error");
+        assert_eq!(replies[1]["in_reply_to"], 11);
+        assert_eq!(replies[1]["code"], MALFORMED_REQUEST);
+        // Only integers have a range.
+        assert_eq!(replies[2]["type"], "broadcast_ok");
+
+        assert!(node.messages.contains(&json!(100)));
+        assert!(!node.messages.contains(&json!(101)));
+        assert!(node.messages.contains(&json!("any")));
+    }
+
+    #[test]
+    fn a_read_before_init_is_an_error_and_after_it_an_empty_list() {
+        let config = Config::default();
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut node = Node::new(&config);
+        let read = |msg_id: u32| {
+            json!({"src": "c1", "dest": "n1", "body": {"type": "read", "msg_id": msg_id}})
+                .to_string()
+        };
+        let replies = |output: &Receiver<Message<Envelope<MessageBody>>>| -> Vec<Value> {
+            output
+                .try_iter()
+                .filter(|message| message.dest == "c1")
+                .map(|message| serde_json::to_value(message.body).unwrap())
+                .collect()
+        };
+
+        runtime.dispatch(&mut node, &read(10));
+        let early = replies(&output);
+        assert_eq!(early.len(), 1);
+        assert_eq!(early[0]["type"], "error");
+        assert_eq!(early[0]["in_reply_to"], 10);
+        assert_eq!(early[0]["code"], TEMPORARILY_UNAVAILABLE);
+
+        runtime.dispatch(&mut node, INIT);
+        runtime.dispatch(&mut node, &read(11));
+        let late = replies(&output);
+        assert_eq!(late.len(), 1);
+        assert_eq!(late[0]["type"], "read_ok");
+        assert_eq!(late[0]["in_reply_to"], 11);
+        assert_eq!(late[0]["messages"], json!([]));
+    }
+
+    #[test]
+    fn replies_carry_only_the_fields_maelstrom_expects() {
+        let config = Config::default();
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut node = Node::new(&config);
+
+        runtime.dispatch(&mut node, INIT);
+        runtime.dispatch(&mut node, TOPOLOGY);
+        runtime.dispatch(&mut node, BROADCAST);
+        for request in [
+            json!({"type": "read", "msg_id": 10}),
+            json!({"type": "write", "msg_id": 11, "key": "k", "value": 1}),
+            json!({"type": "read", "msg_id": 12, "key": "k"}),
+        ] {
+            let line = json!({"src": "c1", "dest": "n1", "body": request});
+            runtime.dispatch(&mut node, &line.to_string());
+        }
+
+        let fields: Vec<(String, BTreeSet<String>)> = output
+            .try_iter()
+            .filter(|message| message.dest.starts_with('c'))
+            .map(|message| {
+                let body = serde_json::to_value(message.body).unwrap();
+                let kind = body["type"].as_str().unwrap().to_string();
+                (kind, body.as_object().unwrap().keys().cloned().collect())
+            })
+            .collect();
+
+        let expected = |kind: &str, extra: &[&str]| {
+            let mut keys: BTreeSet<String> =
+                ["type", "msg_id", "in_reply_to"].map(String::from).into();
+            keys.extend(extra.iter().map(|key| key.to_string()));
+            (kind.to_string(), keys)
+        };
+        assert_eq!(
+            fields,
+            [
+                expected("init_ok", &[]),
+                expected("topology_ok", &[]),
+                expected("broadcast_ok", &[]),
+                expected("read_ok", &["messages"]),
+                expected("write_ok", &[]),
+                expected("read_ok", &["value"]),
+            ]
+        );
+
+        // Requests we send leave their defaults out too.
+        for (body, keys) in [
+            (
+                MessageBody::Cas {
+                    msg_id: 1,
+                    key: "k".to_string(),
+                    from: json!(1),
+                    to: json!(2),
+                    create_if_not_exists: false,
+                },
+                json!(["from", "key", "msg_id", "to", "type"]),
+            ),
+            (
+                MessageBody::SyncState {
+                    msg_id: 1,
+                    ranges: vec![(1, 3)],
+                    others: Vec::new(),
+                },
+                json!(["msg_id", "ranges", "type"]),
+            ),
+        ] {
+            let body = serde_json::to_value(body).unwrap();
+            let sent: Vec<&String> = body.as_object().unwrap().keys().collect();
+            assert_eq!(json!(sent), keys);
+        }
+    }
+
+    #[test]
+    fn the_summary_reflects_what_the_node_was_sent() {
+        let config = Config {
+            gossip_interval: Duration::ZERO,
+            ..Config::default()
+        };
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut node = Node::new(&config);
+
+        runtime.dispatch(&mut node, INIT);
+        runtime.dispatch(&mut node, TOPOLOGY);
+        for (msg_id, value) in [(10, 1), (11, 2), (12, 3), (13, 7)] {
+            let broadcast = json!({"src": "c1", "dest": "n1", "body": {"type": "broadcast", "msg_id": msg_id, "message": value}});
+            runtime.dispatch(&mut node, &broadcast.to_string());
+        }
+        runtime.tick(&mut node);
+        output.try_iter().for_each(drop);
+
+        // The four values went to n2 in one gossip, not yet acked.
+        assert_eq!(
+            node.summary(),
+            "summary: 4 broadcast values in 2 contiguous ranges\n\
+             summary: 1 gossips still unacked\n\
+             summary: 0 values held for unreachable peers\n\
+             summary: 0 values dead-lettered\n"
+        );
+    }
+}
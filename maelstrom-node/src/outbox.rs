@@ -0,0 +1,108 @@
+use crate::rtt::RttEstimate;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Gossip sent to other nodes that hasn't been acknowledged yet, keyed by the
+/// msg_id it went out with.
+///
+/// Maelstrom's partition tests drop messages silently, so anything that isn't
+/// acked in time is handed back by [`Outbox::due`] to be sent again. In time
+/// means within a few of the peer's round trips, as measured from earlier
+/// acks, and never more than `timeout`.
+pub struct Outbox {
+    timeout: Duration,
+    outlier_multiple: f64,
+    pending: HashMap<u32, Unacked>,
+    rtts: HashMap<String, RttEstimate>,
+}
+
+struct Unacked {
+    dest: String,
+    values: Vec<Value>,
+    sent_at: Instant,
+}
+
+impl Outbox {
+    /// See [`RttEstimate`] for `outlier_multiple`.
+    pub fn new(timeout: Duration, outlier_multiple: f64) -> Outbox {
+        Outbox {
+            timeout,
+            outlier_multiple,
+            pending: HashMap::new(),
+            rtts: HashMap::new(),
+        }
+    }
+
+    pub fn track(&mut self, msg_id: u32, dest: &str, values: Vec<Value>) {
+        self.pending.insert(
+            msg_id,
+            Unacked {
+                dest: dest.to_string(),
+                values,
+                sent_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Clears the entry acknowledged by `in_reply_to`, if it's one of ours,
+    /// and times the round trip.
+    pub fn ack(&mut self, in_reply_to: u32) {
+        let Some(unacked) = self.pending.remove(&in_reply_to) else {
+            return;
+        };
+
+        let outlier_multiple = self.outlier_multiple;
+        self.rtts
+            .entry(unacked.dest)
+            .or_insert_with(|| RttEstimate::new(outlier_multiple))
+            .observe(unacked.sent_at.elapsed());
+    }
+
+    /// How long gossip to `dest` waits for an ack.
+    pub fn retry_interval(&self, dest: &str) -> Duration {
+        self.rtts
+            .get(dest)
+            .map_or(self.timeout, |rtt| rtt.retry_interval(self.timeout))
+    }
+
+    /// Removes and returns the `(dest, values)` sent as `msg_id`, if it is
+    /// still waiting on an ack.
+    pub fn take(&mut self, msg_id: u32) -> Option<(String, Vec<Value>)> {
+        self.pending
+            .remove(&msg_id)
+            .map(|unacked| (unacked.dest, unacked.values))
+    }
+
+    /// Removes and returns every `(dest, values)` that has waited longer than
+    /// its retry interval. Callers resend them, which tracks them again
+    /// under a fresh msg_id.
+    pub fn due(&mut self) -> Vec<(String, Vec<Value>)> {
+        let now = Instant::now();
+
+        let expired: Vec<u32> = self
+            .pending
+            .iter()
+            .filter(|(_, unacked)| {
+                now.duration_since(unacked.sent_at) >= self.retry_interval(&unacked.dest)
+            })
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|msg_id| self.pending.remove(&msg_id))
+            .map(|unacked| (unacked.dest, unacked.values))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
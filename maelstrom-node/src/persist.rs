@@ -0,0 +1,97 @@
+//! Opt-in snapshots of handler state, for trying out crash recovery.
+//!
+//! With `--snapshot-dir`, each node periodically writes what
+//! [`Handler::snapshot`] returns to `<dir>/<node_id>.json`, and once more on
+//! the way out. A node started with the same directory and ID picks the file
+//! up again at `init` and hands it to [`Handler::restore`]. Files are written
+//! to a temporary name and renamed into place, so a crash mid-write leaves
+//! the previous snapshot intact.
+
+use crate::runtime::Handler;
+use log::{debug, warn};
+use serde_json::Value;
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+pub struct Snapshots {
+    dir: Option<PathBuf>,
+    interval: Duration,
+    last_saved: Instant,
+    /// This node's file, known from `init`.
+    path: Option<PathBuf>,
+}
+
+impl Snapshots {
+    /// Does nothing unless `dir` is set.
+    pub fn new(dir: Option<&str>, interval: Duration) -> Snapshots {
+        Snapshots {
+            dir: dir.map(PathBuf::from),
+            interval,
+            last_saved: Instant::now(),
+            path: None,
+        }
+    }
+
+    /// Settles on `node_id`'s file and returns what it last held, if
+    /// anything.
+    pub fn init(&mut self, node_id: &str) -> Option<Value> {
+        let dir = self.dir.as_ref()?;
+
+        if let Err(error) = fs::create_dir_all(dir) {
+            warn!(
+                "not snapshotting, can't create {}: {}",
+                dir.display(),
+                error
+            );
+            return None;
+        }
+
+        let path = dir.join(format!("{}.json", node_id));
+        let contents = fs::read(&path).ok();
+        self.path = Some(path.clone());
+
+        match serde_json::from_slice(&contents?) {
+            Ok(state) => {
+                debug!("restoring snapshot from {}", path.display());
+                Some(state)
+            }
+            Err(error) => {
+                warn!("ignoring unreadable snapshot {}: {}", path.display(), error);
+                None
+            }
+        }
+    }
+
+    /// Saves `handler`'s state if the interval has passed since the last
+    /// save.
+    pub fn tick<H: Handler>(&mut self, handler: &H) {
+        if self.last_saved.elapsed() >= self.interval {
+            self.save(handler);
+        }
+    }
+
+    pub fn save<H: Handler>(&mut self, handler: &H) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        self.last_saved = Instant::now();
+
+        let Some(state) = handler.snapshot() else {
+            return;
+        };
+
+        let temporary = path.with_extension("json.tmp");
+        let written = serde_json::to_vec(&state)
+            .map_err(std::io::Error::from)
+            .and_then(|bytes| fs::write(&temporary, bytes))
+            .and_then(|()| fs::rename(&temporary, path));
+
+        if let Err(error) = written {
+            warn!("failed to write snapshot {}: {}", path.display(), error);
+        }
+    }
+}
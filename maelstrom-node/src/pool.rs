@@ -0,0 +1,336 @@
+//! Handling messages on several threads at once, for workloads whose
+//! requests don't depend on each other, such as echo.
+//!
+//! The reader routes each line of input to one of `workers` threads, each
+//! running its own [`Runtime`] with its own clone of the handler, and every
+//! worker sends through one shared stdout writer, which writes each message
+//! whole. So output lines never interleave, but replies can go out in a
+//! different order from the requests.
+//!
+//! A request goes to the worker picked by hashing its sender and msg_id,
+//! so a retried request reaches the worker that remembers answering it.
+//! Each worker stamps its messages with msg_ids from its own residue class,
+//! and a reply goes back to the worker whose msg_id it answers. `init` goes
+//! to every worker, but only one answers it.
+//!
+//! Clones of the handler run concurrently and don't see each other's
+//! changes, so a handler that keeps state has to share it between clones
+//! itself, behind a lock, for its answers to agree.
+
+use crate::{
+    codec::{Codec, JsonCodec},
+    config::Config,
+    error::Error,
+    message::Envelope,
+    runtime::{self, Handler, Runtime},
+};
+use log::{debug, warn};
+use serde::Deserialize;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufReader, Write},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+        Arc,
+    },
+    thread,
+    time::Instant,
+};
+
+/// Runs `config.workers` clones of `handler` on stdin and stdout until
+/// stdin closes, or on SIGTERM once every worker's outstanding requests have
+/// been answered or the shutdown grace period has passed.
+pub fn run<H>(config: &Config, handler: &H) -> Result<(), Error>
+where
+    H: Handler + Clone + Send,
+{
+    runtime::catch_signals();
+
+    run_with(
+        config,
+        handler,
+        JsonCodec,
+        || BufReader::new(io::stdin()),
+        || io::stdout().lock(),
+    )
+}
+
+/// [`run`], reading lines from what `input` returns and writing to what
+/// `open` returns, each called on a thread of its own.
+fn run_with<H, C, R, W>(
+    config: &Config,
+    handler: &H,
+    codec: C,
+    input: impl FnOnce() -> R + Send + 'static,
+    open: impl FnOnce() -> W + Send + 'static,
+) -> Result<(), Error>
+where
+    H: Handler + Clone + Send,
+    C: Codec<Envelope<H::Body>> + Clone + Send + 'static,
+    R: BufRead,
+    W: Write,
+{
+    let workers = config.workers.max(1);
+
+    let (outbound, writer) = runtime::spawn_writer(
+        config,
+        Box::new(codec.clone()),
+        open,
+        Arc::new(AtomicU64::new(0)),
+        Arc::new(AtomicUsize::new(0)),
+    );
+
+    let (inboxes, lines): (Vec<_>, Vec<_>) = (0..workers)
+        .map(|_| mpsc::sync_channel::<String>(config.queue_capacity.max(1)))
+        .unzip();
+
+    // Not joined: after SIGTERM it may be blocked on a read that never
+    // finishes.
+    thread::spawn(move || read(input(), inboxes));
+
+    let finished = thread::scope(|scope| {
+        let running: Vec<_> = lines
+            .into_iter()
+            .enumerate()
+            .map(|(index, lines)| {
+                let mut handler = handler.clone();
+                let codec = codec.clone();
+                let outbound = outbound.clone();
+
+                // Clones share their state, so one snapshot is enough.
+                let mut config = config.clone();
+                if index > 0 {
+                    config.snapshot_dir = None;
+                }
+
+                scope.spawn(move || {
+                    let mut runtime =
+                        Runtime::worker(&config, codec, outbound, index as u32, workers as u32);
+
+                    work(&config, &mut runtime, &mut handler, lines);
+
+                    if config.summary {
+                        runtime.print_summary();
+                    }
+                    eprintln!("metrics: worker {}: {}", index, runtime.stats());
+
+                    runtime.finish(&handler)
+                })
+            })
+            .collect();
+
+        running
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("Worker thread panicked"))
+    });
+
+    drop(outbound);
+    let written = writer.join().expect("Writer thread panicked");
+
+    written.and(finished)
+}
+
+/// Passes each line of `input` to the worker it is for, until `input` ends
+/// or every worker has stopped.
+fn read(input: impl BufRead, inboxes: Vec<SyncSender<String>>) {
+    for line in input.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(error) => {
+                warn!("stopped reading input: {}", error);
+                return;
+            }
+        };
+
+        let delivered = match route(&line, inboxes.len()) {
+            Some(worker) => inboxes[worker].send(line).is_ok(),
+            None => {
+                let mut any = false;
+                for inbox in &inboxes {
+                    any |= inbox.send(line.clone()).is_ok();
+                }
+                any
+            }
+        };
+
+        if !delivered {
+            return;
+        }
+    }
+}
+
+/// Just enough of a message to route it.
+#[derive(Deserialize)]
+struct Route {
+    src: String,
+    body: RouteBody,
+}
+
+#[derive(Deserialize)]
+struct RouteBody {
+    #[serde(rename = "type")]
+    kind: String,
+    msg_id: Option<u32>,
+    in_reply_to: Option<u32>,
+}
+
+/// The worker `line` should go to among `workers`, or `None` for all of
+/// them. Anything that doesn't parse goes to the first, to be logged as
+/// malformed.
+fn route(line: &str, workers: usize) -> Option<usize> {
+    let Ok(Route { src, body }) = serde_json::from_str::<Route>(line) else {
+        return Some(0);
+    };
+
+    if body.kind == "init" {
+        return None;
+    }
+
+    // Worker i hands out msg_ids i + 1, i + 1 + workers and so on.
+    if let Some(in_reply_to) = body.in_reply_to {
+        return Some(in_reply_to.saturating_sub(1) as usize % workers);
+    }
+
+    let mut hasher = DefaultHasher::new();
+    (src, body.msg_id).hash(&mut hasher);
+    Some(hasher.finish() as usize % workers)
+}
+
+/// One worker's event loop: handles its lines and ticks on time until the
+/// reader hangs up, or until SIGTERM and its requests have been answered.
+fn work<H: Handler>(
+    config: &Config,
+    runtime: &mut Runtime<H::Body>,
+    handler: &mut H,
+    lines: Receiver<String>,
+) {
+    let mut next_tick = Instant::now() + config.tick_interval;
+    let mut shutdown_at = None;
+
+    loop {
+        // Ticks are checked first so a steady stream of lines can't hold
+        // them off.
+        if Instant::now() >= next_tick {
+            runtime.on_timer(handler, &mut io::stderr().lock());
+            next_tick += config.tick_interval;
+
+            if shutdown_at.is_none() && runtime::terminated() {
+                debug!(
+                    "terminated with {} requests outstanding",
+                    runtime.pending_rpcs()
+                );
+                shutdown_at = Some(Instant::now() + runtime.shutdown_grace());
+            }
+        }
+
+        match lines.recv_timeout(next_tick.saturating_duration_since(Instant::now())) {
+            Ok(line) => runtime.dispatch(handler, &line),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        if runtime.is_closed() {
+            return;
+        }
+
+        if let Some(deadline) = shutdown_at {
+            if runtime.pending_rpcs() == 0 || Instant::now() >= deadline {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echo::Echo;
+    use serde_json::{json, Value};
+    use std::{collections::HashSet, sync::Mutex};
+
+    /// Collects everything written, from whichever thread writes it.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parallel_echoes_are_all_answered_correctly() {
+        let mut input = vec![
+            json!({"src": "c0", "dest": "n1", "body": {"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}),
+        ];
+
+        for client in 1..=8 {
+            for msg_id in 1..=50 {
+                input.push(json!({
+                    "src": format!("c{}", client),
+                    "dest": "n1",
+                    "body": {"type": "echo", "msg_id": msg_id, "echo": format!("{} from c{}", msg_id, client)},
+                }));
+            }
+        }
+
+        let input: String = input.iter().map(|line| format!("{}\n", line)).collect();
+
+        let config = Config {
+            workers: 4,
+            ..Config::default()
+        };
+        let captured = Captured::default();
+        let out = captured.clone();
+
+        run_with(
+            &config,
+            &Echo::new(),
+            JsonCodec,
+            move || io::Cursor::new(input.into_bytes()),
+            move || out,
+        )
+        .unwrap();
+
+        let written = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+
+        // Every line is a whole message, whichever worker wrote it.
+        let replies: Vec<Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("a whole JSON message"))
+            .collect();
+
+        let init_oks = replies
+            .iter()
+            .filter(|reply| reply["body"]["type"] == "init_ok")
+            .count();
+        assert_eq!(init_oks, 1);
+
+        let mut answered = HashSet::new();
+        let mut msg_ids = HashSet::new();
+
+        for reply in replies
+            .iter()
+            .filter(|reply| reply["body"]["type"] == "echo_ok")
+        {
+            let client = reply["dest"].as_str().unwrap();
+            let in_reply_to = &reply["body"]["in_reply_to"];
+
+            assert_eq!(
+                reply["body"]["echo"],
+                format!("{} from {}", in_reply_to, client)
+            );
+            assert!(answered.insert((client.to_string(), in_reply_to.as_u64().unwrap())));
+            assert!(msg_ids.insert(reply["body"]["msg_id"].as_u64().unwrap()));
+        }
+
+        assert_eq!(answered.len(), 8 * 50);
+    }
+}
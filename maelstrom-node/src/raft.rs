@@ -0,0 +1,880 @@
+use crate::{
+    config::Config,
+    message::{Body, Message, KEY_DOES_NOT_EXIST, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE},
+    runtime::{Context, Handler},
+};
+use log::{debug, info};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+/// Most log entries sent in one `append_entries`.
+const MAX_ENTRIES_PER_APPEND: usize = 64;
+
+/// A client request once it's in the log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    },
+    /// Appended by each new leader, so that it has an entry of its own term
+    /// to commit, and with it everything before, without waiting for a
+    /// client to write.
+    Noop,
+}
+
+/// One slot of the replicated log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub term: u64,
+    pub op: Op,
+    /// The node that took the request and answers it once it is applied.
+    pub node: String,
+    pub client: String,
+    pub msg_id: u32,
+}
+
+/// Message types of the `lin-kv` workload, served here rather than by
+/// Maelstrom, plus the Raft protocol between nodes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RaftBody {
+    Read {
+        msg_id: u32,
+        key: Value,
+    },
+    ReadOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        value: Value,
+    },
+    Write {
+        msg_id: u32,
+        key: Value,
+        value: Value,
+    },
+    WriteOk {
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    Cas {
+        msg_id: u32,
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk {
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    RequestVote {
+        msg_id: u32,
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    RequestVoteOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        term: u64,
+        vote_granted: bool,
+    },
+    AppendEntries {
+        msg_id: u32,
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    /// `match_index` is how far the follower's log now agrees with the
+    /// leader's on success, and its last index on failure, as a hint for
+    /// where to retry from.
+    AppendEntriesOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+impl Body for RaftBody {
+    fn kind(&self) -> &'static str {
+        match self {
+            RaftBody::Read { .. } => "read",
+            RaftBody::ReadOk { .. } => "read_ok",
+            RaftBody::Write { .. } => "write",
+            RaftBody::WriteOk { .. } => "write_ok",
+            RaftBody::Cas { .. } => "cas",
+            RaftBody::CasOk { .. } => "cas_ok",
+            RaftBody::RequestVote { .. } => "request_vote",
+            RaftBody::RequestVoteOk { .. } => "request_vote_ok",
+            RaftBody::AppendEntries { .. } => "append_entries",
+            RaftBody::AppendEntriesOk { .. } => "append_entries_ok",
+            RaftBody::Unsupported => "unsupported",
+        }
+    }
+
+    fn msg_id(&self) -> Option<u32> {
+        match self {
+            RaftBody::Read { msg_id, .. }
+            | RaftBody::ReadOk { msg_id, .. }
+            | RaftBody::Write { msg_id, .. }
+            | RaftBody::WriteOk { msg_id, .. }
+            | RaftBody::Cas { msg_id, .. }
+            | RaftBody::CasOk { msg_id, .. }
+            | RaftBody::RequestVote { msg_id, .. }
+            | RaftBody::RequestVoteOk { msg_id, .. }
+            | RaftBody::AppendEntries { msg_id, .. }
+            | RaftBody::AppendEntriesOk { msg_id, .. } => Some(*msg_id),
+            RaftBody::Unsupported => None,
+        }
+    }
+
+    fn in_reply_to(&self) -> Option<u32> {
+        match self {
+            RaftBody::ReadOk { in_reply_to, .. }
+            | RaftBody::WriteOk { in_reply_to, .. }
+            | RaftBody::CasOk { in_reply_to, .. }
+            | RaftBody::RequestVoteOk { in_reply_to, .. }
+            | RaftBody::AppendEntriesOk { in_reply_to, .. } => Some(*in_reply_to),
+            RaftBody::Read { .. }
+            | RaftBody::Write { .. }
+            | RaftBody::Cas { .. }
+            | RaftBody::RequestVote { .. }
+            | RaftBody::AppendEntries { .. }
+            | RaftBody::Unsupported => None,
+        }
+    }
+
+    fn set_msg_id(&mut self, id: u32) {
+        match self {
+            RaftBody::Read { msg_id, .. }
+            | RaftBody::ReadOk { msg_id, .. }
+            | RaftBody::Write { msg_id, .. }
+            | RaftBody::WriteOk { msg_id, .. }
+            | RaftBody::Cas { msg_id, .. }
+            | RaftBody::CasOk { msg_id, .. }
+            | RaftBody::RequestVote { msg_id, .. }
+            | RaftBody::RequestVoteOk { msg_id, .. }
+            | RaftBody::AppendEntries { msg_id, .. }
+            | RaftBody::AppendEntriesOk { msg_id, .. } => *msg_id = id,
+            RaftBody::Unsupported => {}
+        }
+    }
+
+    fn set_in_reply_to(&mut self, id: u32) {
+        match self {
+            RaftBody::ReadOk { in_reply_to, .. }
+            | RaftBody::WriteOk { in_reply_to, .. }
+            | RaftBody::CasOk { in_reply_to, .. }
+            | RaftBody::RequestVoteOk { in_reply_to, .. }
+            | RaftBody::AppendEntriesOk { in_reply_to, .. } => *in_reply_to = id,
+            RaftBody::Read { .. }
+            | RaftBody::Write { .. }
+            | RaftBody::Cas { .. }
+            | RaftBody::RequestVote { .. }
+            | RaftBody::AppendEntries { .. }
+            | RaftBody::Unsupported => {}
+        }
+    }
+
+    fn is_unsupported(&self) -> bool {
+        matches!(self, RaftBody::Unsupported)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// A `lin-kv` server replicated with Raft.
+///
+/// Every client request, reads included, goes through the log, so the
+/// answers are linearizable. Only the leader accepts requests; the others
+/// answer `temporarily-unavailable` and the client tries again elsewhere.
+/// Each request is answered by the node that took it once it applies the
+/// entry, so a request survives its leader being deposed as long as the
+/// entry does. There are no snapshots: the log grows for the life of the run.
+pub struct Raft {
+    node_id: String,
+    peers: Vec<String>,
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    votes: HashSet<String>,
+    /// Entry `i` (1-based, as in the paper) is at `log[i - 1]`.
+    log: Vec<Entry>,
+    commit_index: u64,
+    last_applied: u64,
+    /// `None` until the first tick, which draws it from the context's RNG.
+    election_deadline: Option<Instant>,
+    /// A follower stands for election after hearing nothing from a leader
+    /// for a random time between half of this and all of it, so candidates
+    /// rarely collide.
+    election_timeout: Duration,
+    /// Leader only: the next entry to send each peer, and how much of their
+    /// log is known to match ours.
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    store: HashMap<Value, Value>,
+}
+
+impl Raft {
+    pub fn new(config: &Config) -> Raft {
+        Raft {
+            node_id: String::new(),
+            peers: Vec::new(),
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            votes: HashSet::new(),
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            election_deadline: None,
+            election_timeout: config.peer_timeout,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            store: HashMap::new(),
+        }
+    }
+
+    fn last_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            index => self
+                .log
+                .get(index as usize - 1)
+                .map_or(0, |entry| entry.term),
+        }
+    }
+
+    fn majority(&self) -> usize {
+        let cluster = self.peers.len() + 1;
+        cluster / 2 + 1
+    }
+
+    /// Adopts a newer `term` seen in any message, falling back to follower.
+    fn observe_term(&mut self, term: u64) {
+        if term > self.term {
+            if self.role != Role::Follower {
+                info!("stepping down for term {}", term);
+            }
+
+            self.term = term;
+            self.role = Role::Follower;
+            self.voted_for = None;
+        }
+    }
+
+    fn start_election(&mut self, ctx: &mut Context<RaftBody>) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.node_id.clone());
+        self.votes = HashSet::from([self.node_id.clone()]);
+        self.election_deadline = Some(election_deadline(ctx.rng(), self.election_timeout));
+
+        debug!("starting election for term {}", self.term);
+
+        if self.votes.len() >= self.majority() {
+            self.become_leader(ctx);
+            return;
+        }
+
+        for peer in &self.peers {
+            ctx.send(
+                peer,
+                RaftBody::RequestVote {
+                    msg_id: 0,
+                    term: self.term,
+                    last_log_index: self.last_index(),
+                    last_log_term: self.term_at(self.last_index()),
+                },
+            );
+        }
+    }
+
+    fn become_leader(&mut self, ctx: &mut Context<RaftBody>) {
+        info!("leader for term {}", self.term);
+
+        self.role = Role::Leader;
+        self.next_index = self
+            .peers
+            .iter()
+            .map(|peer| (peer.clone(), self.last_index() + 1))
+            .collect();
+        self.match_index = self.peers.iter().map(|peer| (peer.clone(), 0)).collect();
+
+        self.log.push(Entry {
+            term: self.term,
+            op: Op::Noop,
+            node: self.node_id.clone(),
+            client: String::new(),
+            msg_id: 0,
+        });
+
+        if self.peers.is_empty() {
+            self.advance_commit(ctx);
+        }
+
+        self.replicate(ctx);
+    }
+
+    /// Sends every peer whatever it is missing, or an empty heartbeat.
+    fn replicate(&mut self, ctx: &mut Context<RaftBody>) {
+        for peer in &self.peers {
+            let next = self.next_index.get(peer).copied().unwrap_or(1).max(1);
+            let prev_log_index = next - 1;
+            let entries: Vec<Entry> = self
+                .log
+                .iter()
+                .skip(prev_log_index as usize)
+                .take(MAX_ENTRIES_PER_APPEND)
+                .cloned()
+                .collect();
+
+            ctx.send(
+                peer,
+                RaftBody::AppendEntries {
+                    msg_id: 0,
+                    term: self.term,
+                    prev_log_index,
+                    prev_log_term: self.term_at(prev_log_index),
+                    entries,
+                    leader_commit: self.commit_index,
+                },
+            );
+        }
+    }
+
+    /// Commits the newest entry of this term that a majority has stored.
+    /// Entries from earlier terms are only committed along with it.
+    fn advance_commit(&mut self, ctx: &mut Context<RaftBody>) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.term {
+                break;
+            }
+
+            let replicas = 1 + self
+                .match_index
+                .values()
+                .filter(|matched| **matched >= index)
+                .count();
+
+            if replicas >= self.majority() {
+                self.commit_index = index;
+                break;
+            }
+        }
+
+        self.apply(ctx);
+    }
+
+    /// Applies committed entries in order, answering the clients of the
+    /// ones this node took.
+    fn apply(&mut self, ctx: &mut Context<RaftBody>) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+
+            let entry = self.log[self.last_applied as usize - 1].clone();
+            let result = self.execute(&entry.op);
+
+            if entry.node != self.node_id || entry.op == Op::Noop {
+                continue;
+            }
+
+            let body = match result {
+                Ok(Some(value)) => RaftBody::ReadOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                    value,
+                },
+                Ok(None) => match entry.op {
+                    Op::Write { .. } => RaftBody::WriteOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    },
+                    _ => RaftBody::CasOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    },
+                },
+                Err((code, text)) => {
+                    ctx.reply_error(&entry.client, entry.msg_id, code, text);
+                    continue;
+                }
+            };
+
+            ctx.reply_to(&entry.client, entry.msg_id, body);
+        }
+    }
+
+    /// Runs `op` against the store: the value read, nothing for a write, cas
+    /// or no-op, or the error code and text to answer with.
+    fn execute(&mut self, op: &Op) -> Result<Option<Value>, (u32, String)> {
+        match op {
+            Op::Noop => Ok(None),
+            Op::Read { key } => match self.store.get(key) {
+                Some(value) => Ok(Some(value.clone())),
+                None => Err((KEY_DOES_NOT_EXIST, format!("key {} does not exist", key))),
+            },
+            Op::Write { key, value } => {
+                self.store.insert(key.clone(), value.clone());
+                Ok(None)
+            }
+            Op::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.store.get(key) {
+                None if *create_if_not_exists => {
+                    self.store.insert(key.clone(), to.clone());
+                    Ok(None)
+                }
+                None => Err((KEY_DOES_NOT_EXIST, format!("key {} does not exist", key))),
+                Some(current) if current != from => Err((
+                    PRECONDITION_FAILED,
+                    format!("expected {}, found {}", from, current),
+                )),
+                Some(_) => {
+                    self.store.insert(key.clone(), to.clone());
+                    Ok(None)
+                }
+            },
+        }
+    }
+
+    /// Appends a client request to the log if we are the leader.
+    fn propose(&mut self, ctx: &mut Context<RaftBody>, client: String, msg_id: u32, op: Op) {
+        if self.role != Role::Leader {
+            ctx.reply_error(&client, msg_id, TEMPORARILY_UNAVAILABLE, "not the leader");
+            return;
+        }
+
+        self.log.push(Entry {
+            term: self.term,
+            op,
+            node: self.node_id.clone(),
+            client,
+            msg_id,
+        });
+
+        if self.peers.is_empty() {
+            self.advance_commit(ctx);
+        }
+    }
+
+    fn on_append_entries(
+        &mut self,
+        leader: &str,
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: &[Entry],
+        leader_commit: u64,
+    ) -> (bool, u64) {
+        if term < self.term {
+            return (false, self.last_index());
+        }
+
+        self.role = Role::Follower;
+
+        if prev_log_index > self.last_index() || self.term_at(prev_log_index) != prev_log_term {
+            debug!("log mismatch with {} at {}", leader, prev_log_index);
+            return (
+                false,
+                self.last_index().min(prev_log_index.saturating_sub(1)),
+            );
+        }
+
+        let mut index = prev_log_index;
+
+        for entry in entries {
+            index += 1;
+
+            if index <= self.last_index() {
+                if self.term_at(index) == entry.term {
+                    continue;
+                }
+
+                self.log.truncate(index as usize - 1);
+            }
+
+            self.log.push(entry.clone());
+        }
+
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(index);
+        }
+
+        (true, index)
+    }
+}
+
+fn election_deadline(rng: &mut impl Rng, timeout: Duration) -> Instant {
+    Instant::now() + rng.gen_range(timeout / 2..timeout)
+}
+
+impl Handler for Raft {
+    type Body = RaftBody;
+
+    fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        self.node_id = node_id.to_string();
+        self.peers = node_ids
+            .iter()
+            .filter(|node| *node != node_id)
+            .cloned()
+            .collect();
+    }
+
+    fn handle(&mut self, message: Message<RaftBody>, ctx: &mut Context<RaftBody>) {
+        match &message.body {
+            RaftBody::Read { msg_id, key } => {
+                let op = Op::Read { key: key.clone() };
+                self.propose(ctx, message.src.clone(), *msg_id, op);
+            }
+
+            RaftBody::Write { msg_id, key, value } => {
+                let op = Op::Write {
+                    key: key.clone(),
+                    value: value.clone(),
+                };
+                self.propose(ctx, message.src.clone(), *msg_id, op);
+            }
+
+            RaftBody::Cas {
+                msg_id,
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => {
+                let op = Op::Cas {
+                    key: key.clone(),
+                    from: from.clone(),
+                    to: to.clone(),
+                    create_if_not_exists: *create_if_not_exists,
+                };
+                self.propose(ctx, message.src.clone(), *msg_id, op);
+            }
+
+            RaftBody::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+                ..
+            } => {
+                self.observe_term(*term);
+
+                let our_last_term = self.term_at(self.last_index());
+                let up_to_date =
+                    (*last_log_term, *last_log_index) >= (our_last_term, self.last_index());
+                let vote_granted = *term == self.term
+                    && up_to_date
+                    && self
+                        .voted_for
+                        .as_ref()
+                        .is_none_or(|candidate| *candidate == message.src);
+
+                if vote_granted {
+                    self.voted_for = Some(message.src.clone());
+                    self.election_deadline =
+                        Some(election_deadline(ctx.rng(), self.election_timeout));
+                }
+
+                ctx.reply(
+                    &message,
+                    RaftBody::RequestVoteOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        term: self.term,
+                        vote_granted,
+                    },
+                );
+            }
+
+            RaftBody::RequestVoteOk {
+                term, vote_granted, ..
+            } => {
+                self.observe_term(*term);
+
+                if self.role == Role::Candidate && *term == self.term && *vote_granted {
+                    self.votes.insert(message.src.clone());
+
+                    if self.votes.len() >= self.majority() {
+                        self.become_leader(ctx);
+                    }
+                }
+            }
+
+            RaftBody::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                ..
+            } => {
+                self.observe_term(*term);
+
+                // Only a current leader holds off our own election.
+                if *term >= self.term {
+                    self.election_deadline =
+                        Some(election_deadline(ctx.rng(), self.election_timeout));
+                }
+
+                let (success, match_index) = self.on_append_entries(
+                    &message.src,
+                    *term,
+                    *prev_log_index,
+                    *prev_log_term,
+                    entries,
+                    *leader_commit,
+                );
+
+                self.apply(ctx);
+
+                ctx.reply(
+                    &message,
+                    RaftBody::AppendEntriesOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        term: self.term,
+                        success,
+                        match_index,
+                    },
+                );
+            }
+
+            RaftBody::AppendEntriesOk {
+                term,
+                success,
+                match_index,
+                ..
+            } => {
+                self.observe_term(*term);
+
+                if self.role != Role::Leader || *term != self.term {
+                    return;
+                }
+
+                if *success {
+                    let matched = self.match_index.entry(message.src.clone()).or_default();
+                    *matched = (*matched).max(*match_index);
+                    let matched = *matched;
+
+                    self.next_index.insert(message.src.clone(), matched + 1);
+                    self.advance_commit(ctx);
+                } else {
+                    // Back up to just past the follower's hint and retry on
+                    // the next heartbeat.
+                    let next = self.next_index.entry(message.src.clone()).or_insert(1);
+                    *next = (*next - 1).min(match_index + 1).max(1);
+                }
+            }
+
+            RaftBody::ReadOk { .. }
+            | RaftBody::WriteOk { .. }
+            | RaftBody::CasOk { .. }
+            | RaftBody::Unsupported => {}
+        }
+    }
+
+    /// Heartbeats as leader; otherwise starts an election once the leader
+    /// has been quiet too long.
+    fn tick(&mut self, ctx: &mut Context<RaftBody>) {
+        match self.role {
+            Role::Leader => self.replicate(ctx),
+            Role::Follower | Role::Candidate => {
+                let deadline = *self
+                    .election_deadline
+                    .get_or_insert_with(|| election_deadline(ctx.rng(), self.election_timeout));
+
+                if Instant::now() >= deadline {
+                    self.start_election(ctx);
+                }
+            }
+        }
+    }
+
+    fn print_summary(&self) {
+        eprintln!(
+            "summary: raft term {} as {:?}, {} entries, {} committed",
+            self.term,
+            self.role,
+            self.log.len(),
+            self.commit_index
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Simulator;
+    use serde_json::json;
+
+    fn cluster() -> Simulator<Raft> {
+        let config = Config {
+            tick_interval: Duration::from_millis(10),
+            peer_timeout: Duration::from_secs(1),
+            ..Config::default()
+        };
+        Simulator::new(3, &config, Raft::new)
+    }
+
+    /// The leader of the newest term among `nodes`, if it has one.
+    fn leader_among(simulator: &Simulator<Raft>, nodes: &[&str]) -> Option<String> {
+        nodes
+            .iter()
+            .filter_map(|node| Some((*node, simulator.handler(node)?)))
+            .filter(|(_, raft)| raft.role == Role::Leader)
+            .max_by_key(|(_, raft)| raft.term)
+            .map(|(node, _)| node.to_string())
+    }
+
+    /// Runs until one of `nodes` leads and the rest follow it in its term.
+    fn elect(simulator: &mut Simulator<Raft>, nodes: &[&str]) -> String {
+        let settled = simulator.run_until(Duration::from_secs(5), |simulator| {
+            let Some(leader) = leader_among(simulator, nodes) else {
+                return false;
+            };
+            let term = simulator.handler(&leader).unwrap().term;
+
+            nodes.iter().filter(|node| **node != leader).all(|node| {
+                let raft = simulator.handler(node).unwrap();
+                raft.role == Role::Follower && raft.term == term
+            })
+        });
+        assert!(settled, "no leader among {:?}", nodes);
+
+        leader_among(simulator, nodes).unwrap()
+    }
+
+    #[test]
+    fn a_leader_is_elected_and_commits_a_no_op() {
+        let mut simulator = cluster();
+        let leader = elect(&mut simulator, &["n0", "n1", "n2"]);
+
+        let committed = simulator.run_until(Duration::from_secs(2), |simulator| {
+            let raft = simulator.handler(&leader).unwrap();
+            raft.commit_index == raft.last_index()
+        });
+        assert!(committed);
+
+        let raft = simulator.handler(&leader).unwrap();
+        let last = raft.log.last().unwrap();
+        assert_eq!(last.op, Op::Noop);
+        assert_eq!(last.term, raft.term);
+        // A no-op answers no one.
+        assert!(simulator
+            .replies()
+            .iter()
+            .all(|reply| reply.body["type"] == "init_ok"));
+    }
+
+    #[test]
+    fn a_write_commits_once_a_majority_has_it() {
+        let mut simulator = cluster();
+        let leader = elect(&mut simulator, &["n0", "n1", "n2"]);
+
+        let followers: Vec<String> = simulator
+            .node_ids()
+            .into_iter()
+            .filter(|node| *node != leader)
+            .collect();
+        simulator.partition(&[&followers[1]], &[&leader, &followers[0]]);
+
+        let reply = simulator.request(
+            &leader,
+            json!({"type": "write", "key": 1, "value": 10}),
+            Duration::from_secs(2),
+        );
+        assert_eq!(reply.unwrap()["type"], "write_ok");
+
+        let written = |raft: &Raft| {
+            raft.log
+                .iter()
+                .any(|entry| matches!(&entry.op, Op::Write { key, .. } if *key == json!(1)))
+        };
+        assert!(written(simulator.handler(&leader).unwrap()));
+        assert!(written(simulator.handler(&followers[0]).unwrap()));
+        assert!(!written(simulator.handler(&followers[1]).unwrap()));
+    }
+
+    #[test]
+    fn a_conflicting_follower_log_is_truncated_and_catches_up() {
+        let mut simulator = cluster();
+        let old = elect(&mut simulator, &["n0", "n1", "n2"]);
+
+        let others: Vec<String> = simulator
+            .node_ids()
+            .into_iter()
+            .filter(|node| *node != old)
+            .collect();
+        let others: Vec<&str> = others.iter().map(String::as_str).collect();
+
+        // Cut off, the old leader takes a write it can never commit...
+        simulator.partition(&[&old], &others);
+        let lost = simulator.send(&old, json!({"type": "write", "key": 1, "value": "lost"}));
+
+        // ...while the rest elect a leader whose log disagrees with it.
+        let new = elect(&mut simulator, &others);
+        let reply = simulator.request(
+            &new,
+            json!({"type": "write", "key": 1, "value": "kept"}),
+            Duration::from_secs(2),
+        );
+        assert_eq!(reply.unwrap()["type"], "write_ok");
+
+        let diverged = simulator.handler(&old).unwrap().log.clone();
+        assert!(diverged.iter().any(|entry| entry.msg_id == lost));
+
+        simulator.heal();
+        let caught_up = simulator.run_until(Duration::from_secs(5), |simulator| {
+            let (old, new) = (
+                simulator.handler(&old).unwrap(),
+                simulator.handler(&new).unwrap(),
+            );
+            old.log == new.log && old.commit_index == new.commit_index
+        });
+        assert!(caught_up);
+
+        let old = simulator.handler(&old).unwrap();
+        assert!(!old
+            .log
+            .iter()
+            .any(|entry| entry.msg_id == lost && entry.node == old.node_id));
+        assert_eq!(old.store.get(&json!(1)), Some(&json!("kept")));
+        assert!(simulator.reply_to(lost).is_none());
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+
+/// A set of integers stored as inclusive `[start, end]` runs. Broadcast values
+/// are usually dense, so even a large set summarizes to a handful of runs.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RangeSet {
+    /// Run starts mapped to their ends. Runs never overlap or touch; touching
+    /// runs are joined on insert.
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl RangeSet {
+    pub fn new() -> RangeSet {
+        RangeSet::default()
+    }
+
+    /// Adds `value`, returning whether it is new.
+    pub fn insert(&mut self, value: u64) -> bool {
+        if self.contains(value) {
+            return false;
+        }
+
+        let below = value.checked_sub(1).and_then(|previous| {
+            self.ranges
+                .range(..=previous)
+                .next_back()
+                .filter(|(_, end)| **end == previous)
+                .map(|(start, _)| *start)
+        });
+        let above = value
+            .checked_add(1)
+            .and_then(|next| self.ranges.remove_entry(&next))
+            .map(|(_, end)| end);
+
+        match below {
+            Some(start) => self.ranges.insert(start, above.unwrap_or(value)),
+            None => self.ranges.insert(value, above.unwrap_or(value)),
+        };
+
+        true
+    }
+
+    pub fn contains(&self, value: u64) -> bool {
+        self.ranges
+            .range(..=value)
+            .next_back()
+            .is_some_and(|(_, end)| *end >= value)
+    }
+
+    /// The runs, in ascending order.
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges.iter().map(|(start, end)| (*start, *end))
+    }
+
+    /// Number of runs, i.e. the size of the summary.
+    pub fn range_count(&self) -> usize {
+        self.ranges.len()
+    }
+}
+
+impl FromIterator<u64> for RangeSet {
+    fn from_iter<I: IntoIterator<Item = u64>>(iter: I) -> RangeSet {
+        let mut set = RangeSet::new();
+
+        for value in iter {
+            set.insert(value);
+        }
+
+        set
+    }
+}
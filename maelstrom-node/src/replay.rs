@@ -0,0 +1,148 @@
+//! Golden-log replay: feed a recorded Maelstrom transcript to a handler and
+//! check what it sends against what it sent when the transcript was taken.
+//!
+//! A transcript is the node's stdin, one JSON message per line, exactly as
+//! Maelstrom delivered it (including the `init`). The expected output is the
+//! node's stdout in the same form. Outgoing msg_ids depend on everything the
+//! node sent before, so they are left out of the comparison; everything else,
+//! `in_reply_to` included, has to match exactly and in order.
+//!
+//! Nothing ticks during a replay, so timers, gossip rounds and RPC timeouts
+//! never fire: only what the handler sends in direct response to input is
+//! checked. Replies from Maelstrom's services belong in the transcript like
+//! any other input.
+
+use crate::{
+    config::Config,
+    message::Message,
+    runtime::{Handler, Runtime},
+};
+use serde_json::Value;
+use std::fmt;
+
+/// Why a replay didn't reproduce the expected output.
+#[derive(Debug)]
+pub enum ReplayError {
+    /// Line `line` (from 1) of the expected output isn't a message.
+    BadExpectation {
+        line: usize,
+        error: serde_json::Error,
+    },
+    /// Outgoing message `index` (from 0) differs. `None` on either side means
+    /// one output ran out before the other.
+    Mismatch {
+        index: usize,
+        expected: Option<Value>,
+        actual: Option<Value>,
+    },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::BadExpectation { line, error } => {
+                write!(
+                    f,
+                    "expected output line {} is not a message: {}",
+                    line, error
+                )
+            }
+            ReplayError::Mismatch {
+                index,
+                expected,
+                actual,
+            } => {
+                write!(f, "message {} differs: expected ", index)?;
+                match expected {
+                    Some(message) => write!(f, "{}", message)?,
+                    None => write!(f, "nothing")?,
+                }
+                write!(f, ", got ")?;
+                match actual {
+                    Some(message) => write!(f, "{}", message),
+                    None => write!(f, "nothing"),
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReplayError::BadExpectation { error, .. } => Some(error),
+            ReplayError::Mismatch { .. } => None,
+        }
+    }
+}
+
+/// Dispatches every line of `transcript` to `handler` and returns what it
+/// sent, in order. Lines that aren't messages are skipped, as they would be
+/// on stdin.
+pub fn replay<H: Handler>(config: &Config, handler: &mut H, transcript: &str) -> Vec<Value> {
+    let (mut runtime, output) = Runtime::<H::Body>::in_memory(config);
+    let mut sent = Vec::new();
+
+    for line in transcript.lines() {
+        runtime.dispatch(handler, line);
+
+        sent.extend(
+            output
+                .try_iter()
+                .filter_map(|message| serde_json::to_value(message).ok()),
+        );
+    }
+
+    sent
+}
+
+/// Replays `transcript` and compares what `handler` sends with `expected`,
+/// ignoring msg_ids. Reports the first difference.
+pub fn check<H: Handler>(
+    config: &Config,
+    handler: &mut H,
+    transcript: &str,
+    expected: &str,
+) -> Result<(), ReplayError> {
+    let expected = expected
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| {
+            serde_json::from_str::<Message<Value>>(line)
+                .map_err(|error| ReplayError::BadExpectation { line: n + 1, error })
+                .and_then(|message| {
+                    serde_json::to_value(message)
+                        .map_err(|error| ReplayError::BadExpectation { line: n + 1, error })
+                })
+        })
+        .collect::<Result<Vec<Value>, ReplayError>>()?;
+
+    let actual = replay(config, handler, transcript);
+
+    for index in 0..expected.len().max(actual.len()) {
+        let expected = expected.get(index).map(without_msg_id);
+        let actual = actual.get(index).map(without_msg_id);
+
+        if expected != actual {
+            return Err(ReplayError::Mismatch {
+                index,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// `message` with its body's msg_id removed.
+fn without_msg_id(message: &Value) -> Value {
+    let mut message = message.clone();
+
+    if let Some(body) = message.get_mut("body").and_then(Value::as_object_mut) {
+        body.remove("msg_id");
+    }
+
+    message
+}
@@ -0,0 +1,103 @@
+//! Round-trip time estimates, so gossip is resent once its ack is overdue
+//! for that peer rather than always after the same fixed wait.
+
+use std::time::Duration;
+
+/// The smoothed round-trip time to one peer: a moving average of measured
+/// RTTs, each new one weighted [`RttEstimate::WEIGHT`].
+///
+/// A measurement over `outlier_multiple` times the current estimate, such
+/// as one very late ack, is clamped to that multiple before it is averaged
+/// in, so a single stall can't blow up the estimate and the retry interval
+/// with it. A run of slow acks still raises it, just a step at a time.
+#[derive(Debug, Clone, Copy)]
+pub struct RttEstimate {
+    smoothed: Option<Duration>,
+    outlier_multiple: f64,
+}
+
+impl RttEstimate {
+    const WEIGHT: f64 = 0.125;
+    /// How many round trips an ack may take before gossip is resent.
+    const RETRY_FACTOR: u32 = 4;
+    /// The shortest retry interval, however fast the peer answers.
+    const MIN_RETRY: Duration = Duration::from_millis(10);
+
+    /// A multiple below 1 is taken as 1.
+    pub fn new(outlier_multiple: f64) -> RttEstimate {
+        RttEstimate {
+            smoothed: None,
+            outlier_multiple: outlier_multiple.max(1.0),
+        }
+    }
+
+    /// Averages in one measured round trip.
+    pub fn observe(&mut self, sample: Duration) {
+        self.smoothed = Some(match self.smoothed {
+            None => sample,
+            Some(smoothed) => {
+                let sample = sample.min(smoothed.mul_f64(self.outlier_multiple));
+                smoothed.mul_f64(1.0 - Self::WEIGHT) + sample.mul_f64(Self::WEIGHT)
+            }
+        });
+    }
+
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.smoothed
+    }
+
+    /// How long to wait for an ack before resending: a few round trips, but
+    /// never longer than `ceiling`, which is also used until an RTT has
+    /// been measured.
+    pub fn retry_interval(&self, ceiling: Duration) -> Duration {
+        match self.smoothed {
+            Some(smoothed) => (smoothed * Self::RETRY_FACTOR)
+                .max(Self::MIN_RETRY)
+                .min(ceiling),
+            None => ceiling,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_huge_outlier_barely_moves_the_retry_interval() {
+        let ceiling = Duration::from_secs(30);
+        let mut rtt = RttEstimate::new(3.0);
+
+        for _ in 0..20 {
+            rtt.observe(Duration::from_millis(20));
+        }
+        let steady = rtt.retry_interval(ceiling);
+        assert_eq!(steady, Duration::from_millis(80));
+
+        rtt.observe(Duration::from_secs(20));
+
+        // Counted as 60ms, not 20s: 20ms * 7/8 + 60ms / 8 = 25ms.
+        assert_eq!(rtt.smoothed(), Some(Duration::from_millis(25)));
+        assert!(rtt.retry_interval(ceiling) <= steady * 2);
+
+        for _ in 0..20 {
+            rtt.observe(Duration::from_millis(20));
+        }
+        assert!(rtt.retry_interval(ceiling) < Duration::from_millis(85));
+    }
+
+    #[test]
+    fn the_retry_interval_stays_within_its_bounds() {
+        let mut rtt = RttEstimate::new(3.0);
+        let ceiling = Duration::from_millis(500);
+        assert_eq!(rtt.retry_interval(ceiling), ceiling);
+
+        rtt.observe(Duration::from_micros(50));
+        assert_eq!(rtt.retry_interval(ceiling), RttEstimate::MIN_RETRY);
+
+        for _ in 0..100 {
+            rtt.observe(Duration::from_secs(10));
+        }
+        assert_eq!(rtt.retry_interval(ceiling), ceiling);
+    }
+}
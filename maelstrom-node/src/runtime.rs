@@ -0,0 +1,2129 @@
+use crate::{
+    codec::{Codec, JsonCodec},
+    config::{node_rng, Config},
+    dedup::{Dedup, Seen},
+    error::Error,
+    health::PeerHealth,
+    message::{Body, Control, Envelope, Message, MessageBody, NOT_SUPPORTED},
+    metrics::Metrics,
+    persist::Snapshots,
+};
+use log::{debug, error, trace, warn};
+use rand::rngs::StdRng;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self as std_mpsc, Receiver, Sender, SyncSender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::mpsc::{
+        self,
+        error::{TryRecvError, TrySendError},
+    },
+    time::{self, MissedTickBehavior},
+};
+
+/// A workload implementation driven by the [`Runtime`].
+///
+/// The runtime answers `init` itself; handlers only see it through
+/// [`Handler::init`] and receive every other message through
+/// [`Handler::handle`], except replies to requests sent with
+/// [`Context::rpc`], which go to [`Handler::reply`].
+pub trait Handler {
+    /// The message bodies this workload speaks.
+    type Body: Body;
+
+    /// Called once with this node's id and the cluster membership from
+    /// Maelstrom's `init` message, before `init_ok` is sent.
+    fn init(&mut self, node_id: &str, node_ids: &[String]);
+
+    fn handle(&mut self, message: Message<Self::Body>, ctx: &mut Context<Self::Body>);
+
+    /// Called with the outcome of the [`Context::rpc`] that returned
+    /// `request`: its reply, the `error` it was answered with, or a timeout
+    /// once it ran out of time and retries. By default replies go to
+    /// [`Handler::handle`] like any other message and failures are only
+    /// logged.
+    fn reply(
+        &mut self,
+        request: u32,
+        reply: Result<Message<Self::Body>, RpcError>,
+        ctx: &mut Context<Self::Body>,
+    ) {
+        match reply {
+            Ok(message) => self.handle(message, ctx),
+            Err(error) => debug!("request {} failed: {}", request, error),
+        }
+    }
+
+    /// Called every [`Context::tick_interval`], independent of input.
+    fn tick(&mut self, _ctx: &mut Context<Self::Body>) {}
+
+    /// Called once after the run to log final state, e.g. for comparing
+    /// nodes afterwards.
+    fn report(&self) {}
+
+    /// State worth keeping across a restart, saved with `--snapshot-dir`;
+    /// see [`crate::persist`]. `None`, the default, saves nothing.
+    fn snapshot(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Takes back what [`Handler::snapshot`] returned in an earlier run.
+    /// Called right after [`Handler::init`].
+    fn restore(&mut self, _state: serde_json::Value) {}
+
+    /// Called after [`Handler::report`] when `--summary` is given.
+    fn print_summary(&self) {}
+
+    /// Fills in the parts of a `SIGUSR1` dump this handler knows about.
+    fn debug_state(&self, _state: &mut DebugState) {}
+}
+
+/// What a node dumps to stderr on `SIGUSR1`, for looking inside one that
+/// seems stuck without stopping it. Fields a handler has nothing for are
+/// left out.
+#[derive(Debug, Default, Serialize)]
+pub struct DebugState {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub set_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counter: Option<i64>,
+    /// Values sent to peers but not yet acknowledged, or waiting to go.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_gossip: Option<usize>,
+    pub outstanding_rpcs: usize,
+}
+
+/// Why a [`Context::rpc`] didn't get a normal reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcError {
+    /// No reply arrived in time; the request may or may not have happened.
+    Timeout,
+    /// The peer answered with an `error` body.
+    Rejected { code: u32, text: String },
+    /// [`Config::max_pending_rpcs`] requests were already waiting on replies,
+    /// so this one was never sent.
+    Overloaded,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "timed out"),
+            RpcError::Rejected { code, text } => write!(f, "error {}: {}", code, text),
+            RpcError::Overloaded => write!(f, "too many requests outstanding"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// How a request sent with [`Context::rpc_with`] is retried: it is resent
+/// whenever `initial` (doubling each time, up to `max`) passes without a reply,
+/// and given up on after `attempts` sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub attempts: u32,
+}
+
+impl Backoff {
+    /// For pushing state to other nodes: resent until acknowledged, for long
+    /// enough to ride out a partition.
+    pub const REPLICATION: Backoff = Backoff {
+        initial: Duration::from_millis(200),
+        max: Duration::from_secs(2),
+        attempts: 30,
+    };
+}
+
+/// Names one inbound message or tick in the debug log. Every line logged
+/// while handling it carries its span, so when a run fails the log can be
+/// cut down to one request and everything it set off; see
+/// [`Context::follows_from`] for work that happens later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SpanId(u64);
+
+impl fmt::Display for SpanId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "span {}", self.0)
+    }
+}
+
+/// A request waiting on its reply.
+struct Pending<B> {
+    dest: String,
+    sent_at: Instant,
+    /// Where the request was first sent from, for linking retries to it.
+    span: SpanId,
+    /// Kept for resending; `None` for requests that aren't retried.
+    body: Option<B>,
+    deadline: Instant,
+    wait: Duration,
+    max_wait: Duration,
+    attempts_left: u32,
+}
+
+/// What a handler gets to talk back to Maelstrom with.
+pub struct Context<B = MessageBody> {
+    node_id: String,
+    next_msg_id: u32,
+    /// How far apart successive msg_ids are: the number of workers in a
+    /// [`crate::pool`], so that each hands out its own residue and replies
+    /// can be routed back to it, and 1 otherwise.
+    msg_id_stride: u32,
+    tick_interval: Duration,
+    rpc_timeout: Duration,
+    /// Outstanding [`Context::rpc`] requests, by msg_id.
+    pending: HashMap<u32, Pending<B>>,
+    /// See [`Config::max_pending_rpcs`].
+    max_pending: Option<usize>,
+    /// Requests turned away for being over `max_pending`, to be failed once
+    /// the handler call that made them returns.
+    refused: Vec<u32>,
+    health: PeerHealth,
+    dedup: Dedup<B>,
+    /// Reseeded for our node ID at `init`; see [`Context::rng`].
+    rng: StdRng,
+    seed: Option<u64>,
+    /// What we are handling right now.
+    span: SpanId,
+    next_span: u64,
+    output: Output<B>,
+}
+
+impl<B: Body> Context<B> {
+    /// This node's id, empty until `init` arrives.
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// The other nodes not known to be down; see [`PeerHealth`].
+    pub fn alive_peers(&self) -> Vec<&str> {
+        self.health.alive_peers()
+    }
+
+    /// Whether `node` has answered since a request to it last timed out.
+    /// Always true for anything that isn't a peer.
+    pub fn is_alive(&self, node: &str) -> bool {
+        self.health.is_alive(node)
+    }
+
+    /// Counts `node` as down until it is next heard from, for messages the
+    /// handler waits on itself rather than through [`Context::rpc`].
+    pub fn timed_out(&mut self, node: &str) {
+        self.health.timed_out(node);
+    }
+
+    /// The span of the message or tick being handled.
+    pub fn span(&self) -> SpanId {
+        self.span
+    }
+
+    /// Logs that the current span is continuing work started in `cause`,
+    /// e.g. a gossip round carrying values from an earlier broadcast.
+    pub fn follows_from(&self, cause: SpanId) {
+        if cause != self.span {
+            debug!("{} follows from {}", self.span, cause);
+        }
+    }
+
+    /// Starts a new span and makes it current.
+    fn open_span(&mut self) -> SpanId {
+        self.span = SpanId(self.next_span);
+        self.next_span += 1;
+        self.span
+    }
+
+    /// Where handlers should take every random choice from. With `--seed`
+    /// it produces the same sequence on every run, so a failure can be
+    /// reproduced.
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
+    /// How often [`Handler::tick`] is called.
+    pub fn tick_interval(&self) -> Duration {
+        self.tick_interval
+    }
+
+    /// Allocates the msg_id for an outgoing message. Every message this node
+    /// sends takes its id from here, so ids are unique and increasing for the
+    /// lifetime of the process.
+    pub fn next_id(&mut self) -> u32 {
+        let id = self.next_msg_id;
+        self.next_msg_id += self.msg_id_stride;
+        id
+    }
+
+    /// A handle onto the stdout writer thread, for code running outside the
+    /// handler. Messages sent through it go out as-is: they aren't given a
+    /// msg_id, traced or counted.
+    pub fn outbound(&self) -> Outbound<B> {
+        self.output.outbound.clone().expect("Output already closed")
+    }
+
+    /// Sends `body` to `dest` from this node, stamped with a fresh msg_id,
+    /// which is returned. Nothing waits for a reply; see [`Context::rpc`].
+    pub fn send(&mut self, dest: &str, mut body: B) -> u32 {
+        let msg_id = self.next_id();
+
+        body.set_msg_id(msg_id);
+        self.send_message(Message {
+            src: self.node_id.clone(),
+            dest: dest.to_string(),
+            body,
+        });
+
+        msg_id
+    }
+
+    /// Answers `request`: sends `body` back to its sender with `in_reply_to`
+    /// filled in from the request's msg_id. Both ids in `body` are
+    /// overwritten, so it can be built with zeros.
+    pub fn reply(&mut self, request: &Message<B>, body: B) {
+        let Some(msg_id) = request.body.msg_id() else {
+            debug!("not replying to {}: it has no msg_id", request.src);
+            return;
+        };
+
+        self.reply_to(&request.src, msg_id, body);
+    }
+
+    /// Answers request `in_reply_to` from `dest`, for a reply sent after the
+    /// request itself has gone, e.g. once a KV round trip finishes.
+    pub fn reply_to(&mut self, dest: &str, in_reply_to: u32, mut body: B) {
+        body.set_in_reply_to(in_reply_to);
+        self.send(dest, body);
+    }
+
+    /// Sends `message` exactly as given, msg_id and all.
+    fn send_message(&mut self, message: Message<B>) {
+        self.send_envelope(Message {
+            src: message.src,
+            dest: message.dest,
+            body: Envelope::Body(message.body),
+        });
+    }
+
+    /// Every outgoing message passes through here, so replies to client
+    /// requests are remembered for [`Dedup`].
+    fn send_envelope(&mut self, message: Message<Envelope<B>>) {
+        self.dedup.record(&message);
+        self.output.send(message, self.span);
+    }
+
+    /// Answers request `in_reply_to` from `dest` with a Maelstrom `error`
+    /// body, usually with one of the code constants in [`crate::message`].
+    pub fn reply_error(
+        &mut self,
+        dest: &str,
+        in_reply_to: u32,
+        code: u32,
+        text: impl Into<String>,
+    ) {
+        self.send_control(
+            dest,
+            Control::Error {
+                in_reply_to,
+                code,
+                text: text.into(),
+            },
+        );
+    }
+
+    fn send_control(&mut self, dest: &str, control: Control) {
+        self.send_envelope(Message {
+            src: self.node_id.clone(),
+            dest: dest.to_string(),
+            body: Envelope::Control(control),
+        });
+    }
+
+    /// Sends `body` to `dest` as a request: it is stamped with a fresh msg_id,
+    /// which is returned, and the matching reply is delivered to
+    /// [`Handler::reply`] rather than [`Handler::handle`], as is a timeout
+    /// if none arrives within the configured RPC timeout.
+    ///
+    /// With [`Config::max_pending_rpcs`] already outstanding, nothing is sent
+    /// and [`RpcError::Overloaded`] is delivered as soon as the current
+    /// handler call returns.
+    pub fn rpc(&mut self, dest: &str, body: B) -> u32 {
+        let timeout = self.rpc_timeout;
+
+        self.request(dest, body, timeout, timeout, 1)
+    }
+
+    /// How many more requests can be sent before [`Config::max_pending_rpcs`]
+    /// turns them away, or `None` if there is no cap.
+    pub fn rpc_room(&self) -> Option<usize> {
+        self.max_pending
+            .map(|max| max.saturating_sub(self.pending.len()))
+    }
+
+    /// Like [`Context::rpc`], but resends the request according to `backoff`
+    /// before giving up. Every attempt carries the same msg_id, so a late
+    /// reply to an earlier attempt still counts.
+    pub fn rpc_with(&mut self, dest: &str, body: B, backoff: Backoff) -> u32 {
+        self.request(dest, body, backoff.initial, backoff.max, backoff.attempts)
+    }
+
+    fn request(
+        &mut self,
+        dest: &str,
+        mut body: B,
+        wait: Duration,
+        max_wait: Duration,
+        attempts: u32,
+    ) -> u32 {
+        let msg_id = self.next_id();
+
+        body.set_msg_id(msg_id);
+
+        if self
+            .max_pending
+            .is_some_and(|max| self.pending.len() >= max)
+        {
+            debug!(
+                "{}: refusing request {} to {} with {} outstanding",
+                self.span,
+                msg_id,
+                dest,
+                self.pending.len()
+            );
+            self.output.metrics.refused_rpcs += 1;
+            self.refused.push(msg_id);
+            return msg_id;
+        }
+
+        let attempts_left = attempts.saturating_sub(1);
+        self.pending.insert(
+            msg_id,
+            Pending {
+                dest: dest.to_string(),
+                sent_at: Instant::now(),
+                span: self.span,
+                body: (attempts_left > 0).then(|| body.clone()),
+                deadline: Instant::now() + wait,
+                wait,
+                max_wait,
+                attempts_left,
+            },
+        );
+
+        let metrics = &mut self.output.metrics;
+        metrics.peak_pending_rpcs = metrics.peak_pending_rpcs.max(self.pending.len());
+
+        self.send_message(Message {
+            src: self.node_id.clone(),
+            dest: dest.to_string(),
+            body,
+        });
+
+        msg_id
+    }
+
+    /// Resends every request whose deadline has passed and still has attempts
+    /// left, and returns the ones that have run out.
+    fn expire(&mut self) -> Vec<u32> {
+        let now = Instant::now();
+        let mut resend = Vec::new();
+        let mut expired = Vec::new();
+
+        for (&msg_id, pending) in &mut self.pending {
+            if pending.deadline > now {
+                continue;
+            }
+
+            match &pending.body {
+                Some(body) if pending.attempts_left > 0 => {
+                    pending.attempts_left -= 1;
+                    pending.wait = (pending.wait * 2).min(pending.max_wait);
+                    pending.deadline = now + pending.wait;
+
+                    debug!(
+                        "{}: resending request {} to {} from {}",
+                        self.span, msg_id, pending.dest, pending.span
+                    );
+
+                    resend.push(Message {
+                        src: self.node_id.clone(),
+                        dest: pending.dest.clone(),
+                        body: body.clone(),
+                    });
+                }
+                _ => expired.push(msg_id),
+            }
+        }
+
+        for msg_id in &expired {
+            if let Some(pending) = self.pending.remove(msg_id) {
+                self.health.timed_out(&pending.dest);
+                debug!(
+                    "request {} to {} timed out after {:?}",
+                    msg_id,
+                    pending.dest,
+                    pending.sent_at.elapsed()
+                );
+            }
+        }
+
+        self.output.metrics.retries += resend.len() as u64;
+
+        // Resends reuse their original msg_id on purpose.
+        for message in resend {
+            self.send_message(message);
+        }
+
+        expired
+    }
+
+    /// Number of [`Context::rpc`] requests still waiting for a reply.
+    pub fn pending_rpcs(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Claims a message from `src` answering `in_reply_to` if that is one of
+    /// our outstanding requests to it, returning the request's msg_id.
+    fn take_pending(&mut self, src: &str, in_reply_to: Option<u32>) -> Option<u32> {
+        let request = in_reply_to?;
+
+        if self.pending.get(&request)?.dest != src {
+            return None;
+        }
+
+        if let Some(pending) = self.pending.remove(&request) {
+            debug!(
+                "{} answered request {} after {:?}",
+                src,
+                request,
+                pending.sent_at.elapsed()
+            );
+        }
+
+        Some(request)
+    }
+}
+
+/// The way out of a [`Runtime`]: a bounded queue to the stdout writer
+/// thread, or an unbounded one to an in-memory receiver that is drained
+/// separately.
+///
+/// The writer's queue holds `--queue-capacity` messages. Once it is full,
+/// sending blocks until the writer catches up, so a node that produces
+/// faster than stdout takes it is slowed down instead of buffering without
+/// limit.
+#[derive(Clone)]
+pub enum Outbound<B> {
+    Writer {
+        queue: SyncSender<Queued<B>>,
+        /// Messages queued but not yet taken by the writer.
+        depth: Arc<AtomicUsize>,
+    },
+    Memory(Sender<Message<Envelope<B>>>),
+}
+
+/// An entry in the stdout writer's queue.
+pub enum Queued<B> {
+    Message(Message<Envelope<B>>),
+    /// Answered once everything queued ahead of it has been written and
+    /// flushed.
+    Flush(Sender<()>),
+}
+
+impl<B> Outbound<B> {
+    /// Queues `message`, waiting for room if the writer is behind. Returns
+    /// whether the other end is still there.
+    pub fn send(&self, message: Message<Envelope<B>>) -> bool {
+        match self {
+            Outbound::Writer { queue, depth } => {
+                depth.fetch_add(1, Ordering::Relaxed);
+                queue.send(Queued::Message(message)).is_ok()
+            }
+            Outbound::Memory(sender) => sender.send(message).is_ok(),
+        }
+    }
+
+    /// Blocks until everything queued so far has been written and flushed.
+    /// Messages sent to memory are delivered as they are queued, so there is
+    /// nothing to wait for.
+    pub fn flush_and_wait(&self) {
+        if let Outbound::Writer { queue, .. } = self {
+            let (done, flushed) = std_mpsc::channel();
+
+            // If the writer has gone there is nothing left to wait for.
+            if queue.send(Queued::Flush(done)).is_ok() {
+                let _ = flushed.recv();
+            }
+        }
+    }
+}
+
+/// Everything on the way out: the outbound channel to the stdout writer
+/// thread, the optional trace file and the traffic counters.
+struct Output<B> {
+    codec: Box<dyn Codec<Envelope<B>>>,
+    outbound: Option<Outbound<B>>,
+    writer: Option<JoinHandle<Result<(), Error>>>,
+    /// Set once the writer thread has gone away; the event loop stops at the
+    /// next opportunity.
+    closed: bool,
+    trace: Option<BufWriter<File>>,
+    metrics: Metrics,
+    /// Reused for sizing messages when there is no writer thread to count
+    /// them.
+    scratch: Vec<u8>,
+}
+
+impl<B: Body> Output<B> {
+    fn new<C>(config: &Config, codec: C) -> Output<B>
+    where
+        C: Codec<Envelope<B>> + Clone + Send + 'static,
+    {
+        Output::with_writer(config, codec, || io::stdout().lock())
+    }
+
+    /// Output whose writer thread writes to what `open` returns, called on
+    /// that thread.
+    fn with_writer<C, W>(
+        config: &Config,
+        codec: C,
+        open: impl FnOnce() -> W + Send + 'static,
+    ) -> Output<B>
+    where
+        C: Codec<Envelope<B>> + Clone + Send + 'static,
+        W: Write,
+    {
+        let metrics = Metrics::default();
+        let (outbound, writer) = spawn_writer(
+            config,
+            Box::new(codec.clone()),
+            open,
+            metrics.bytes_out.clone(),
+            metrics.outbound_queue.clone(),
+        );
+
+        Output {
+            codec: Box::new(codec),
+            outbound: Some(outbound),
+            writer: Some(writer),
+            closed: false,
+            trace: config
+                .trace_out
+                .as_ref()
+                .and_then(|path| match File::create(path) {
+                    Ok(file) => Some(BufWriter::new(file)),
+                    Err(error) => {
+                        warn!("not tracing, can't create {}: {}", path, error);
+                        None
+                    }
+                }),
+            metrics,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Output that goes to `outbound` without a writer thread of its own,
+    /// either to a receiver in memory or to a writer shared with other
+    /// runtimes, with no trace file.
+    fn detached(outbound: Outbound<B>, codec: impl Codec<Envelope<B>> + 'static) -> Output<B> {
+        Output {
+            codec: Box::new(codec),
+            outbound: Some(outbound),
+            writer: None,
+            closed: false,
+            trace: None,
+            metrics: Metrics::default(),
+            scratch: Vec::with_capacity(SCRATCH_CAPACITY),
+        }
+    }
+
+    fn send(&mut self, message: Message<Envelope<B>>, span: SpanId) {
+        debug!(
+            "{}: sent {} to {} (msg_id {:?}, in_reply_to {:?})",
+            span,
+            message.body.kind(),
+            message.dest,
+            message.body.msg_id(),
+            message.body.in_reply_to()
+        );
+
+        self.record(&message);
+        *self.metrics.sent.entry(message.body.kind()).or_default() += 1;
+
+        // With no writer thread there is nothing to count bytes as they go
+        // out, so count what they would have been.
+        if self.writer.is_none() {
+            self.scratch.clear();
+
+            if self.codec.encode_into(&message, &mut self.scratch).is_ok() {
+                self.metrics
+                    .bytes_out
+                    .fetch_add(self.scratch.len() as u64 + 1, Ordering::Relaxed);
+            }
+        }
+
+        let sent = match &self.outbound {
+            Some(outbound) => outbound.send(message),
+            None => false,
+        };
+
+        let depth = self.metrics.outbound_queue.load(Ordering::Relaxed);
+        self.metrics.peak_outbound_queue = self.metrics.peak_outbound_queue.max(depth);
+
+        if !sent {
+            self.closed = true;
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(trace) = &mut self.trace {
+            if let Err(error) = trace.flush() {
+                self.stop_tracing(error);
+            }
+        }
+    }
+
+    /// Flushes the trace file and waits for the writer thread to write out
+    /// and flush everything queued so far.
+    fn flush_and_wait(&mut self) {
+        self.flush();
+
+        if let Some(outbound) = &self.outbound {
+            outbound.flush_and_wait();
+        }
+    }
+
+    /// Hangs up on the writer thread and waits for it to drain, returning
+    /// whatever stopped it early, if anything.
+    fn close(&mut self) -> Result<(), Error> {
+        self.flush();
+
+        drop(self.outbound.take());
+
+        match self.writer.take() {
+            Some(writer) => writer.join().expect("Writer thread panicked"),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs `message` as what opened `span`.
+    fn receive(&mut self, message: &Message<Envelope<B>>, span: SpanId) {
+        debug!(
+            "{}: received {} from {} to {} (msg_id {:?}, in_reply_to {:?})",
+            span,
+            message.body.kind(),
+            message.src,
+            message.dest,
+            message.body.msg_id(),
+            message.body.in_reply_to()
+        );
+
+        self.record(message);
+        *self
+            .metrics
+            .received
+            .entry(message.body.kind())
+            .or_default() += 1;
+    }
+
+    /// Appends a message to the trace file, if one is configured, as one JSON
+    /// object per line like Maelstrom's own node logs.
+    fn record(&mut self, message: &Message<Envelope<B>>) {
+        let Some(trace) = &mut self.trace else {
+            return;
+        };
+
+        let written = serde_json::to_writer(&mut *trace, message)
+            .map_err(io::Error::from)
+            .and_then(|()| trace.write_all(b"\n"));
+
+        if let Err(error) = written {
+            self.stop_tracing(error);
+        }
+    }
+
+    /// The trace is a debugging aid, so failing to write it isn't worth
+    /// stopping the node for.
+    fn stop_tracing(&mut self, error: io::Error) {
+        warn!("stopped tracing after write error: {}", error);
+        self.trace = None;
+    }
+}
+
+/// Starting size of the buffers messages are encoded into; they grow as
+/// needed and keep their size from then on.
+const SCRATCH_CAPACITY: usize = 4096;
+
+/// When the stdout writer flushes: once a message for anything other than a
+/// peer node has waited `window`, since clients and services are waiting on
+/// it, and otherwise once `max_messages` have piled up or the oldest has
+/// waited `max_delay`. Gossip between nodes is batched into fewer writes that
+/// way, and with a window so are replies sent close together; a zero delay
+/// or window flushes whenever the queue runs dry.
+#[derive(Debug, Clone, Copy)]
+struct FlushPolicy {
+    max_messages: usize,
+    max_delay: Duration,
+    window: Duration,
+}
+
+/// Where the writer gets the time from, so its timing can be tested
+/// without depending on how fast the machine runs the test.
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+/// The real time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Starts a thread writing everything queued on the returned [`Outbound`]
+/// to what `open` returns, called on that thread, as [`write_out`] does.
+/// Several runtimes may share it; each message is still written whole.
+pub(crate) fn spawn_writer<B: Body, W: Write>(
+    config: &Config,
+    codec: Box<dyn Codec<Envelope<B>> + Send>,
+    open: impl FnOnce() -> W + Send + 'static,
+    bytes_out: Arc<AtomicU64>,
+    depth: Arc<AtomicUsize>,
+) -> (Outbound<B>, JoinHandle<Result<(), Error>>) {
+    let (queue, messages) = std_mpsc::sync_channel(config.queue_capacity);
+    let policy = FlushPolicy {
+        max_messages: config.flush_every.max(1),
+        max_delay: config.flush_delay,
+        window: config.coalesce_window,
+    };
+
+    let outbound = Outbound::Writer {
+        queue,
+        depth: depth.clone(),
+    };
+    let writer = thread::spawn(move || {
+        write_out(
+            open(),
+            codec,
+            messages,
+            policy,
+            &SystemClock,
+            bytes_out,
+            depth,
+        )
+    });
+
+    (outbound, writer)
+}
+
+/// Owns stdout (or `out`), buffering according to `policy` as timed by
+/// `clock`.
+///
+/// A message that fails to encode is dropped; a failed write stops the thread.
+fn write_out<B: Body>(
+    out: impl Write,
+    codec: Box<dyn Codec<Envelope<B>> + Send>,
+    messages: Receiver<Queued<B>>,
+    policy: FlushPolicy,
+    clock: &dyn Clock,
+    bytes_out: Arc<AtomicU64>,
+    depth: Arc<AtomicUsize>,
+) -> Result<(), Error> {
+    let mut writer = BufWriter::new(out);
+    // Every message is encoded into this one buffer, newline included, and
+    // written with a single call.
+    let mut scratch = Vec::with_capacity(SCRATCH_CAPACITY);
+    let mut unflushed = 0;
+    let mut oldest: Option<Instant> = None;
+    // When the oldest unflushed message for a client or service was written.
+    let mut oldest_reply: Option<Instant> = None;
+
+    loop {
+        let deadline = [
+            oldest.map(|at| at + policy.max_delay),
+            oldest_reply.map(|at| at + policy.window),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+
+        let next = match deadline {
+            None => match messages.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            },
+            Some(deadline) => {
+                match messages.recv_timeout(deadline.saturating_duration_since(clock.now())) {
+                    Ok(message) => Some(message),
+                    Err(std_mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(std_mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        };
+
+        // Nothing came before the deadline.
+        let mut due = true;
+
+        let next = match next {
+            Some(Queued::Flush(done)) => {
+                writer.flush()?;
+                unflushed = 0;
+                oldest = None;
+                oldest_reply = None;
+                let _ = done.send(());
+                continue;
+            }
+            Some(Queued::Message(message)) => Some(message),
+            None => None,
+        };
+
+        if let Some(message) = next {
+            depth.fetch_sub(1, Ordering::Relaxed);
+            let reply = !is_node_id(&message.dest);
+            due = reply && policy.window.is_zero();
+
+            scratch.clear();
+
+            match codec.encode_into(&message, &mut scratch) {
+                Ok(()) => {
+                    scratch.push(b'\n');
+                    writer.write_all(&scratch)?;
+                    bytes_out.fetch_add(scratch.len() as u64, Ordering::Relaxed);
+
+                    let now = clock.now();
+                    unflushed += 1;
+                    oldest.get_or_insert(now);
+                    if reply {
+                        oldest_reply.get_or_insert(now);
+                    }
+                }
+                Err(error) => error!("dropping unencodable message {:?}: {}", message, error),
+            }
+        }
+
+        if unflushed > 0 && (due || unflushed >= policy.max_messages) {
+            writer.flush()?;
+            unflushed = 0;
+            oldest = None;
+            oldest_reply = None;
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Whether `id` looks like one of Maelstrom's node IDs (`n0`, `n1`, ...)
+/// rather than a client (`c1`) or service (`lin-kv`).
+fn is_node_id(id: &str) -> bool {
+    id.strip_prefix('n')
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Counts malformed input lines per source over a fixed window, so a peer
+/// spewing garbage shows up as one clear warning rather than only as a wall of
+/// individual parse errors.
+struct ParseErrors {
+    threshold: usize,
+    window: Duration,
+    window_start: Instant,
+    by_source: HashMap<String, usize>,
+    reported: bool,
+}
+
+impl ParseErrors {
+    fn new(threshold: usize) -> ParseErrors {
+        ParseErrors {
+            threshold,
+            window: Duration::from_secs(1),
+            window_start: Instant::now(),
+            by_source: HashMap::new(),
+            reported: false,
+        }
+    }
+
+    /// Records one malformed line. The source is taken from a top-level `src`
+    /// string if the line is at least valid JSON.
+    fn record(&mut self, line: &str) {
+        if self.window_start.elapsed() >= self.window {
+            self.window_start = Instant::now();
+            self.by_source.clear();
+            self.reported = false;
+        }
+
+        let source = serde_json::from_str::<serde_json::Value>(line)
+            .ok()
+            .and_then(|value| value.get("src")?.as_str().map(String::from))
+            .unwrap_or_else(|| String::from("unknown"));
+
+        *self.by_source.entry(source).or_default() += 1;
+
+        let total: usize = self.by_source.values().sum();
+
+        if !self.reported && total > self.threshold {
+            self.reported = true;
+
+            warn!(
+                "{} malformed messages in the last {:?}, by source: {:?}",
+                total, self.window, self.by_source
+            );
+        }
+    }
+}
+
+/// Everything the event loop waits on, merged into one channel.
+enum Event {
+    Line(String),
+    Tick,
+    /// SIGTERM arrived.
+    Terminated,
+    Eof,
+    Failed(io::Error),
+}
+
+/// Forwards stdin to the event loop one line at a time. While the event
+/// queue is full, stdin isn't read, so a burst backs up into the pipe and
+/// ultimately Maelstrom rather than into our memory.
+async fn read_stdin(events: mpsc::Sender<Event>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    loop {
+        let event = match lines.next_line().await {
+            Ok(Some(line)) => Event::Line(line),
+            Ok(None) => Event::Eof,
+            Err(error) => Event::Failed(error),
+        };
+        let last = !matches!(event, Event::Line(_));
+
+        if events.send(event).await.is_err() || last {
+            return;
+        }
+    }
+}
+
+/// Injects a tick into the event loop every `interval`. Ticks that fall behind
+/// a slow handler are pushed back rather than delivered in a burst.
+///
+/// A tick that finds the event queue full is dropped: the handler is busy
+/// enough already, and the next one will do.
+///
+/// Also passes on SIGTERM, which is only noticed here, so at most one
+/// interval late.
+async fn tick_every(interval: Duration, events: mpsc::Sender<Event>) {
+    let mut ticks = time::interval_at(time::Instant::now() + interval, interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut terminated = false;
+
+    loop {
+        ticks.tick().await;
+
+        if !terminated && self::terminated() {
+            terminated = true;
+
+            if events.send(Event::Terminated).await.is_err() {
+                return;
+            }
+        }
+
+        if let Err(TrySendError::Closed(_)) = events.try_send(Event::Tick) {
+            return;
+        }
+    }
+}
+
+/// Set by the SIGTERM handler. A signal handler can't do much more than
+/// this safely.
+static TERMINATED: AtomicBool = AtomicBool::new(false);
+
+/// Set by the SIGUSR1 handler, and cleared by the event loop once it has
+/// dumped the node's [`DebugState`].
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether SIGTERM has arrived since [`catch_signals`].
+pub(crate) fn terminated() -> bool {
+    TERMINATED.load(Ordering::Relaxed)
+}
+
+/// Whether SIGUSR1 has arrived since this was last asked.
+pub(crate) fn take_dump_request() -> bool {
+    DUMP_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+#[cfg(unix)]
+pub(crate) fn catch_signals() {
+    extern "C" fn on_sigterm(_: libc::c_int) {
+        TERMINATED.store(true, Ordering::Relaxed);
+    }
+
+    extern "C" fn on_sigusr1(_: libc::c_int) {
+        DUMP_REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    // SAFETY: the handlers only store to an atomic, which is
+    // async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGTERM, on_sigterm as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGUSR1, on_sigusr1 as *const () as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn catch_signals() {}
+
+/// The stdin/stdout event loop shared by every challenge binary.
+pub struct Runtime<B> {
+    ctx: Context<B>,
+    parse_errors: ParseErrors,
+    reply_unsupported: bool,
+    /// Off for all but one worker of a [`crate::pool`], which all see the
+    /// same `init`.
+    answer_init: bool,
+    shutdown_grace: Duration,
+    snapshots: Snapshots,
+    queue_capacity: usize,
+}
+
+impl<B: Body> Runtime<B> {
+    /// A runtime speaking JSON; see [`Runtime::with_codec`].
+    pub fn new(config: &Config) -> Runtime<B> {
+        Runtime::with_codec(config, JsonCodec)
+    }
+
+    /// A runtime that reads and writes messages with `codec`. The stdout
+    /// writer thread gets a clone of its own.
+    pub fn with_codec<C>(config: &Config, codec: C) -> Runtime<B>
+    where
+        C: Codec<Envelope<B>> + Clone + Send + 'static,
+    {
+        Runtime::with_output(config, Output::new(config, codec))
+    }
+
+    /// A runtime that reads nothing by itself and sends everything to the
+    /// returned receiver rather than stdout. Drive it with
+    /// [`Runtime::dispatch`] and [`Runtime::tick`]; see [`crate::sim`].
+    pub fn in_memory(config: &Config) -> (Runtime<B>, Receiver<Message<Envelope<B>>>) {
+        Runtime::in_memory_with_codec(config, JsonCodec)
+    }
+
+    /// [`Runtime::in_memory`], decoding lines with `codec`.
+    pub fn in_memory_with_codec(
+        config: &Config,
+        codec: impl Codec<Envelope<B>> + 'static,
+    ) -> (Runtime<B>, Receiver<Message<Envelope<B>>>) {
+        let (outbound, messages) = std_mpsc::channel();
+
+        (
+            Runtime::with_output(config, Output::detached(Outbound::Memory(outbound), codec)),
+            messages,
+        )
+    }
+
+    /// Worker `index` of a [`crate::pool`] of `workers`, writing through
+    /// the pool's shared `outbound`. Its msg_ids are those congruent to
+    /// `index + 1` modulo `workers`, and only worker 0 answers `init`.
+    pub(crate) fn worker(
+        config: &Config,
+        codec: impl Codec<Envelope<B>> + 'static,
+        outbound: Outbound<B>,
+        index: u32,
+        workers: u32,
+    ) -> Runtime<B> {
+        let mut runtime = Runtime::with_output(config, Output::detached(outbound, codec));
+
+        runtime.ctx.next_msg_id = index + 1;
+        runtime.ctx.msg_id_stride = workers;
+        runtime.answer_init = index == 0;
+        runtime
+    }
+
+    /// Writes `handler`'s [`DebugState`] to `out` as one line of JSON.
+    pub fn dump_state<H: Handler<Body = B>>(&self, handler: &H, out: &mut impl Write) {
+        let mut state = DebugState {
+            outstanding_rpcs: self.ctx.pending.len(),
+            ..DebugState::default()
+        };
+        handler.debug_state(&mut state);
+
+        let dumped = serde_json::to_string(&state)
+            .map_err(io::Error::from)
+            .and_then(|state| writeln!(out, "debug state: {}", state));
+
+        if let Err(error) = dumped {
+            warn!("couldn't dump debug state: {}", error);
+        }
+    }
+
+    /// Requests this runtime is still waiting on replies to.
+    pub(crate) fn pending_rpcs(&self) -> usize {
+        self.ctx.pending.len()
+    }
+
+    pub(crate) fn shutdown_grace(&self) -> Duration {
+        self.shutdown_grace
+    }
+
+    /// Whether the writer has gone away, so nothing more can be sent.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.ctx.output.closed
+    }
+
+    fn with_output(config: &Config, output: Output<B>) -> Runtime<B> {
+        Runtime {
+            ctx: Context {
+                node_id: String::new(),
+                next_msg_id: 1,
+                msg_id_stride: 1,
+                tick_interval: config.tick_interval,
+                rpc_timeout: config.rpc_timeout,
+                pending: HashMap::new(),
+                max_pending: config.max_pending_rpcs,
+                refused: Vec::new(),
+                health: PeerHealth::new(),
+                // A client whose request goes unanswered for this long has
+                // long since retried.
+                dedup: Dedup::new(config.dedup_window, config.rpc_timeout),
+                rng: node_rng(config.seed, ""),
+                seed: config.seed,
+                span: SpanId(0),
+                next_span: 1,
+                output,
+            },
+            parse_errors: ParseErrors::new(config.parse_error_threshold),
+            reply_unsupported: config.reply_unsupported,
+            answer_init: true,
+            shutdown_grace: config.shutdown_grace,
+            queue_capacity: config.queue_capacity,
+            snapshots: Snapshots::new(config.snapshot_dir.as_deref(), config.snapshot_interval),
+        }
+    }
+
+    /// Feeds every message on stdin to `handler` until stdin closes, calling
+    /// its tick hook on a timer in between.
+    ///
+    /// Input and the timer run as tokio tasks on a single-threaded runtime;
+    /// the handler itself is only ever called from the dispatch loop, one
+    /// event at a time, so it needs no locking.
+    ///
+    /// The run ends when stdin closes, or on SIGTERM once every outstanding
+    /// [`Context::rpc`] has been answered or the shutdown grace period has
+    /// passed. Either way, everything queued for stdout and the trace file
+    /// is written out before this returns. SIGUSR1 dumps the node's
+    /// [`DebugState`] to stderr at the next tick and carries on.
+    ///
+    /// Returns early with an error if stdin or stdout fails; everything that
+    /// was already queued for stdout is still written out first.
+    pub fn run<H: Handler<Body = B>>(&mut self, handler: &mut H) -> Result<(), Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        catch_signals();
+
+        let read = runtime.block_on(self.dispatch_loop(handler));
+
+        // After SIGTERM the stdin reader is still blocked on a read that may
+        // never finish; waiting for it would hang the exit.
+        runtime.shutdown_background();
+
+        self.finish(handler).and(read)
+    }
+
+    /// Writes out everything queued, saves a last snapshot and hangs up on
+    /// the writer, returning whatever stopped it early, if anything.
+    pub(crate) fn finish<H: Handler<Body = B>>(&mut self, handler: &H) -> Result<(), Error> {
+        // Replies go out before a possibly slow snapshot is written.
+        self.ctx.output.flush_and_wait();
+        self.snapshots.save(handler);
+
+        self.ctx.output.close()
+    }
+
+    async fn dispatch_loop<H: Handler<Body = B>>(&mut self, handler: &mut H) -> Result<(), Error> {
+        let (events, mut inbox) = mpsc::channel(self.queue_capacity.max(1));
+
+        tokio::spawn(read_stdin(events.clone()));
+        tokio::spawn(tick_every(self.ctx.tick_interval, events));
+
+        let mut shutdown_at = None;
+
+        while let Some(event) = self.next_event(&mut inbox).await {
+            match event {
+                Event::Line(line) => self.dispatch(handler, &line),
+                Event::Tick => self.on_timer(handler, &mut io::stderr().lock()),
+                Event::Terminated => {
+                    debug!(
+                        "terminated with {} requests outstanding",
+                        self.ctx.pending.len()
+                    );
+                    shutdown_at = Some(Instant::now() + self.shutdown_grace);
+                }
+                Event::Eof => break,
+                Event::Failed(error) => return Err(error.into()),
+            }
+
+            if self.ctx.output.closed {
+                return Err(Error::WriterClosed);
+            }
+
+            if let Some(deadline) = shutdown_at {
+                if self.ctx.pending.is_empty() || Instant::now() >= deadline {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the next event, flushing the trace file whenever the queue
+    /// drains.
+    async fn next_event(&mut self, inbox: &mut mpsc::Receiver<Event>) -> Option<Event> {
+        self.ctx.output.metrics.inbound(inbox.len());
+
+        match inbox.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Empty) => {
+                self.ctx.output.flush();
+                inbox.recv().await
+            }
+            Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    /// What the event loop does on each tick of the timer: dumps the
+    /// [`DebugState`] to `dump_to` if SIGUSR1 asked for it, then ticks. The
+    /// signal handler only sets a flag, so the dump waits for this.
+    pub(crate) fn on_timer<H: Handler<Body = B>>(
+        &mut self,
+        handler: &mut H,
+        dump_to: &mut impl Write,
+    ) {
+        if take_dump_request() {
+            self.dump_state(handler, dump_to);
+        }
+
+        self.tick(handler);
+    }
+
+    /// Times out overdue requests and runs the handler's tick hook, as the
+    /// timer does in [`Runtime::run`].
+    pub fn tick<H: Handler<Body = B>>(&mut self, handler: &mut H) {
+        let span = self.ctx.open_span();
+        trace!("{}: tick", span);
+
+        for request in self.ctx.expire() {
+            handler.reply(request, Err(RpcError::Timeout), &mut self.ctx);
+        }
+
+        handler.tick(&mut self.ctx);
+        self.fail_refused(handler);
+        self.snapshots.tick(handler);
+    }
+
+    /// Delivers [`RpcError::Overloaded`] for every request turned away while
+    /// the handler last ran. Any it makes in turn wait for the next event.
+    fn fail_refused<H: Handler<Body = B>>(&mut self, handler: &mut H) {
+        for request in std::mem::take(&mut self.ctx.refused) {
+            handler.reply(request, Err(RpcError::Overloaded), &mut self.ctx);
+        }
+    }
+
+    /// Handles one line of input, as [`Runtime::run`] does for each line of
+    /// stdin.
+    pub fn dispatch<H: Handler<Body = B>>(&mut self, handler: &mut H, line: &str) {
+        self.ctx.output.metrics.bytes_in += line.len() as u64 + 1;
+
+        if line.trim().is_empty() {
+            return;
+        }
+
+        let message = match self.ctx.output.codec.decode(line.trim_end().as_bytes()) {
+            Ok(message) => message,
+            Err(error) => {
+                error!("skipping malformed message ({}): {}", error, line.trim());
+                self.parse_errors.record(line);
+                return;
+            }
+        };
+
+        let span = self.ctx.open_span();
+        self.ctx.output.receive(&message, span);
+        self.ctx.health.heard_from(&message.src);
+
+        let Message { src, dest, body } = message;
+
+        match body {
+            Envelope::Control(Control::Init {
+                msg_id,
+                node_id,
+                node_ids,
+            }) => {
+                self.ctx.node_id = node_id.clone();
+                self.ctx.health.init(&node_id, &node_ids);
+                self.ctx.dedup.init(&node_ids);
+                self.ctx.rng = node_rng(self.ctx.seed, &node_id);
+
+                handler.init(&node_id, &node_ids);
+
+                if let Some(state) = self.snapshots.init(&node_id) {
+                    handler.restore(state);
+                }
+
+                if !self.answer_init {
+                    return;
+                }
+
+                let reply_id = self.ctx.next_id();
+
+                self.ctx.send_control(
+                    &src,
+                    Control::InitOk {
+                        msg_id: reply_id,
+                        in_reply_to: msg_id,
+                    },
+                );
+            }
+            Envelope::Control(Control::Stats { msg_id }) => {
+                let reply_id = self.ctx.next_id();
+                let stats = self.stats();
+
+                self.ctx.send_control(
+                    &src,
+                    Control::StatsOk {
+                        msg_id: reply_id,
+                        in_reply_to: msg_id,
+                        stats,
+                    },
+                );
+            }
+            Envelope::Control(Control::InitOk { .. } | Control::StatsOk { .. }) => {}
+            Envelope::Control(Control::Error {
+                in_reply_to,
+                code,
+                text,
+            }) => match self.ctx.take_pending(&src, Some(in_reply_to)) {
+                Some(request) => handler.reply(
+                    request,
+                    Err(RpcError::Rejected { code, text }),
+                    &mut self.ctx,
+                ),
+                None => warn!(
+                    "{} answered {} with error {}: {}",
+                    src, in_reply_to, code, text
+                ),
+            },
+            Envelope::Body(body) if body.is_unsupported() => self.unsupported(&src, line),
+            Envelope::Body(body) => {
+                if let (None, Some(msg_id)) = (body.in_reply_to(), body.msg_id()) {
+                    match self.ctx.dedup.check(&src, msg_id) {
+                        Seen::New => {}
+                        Seen::InProgress => {
+                            debug!("ignoring duplicate of request {} from {}", msg_id, src);
+                            return;
+                        }
+                        Seen::Replied(mut reply) => {
+                            debug!("answering duplicate of request {} from {}", msg_id, src);
+                            // Same reply, but no msg_id goes out twice.
+                            reply.body.set_msg_id(self.ctx.next_id());
+                            self.ctx.output.send(reply, span);
+                            return;
+                        }
+                    }
+                }
+
+                let request = self.ctx.take_pending(&src, body.in_reply_to());
+                let message = Message { src, dest, body };
+
+                match request {
+                    Some(request) => handler.reply(request, Ok(message), &mut self.ctx),
+                    None => handler.handle(message, &mut self.ctx),
+                }
+            }
+        }
+
+        self.fail_refused(handler);
+    }
+
+    /// Logs a message of a type we don't know and, if configured, tells the
+    /// sender. The parsed body has nothing left in it, so the type and msg_id
+    /// are dug back out of the raw line.
+    fn unsupported(&mut self, src: &str, line: &str) {
+        let body = serde_json::from_str::<serde_json::Value>(line)
+            .ok()
+            .and_then(|value| value.get("body").cloned())
+            .unwrap_or_default();
+        let kind = body
+            .get("type")
+            .and_then(|kind| kind.as_str())
+            .unwrap_or("?");
+
+        warn!("ignoring unsupported message type {} from {}", kind, src);
+
+        let Some(msg_id) = body.get("msg_id").and_then(|id| id.as_u64()) else {
+            return;
+        };
+
+        if !self.reply_unsupported {
+            return;
+        }
+
+        // Cut down to 32 bits, the reply would answer some other request.
+        let Ok(msg_id) = u32::try_from(msg_id) else {
+            warn!(
+                "not answering {} from {}: msg_id {} is out of range",
+                kind, src, msg_id
+            );
+            return;
+        };
+
+        self.ctx.reply_error(
+            src,
+            msg_id,
+            NOT_SUPPORTED,
+            format!("{} is not supported", kind),
+        );
+    }
+
+    /// Blocks until everything sent so far has been written out and
+    /// flushed, e.g. before checking what a test captured.
+    pub fn flush_and_wait(&mut self) {
+        self.ctx.output.flush_and_wait();
+    }
+
+    /// The traffic counters, as the `stats` RPC answers with.
+    pub fn stats(&self) -> serde_json::Value {
+        self.ctx.output.metrics.snapshot(self.ctx.pending.len())
+    }
+
+    /// Prints an end-of-run overview of the traffic this node handled.
+    pub fn print_summary(&self) {
+        eprint!("{}", self.summary());
+    }
+
+    /// What [`Runtime::print_summary`] prints: how many messages of each
+    /// type went each way.
+    pub fn summary(&self) -> String {
+        format!(
+            "summary: received {:?}\nsummary: sent {:?}\n",
+            self.ctx.output.metrics.received, self.ctx.output.metrics.sent
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{echo::EchoBody, test_log::warnings};
+
+    const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#;
+
+    /// Answers echoes, and sends a request to `n2` on every one as well.
+    struct Echoer;
+
+    impl Handler for Echoer {
+        type Body = EchoBody;
+
+        fn init(&mut self, _node_id: &str, _node_ids: &[String]) {}
+
+        fn handle(&mut self, message: Message<EchoBody>, ctx: &mut Context<EchoBody>) {
+            if let EchoBody::Echo { echo, .. } = &message.body {
+                let echo = echo.clone();
+
+                ctx.reply(
+                    &message,
+                    EchoBody::EchoOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        echo,
+                    },
+                );
+                ctx.rpc(
+                    "n2",
+                    EchoBody::Echo {
+                        msg_id: 0,
+                        echo: "ping".to_string(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// JSON, counting how many lines it was asked to decode.
+    #[derive(Clone, Default)]
+    struct CountingCodec(std::rc::Rc<std::cell::Cell<usize>>);
+
+    impl<B: Body> Codec<Envelope<B>> for CountingCodec {
+        fn encode(&self, message: &Message<Envelope<B>>) -> Result<Vec<u8>, Error> {
+            JsonCodec.encode(message)
+        }
+
+        fn decode(&self, line: &[u8]) -> Result<Message<Envelope<B>>, Error> {
+            self.0.set(self.0.get() + 1);
+            JsonCodec.decode(line)
+        }
+    }
+
+    #[test]
+    fn input_is_decoded_with_the_given_codec() {
+        let codec = CountingCodec::default();
+        let (mut runtime, output) =
+            Runtime::in_memory_with_codec(&Config::default(), codec.clone());
+        let mut handler = Echoer;
+
+        runtime.dispatch(&mut handler, INIT);
+        runtime.dispatch(&mut handler, &echo(2));
+
+        assert_eq!(codec.0.get(), 2);
+        assert_eq!(output.try_iter().count(), 3);
+    }
+
+    fn echo(msg_id: u32) -> String {
+        format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":{},"echo":"e{}"}}}}"#,
+            msg_id, msg_id
+        )
+    }
+
+    #[test]
+    fn outgoing_msg_ids_are_unique_and_increasing() {
+        let config = Config::default();
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut handler = Echoer;
+
+        runtime.dispatch(&mut handler, INIT);
+
+        for msg_id in 2..10 {
+            runtime.dispatch(&mut handler, &echo(msg_id));
+        }
+
+        // Retransmits, answered from the dedup cache.
+        for msg_id in 2..5 {
+            runtime.dispatch(&mut handler, &echo(msg_id));
+        }
+
+        runtime.ctx.send(
+            "n2",
+            EchoBody::Echo {
+                msg_id: 0,
+                echo: "last".to_string(),
+            },
+        );
+
+        let sent: Vec<_> = output.try_iter().collect();
+        let resent = sent
+            .iter()
+            .filter(|message| message.body.in_reply_to() == Some(2))
+            .count();
+        assert_eq!(resent, 2, "the retransmit was answered from the cache");
+
+        let ids: Vec<u32> = sent
+            .iter()
+            .map(|message| message.body.msg_id().expect("every message has a msg_id"))
+            .collect();
+        assert_eq!(ids.len(), 1 + 8 * 2 + 3 + 1);
+        assert!(
+            ids.windows(2).all(|pair| pair[0] < pair[1]),
+            "msg_ids repeat or go backwards: {:?}",
+            ids
+        );
+    }
+
+    /// A writer whose bytes can be inspected from the test thread.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_and_wait_writes_out_everything_queued() {
+        // Gossip-style traffic that the writer would otherwise hold back.
+        let config = Config {
+            flush_every: 1_000,
+            flush_delay: Duration::from_secs(3600),
+            ..Config::default()
+        };
+        let captured = Captured::default();
+        let out = captured.clone();
+        let mut runtime: Runtime<EchoBody> =
+            Runtime::with_output(&config, Output::with_writer(&config, JsonCodec, || out));
+
+        for n in 0..5 {
+            runtime.ctx.send(
+                "n2",
+                EchoBody::Echo {
+                    msg_id: 0,
+                    echo: format!("e{}", n),
+                },
+            );
+        }
+
+        runtime.flush_and_wait();
+
+        let bytes = captured.0.lock().unwrap().clone();
+        let lines: Vec<&str> = std::str::from_utf8(&bytes).unwrap().lines().collect();
+        assert_eq!(lines.len(), 5, "{:?}", lines);
+        for (n, line) in lines.iter().enumerate() {
+            assert!(line.contains(&format!(r#""echo":"e{}""#, n)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn the_trace_holds_every_message_as_a_replayable_json_line() {
+        let path = std::env::temp_dir().join(format!("trace-test-{}.jsonl", std::process::id()));
+        let config = Config {
+            trace_out: Some(path.to_string_lossy().into_owned()),
+            ..Config::default()
+        };
+        let captured = Captured::default();
+        let out = captured.clone();
+        let mut runtime: Runtime<EchoBody> =
+            Runtime::with_output(&config, Output::with_writer(&config, JsonCodec, || out));
+        let mut echo = crate::echo::Echo::new();
+
+        let echo_request =
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hi"}}"#;
+        runtime.dispatch(&mut echo, INIT);
+        runtime.dispatch(&mut echo, echo_request);
+        runtime.flush_and_wait();
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let messages: Vec<Message<serde_json::Value>> = trace
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("a whole JSON message"))
+            .collect();
+        let kinds: Vec<(&str, &str)> = messages
+            .iter()
+            .map(|message| {
+                (
+                    message.dest.as_str(),
+                    message.body["type"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("n1", "init"),
+                ("c0", "init_ok"),
+                ("n1", "echo"),
+                ("c1", "echo_ok")
+            ]
+        );
+
+        // What went out is what reached stdout, and what came in replays to it.
+        let lines = |inbound: bool| -> String {
+            trace
+                .lines()
+                .zip(&messages)
+                .filter(|(_, message)| (message.dest == "n1") == inbound)
+                .map(|(line, _)| format!("{}\n", line))
+                .collect()
+        };
+        let stdout = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(lines(false), stdout);
+
+        let replayed = crate::replay::check(
+            &Config::default(),
+            &mut crate::echo::Echo::new(),
+            &lines(true),
+            &lines(false),
+        );
+        assert!(replayed.is_ok(), "{:?}", replayed);
+    }
+
+    #[test]
+    fn a_burst_of_malformed_lines_warns_once() {
+        let (mut runtime, _output) = Runtime::in_memory(&Config {
+            parse_error_threshold: 5,
+            ..Config::default()
+        });
+        let mut echoer = Echoer;
+        warnings("");
+
+        runtime.dispatch(&mut echoer, INIT);
+        for n in 0..20 {
+            runtime.dispatch(
+                &mut echoer,
+                &format!(r#"{{"src":"c9","dest":"n1","body":{{"type":{}}}}}"#, n),
+            );
+        }
+        runtime.dispatch(&mut echoer, "not json at all");
+
+        assert_eq!(warnings("malformed messages in the last"), 1);
+        assert_eq!(
+            warnings(r#"6 malformed messages in the last 1s, by source: {"c9": 6}"#),
+            1
+        );
+    }
+
+    #[test]
+    fn the_summary_counts_each_type_of_message() {
+        let (mut runtime, _output) = Runtime::in_memory(&Config::default());
+        let mut echoer = Echoer;
+
+        runtime.dispatch(&mut echoer, INIT);
+        for n in 2..5 {
+            let echo = format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":{},"echo":"e"}}}}"#,
+                n
+            );
+            runtime.dispatch(&mut echoer, &echo);
+        }
+
+        assert_eq!(
+            runtime.summary(),
+            concat!(
+                r#"summary: received {"echo": 3, "init": 1}"#,
+                "\n",
+                r#"summary: sent {"echo": 3, "echo_ok": 3, "init_ok": 1}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn an_unsupported_request_is_only_answered_if_its_msg_id_fits() {
+        let (mut runtime, output) = Runtime::in_memory(&Config {
+            reply_unsupported: true,
+            ..Config::default()
+        });
+        let mut echoer = Echoer;
+
+        runtime.dispatch(&mut echoer, INIT);
+        output.try_iter().for_each(drop);
+
+        for msg_id in [u64::from(u32::MAX) + 6, 6] {
+            let line = format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"frobnicate","msg_id":{}}}}}"#,
+                msg_id
+            );
+            runtime.dispatch(&mut echoer, &line);
+        }
+
+        let replies: Vec<_> = output
+            .try_iter()
+            .map(|message| (message.dest, message.body.in_reply_to()))
+            .collect();
+        assert_eq!(replies, [("c1".to_string(), Some(6))]);
+    }
+
+    /// Counts the flushes that reach the writer underneath, noting when the
+    /// first one carrying any bytes happened.
+    #[derive(Clone, Default)]
+    struct Flushes {
+        count: Arc<AtomicUsize>,
+        unflushed: Arc<AtomicUsize>,
+        first: Arc<std::sync::Mutex<Option<Instant>>>,
+    }
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.unflushed.fetch_add(buf.len(), Ordering::Relaxed);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            if self.unflushed.swap(0, Ordering::Relaxed) > 0 {
+                self.first.lock().unwrap().get_or_insert_with(Instant::now);
+            }
+            Ok(())
+        }
+    }
+
+    /// A clock that never moves, so only the queue decides when to flush.
+    struct Frozen(Instant);
+
+    impl Clock for Frozen {
+        fn now(&self) -> Instant {
+            self.0
+        }
+    }
+
+    fn reply_to_c1(n: u32) -> Queued<EchoBody> {
+        message_to("c1", n)
+    }
+
+    fn message_to(dest: &str, n: u32) -> Queued<EchoBody> {
+        Queued::Message(Message {
+            src: "n1".to_string(),
+            dest: dest.to_string(),
+            body: Envelope::Body(EchoBody::EchoOk {
+                msg_id: n,
+                in_reply_to: n,
+                echo: format!("e{}", n),
+            }),
+        })
+    }
+
+    fn policy(window: Duration) -> FlushPolicy {
+        FlushPolicy {
+            max_messages: 1_000,
+            max_delay: Duration::from_secs(3600),
+            window,
+        }
+    }
+
+    #[test]
+    fn a_burst_of_replies_shares_flushes_within_the_window() {
+        let flushes_for = |window: Duration| {
+            let (queue, messages) = std_mpsc::sync_channel(100);
+            for n in 0..50 {
+                queue.send(reply_to_c1(n)).unwrap();
+            }
+            drop(queue);
+
+            let out = Flushes::default();
+            write_out(
+                out.clone(),
+                Box::new(JsonCodec),
+                messages,
+                policy(window),
+                &Frozen(Instant::now()),
+                Arc::new(AtomicU64::new(0)),
+                Arc::new(AtomicUsize::new(50)),
+            )
+            .unwrap();
+
+            out.count.load(Ordering::Relaxed)
+        };
+
+        // One per reply plus the last, against just the last.
+        assert_eq!(flushes_for(Duration::ZERO), 51);
+        assert_eq!(flushes_for(Duration::from_millis(10)), 1);
+    }
+
+    #[test]
+    fn a_lone_reply_is_flushed_once_the_window_is_over() {
+        let window = Duration::from_millis(20);
+        let (queue, messages) = std_mpsc::sync_channel(1);
+        let out = Flushes::default();
+        let writer = {
+            let out = out.clone();
+            thread::spawn(move || {
+                write_out(
+                    out,
+                    Box::new(JsonCodec),
+                    messages,
+                    policy(window),
+                    &SystemClock,
+                    Arc::new(AtomicU64::new(0)),
+                    Arc::new(AtomicUsize::new(1)),
+                )
+            })
+        };
+
+        let sent = Instant::now();
+        queue.send(reply_to_c1(1)).unwrap();
+
+        // The queue stays open, so only the window can flush it.
+        while out.first.lock().unwrap().is_none() && sent.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let flushed = out.first.lock().unwrap().expect("flushed");
+        assert!(flushed - sent < window * 5, "held for {:?}", flushed - sent);
+
+        drop(queue);
+        writer.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn a_burst_of_gossip_is_flushed_once_the_queue_runs_dry() {
+        let (queue, messages) = std_mpsc::sync_channel(100);
+        for n in 0..50 {
+            queue.send(message_to("n2", n)).unwrap();
+        }
+        drop(queue);
+
+        let out = Flushes::default();
+        let policy = FlushPolicy {
+            max_messages: 1_000,
+            max_delay: Duration::ZERO,
+            window: Duration::ZERO,
+        };
+        write_out(
+            out.clone(),
+            Box::new(JsonCodec),
+            messages,
+            policy,
+            &Frozen(Instant::now()),
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(AtomicUsize::new(50)),
+        )
+        .unwrap();
+
+        // Everything was already queued, so the queue never ran dry before
+        // it closed.
+        assert_eq!(out.count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn a_lone_gossip_is_flushed_as_soon_as_the_queue_runs_dry() {
+        let (queue, messages) = std_mpsc::sync_channel(1);
+        let out = Flushes::default();
+        let writer = {
+            let out = out.clone();
+            thread::spawn(move || {
+                let policy = FlushPolicy {
+                    max_messages: 1_000,
+                    max_delay: Duration::ZERO,
+                    window: Duration::ZERO,
+                };
+                write_out(
+                    out,
+                    Box::new(JsonCodec),
+                    messages,
+                    policy,
+                    &SystemClock,
+                    Arc::new(AtomicU64::new(0)),
+                    Arc::new(AtomicUsize::new(1)),
+                )
+            })
+        };
+
+        let sent = Instant::now();
+        queue.send(message_to("n2", 1)).unwrap();
+
+        while out.first.lock().unwrap().is_none() && sent.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let flushed = out.first.lock().unwrap().expect("flushed");
+        assert!(
+            flushed - sent < Duration::from_millis(100),
+            "held for {:?}",
+            flushed - sent
+        );
+
+        drop(queue);
+        writer.join().unwrap().unwrap();
+    }
+
+    /// Sends a request to `n2` on every echo, remembering how each ended.
+    #[derive(Default)]
+    struct Requester {
+        outcomes: Vec<(u32, Result<(), RpcError>)>,
+    }
+
+    impl Handler for Requester {
+        type Body = EchoBody;
+
+        fn init(&mut self, _node_id: &str, _node_ids: &[String]) {}
+
+        fn handle(&mut self, _message: Message<EchoBody>, ctx: &mut Context<EchoBody>) {
+            ctx.rpc(
+                "n2",
+                EchoBody::Echo {
+                    msg_id: 0,
+                    echo: "ping".to_string(),
+                },
+            );
+        }
+
+        fn reply(
+            &mut self,
+            request: u32,
+            reply: Result<Message<EchoBody>, RpcError>,
+            _ctx: &mut Context<EchoBody>,
+        ) {
+            self.outcomes.push((request, reply.map(|_| ())));
+        }
+    }
+
+    #[test]
+    fn requests_over_the_cap_fail_without_being_sent() {
+        let config = Config {
+            max_pending_rpcs: Some(2),
+            ..Config::default()
+        };
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut handler = Requester::default();
+
+        runtime.dispatch(&mut handler, INIT);
+        for n in 2..5 {
+            runtime.dispatch(&mut handler, &echo(n));
+        }
+
+        let sent: Vec<u32> = output
+            .try_iter()
+            .filter(|message| message.dest == "n2")
+            .filter_map(|message| message.body.msg_id())
+            .collect();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(handler.outcomes.len(), 1);
+        assert_eq!(handler.outcomes[0].1, Err(RpcError::Overloaded));
+        assert!(!sent.contains(&handler.outcomes[0].0));
+
+        // An answer frees a slot for the next request.
+        runtime.dispatch(
+            &mut handler,
+            &format!(
+                r#"{{"src":"n2","dest":"n1","body":{{"type":"echo_ok","msg_id":1,"in_reply_to":{},"echo":"ping"}}}}"#,
+                sent[0]
+            ),
+        );
+        runtime.dispatch(&mut handler, &echo(5));
+
+        assert_eq!(
+            output
+                .try_iter()
+                .filter(|message| message.dest == "n2")
+                .count(),
+            1
+        );
+        assert_eq!(handler.outcomes.len(), 2);
+        assert_eq!(handler.outcomes[1], (sent[0], Ok(())));
+        assert_eq!(runtime.stats()["refused_rpcs"], 1);
+    }
+
+    #[test]
+    fn a_dump_request_is_answered_at_the_next_tick() {
+        let (mut runtime, _output) = Runtime::in_memory(&Config::default());
+        let mut handler = Echoer;
+
+        runtime.dispatch(&mut handler, INIT);
+        runtime.dispatch(&mut handler, &echo(2));
+
+        let mut dumped = Vec::new();
+        runtime.on_timer(&mut handler, &mut dumped);
+        assert!(dumped.is_empty());
+
+        // What the SIGUSR1 handler does.
+        DUMP_REQUESTED.store(true, Ordering::Relaxed);
+
+        runtime.on_timer(&mut handler, &mut dumped);
+        assert_eq!(
+            String::from_utf8(dumped).unwrap(),
+            "debug state: {\"outstanding_rpcs\":1}\n"
+        );
+        assert!(!take_dump_request());
+    }
+}
@@ -0,0 +1,375 @@
+//! An in-process stand-in for Maelstrom, for exercising several nodes
+//! together without the external jar.
+//!
+//! A [`Simulator`] runs one handler per node, each behind its own in-memory
+//! [`Runtime`], and carries messages between them with a fixed latency.
+//! Links can be cut to simulate partitions, and nodes crashed and restarted;
+//! see [`Simulator::crash`]. Anything sent to a destination
+//! that isn't a node is taken to be a client reply and collected, except for
+//! services registered with [`Simulator::add_service`], which stand in for
+//! Maelstrom's `seq-kv` and `lin-kv`. Messages to any other service are
+//! collected the same way and never answered.
+//!
+//! Time is real: timers, gossip rounds and RPC timeouts all run off the
+//! clock, so a simulated second takes a second.
+
+use crate::{
+    config::{node_rng, Config},
+    message::{Envelope, Message},
+    mock_kv::MockKv,
+    runtime::{Handler, Runtime},
+};
+use log::warn;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::mpsc::Receiver,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The client every request from [`Simulator::send`] comes from.
+pub const CLIENT: &str = "c0";
+
+struct SimNode<H: Handler> {
+    runtime: Runtime<H::Body>,
+    handler: H,
+    output: Receiver<Message<Envelope<H::Body>>>,
+}
+
+/// A message on its way to a node.
+struct InFlight {
+    deliver_at: Instant,
+    dest: String,
+    line: String,
+}
+
+pub struct Simulator<H: Handler> {
+    node_ids: Vec<String>,
+    /// The ones running right now; a crashed node is missing until restarted.
+    nodes: BTreeMap<String, SimNode<H>>,
+    config: Config,
+    make: Box<dyn FnMut(&Config) -> H>,
+    /// The last `topology` each node was sent, for replaying on restart.
+    topologies: HashMap<String, Value>,
+    services: BTreeMap<String, MockKv>,
+    in_flight: Vec<InFlight>,
+    latency: Duration,
+    /// Directed links that drop everything, as `(src, dest)`.
+    cut: HashSet<(String, String)>,
+    /// Everything addressed to something other than a node, in send order.
+    replies: Vec<Message<Value>>,
+    next_msg_id: u32,
+    tick_interval: Duration,
+    last_tick: Instant,
+    seed: Option<u64>,
+}
+
+impl<H: Handler> Simulator<H> {
+    /// Starts nodes `n0` to `n<count - 1>`, each with a handler made by
+    /// `make`, and initializes them. `config` is shared by every node.
+    pub fn new(count: usize, config: &Config, make: impl FnMut(&Config) -> H + 'static) -> Self {
+        let node_ids: Vec<String> = (0..count).map(|n| format!("n{}", n)).collect();
+
+        let mut simulator = Simulator {
+            node_ids: node_ids.clone(),
+            nodes: BTreeMap::new(),
+            config: config.clone(),
+            make: Box::new(make),
+            topologies: HashMap::new(),
+            services: BTreeMap::new(),
+            in_flight: Vec::new(),
+            latency: Duration::from_millis(5),
+            cut: HashSet::new(),
+            replies: Vec::new(),
+            next_msg_id: 1,
+            tick_interval: config.tick_interval,
+            last_tick: Instant::now(),
+            seed: config.seed,
+        };
+
+        for node_id in &node_ids {
+            simulator.start(node_id);
+        }
+
+        simulator.run_for(Duration::ZERO);
+        simulator
+    }
+
+    /// Every node, whether running or crashed.
+    pub fn node_ids(&self) -> Vec<String> {
+        self.node_ids.clone()
+    }
+
+    /// Starts `node_id` afresh and sends it `init`.
+    fn start(&mut self, node_id: &str) {
+        let (runtime, output) = Runtime::in_memory(&self.config);
+
+        let node = SimNode {
+            runtime,
+            handler: (self.make)(&self.config),
+            output,
+        };
+
+        self.nodes.insert(node_id.to_string(), node);
+
+        let node_ids = self.node_ids.clone();
+        self.send(
+            node_id,
+            json!({"type": "init", "node_id": node_id, "node_ids": node_ids}),
+        );
+    }
+
+    /// Kills `node_id`: its handler and runtime are dropped without saving
+    /// anything, so all that survives is the last snapshot written under
+    /// `--snapshot-dir`, if any. Messages to it are lost until
+    /// [`Simulator::restart`].
+    pub fn crash(&mut self, node_id: &str) {
+        self.nodes.remove(node_id);
+    }
+
+    /// Brings a crashed `node_id` back with a new handler, which restores
+    /// from its snapshot on `init` as a real restart would. The last
+    /// `topology` it was sent is sent again, since Maelstrom only sends one.
+    pub fn restart(&mut self, node_id: &str) {
+        if self.nodes.contains_key(node_id) || !self.node_ids.iter().any(|id| id == node_id) {
+            warn!("not restarting {}, which isn't a crashed node", node_id);
+            return;
+        }
+
+        self.start(node_id);
+
+        if let Some(topology) = self.topologies.get(node_id).cloned() {
+            self.send(node_id, topology);
+        }
+
+        self.run_for(Duration::ZERO);
+    }
+
+    /// Whether `node_id` is running, i.e. hasn't crashed.
+    pub fn is_running(&self, node_id: &str) -> bool {
+        self.nodes.contains_key(node_id)
+    }
+
+    /// The handler running as `node_id`.
+    pub fn handler(&self, node_id: &str) -> Option<&H> {
+        self.nodes.get(node_id).map(|node| &node.handler)
+    }
+
+    /// What `node_id` would answer a `stats` request with.
+    pub fn stats(&self, node_id: &str) -> Option<Value> {
+        self.nodes.get(node_id).map(|node| node.runtime.stats())
+    }
+
+    /// Answers everything the nodes send to `name`, e.g. `"lin-kv"`, from
+    /// `kv`. With a seed in the config, its injected conflicts are seeded too.
+    pub fn add_service(&mut self, name: &str, mut kv: MockKv) {
+        kv.reseed(node_rng(self.seed, name));
+        self.services.insert(name.to_string(), kv);
+    }
+
+    /// The service registered as `name`, e.g. to look at what it stores.
+    pub fn service(&self, name: &str) -> Option<&MockKv> {
+        self.services.get(name)
+    }
+
+    /// How long every message between nodes takes to arrive. Client
+    /// requests are delivered on the next step regardless.
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = latency;
+    }
+
+    /// Cuts every link between a node in `left` and one in `right`, both
+    /// ways. Messages already in flight still arrive.
+    pub fn partition(&mut self, left: &[&str], right: &[&str]) {
+        for a in left {
+            for b in right {
+                self.cut.insert((a.to_string(), b.to_string()));
+                self.cut.insert((b.to_string(), a.to_string()));
+            }
+        }
+    }
+
+    /// Restores every cut link.
+    pub fn heal(&mut self) {
+        self.cut.clear();
+    }
+
+    /// Sends `body` to `dest` from [`CLIENT`], with a fresh msg_id, which is
+    /// returned.
+    pub fn send(&mut self, dest: &str, body: Value) -> u32 {
+        let msg_id = self.next_msg_id;
+        self.next_msg_id += 1;
+
+        if body["type"] == "topology" {
+            self.topologies.insert(dest.to_string(), body.clone());
+        }
+
+        self.resend(dest, msg_id, body);
+        msg_id
+    }
+
+    /// Sends client request `msg_id` to `dest` again, as a client would
+    /// after losing the reply.
+    pub fn resend(&mut self, dest: &str, msg_id: u32, mut body: Value) {
+        body["msg_id"] = msg_id.into();
+
+        let message = json!({"src": CLIENT, "dest": dest, "body": body});
+
+        self.in_flight.push(InFlight {
+            deliver_at: Instant::now(),
+            dest: dest.to_string(),
+            line: message.to_string(),
+        });
+    }
+
+    /// Sends `body` to `dest` and runs until it is answered, returning the
+    /// reply's body, or `None` after `timeout`.
+    pub fn request(&mut self, dest: &str, body: Value, timeout: Duration) -> Option<Value> {
+        let msg_id = self.send(dest, body);
+
+        let answered = |simulator: &mut Simulator<H>| simulator.reply_to(msg_id).is_some();
+
+        if !self.run_until(timeout, answered) {
+            return None;
+        }
+
+        self.reply_to(msg_id).map(|reply| reply.body.clone())
+    }
+
+    /// The first reply to client request `msg_id`.
+    pub fn reply_to(&self, msg_id: u32) -> Option<&Message<Value>> {
+        self.replies.iter().find(|reply| {
+            reply.dest == CLIENT && reply.body["in_reply_to"].as_u64() == Some(msg_id.into())
+        })
+    }
+
+    /// Everything nodes sent outside the cluster so far.
+    pub fn replies(&self) -> &[Message<Value>] {
+        &self.replies
+    }
+
+    /// Runs the cluster for `duration`, then until nothing is left to
+    /// deliver right away.
+    pub fn run_for(&mut self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+
+        while Instant::now() < deadline {
+            self.step();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        while self.step() {}
+    }
+
+    /// Runs the cluster until `done` holds, checking after every step, or
+    /// until `timeout` passes. Returns whether `done` was reached.
+    pub fn run_until(
+        &mut self,
+        timeout: Duration,
+        mut done: impl FnMut(&mut Simulator<H>) -> bool,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            self.step();
+
+            if done(self) {
+                return true;
+            }
+
+            if Instant::now() >= deadline {
+                return false;
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Ticks every node if a tick is due, delivers every message whose time
+    /// has come and collects what the nodes sent. Returns whether anything
+    /// was delivered.
+    pub fn step(&mut self) -> bool {
+        if self.last_tick.elapsed() >= self.tick_interval {
+            self.last_tick = Instant::now();
+
+            for node in self.nodes.values_mut() {
+                node.runtime.tick(&mut node.handler);
+            }
+        }
+
+        let now = Instant::now();
+        let (due, waiting): (Vec<InFlight>, Vec<InFlight>) = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition(|message| message.deliver_at <= now);
+
+        self.in_flight = waiting;
+
+        let delivered = !due.is_empty();
+
+        for message in due {
+            if let Some(node) = self.nodes.get_mut(&message.dest) {
+                node.runtime.dispatch(&mut node.handler, &message.line);
+            }
+        }
+
+        self.collect();
+        delivered
+    }
+
+    /// Routes everything the nodes have sent since the last step.
+    fn collect(&mut self) {
+        let mut sent = Vec::new();
+
+        for node in self.nodes.values() {
+            sent.extend(node.output.try_iter());
+        }
+
+        for message in sent {
+            let line = match serde_json::to_string(&message) {
+                Ok(line) => line,
+                Err(error) => {
+                    warn!("dropping unencodable message {:?}: {}", message, error);
+                    continue;
+                }
+            };
+
+            if let Some(kv) = self.services.get_mut(&message.dest) {
+                let reply = serde_json::from_str(&line)
+                    .ok()
+                    .and_then(|request| kv.handle(&request));
+
+                if let Some(body) = reply {
+                    let reply = json!({"src": message.dest, "dest": message.src, "body": body});
+
+                    self.in_flight.push(InFlight {
+                        deliver_at: Instant::now() + self.latency + kv.latency(),
+                        dest: message.src,
+                        line: reply.to_string(),
+                    });
+                }
+                continue;
+            }
+
+            let is_node = self.node_ids.contains(&message.dest);
+
+            if !is_node {
+                match serde_json::from_str(&line) {
+                    Ok(reply) => self.replies.push(reply),
+                    Err(error) => warn!("dropping unreadable reply {}: {}", line, error),
+                }
+                continue;
+            }
+
+            if self.cut.contains(&(message.src, message.dest.clone())) {
+                continue;
+            }
+
+            self.in_flight.push(InFlight {
+                deliver_at: Instant::now() + self.latency,
+                dest: message.dest,
+                line,
+            });
+        }
+    }
+}
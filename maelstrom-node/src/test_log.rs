@@ -0,0 +1,40 @@
+//! A logger for tests that keeps every warning, so a test can check what it
+//! warned about. Tests run side by side in one process, so each should look
+//! for text only it would log.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::Mutex;
+
+struct Warnings(Mutex<Vec<String>>);
+
+impl Log for Warnings {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static WARNINGS: Warnings = Warnings(Mutex::new(Vec::new()));
+
+/// How many warnings logged so far contain `text`. Call it once before
+/// doing anything, as whichever test calls it first installs the logger.
+pub(crate) fn warnings(text: &str) -> usize {
+    if log::set_logger(&WARNINGS).is_ok() {
+        log::set_max_level(LevelFilter::Warn);
+    }
+
+    WARNINGS
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|warning| warning.contains(text))
+        .count()
+}
@@ -0,0 +1,138 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    str::FromStr,
+};
+
+/// How a node picks its gossip neighbors.
+///
+/// `Given` uses the topology Maelstrom sends. The others ignore it and build
+/// an overlay over the `init` membership instead, trading latency (more hops)
+/// for fewer messages. Every node computes the same overlay because they all
+/// see the same membership in the same order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopologyStrategy {
+    Given,
+    /// A tree where every node has up to `k` children.
+    Tree(usize),
+    /// The first node is a hub connected to everyone else.
+    Star,
+    /// Every node is connected to the nodes either side of it, wrapping
+    /// around.
+    Ring,
+    /// Every node is connected to every other.
+    Mesh,
+}
+
+impl FromStr for TopologyStrategy {
+    type Err = String;
+
+    /// Parses `given`, `star`, `ring`, `mesh` or `tree:<k>`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            None => TopologyStrategy::with_args(value, &[]),
+            Some((name, k)) => match k.parse() {
+                Ok(k) => TopologyStrategy::with_args(name, &[k]),
+                Err(_) => Err(format!("invalid tree degree {}", k)),
+            },
+        }
+    }
+}
+
+impl TopologyStrategy {
+    /// The strategy called `name`, with its parameters (only `tree` takes
+    /// one, the degree) given separately, as in a `set_topology` message.
+    pub fn with_args(name: &str, args: &[usize]) -> Result<Self, String> {
+        match (name, args) {
+            ("given", []) => Ok(TopologyStrategy::Given),
+            ("star", []) => Ok(TopologyStrategy::Star),
+            ("ring", []) => Ok(TopologyStrategy::Ring),
+            ("mesh", []) => Ok(TopologyStrategy::Mesh),
+            ("tree", [k]) if *k > 0 => Ok(TopologyStrategy::Tree(*k)),
+            ("tree", [k]) => Err(format!("invalid tree degree {}", k)),
+            ("tree", _) => Err(String::from("tree takes a degree")),
+            ("given" | "star" | "ring" | "mesh", _) => Err(format!("{} takes no arguments", name)),
+            _ => Err(format!("unknown topology strategy {}", name)),
+        }
+    }
+
+    /// The neighbors of `node_id` in the overlay over `all_nodes`, or `None`
+    /// for `Given`, which waits for Maelstrom's topology message.
+    pub fn neighbors(&self, node_id: &str, all_nodes: &[String]) -> Option<Vec<String>> {
+        let index = all_nodes.iter().position(|node| node == node_id)?;
+
+        let indices: Vec<usize> = match *self {
+            TopologyStrategy::Given => return None,
+            TopologyStrategy::Tree(k) => {
+                let parent = index.checked_sub(1).map(|i| i / k);
+                let children = (k * index + 1)..=(k * index + k);
+
+                parent
+                    .into_iter()
+                    .chain(children.filter(|child| *child < all_nodes.len()))
+                    .collect()
+            }
+            TopologyStrategy::Star if index == 0 => (1..all_nodes.len()).collect(),
+            TopologyStrategy::Star => vec![0],
+            TopologyStrategy::Ring => {
+                let count = all_nodes.len();
+                let mut sides = vec![(index + count - 1) % count, (index + 1) % count];
+                // With two nodes both sides are the same node; with one,
+                // both are us.
+                sides.dedup();
+                sides.retain(|side| *side != index);
+                sides
+            }
+            TopologyStrategy::Mesh => (0..all_nodes.len()).filter(|i| *i != index).collect(),
+        };
+
+        Some(indices.into_iter().map(|i| all_nodes[i].clone()).collect())
+    }
+}
+
+/// The most links a message needs to get from any node to any other it can
+/// reach, following each node's neighbor list in `overlay`. Nodes that can't
+/// be reached at all don't count.
+pub fn diameter(overlay: &HashMap<String, Vec<String>>) -> u32 {
+    let mut diameter = 0;
+
+    for start in overlay.keys() {
+        let mut seen = HashSet::from([start.as_str()]);
+        let mut queue = VecDeque::from([(start.as_str(), 0)]);
+
+        while let Some((node, distance)) = queue.pop_front() {
+            diameter = diameter.max(distance);
+
+            for neighbor in overlay.get(node).into_iter().flatten() {
+                if seen.insert(neighbor) {
+                    queue.push_back((neighbor, distance + 1));
+                }
+            }
+        }
+    }
+
+    diameter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlay(strategy: TopologyStrategy, count: usize) -> HashMap<String, Vec<String>> {
+        let nodes: Vec<String> = (0..count).map(|n| format!("n{}", n)).collect();
+
+        nodes
+            .iter()
+            .map(|node| (node.clone(), strategy.neighbors(node, &nodes).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn diameters_of_the_built_in_overlays() {
+        assert_eq!(diameter(&overlay(TopologyStrategy::Mesh, 5)), 1);
+        assert_eq!(diameter(&overlay(TopologyStrategy::Star, 5)), 2);
+        assert_eq!(diameter(&overlay(TopologyStrategy::Ring, 10)), 5);
+        // n7 to n8 goes up to n0 and back down.
+        assert_eq!(diameter(&overlay(TopologyStrategy::Tree(2), 10)), 5);
+        assert_eq!(diameter(&overlay(TopologyStrategy::Mesh, 1)), 0);
+    }
+}
@@ -0,0 +1,439 @@
+use crate::{
+    config::Config,
+    message::{Body, Message, TEMPORARILY_UNAVAILABLE},
+    mvcc::Mvcc,
+    runtime::{Backoff, Context, Handler},
+};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{collections::BTreeMap, fmt};
+
+/// One step of a transaction. On the wire it is a `[op, key, value]` triple:
+/// `["r", 1, null]` reads key 1 (the reply fills in what was read) and
+/// `["w", 1, 5]` writes 5 to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MicroOp {
+    Read { key: u64, value: Option<u64> },
+    Write { key: u64, value: u64 },
+}
+
+impl Serialize for MicroOp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(3)?;
+
+        match self {
+            MicroOp::Read { key, value } => {
+                tuple.serialize_element("r")?;
+                tuple.serialize_element(key)?;
+                tuple.serialize_element(value)?;
+            }
+            MicroOp::Write { key, value } => {
+                tuple.serialize_element("w")?;
+                tuple.serialize_element(key)?;
+                tuple.serialize_element(value)?;
+            }
+        }
+
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for MicroOp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(3, MicroOpVisitor)
+    }
+}
+
+struct MicroOpVisitor;
+
+impl<'de> Visitor<'de> for MicroOpVisitor {
+    type Value = MicroOp;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "an [op, key, value] triple")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<MicroOp, A::Error> {
+        let op: String = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let key = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+
+        let micro_op = match op.as_str() {
+            "r" => MicroOp::Read {
+                key,
+                value: seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?,
+            },
+            "w" => MicroOp::Write {
+                key,
+                value: seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?,
+            },
+            other => return Err(de::Error::unknown_variant(other, &["r", "w"])),
+        };
+
+        if seq.next_element::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(4, &self));
+        }
+
+        Ok(micro_op)
+    }
+}
+
+/// Message types of the transaction workload (challenge #6).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TxnBody {
+    Txn {
+        msg_id: u32,
+        txn: Vec<MicroOp>,
+    },
+    /// The transaction as executed, with every read's value filled in.
+    TxnOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        txn: Vec<MicroOp>,
+    },
+    /// Node-to-node: the final values a committed transaction wrote, stamped
+    /// with the version they were written at.
+    Replicate {
+        msg_id: u32,
+        clock: u64,
+        writes: Vec<(u64, u64)>,
+    },
+    ReplicateOk {
+        msg_id: u32,
+        in_reply_to: u32,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+impl Body for TxnBody {
+    fn kind(&self) -> &'static str {
+        match self {
+            TxnBody::Txn { .. } => "txn",
+            TxnBody::TxnOk { .. } => "txn_ok",
+            TxnBody::Replicate { .. } => "replicate",
+            TxnBody::ReplicateOk { .. } => "replicate_ok",
+            TxnBody::Unsupported => "unsupported",
+        }
+    }
+
+    fn msg_id(&self) -> Option<u32> {
+        match self {
+            TxnBody::Txn { msg_id, .. }
+            | TxnBody::TxnOk { msg_id, .. }
+            | TxnBody::Replicate { msg_id, .. }
+            | TxnBody::ReplicateOk { msg_id, .. } => Some(*msg_id),
+            TxnBody::Unsupported => None,
+        }
+    }
+
+    fn in_reply_to(&self) -> Option<u32> {
+        match self {
+            TxnBody::TxnOk { in_reply_to, .. } | TxnBody::ReplicateOk { in_reply_to, .. } => {
+                Some(*in_reply_to)
+            }
+            TxnBody::Txn { .. } | TxnBody::Replicate { .. } | TxnBody::Unsupported => None,
+        }
+    }
+
+    fn set_msg_id(&mut self, id: u32) {
+        match self {
+            TxnBody::Txn { msg_id, .. }
+            | TxnBody::TxnOk { msg_id, .. }
+            | TxnBody::Replicate { msg_id, .. }
+            | TxnBody::ReplicateOk { msg_id, .. } => *msg_id = id,
+            TxnBody::Unsupported => {}
+        }
+    }
+
+    fn set_in_reply_to(&mut self, id: u32) {
+        match self {
+            TxnBody::TxnOk { in_reply_to, .. } | TxnBody::ReplicateOk { in_reply_to, .. } => {
+                *in_reply_to = id
+            }
+            TxnBody::Txn { .. } | TxnBody::Replicate { .. } | TxnBody::Unsupported => {}
+        }
+    }
+
+    fn is_unsupported(&self) -> bool {
+        matches!(self, TxnBody::Unsupported)
+    }
+}
+
+/// Handler for the totally-available transaction workload.
+///
+/// Each transaction runs to completion against the local store before the
+/// next message is looked at and is acknowledged straight away, so a node
+/// never waits on its peers. It either commits whole or not at all: if its
+/// writes couldn't be sent to every peer, because too many requests are
+/// outstanding, none of them are kept and the client is told to retry. Only once it has committed are its writes sent
+/// on, and only the last value it wrote to each key, so other nodes never
+/// see a value it overwrote itself: that gives read committed. Writes are
+/// kept in an [`Mvcc`] store, so peers' writes take their place by version
+/// (last writer wins) and each transaction reads from its own snapshot.
+#[derive(Debug)]
+pub struct Txn {
+    node_id: String,
+    peers: Vec<String>,
+    clock: u64,
+    store: Mvcc<u64, u64>,
+    /// How committed writes are resent to a peer until it acks them; they
+    /// are gossip of a kind, so they follow the gossip retry settings.
+    replication: Backoff,
+}
+
+impl Txn {
+    pub fn new(config: &Config) -> Txn {
+        Txn {
+            node_id: String::new(),
+            peers: Vec::new(),
+            clock: 0,
+            store: Mvcc::new(),
+            replication: Backoff {
+                initial: Backoff::REPLICATION.initial.min(config.gossip_retry),
+                max: config.gossip_retry,
+                attempts: config
+                    .gossip_max_retries
+                    .unwrap_or(Backoff::REPLICATION.attempts),
+            },
+        }
+    }
+
+    /// Applies `txn` in order at a fresh version, sends its writes to every
+    /// peer and returns it with the reads filled in. If the writes can't all
+    /// be sent, nothing is committed and the reason is returned instead.
+    fn execute(
+        &mut self,
+        ctx: &mut Context<TxnBody>,
+        txn: Vec<MicroOp>,
+    ) -> Result<Vec<MicroOp>, String> {
+        let mut snapshot = self.store.begin_snapshot();
+
+        let txn = txn
+            .into_iter()
+            .map(|micro_op| match micro_op {
+                MicroOp::Read { key, .. } => MicroOp::Read {
+                    key,
+                    value: snapshot.read(&key).copied(),
+                },
+                MicroOp::Write { key, value } => {
+                    snapshot.write(key, value);
+                    micro_op
+                }
+            })
+            .collect();
+
+        let writes: BTreeMap<u64, u64> = snapshot.into_writes().into_iter().collect();
+
+        if writes.is_empty() {
+            return Ok(txn);
+        }
+
+        // A write some peer never hears of would leave the nodes apart for
+        // good, so a transaction that can't replicate doesn't commit.
+        if ctx.rpc_room().is_some_and(|room| room < self.peers.len()) {
+            return Err(format!(
+                "{} requests outstanding; can't replicate {} writes",
+                ctx.pending_rpcs(),
+                writes.len()
+            ));
+        }
+
+        self.clock += 1;
+        self.store
+            .commit((self.clock, self.node_id.clone()), writes.clone());
+
+        let writes: Vec<(u64, u64)> = writes.into_iter().collect();
+
+        for peer in &self.peers {
+            ctx.rpc_with(
+                peer,
+                TxnBody::Replicate {
+                    msg_id: 0,
+                    clock: self.clock,
+                    writes: writes.clone(),
+                },
+                self.replication,
+            );
+        }
+
+        Ok(txn)
+    }
+
+    /// Stores writes replicated from `src` at the version they were made.
+    fn apply(&mut self, src: &str, clock: u64, writes: Vec<(u64, u64)>) {
+        self.clock = self.clock.max(clock);
+        self.store.commit((clock, src.to_string()), writes);
+    }
+}
+
+impl Handler for Txn {
+    type Body = TxnBody;
+
+    fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        self.node_id = node_id.to_string();
+        self.peers = node_ids
+            .iter()
+            .filter(|node| *node != node_id)
+            .cloned()
+            .collect();
+    }
+
+    fn handle(&mut self, message: Message<TxnBody>, ctx: &mut Context<TxnBody>) {
+        let body = match &message.body {
+            TxnBody::Txn { msg_id, txn } => match self.execute(ctx, txn.clone()) {
+                Ok(txn) => TxnBody::TxnOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                    txn,
+                },
+                Err(text) => {
+                    ctx.reply_error(&message.src, *msg_id, TEMPORARILY_UNAVAILABLE, text);
+                    return;
+                }
+            },
+
+            TxnBody::Replicate { clock, writes, .. } => {
+                self.apply(&message.src, *clock, writes.clone());
+
+                TxnBody::ReplicateOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                }
+            }
+
+            TxnBody::TxnOk { .. } | TxnBody::ReplicateOk { .. } | TxnBody::Unsupported => return,
+        };
+
+        ctx.reply(&message, body);
+    }
+
+    fn print_summary(&self) {
+        eprintln!("summary: {} keys stored", self.store.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{Control, Envelope},
+        runtime::Runtime,
+    };
+
+    const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3"]}}"#;
+
+    fn txn(msg_id: u32, ops: &str) -> String {
+        format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"txn","msg_id":{},"txn":{}}}}}"#,
+            msg_id, ops
+        )
+    }
+
+    #[test]
+    fn a_retried_txn_is_answered_without_running_again() {
+        let config = Config::default();
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut handler = Txn::new(&config);
+
+        runtime.dispatch(&mut handler, INIT);
+        output.try_iter().for_each(drop);
+
+        let request = txn(2, r#"[["r",1,null],["w",1,5]]"#);
+        runtime.dispatch(&mut handler, &request);
+        runtime.dispatch(&mut handler, &request);
+
+        let sent: Vec<_> = output.try_iter().collect();
+        let answers: Vec<_> = sent
+            .iter()
+            .filter(|message| message.dest == "c1")
+            .map(|message| match &message.body {
+                Envelope::Body(TxnBody::TxnOk {
+                    txn, in_reply_to, ..
+                }) => (*in_reply_to, txn.clone()),
+                other => panic!("expected txn_ok, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0], answers[1]);
+        assert_eq!(
+            answers[0].1[0],
+            MicroOp::Read {
+                key: 1,
+                value: None
+            }
+        );
+
+        // Committed and replicated once.
+        assert_eq!(handler.clock, 1);
+        assert_eq!(
+            sent.iter().filter(|message| message.dest != "c1").count(),
+            2
+        );
+    }
+
+    #[test]
+    fn a_txn_that_cant_replicate_commits_none_of_its_writes() {
+        let config = Config {
+            max_pending_rpcs: Some(2),
+            ..Config::default()
+        };
+        let (mut runtime, output) = Runtime::in_memory(&config);
+        let mut handler = Txn::new(&config);
+
+        runtime.dispatch(&mut handler, INIT);
+
+        // Its replication to both peers fills the cap.
+        runtime.dispatch(&mut handler, &txn(2, r#"[["w",3,1]]"#));
+        output.try_iter().for_each(drop);
+
+        runtime.dispatch(
+            &mut handler,
+            &txn(3, r#"[["w",1,9],["w",2,9],["r",1,null]]"#),
+        );
+
+        let replies: Vec<_> = output.try_iter().collect();
+        assert_eq!(replies.len(), 1);
+        match &replies[0].body {
+            Envelope::Control(Control::Error {
+                in_reply_to, code, ..
+            }) => {
+                assert_eq!(*in_reply_to, 3);
+                assert_eq!(*code, TEMPORARILY_UNAVAILABLE);
+            }
+            other => panic!("expected an error, got {:?}", other),
+        }
+
+        // Neither write was kept, and reads still go through.
+        runtime.dispatch(&mut handler, &txn(4, r#"[["r",1,null],["r",2,null]]"#));
+
+        match &output.try_recv().unwrap().body {
+            Envelope::Body(TxnBody::TxnOk { txn, .. }) => assert_eq!(
+                txn,
+                &vec![
+                    MicroOp::Read {
+                        key: 1,
+                        value: None
+                    },
+                    MicroOp::Read {
+                        key: 2,
+                        value: None
+                    },
+                ]
+            ),
+            other => panic!("expected txn_ok, got {:?}", other),
+        }
+        assert_eq!(handler.store.len(), 1);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{
+    config::{node_rng, Config},
+    ids::{IdFormat, IdGenerator},
+    message::{Body, Message, MALFORMED_REQUEST},
+    runtime::{Context, Handler},
+};
+use serde::{Deserialize, Serialize};
+
+/// Most IDs one `generate` may ask for.
+const MAX_GENERATE_COUNT: usize = 10_000;
+
+/// Message types of the unique-id workload (challenge #2).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UniqueIdsBody {
+    /// Asks for one ID, or `count` of them in a single reply.
+    Generate {
+        msg_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        count: Option<usize>,
+    },
+    /// Carries `id` when no count was asked for, `ids` otherwise.
+    GenerateOk {
+        msg_id: u32,
+        in_reply_to: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ids: Option<Vec<String>>,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+impl Body for UniqueIdsBody {
+    fn kind(&self) -> &'static str {
+        match self {
+            UniqueIdsBody::Generate { .. } => "generate",
+            UniqueIdsBody::GenerateOk { .. } => "generate_ok",
+            UniqueIdsBody::Unsupported => "unsupported",
+        }
+    }
+
+    fn msg_id(&self) -> Option<u32> {
+        match self {
+            UniqueIdsBody::Generate { msg_id, .. } | UniqueIdsBody::GenerateOk { msg_id, .. } => {
+                Some(*msg_id)
+            }
+            UniqueIdsBody::Unsupported => None,
+        }
+    }
+
+    fn in_reply_to(&self) -> Option<u32> {
+        match self {
+            UniqueIdsBody::GenerateOk { in_reply_to, .. } => Some(*in_reply_to),
+            UniqueIdsBody::Generate { .. } | UniqueIdsBody::Unsupported => None,
+        }
+    }
+
+    fn set_msg_id(&mut self, id: u32) {
+        match self {
+            UniqueIdsBody::Generate { msg_id, .. } | UniqueIdsBody::GenerateOk { msg_id, .. } => {
+                *msg_id = id
+            }
+            UniqueIdsBody::Unsupported => {}
+        }
+    }
+
+    fn set_in_reply_to(&mut self, id: u32) {
+        match self {
+            UniqueIdsBody::GenerateOk { in_reply_to, .. } => *in_reply_to = id,
+            UniqueIdsBody::Generate { .. } | UniqueIdsBody::Unsupported => {}
+        }
+    }
+
+    fn is_unsupported(&self) -> bool {
+        matches!(self, UniqueIdsBody::Unsupported)
+    }
+}
+
+/// Handler for the unique-id workload, minting IDs in the configured
+/// [`IdFormat`] without talking to other nodes.
+pub struct UniqueIds {
+    id_format: IdFormat,
+    seed: Option<u64>,
+    /// Made for our node ID at `init`.
+    ids: Box<dyn IdGenerator>,
+}
+
+impl UniqueIds {
+    pub fn new(config: &Config) -> UniqueIds {
+        UniqueIds {
+            id_format: config.id_format,
+            seed: config.seed,
+            ids: config.id_format.generator("", 0, node_rng(config.seed, "")),
+        }
+    }
+}
+
+impl Handler for UniqueIds {
+    type Body = UniqueIdsBody;
+
+    fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        let node_index = node_ids
+            .iter()
+            .position(|node| node == node_id)
+            .unwrap_or_default();
+
+        self.ids =
+            self.id_format
+                .generator(node_id, node_index as u32, node_rng(self.seed, node_id));
+    }
+
+    fn handle(&mut self, message: Message<UniqueIdsBody>, ctx: &mut Context<UniqueIdsBody>) {
+        let UniqueIdsBody::Generate { msg_id, count } = message.body else {
+            return;
+        };
+
+        if count.is_some_and(|count| count > MAX_GENERATE_COUNT) {
+            ctx.reply_error(
+                &message.src,
+                msg_id,
+                MALFORMED_REQUEST,
+                format!("at most {} ids per request", MAX_GENERATE_COUNT),
+            );
+            return;
+        }
+
+        let (id, ids) = match count {
+            Some(count) => (None, Some(self.ids.next_ids(count))),
+            None => (Some(self.ids.next_id()), None),
+        };
+
+        ctx.reply(
+            &message,
+            UniqueIdsBody::GenerateOk {
+                id,
+                ids,
+                msg_id: 0,
+                in_reply_to: 0,
+            },
+        );
+    }
+}
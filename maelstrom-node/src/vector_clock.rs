@@ -0,0 +1,79 @@
+use crate::crdt::Merge;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::BTreeMap};
+
+/// One counter per node, tracking how many of each node's events are known.
+/// Clocks are partially ordered: one happened before another if it is no
+/// greater in every entry, and two clocks neither of which is before the
+/// other are concurrent. Missing entries count as zero.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock {
+    entries: BTreeMap<String, u64>,
+}
+
+impl VectorClock {
+    pub fn new() -> VectorClock {
+        VectorClock::default()
+    }
+
+    pub fn get(&self, node_id: &str) -> u64 {
+        self.entries.get(node_id).copied().unwrap_or_default()
+    }
+
+    pub fn set(&mut self, node_id: &str, value: u64) {
+        // Zero entries are left out so equal clocks compare equal.
+        if value == 0 {
+            self.entries.remove(node_id);
+        } else {
+            self.entries.insert(node_id.to_string(), value);
+        }
+    }
+
+    /// Records a new event at `node_id` and returns its entry's new value.
+    pub fn increment(&mut self, node_id: &str) -> u64 {
+        let entry = self.entries.entry(node_id.to_string()).or_default();
+        *entry += 1;
+        *entry
+    }
+
+    /// Iterates over the non-zero entries.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.entries
+            .iter()
+            .map(|(node_id, value)| (node_id.as_str(), *value))
+    }
+
+    /// Whether every entry is at least the matching entry of `other`.
+    pub fn dominates(&self, other: &VectorClock) -> bool {
+        other
+            .entries
+            .iter()
+            .all(|(node_id, value)| self.get(node_id) >= *value)
+    }
+
+    pub fn concurrent_with(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &VectorClock) -> Option<Ordering> {
+        match (self.dominates(other), other.dominates(self)) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Greater),
+            (false, true) => Some(Ordering::Less),
+            (false, false) => None,
+        }
+    }
+}
+
+impl Merge for VectorClock {
+    /// Takes the larger value of every entry.
+    fn merge(&mut self, other: &Self) {
+        for (node_id, value) in &other.entries {
+            let entry = self.entries.entry(node_id.clone()).or_default();
+            *entry = (*entry).max(*value);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Broadcasts made on both sides of a partition reach all five nodes once it
+//! heals.
+
+use maelstrom_node::{sim::Simulator, Config, Node};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn read(simulator: &mut Simulator<Node>, node: &str) -> Vec<Value> {
+    let reply = simulator
+        .request(node, json!({"type": "read"}), TIMEOUT)
+        .expect("read_ok");
+
+    serde_json::from_value(reply["messages"].clone()).expect("messages")
+}
+
+#[test]
+fn broadcast_converges_after_a_partition_heals() {
+    let config = Config {
+        tick_interval: Duration::from_millis(10),
+        gossip_retry: Duration::from_millis(100),
+        ..Config::default()
+    };
+
+    let mut simulator = Simulator::new(5, &config, Node::new);
+    let nodes = simulator.node_ids();
+    let topology: serde_json::Map<String, Value> = nodes
+        .iter()
+        .map(|node| {
+            let peers: Vec<&String> = nodes.iter().filter(|peer| *peer != node).collect();
+            (node.clone(), json!(peers))
+        })
+        .collect();
+    for node in &nodes {
+        simulator.send(node, json!({"type": "topology", "topology": topology}));
+    }
+
+    simulator.send("n0", json!({"type": "broadcast", "message": 0}));
+    simulator.run_for(Duration::from_millis(100));
+
+    simulator.partition(&["n0", "n1"], &["n2", "n3", "n4"]);
+    for value in 1..5 {
+        simulator.send("n1", json!({"type": "broadcast", "message": value}));
+    }
+    for value in 5..10 {
+        simulator.send("n3", json!({"type": "broadcast", "message": value}));
+    }
+    simulator.run_for(Duration::from_millis(300));
+
+    // Each side has only its own writes, plus what went out before.
+    assert!(!read(&mut simulator, "n0").contains(&json!(5)));
+    assert!(!read(&mut simulator, "n4").contains(&json!(1)));
+
+    simulator.heal();
+
+    let everything: Vec<Value> = (0..10).map(Value::from).collect();
+    let converged = simulator.run_until(TIMEOUT, |simulator| {
+        nodes.iter().all(|node| read(simulator, node) == everything)
+    });
+    assert!(converged);
+}
@@ -0,0 +1,79 @@
+//! Adds made on every node of a g-counter backed by a mock `seq-kv` add up to
+//! the same total everywhere.
+
+use maelstrom_node::{kv_counter::KvCounter, mock_kv::MockKv, sim::Simulator, Config};
+use serde_json::json;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn read(simulator: &mut Simulator<KvCounter>, node: &str) -> Option<i64> {
+    simulator
+        .request(node, json!({"type": "read"}), TIMEOUT)?
+        .get("value")?
+        .as_i64()
+}
+
+#[test]
+fn counter_converges_against_mock_kv() {
+    let config = Config {
+        tick_interval: Duration::from_millis(10),
+        ..Config::default()
+    };
+
+    let mut simulator = Simulator::new(3, &config, KvCounter::new);
+    simulator.add_service(
+        "seq-kv",
+        MockKv::new().with_latency(Duration::from_millis(5)),
+    );
+    let nodes = simulator.node_ids();
+
+    let mut total = 0;
+    for round in 1..=4 {
+        for node in &nodes {
+            let reply = simulator.request(node, json!({"type": "add", "delta": round}), TIMEOUT);
+            assert_eq!(reply.expect("add_ok")["type"], "add_ok");
+            total += round;
+        }
+    }
+
+    let converged = simulator.run_until(TIMEOUT, |simulator| {
+        nodes
+            .iter()
+            .all(|node| read(simulator, node) == Some(total))
+    });
+    assert!(converged);
+}
+
+#[test]
+fn a_retried_add_is_counted_once() {
+    let config = Config {
+        tick_interval: Duration::from_millis(10),
+        ..Config::default()
+    };
+
+    let mut simulator = Simulator::new(3, &config, KvCounter::new);
+    simulator.add_service("seq-kv", MockKv::new());
+    let nodes = simulator.node_ids();
+
+    let add = json!({"type": "add", "delta": 5});
+    let msg_id = simulator.send("n0", add.clone());
+    assert!(simulator.run_until(TIMEOUT, |simulator| simulator.reply_to(msg_id).is_some()));
+
+    // The reply was lost, so the client sends the same request again.
+    simulator.resend("n0", msg_id, add);
+    simulator.run_for(Duration::from_millis(100));
+
+    let replies: Vec<_> = simulator
+        .replies()
+        .iter()
+        .filter(|reply| reply.body["in_reply_to"] == msg_id)
+        .map(|reply| reply.body["type"].clone())
+        .collect();
+    assert_eq!(replies, ["add_ok", "add_ok"]);
+
+    let converged = simulator.run_until(TIMEOUT, |simulator| {
+        nodes.iter().all(|node| read(simulator, node) == Some(5))
+    });
+    assert!(converged);
+}
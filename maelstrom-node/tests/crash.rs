@@ -0,0 +1,63 @@
+//! A broadcast node crashed mid-run comes back from its snapshot and catches
+//! up on what it missed.
+
+use maelstrom_node::{sim::Simulator, Config, Node};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn read(simulator: &mut Simulator<Node>, node: &str) -> Vec<Value> {
+    let reply = simulator
+        .request(node, json!({"type": "read"}), TIMEOUT)
+        .expect("read_ok");
+
+    serde_json::from_value(reply["messages"].clone()).expect("messages")
+}
+
+#[test]
+fn crashed_node_restores_and_catches_up() {
+    let snapshot_dir = std::env::temp_dir().join(format!("crash-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&snapshot_dir);
+
+    let config = Config {
+        snapshot_dir: Some(snapshot_dir.to_string_lossy().into_owned()),
+        tick_interval: Duration::from_millis(10),
+        snapshot_interval: Duration::from_millis(20),
+        sync_interval: Some(Duration::from_millis(50)),
+        ..Config::default()
+    };
+
+    let mut simulator = Simulator::new(3, &config, Node::new);
+    let topology = json!({"n0": ["n1", "n2"], "n1": ["n0", "n2"], "n2": ["n0", "n1"]});
+    for node in simulator.node_ids() {
+        simulator.send(&node, json!({"type": "topology", "topology": topology}));
+    }
+
+    for value in 0..5 {
+        simulator.send("n0", json!({"type": "broadcast", "message": value}));
+    }
+
+    let before: Vec<Value> = (0..5).map(Value::from).collect();
+    assert!(simulator.run_until(TIMEOUT, |simulator| read(simulator, "n2") == before));
+
+    // Long enough for a snapshot to have been written since.
+    simulator.run_for(Duration::from_millis(50));
+    simulator.crash("n2");
+
+    for value in 5..10 {
+        simulator.send("n1", json!({"type": "broadcast", "message": value}));
+    }
+    simulator.run_for(Duration::from_millis(50));
+
+    simulator.restart("n2");
+
+    // Straight back from disk, before anyone has gossiped to it.
+    let restored = read(&mut simulator, "n2");
+    assert!(before.iter().all(|value| restored.contains(value)));
+
+    let everything: Vec<Value> = (0..10).map(Value::from).collect();
+    assert!(simulator.run_until(TIMEOUT, |simulator| read(simulator, "n2") == everything));
+
+    let _ = std::fs::remove_dir_all(&snapshot_dir);
+}
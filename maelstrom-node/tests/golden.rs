@@ -0,0 +1,60 @@
+//! Recorded stdin/stdout pairs under `tests/golden/`, replayed against the
+//! handlers that produced them. Each `<name>.in.jsonl` is what Maelstrom sent
+//! and `<name>.out.jsonl` what the node answered, so a reply that goes to the
+//! wrong request shows up as a mismatch.
+
+use maelstrom_node::{
+    echo::Echo,
+    replay::{self, ReplayError},
+    Config, Node,
+};
+
+const BROADCAST_IN: &str = include_str!("golden/broadcast.in.jsonl");
+const BROADCAST_OUT: &str = include_str!("golden/broadcast.out.jsonl");
+const ECHO_IN: &str = include_str!("golden/echo.in.jsonl");
+const ECHO_OUT: &str = include_str!("golden/echo.out.jsonl");
+
+fn config() -> Config {
+    Config {
+        reply_unsupported: true,
+        ..Config::default()
+    }
+}
+
+#[test]
+fn broadcast_replays_its_golden_log() {
+    let config = config();
+    let result = replay::check(
+        &config,
+        &mut Node::new(&config),
+        BROADCAST_IN,
+        BROADCAST_OUT,
+    );
+
+    if let Err(error) = result {
+        panic!("{}", error);
+    }
+}
+
+#[test]
+fn echo_replays_its_golden_log() {
+    let config = config();
+    let result = replay::check(&config, &mut Echo::new(), ECHO_IN, ECHO_OUT);
+
+    if let Err(error) = result {
+        panic!("{}", error);
+    }
+}
+
+#[test]
+fn a_reply_to_the_wrong_request_is_caught() {
+    let config = config();
+    let expected = ECHO_OUT.replace(r#""in_reply_to":3"#, r#""in_reply_to":2"#);
+    let result = replay::check(&config, &mut Echo::new(), ECHO_IN, &expected);
+
+    assert!(
+        matches!(result, Err(ReplayError::Mismatch { index: 3, .. })),
+        "{:?}",
+        result
+    );
+}
@@ -0,0 +1,99 @@
+//! A message sent through one node of the Kafka-style log can be polled from
+//! another.
+
+use maelstrom_node::{kafka::Kafka, mock_kv::MockKv, sim::Simulator, Config};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn poll(simulator: &mut Simulator<Kafka>, node: &str, key: &str) -> Vec<Value> {
+    let reply = simulator
+        .request(node, json!({"type": "poll", "offsets": {key: 0}}), TIMEOUT)
+        .expect("poll_ok");
+
+    serde_json::from_value(reply["msgs"][key].clone()).unwrap_or_default()
+}
+
+#[test]
+fn a_send_on_one_node_is_polled_on_another() {
+    let config = Config {
+        tick_interval: Duration::from_millis(10),
+        peer_timeout: Duration::from_millis(50),
+        ..Config::default()
+    };
+
+    let mut simulator = Simulator::new(3, &config, Kafka::new);
+    simulator.add_service("lin-kv", MockKv::new());
+
+    let mut offsets = Vec::new();
+    for (msg, node) in ["n1", "n0", "n2"].into_iter().enumerate() {
+        let reply = simulator
+            .request(
+                node,
+                json!({"type": "send", "key": "k", "msg": msg}),
+                TIMEOUT,
+            )
+            .expect("send_ok");
+        offsets.push(reply["offset"].clone());
+    }
+
+    let expected: Vec<Value> = offsets
+        .iter()
+        .enumerate()
+        .map(|(msg, offset)| json!([offset, msg]))
+        .collect();
+
+    for node in ["n0", "n2"] {
+        let caught_up =
+            simulator.run_until(TIMEOUT, |simulator| poll(simulator, node, "k") == expected);
+        assert!(
+            caught_up,
+            "{} polled {:?}",
+            node,
+            poll(&mut simulator, node, "k")
+        );
+    }
+}
+
+#[test]
+fn a_retried_send_is_appended_once() {
+    let config = Config {
+        tick_interval: Duration::from_millis(10),
+        peer_timeout: Duration::from_millis(50),
+        ..Config::default()
+    };
+
+    let mut simulator = Simulator::new(3, &config, Kafka::new);
+    simulator.add_service("lin-kv", MockKv::new());
+
+    let send = json!({"type": "send", "key": "k", "msg": "once"});
+    let msg_id = simulator.send("n1", send.clone());
+    assert!(simulator.run_until(TIMEOUT, |simulator| simulator.reply_to(msg_id).is_some()));
+
+    // The reply was lost, so the client sends the same request again.
+    simulator.resend("n1", msg_id, send);
+    simulator.run_for(Duration::from_millis(100));
+
+    let offsets: Vec<Value> = simulator
+        .replies()
+        .iter()
+        .filter(|reply| reply.body["in_reply_to"] == msg_id)
+        .map(|reply| reply.body["offset"].clone())
+        .collect();
+    assert_eq!(offsets, [json!(0), json!(0)]);
+
+    let next = simulator
+        .request(
+            "n1",
+            json!({"type": "send", "key": "k", "msg": "next"}),
+            TIMEOUT,
+        )
+        .expect("send_ok");
+    assert_eq!(next["offset"], 1);
+
+    let caught_up = simulator.run_until(TIMEOUT, |simulator| {
+        poll(simulator, "n2", "k") == [json!([0, "once"]), json!([1, "next"])]
+    });
+    assert!(caught_up, "n2 polled {:?}", poll(&mut simulator, "n2", "k"));
+}